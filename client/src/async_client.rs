@@ -0,0 +1,555 @@
+//! [`AsyncClient`], the same transfers as [`Client`](crate::Client) on tokio.
+//!
+//! Bodies are streamed through a pipe between the tar builder and the request,
+//! nothing is buffered beyond that. Dropping a future drops the request with
+//! it, which closes the connection, so the server never marks a cancelled
+//! upload as finished.
+
+use std::{
+    fs::Permissions,
+    io,
+    os::unix::prelude::PermissionsExt,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use anyhow::Context as _;
+use common::{AsyncEncryptedReader, AsyncEncryptedWriter, TarPassword};
+use filetime::FileTime;
+use futures_util::{StreamExt, TryStreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::{
+    alias_body, alias_link, alias_url, raw_url,
+    receive::compare_checksums,
+    send::{self, Plan, Walk, TAR_HEADER_SIZE},
+    share_url, status_url, OnDuplicate, Progress, Protocol, ReceiveResult, SendResult, UploadInfo,
+};
+
+/// Size of the pipe between the tar builder and the request body.
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// Called with the progress of a transfer, from the task running it.
+pub type ProgressHook = Arc<dyn Fn(Progress) + Send + Sync>;
+
+#[derive(Default, Clone)]
+pub struct AsyncSendOptions {
+    /// Generated when not given.
+    pub code: Option<TarPassword>,
+    pub on_duplicate: OnDuplicate,
+    /// Check afterwards that the server stored the whole upload.
+    pub verify_upload: bool,
+    /// See [`SendOptions::follow_symlinks`](crate::SendOptions::follow_symlinks).
+    pub follow_symlinks: bool,
+    pub max_depth: Option<usize>,
+    pub sort_files: bool,
+    pub progress: Option<ProgressHook>,
+}
+
+impl AsyncSendOptions {
+    pub fn with_progress(mut self, progress: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct AsyncReceiveOptions {
+    /// Replace existing files, otherwise they are skipped.
+    pub overwrite: bool,
+    /// Check the extracted files against the checksums of the server afterwards.
+    pub verify: bool,
+    /// Set the modification times from the archive, on by default.
+    pub preserve_mtime: bool,
+    pub progress: Option<ProgressHook>,
+}
+
+impl Default for AsyncReceiveOptions {
+    fn default() -> Self {
+        Self {
+            overwrite: false,
+            verify: false,
+            preserve_mtime: true,
+            progress: None,
+        }
+    }
+}
+
+impl AsyncReceiveOptions {
+    pub fn with_progress(mut self, progress: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+}
+
+/// Counts bytes for the progress hook of a transfer.
+struct Tracker {
+    done: u64,
+    total: u64,
+    hook: Option<ProgressHook>,
+}
+
+impl Tracker {
+    fn add(&mut self, n: u64, path: &str) {
+        self.done += n;
+        if let Some(hook) = &self.hook {
+            hook(Progress {
+                done: self.done,
+                total: self.total,
+                path,
+            });
+        }
+    }
+}
+
+struct TrackedReader<'a, R> {
+    tracker: &'a mut Tracker,
+    path: &'a str,
+    inner: R,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TrackedReader<'_, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - before;
+        if n > 0 {
+            this.tracker.add(n as u64, this.path);
+        }
+        result
+    }
+}
+
+/// Hashes what is written through it, the plaintext of an upload.
+struct HashWriter<W> {
+    inner: W,
+    hasher: blake3::Hasher,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            this.hasher.update(&buf[..n]);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Like [`Client`](crate::Client), for tokio. Sending over websockets is not
+/// supported, use the blocking client for `ws://` and `wss://`.
+#[derive(Clone)]
+pub struct AsyncClient {
+    http: reqwest::Client,
+    host: String,
+    protocol: Protocol,
+    token: Option<String>,
+}
+
+impl AsyncClient {
+    pub fn new(host: impl Into<String>, protocol: Protocol, token: Option<String>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            host: host.into(),
+            protocol,
+            token,
+        }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// The link to share, opens the file list in a browser.
+    pub fn share_url(&self, code: &TarPassword) -> String {
+        share_url(self.protocol, &self.host, code)
+    }
+
+    /// Where the encrypted upload is stored, the server never sees the code.
+    pub fn raw_url(&self, code: &TarPassword) -> String {
+        raw_url(self.protocol, &self.host, code)
+    }
+
+    fn token(&self) -> anyhow::Result<&str> {
+        self.token
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("No token specified."))
+    }
+
+    /// Sends `paths` as one tar archive, see [`Client::send`](crate::Client::send).
+    pub async fn send(
+        &self,
+        paths: &[PathBuf],
+        options: AsyncSendOptions,
+    ) -> anyhow::Result<SendResult> {
+        let AsyncSendOptions {
+            code,
+            on_duplicate,
+            verify_upload,
+            follow_symlinks,
+            max_depth,
+            sort_files,
+            progress,
+        } = options;
+        let token = self.token()?;
+        if self.protocol.is_websocket() {
+            anyhow::bail!("{}:// uploads need the blocking client.", self.protocol);
+        }
+
+        let paths = paths.to_vec();
+        let Plan {
+            entries,
+            skipped,
+            skipped_links,
+            total_size,
+            encrypted_size,
+        } = tokio::task::spawn_blocking(move || {
+            let walk = Walk {
+                follow_symlinks,
+                max_depth,
+                sort_files,
+            };
+            send::plan(&paths, on_duplicate, walk)
+        })
+        .await??;
+
+        let code = code.unwrap_or_else(TarPassword::generate);
+        let url = self.raw_url(&code);
+
+        let (writer, reader) = tokio::io::duplex(PIPE_SIZE);
+        let writer = HashWriter {
+            inner: AsyncEncryptedWriter::new(writer, code.to_string().as_bytes()),
+            hasher: blake3::Hasher::new(),
+        };
+
+        let upload = async {
+            let response = self
+                .http
+                .post(&url)
+                .bearer_auth(token)
+                .header("Content-Length", encrypted_size)
+                .header("Accept", "application/json")
+                .body(reqwest::Body::wrap_stream(ReaderStream::new(reader)))
+                .send()
+                .await
+                .context("Failed to send request.")?;
+            let response = check_status(response).await?;
+            let upload_id = response
+                .headers()
+                .get("X-Upload-Id")
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string);
+            Ok::<_, anyhow::Error>((response.text().await?, upload_id))
+        };
+
+        let archive = async {
+            let mut tracker = Tracker {
+                done: 0,
+                total: total_size,
+                hook: progress,
+            };
+            let mut sent_files = vec![];
+            let mut sent_bytes = 0;
+
+            let mut tar = tokio_tar::Builder::new_non_terminated(writer);
+            for (src_path, p, size, is_dir) in entries {
+                let mut header = tokio_tar::Header::new_gnu();
+                header.set_path(&p)?;
+
+                let display = src_path.display().to_string();
+                tracker.add(TAR_HEADER_SIZE as _, &display);
+                if is_dir {
+                    // Extracted as a file otherwise, and unwritable without a mode.
+                    let mode = tokio::fs::metadata(&src_path).await?.permissions().mode();
+                    header.set_entry_type(tokio_tar::EntryType::Directory);
+                    header.set_mode(mode);
+                    header.set_size(0);
+                    header.set_cksum();
+                    tar.append(&header, tokio::io::empty()).await?;
+                } else {
+                    let file = tokio::fs::File::open(&src_path).await?;
+                    let metadata = file.metadata().await?;
+                    let time = metadata.modified()?;
+                    header.set_size(size as u64);
+                    header.set_mode(metadata.permissions().mode());
+                    header.set_mtime(time.duration_since(std::time::UNIX_EPOCH)?.as_secs());
+                    header.set_cksum();
+                    let reader = TrackedReader {
+                        tracker: &mut tracker,
+                        path: &display,
+                        inner: file,
+                    };
+                    tar.append(&header, reader).await?;
+                    sent_files.push(p);
+                    sent_bytes += size as u64;
+                }
+            }
+
+            let mut writer = tar.into_inner().await?;
+            // Writes the last block and ends the body.
+            writer.shutdown().await?;
+            let hash = *writer.hasher.finalize().as_bytes();
+            Ok::<_, anyhow::Error>((sent_files, sent_bytes, hash))
+        };
+
+        // Either side failing drops the other, which aborts the request.
+        let ((response, upload_id), (files, bytes, hash)) = tokio::try_join!(upload, archive)?;
+
+        if verify_upload {
+            self.verify_stored(&url, encrypted_size).await?;
+        }
+
+        Ok(SendResult {
+            url: self.share_url(&code),
+            code,
+            bytes,
+            files,
+            skipped,
+            skipped_links,
+            expires_at: send::expires_at(&response),
+            upload_id,
+            plaintext_hash: Some(hash),
+        })
+    }
+
+    async fn verify_stored(&self, url: &str, expected: u64) -> anyhow::Result<()> {
+        let response = self
+            .http
+            .head(url)
+            .send()
+            .await
+            .context("Failed to check the upload.")?;
+        let headers = response.headers();
+        let stored = headers
+            .get("X-Toc-Stored-Length")
+            .or_else(|| headers.get("Content-Length"))
+            .and_then(|v| v.to_str().ok()?.parse::<u64>().ok())
+            .ok_or_else(|| anyhow::anyhow!("Server did not report the stored size."))?;
+        send::check_stored(stored, expected)
+    }
+
+    /// Extracts an upload into `destination`, which has to exist.
+    pub async fn receive(
+        &self,
+        code: &TarPassword,
+        destination: &Path,
+        options: AsyncReceiveOptions,
+    ) -> anyhow::Result<ReceiveResult> {
+        let response = self.http.get(self.raw_url(code)).send().await?;
+        let response = check_status(response).await?;
+
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let is_text = header("Content-Type").is_some_and(|v| v.trim().starts_with("text/plain"));
+        let format = header("X-Toc-Format");
+
+        // Messages like "Upload not finished yet" would only fail to decrypt.
+        if is_text {
+            let message = response.text().await?;
+            anyhow::bail!(
+                "Server answered with a message instead of the upload, check the code: {}",
+                message.trim()
+            );
+        }
+        // Uploads of the old server, only the server decrypts those.
+        if format.as_deref() == Some("age") {
+            anyhow::bail!(
+                "This upload is in an old format, download it with: curl '{}' | tar -xkvf -",
+                self.share_url(code)
+            );
+        }
+        let content_length = response.content_length().unwrap_or(0);
+
+        let body = response.bytes_stream().map_err(io::Error::other);
+        let reader =
+            AsyncEncryptedReader::new(StreamReader::new(body), code.to_string().as_bytes());
+        let mut tar = tokio_tar::Archive::new(reader);
+
+        let mut tracker = Tracker {
+            done: 0,
+            total: content_length,
+            hook: options.progress,
+        };
+        let mut result = ReceiveResult::default();
+
+        let mut buf = vec![0; 128 * 1024];
+        let mut entries = tar.entries()?;
+        while let Some(entry) = entries.next().await {
+            let mut file = entry?;
+            let display = file.path()?.display().to_string();
+            let file_destination = destination.join(file.path()?);
+
+            if content_length == 0 {
+                tracker.total += 512;
+                tracker.total += file.header().size().unwrap_or(0);
+            }
+            tracker.add(512, &display);
+
+            if display == "./" || display == "." {
+                // Current directory does not need to be created
+                continue;
+            }
+
+            if tokio::fs::try_exists(&file_destination).await? && !options.overwrite {
+                loop {
+                    let n = file.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    tracker.add(n as u64, &display);
+                }
+                result.skipped.push(display);
+                continue;
+            }
+
+            let perm = file.header().mode().unwrap_or(0o644);
+            if file.header().entry_type().is_dir() {
+                tokio::fs::create_dir_all(&file_destination).await?;
+                tokio::fs::set_permissions(&file_destination, Permissions::from_mode(perm)).await?;
+            } else if file.header().entry_type().is_file() {
+                let mut open = tokio::fs::OpenOptions::new();
+                if options.overwrite {
+                    open.write(true).create(true).truncate(true);
+                } else {
+                    open.write(true).create_new(true);
+                }
+                let mut new_file = open.open(&file_destination).await.with_context(|| {
+                    format!("Failed to create file {}", file_destination.display())
+                })?;
+
+                loop {
+                    let n = file.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    new_file.write_all(&buf[..n]).await?;
+                    tracker.add(n as u64, &display);
+                    result.bytes += n as u64;
+                }
+                new_file.flush().await?;
+                drop(new_file);
+
+                if options.preserve_mtime {
+                    if let Ok(mtime) = file.header().mtime() {
+                        let mtime = FileTime::from_unix_time(mtime as i64, 0);
+                        filetime::set_file_mtime(&file_destination, mtime)?;
+                    }
+                }
+                result.files.push(display);
+            }
+        }
+
+        if options.verify {
+            result.verified = self.verify_checksums(code, destination).await?;
+        }
+        Ok(result)
+    }
+
+    async fn verify_checksums(
+        &self,
+        code: &TarPassword,
+        destination: &Path,
+    ) -> anyhow::Result<usize> {
+        let url = format!("{}sha256", self.share_url(code));
+        let response = self
+            .http
+            .get(url)
+            .send()
+            .await
+            .context("Failed to fetch checksums.")?;
+        let checksums = check_status(response)
+            .await
+            .context("Failed to fetch checksums.")?
+            .text()
+            .await?;
+
+        let destination = destination.to_path_buf();
+        tokio::task::spawn_blocking(move || compare_checksums(&checksums, &destination)).await?
+    }
+
+    /// Deletes an upload of the user of the token.
+    pub async fn delete(&self, code: &TarPassword) -> anyhow::Result<()> {
+        let response = self
+            .http
+            .delete(self.share_url(code))
+            .bearer_auth(self.token()?)
+            .header("Accept", "application/json")
+            .send()
+            .await?;
+        check_status(response).await?;
+        Ok(())
+    }
+
+    /// Whether an upload exists and how far it is, without downloading it.
+    pub async fn info(&self, code: &TarPassword) -> anyhow::Result<UploadInfo> {
+        let response = self
+            .http
+            .get(status_url(self.protocol, &self.host, code))
+            .send()
+            .await?;
+        let body = check_status(response).await?.text().await?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    /// Short link to an upload, see the blocking client.
+    pub async fn alias(&self, code: &TarPassword, slug: Option<&str>) -> anyhow::Result<String> {
+        let response = self
+            .http
+            .post(alias_url(self.protocol, &self.host, code))
+            .bearer_auth(self.token()?)
+            .header("Accept", "application/json")
+            .header("Content-Type", "application/json")
+            .body(alias_body(code, slug))
+            .send()
+            .await?;
+        let body = check_status(response).await?.text().await?;
+        alias_link(&body)
+    }
+}
+
+/// Same messages as the blocking client.
+async fn check_status(response: reqwest::Response) -> anyhow::Result<reqwest::Response> {
+    let status = response.status();
+    if status == reqwest::StatusCode::NOT_FOUND {
+        anyhow::bail!("Upload not found.");
+    }
+    if status.is_client_error() || status.is_server_error() {
+        let message = response.text().await.unwrap_or_default();
+        anyhow::bail!(
+            "Server returned status code: {}\n{}",
+            status.as_u16(),
+            message
+        );
+    }
+    Ok(response)
+}
@@ -0,0 +1,283 @@
+//! Blocking client for tarcloud, what `toc` is built on.
+//!
+//! ```no_run
+//! use piper_client::{Client, Protocol, ReceiveOptions, SendOptions};
+//!
+//! let client = Client::new("example.com", Protocol::Https, Some("token".to_string()));
+//! let sent = client.send(&["notes".into()], SendOptions::default())?;
+//! println!("{}", sent.url);
+//!
+//! client.receive(&sent.code, "out".as_ref(), ReceiveOptions::default())?;
+//! # anyhow::Ok(())
+//! ```
+//!
+//! With the `async` feature, `AsyncClient` does the same on tokio.
+
+use std::{
+    fmt::Display,
+    io::{Read, Write},
+};
+
+use common::{TarHash, TarPassword};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "async")]
+mod async_client;
+mod receive;
+mod send;
+mod ws;
+
+#[cfg(feature = "async")]
+pub use async_client::{AsyncClient, AsyncReceiveOptions, AsyncSendOptions, ProgressHook};
+pub use receive::{Download, ReceiveOptions, ReceiveResult};
+pub use send::{OnDuplicate, SendOptions, SendResult};
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Protocol {
+    #[default]
+    Https,
+    Http,
+    /// Sends over a websocket, downloads still use https.
+    Wss,
+    Ws,
+}
+
+impl Protocol {
+    pub fn is_websocket(self) -> bool {
+        matches!(self, Protocol::Wss | Protocol::Ws)
+    }
+
+    /// For plain requests, websockets are served on the same port.
+    pub fn http(self) -> Protocol {
+        match self {
+            Protocol::Https | Protocol::Wss => Protocol::Https,
+            Protocol::Http | Protocol::Ws => Protocol::Http,
+        }
+    }
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protocol::Https => write!(f, "https"),
+            Protocol::Http => write!(f, "http"),
+            Protocol::Wss => write!(f, "wss"),
+            Protocol::Ws => write!(f, "ws"),
+        }
+    }
+}
+
+/// How far a transfer is, `path` is the file being sent or extracted.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress<'a> {
+    pub done: u64,
+    pub total: u64,
+    pub path: &'a str,
+}
+
+/// Counts bytes for the progress callback of a transfer.
+struct Tracker<'a> {
+    done: u64,
+    total: u64,
+    callback: Option<&'a mut dyn FnMut(Progress)>,
+}
+
+impl<'a> Tracker<'a> {
+    fn new(total: u64, callback: Option<&'a mut dyn FnMut(Progress)>) -> Self {
+        Self {
+            done: 0,
+            total,
+            callback,
+        }
+    }
+
+    fn add(&mut self, n: u64, path: &str) {
+        self.done += n;
+        if let Some(callback) = &mut self.callback {
+            callback(Progress {
+                done: self.done,
+                total: self.total,
+                path,
+            });
+        }
+    }
+}
+
+struct TrackedReader<'a, 'b, R> {
+    tracker: &'a mut Tracker<'b>,
+    path: &'a str,
+    inner: R,
+}
+
+impl<R: Read> Read for TrackedReader<'_, '_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.tracker.add(n as u64, self.path);
+        Ok(n)
+    }
+}
+
+/// State of an upload, as `/api/v1/status/{code}/` reports it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UploadInfo {
+    pub exists: bool,
+    #[serde(default)]
+    pub finished: bool,
+    /// Stored size, encrypted.
+    #[serde(default)]
+    pub size_bytes: u64,
+    /// Files in the archive, `None` for older servers and uploads that are
+    /// no archive or not finished.
+    #[serde(default)]
+    pub file_count: Option<u64>,
+    /// Size of these files.
+    #[serde(default)]
+    pub content_bytes: Option<u64>,
+    /// RFC 3339.
+    #[serde(default)]
+    pub created_at: Option<String>,
+    #[serde(default)]
+    pub expires_at: Option<String>,
+}
+
+/// One server and the token to upload with. Receiving works without a token.
+#[derive(Clone)]
+pub struct Client {
+    agent: ureq::Agent,
+    host: String,
+    protocol: Protocol,
+    token: Option<String>,
+}
+
+impl Client {
+    pub fn new(host: impl Into<String>, protocol: Protocol, token: Option<String>) -> Self {
+        Self {
+            agent: ureq::agent(),
+            host: host.into(),
+            protocol,
+            token,
+        }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// The link to share, opens the file list in a browser.
+    pub fn share_url(&self, code: &TarPassword) -> String {
+        share_url(self.protocol, &self.host, code)
+    }
+
+    /// Where the encrypted upload is stored, the server never sees the code.
+    pub fn raw_url(&self, code: &TarPassword) -> String {
+        raw_url(self.protocol, &self.host, code)
+    }
+
+    fn token(&self) -> anyhow::Result<&str> {
+        self.token
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("No token specified."))
+    }
+
+    /// Deletes an upload of the user of the token.
+    pub fn delete(&self, code: &TarPassword) -> anyhow::Result<()> {
+        self.agent
+            .delete(&self.share_url(code))
+            .set("Authorization", &format!("Bearer {}", self.token()?))
+            .set("Accept", "application/json")
+            .call()
+            .map_err(status_error)?;
+        Ok(())
+    }
+
+    /// Whether an upload exists and how far it is, without downloading it.
+    pub fn info(&self, code: &TarPassword) -> anyhow::Result<UploadInfo> {
+        let body = self
+            .agent
+            .get(&status_url(self.protocol, &self.host, code))
+            .call()
+            .map_err(status_error)?
+            .into_string()?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    /// Short link to an upload of the user of the token, `slug` or a random
+    /// one. The server keeps the code to redirect to the upload, anyone who
+    /// can read its data can then open it.
+    pub fn alias(&self, code: &TarPassword, slug: Option<&str>) -> anyhow::Result<String> {
+        let body = self
+            .agent
+            .post(&alias_url(self.protocol, &self.host, code))
+            .set("Authorization", &format!("Bearer {}", self.token()?))
+            .set("Accept", "application/json")
+            .set("Content-Type", "application/json")
+            .send_string(&alias_body(code, slug))
+            .map_err(status_error)?
+            .into_string()?;
+        alias_link(&body)
+    }
+
+    /// Encrypts like an upload, for storing it somewhere else.
+    pub fn encrypt_stream(
+        mut input: impl Read,
+        output: impl Write,
+        code: &TarPassword,
+    ) -> std::io::Result<u64> {
+        let mut writer = common::EncryptedWriter::new(output, code.to_string().as_bytes());
+        std::io::copy(&mut input, &mut writer)
+    }
+
+    pub fn decrypt_stream(
+        input: impl Read,
+        mut output: impl Write,
+        code: &TarPassword,
+    ) -> std::io::Result<u64> {
+        let mut reader = common::EncryptedReader::new(input, code.to_string().as_bytes());
+        std::io::copy(&mut reader, &mut output)
+    }
+}
+
+fn share_url(protocol: Protocol, host: &str, code: &TarPassword) -> String {
+    format!("{}://{}/{}/", protocol.http(), host, code)
+}
+
+fn raw_url(protocol: Protocol, host: &str, code: &TarPassword) -> String {
+    let hash = TarHash::from_tarid(code, host);
+    format!("{}://{}/raw/{}/", protocol.http(), host, hash)
+}
+
+fn status_url(protocol: Protocol, host: &str, code: &TarPassword) -> String {
+    format!("{}://{}/api/v1/status/{}/", protocol.http(), host, code)
+}
+
+fn alias_url(protocol: Protocol, host: &str, code: &TarPassword) -> String {
+    let hash = TarHash::from_tarid(code, host);
+    format!("{}://{}/api/uploads/{}/alias", protocol.http(), host, hash)
+}
+
+fn alias_body(code: &TarPassword, slug: Option<&str>) -> String {
+    serde_json::json!({ "code": code.to_string(), "slug": slug }).to_string()
+}
+
+fn alias_link(body: &str) -> anyhow::Result<String> {
+    let json: serde_json::Value = serde_json::from_str(body)?;
+    json["url"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow::anyhow!("Server answered without a link."))
+}
+
+fn status_error(e: ureq::Error) -> anyhow::Error {
+    match e {
+        ureq::Error::Status(404, _) => anyhow::anyhow!("Upload not found."),
+        ureq::Error::Status(code, response) => {
+            let message = response.into_string().unwrap_or_default();
+            anyhow::anyhow!("Server returned status code: {}\n{}", code, message)
+        }
+        e => e.into(),
+    }
+}
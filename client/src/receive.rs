@@ -0,0 +1,239 @@
+use std::{
+    fs::Permissions,
+    io::{Read, Write},
+    os::unix::prelude::PermissionsExt,
+    path::Path,
+};
+
+use anyhow::Context;
+use common::{sanitize_entry_path, EncryptedReader, TarPassword};
+use filetime::FileTime;
+
+use crate::{status_error, Client, Progress, Tracker};
+
+pub struct ReceiveOptions<'a> {
+    /// Replace existing files, otherwise they are skipped.
+    pub overwrite: bool,
+    /// Check the extracted files against the checksums of the server afterwards.
+    pub verify: bool,
+    /// Set the modification times from the archive, on by default.
+    pub preserve_mtime: bool,
+    pub progress: Option<&'a mut dyn FnMut(Progress)>,
+}
+
+impl Default for ReceiveOptions<'_> {
+    fn default() -> Self {
+        Self {
+            overwrite: false,
+            verify: false,
+            preserve_mtime: true,
+            progress: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReceiveResult {
+    /// Paths of the extracted files.
+    pub files: Vec<String>,
+    pub bytes: u64,
+    /// Paths that already existed.
+    pub skipped: Vec<String>,
+    /// Files checked with `ReceiveOptions::verify`.
+    pub verified: usize,
+}
+
+/// The decrypted tar stream of an upload.
+pub struct Download {
+    reader: EncryptedReader<Box<dyn Read + Send + Sync>>,
+    /// Of the encrypted upload, 0 when the server did not tell.
+    pub content_length: u64,
+}
+
+impl Read for Download {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl Client {
+    /// Opens an upload for reading, e.g. to pipe it somewhere else.
+    pub fn download(&self, code: &TarPassword) -> anyhow::Result<Download> {
+        let response = self
+            .agent
+            .get(&self.raw_url(code))
+            .call()
+            .map_err(status_error)?;
+
+        // Messages like "Upload not finished yet" would only fail to decrypt.
+        let is_text = response
+            .header("Content-Type")
+            .map(|v| v.trim().starts_with("text/plain"))
+            .unwrap_or(false);
+        if is_text {
+            let message = response.into_string()?;
+            anyhow::bail!(
+                "Server answered with a message instead of the upload, check the code: {}",
+                message.trim()
+            );
+        }
+
+        // Uploads of the old server, only the server decrypts those.
+        if response.header("X-Toc-Format") == Some("age") {
+            anyhow::bail!(
+                "This upload is in an old format, download it with: curl '{}' | tar -xkvf -",
+                self.share_url(code)
+            );
+        }
+
+        let content_length = response
+            .header("Content-Length")
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(0);
+
+        Ok(Download {
+            reader: EncryptedReader::new(response.into_reader(), code.to_string().as_bytes()),
+            content_length,
+        })
+    }
+
+    /// Extracts an upload into `destination`, which has to exist.
+    pub fn receive(
+        &self,
+        code: &TarPassword,
+        destination: &Path,
+        options: ReceiveOptions,
+    ) -> anyhow::Result<ReceiveResult> {
+        let download = self.download(code)?;
+        let content_length = download.content_length;
+        let mut tar = tar::Archive::new(download);
+
+        let mut tracker = Tracker::new(content_length, options.progress);
+        let mut result = ReceiveResult::default();
+
+        let mut buf = vec![0; 128 * 1024];
+        for entry in tar.entries()? {
+            let mut file = entry?;
+            let display = file.path()?.display().to_string();
+            let file_destination = destination.join(file.path()?);
+
+            if content_length == 0 {
+                tracker.total += 512;
+                tracker.total += file.header().size().unwrap_or(0);
+            }
+            tracker.add(512, &display);
+
+            if display == "./" || display == "." {
+                // Current directory does not need to be created
+                continue;
+            }
+
+            if file_destination.exists() && !options.overwrite {
+                loop {
+                    let n = file.read(&mut buf)?;
+                    if n == 0 {
+                        break;
+                    }
+                    tracker.add(n as u64, &display);
+                }
+                result.skipped.push(display);
+                continue;
+            }
+
+            let perm = file.header().mode().unwrap_or(0o644);
+            if file.header().entry_type().is_dir() {
+                std::fs::create_dir_all(&file_destination)?;
+                std::fs::set_permissions(&file_destination, Permissions::from_mode(perm))?;
+            } else if file.header().entry_type().is_file() {
+                let mut new_file = if options.overwrite {
+                    std::fs::OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .open(&file_destination)
+                } else {
+                    std::fs::OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .open(&file_destination)
+                }
+                .with_context(|| format!("Failed to create file {}", file_destination.display()))?;
+
+                loop {
+                    let n = file.read(&mut buf)?;
+                    if n == 0 {
+                        break;
+                    }
+                    new_file.write_all(&buf[..n])?;
+                    tracker.add(n as u64, &display);
+                    result.bytes += n as u64;
+                }
+                drop(new_file);
+
+                if options.preserve_mtime {
+                    if let Ok(mtime) = file.header().mtime() {
+                        let mtime = FileTime::from_unix_time(mtime as i64, 0);
+                        filetime::set_file_mtime(&file_destination, mtime)?;
+                    }
+                }
+                result.files.push(display);
+            }
+        }
+
+        if options.verify {
+            result.verified = self.verify_checksums(code, destination)?;
+        }
+        Ok(result)
+    }
+
+    /// Compares extracted files with the `sha256sum` list of the server.
+    fn verify_checksums(&self, code: &TarPassword, destination: &Path) -> anyhow::Result<usize> {
+        let url = format!("{}sha256", self.share_url(code));
+        let checksums = self
+            .agent
+            .get(&url)
+            .call()
+            .map_err(status_error)
+            .context("Failed to fetch checksums.")?
+            .into_string()?;
+
+        compare_checksums(&checksums, destination)
+    }
+}
+
+/// Checks the files of a `sha256sum` list below `destination`, returns how
+/// many there were.
+pub(crate) fn compare_checksums(checksums: &str, destination: &Path) -> anyhow::Result<usize> {
+    let mut failed = vec![];
+    for line in checksums.lines() {
+        let (expected, path) = line
+            .split_once("  ")
+            .ok_or_else(|| anyhow::anyhow!("Invalid checksum line: {}", line))?;
+        // The list comes from the server, it must not point outside `destination`.
+        let relative = sanitize_entry_path(path)
+            .filter(|relative| !relative.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("Invalid path in checksums: {}", path))?;
+        match sha256_file(&destination.join(relative)) {
+            Ok(actual) if actual == expected => {}
+            Ok(_) => failed.push(format!("{path} (checksum mismatch)")),
+            Err(e) => failed.push(format!("{path} ({e})")),
+        }
+    }
+
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "{} files don't match the upload: {}",
+            failed.len(),
+            failed.join(", ")
+        );
+    }
+    Ok(checksums.lines().count())
+}
+
+fn sha256_file(path: &Path) -> std::io::Result<String> {
+    use sha2::Digest;
+
+    let mut hasher = sha2::Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -0,0 +1,427 @@
+use std::{
+    collections::HashSet,
+    io::Write,
+    os::unix::prelude::{MetadataExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::Context;
+use common::TarPassword;
+
+use crate::{status_error, ws, Client, Progress, TrackedReader, Tracker};
+
+pub(crate) const TAR_HEADER_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnDuplicate {
+    /// Fail before anything is sent.
+    #[default]
+    Error,
+    /// Keep the first file, the others end up in `SendResult::skipped`.
+    Skip,
+}
+
+#[derive(Default)]
+pub struct SendOptions<'a> {
+    /// Generated when not given. Over a websocket the server picks it.
+    pub code: Option<TarPassword>,
+    /// What to do when two files end up at the same path in the archive.
+    pub on_duplicate: OnDuplicate,
+    /// Check afterwards that the server stored the whole upload.
+    pub verify_upload: bool,
+    /// Follow symlinks inside the given directories, they are left out
+    /// otherwise. Links that loop back are always left out.
+    pub follow_symlinks: bool,
+    /// How many levels below the given paths to go, all if `None`.
+    pub max_depth: Option<usize>,
+    /// Pack the files of each directory sorted by name, so the same tree
+    /// gives the same archive on every filesystem.
+    pub sort_files: bool,
+    /// Called with the share url before the data is sent, so the receiver
+    /// can already start.
+    pub on_url: Option<&'a mut dyn FnMut(&str)>,
+    pub progress: Option<&'a mut dyn FnMut(Progress)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SendResult {
+    pub code: TarPassword,
+    pub url: String,
+    /// Size of the sent files, without the archive around them.
+    pub bytes: u64,
+    /// Paths in the archive.
+    pub files: Vec<String>,
+    /// Files left out by `OnDuplicate::Skip`.
+    pub skipped: Vec<PathBuf>,
+    /// Symlinks that were not followed, or would have looped.
+    pub skipped_links: Vec<PathBuf>,
+    /// Unix time, from servers answering with JSON.
+    pub expires_at: Option<i64>,
+    pub upload_id: Option<String>,
+    /// BLAKE3 of the archive, a fingerprint of the content. Not known for
+    /// websocket uploads, the server encrypts those.
+    pub plaintext_hash: Option<[u8; 32]>,
+}
+
+impl Client {
+    /// Sends `paths` as one tar archive. A single directory is sent without
+    /// its own name in front.
+    pub fn send(&self, paths: &[PathBuf], options: SendOptions) -> anyhow::Result<SendResult> {
+        let SendOptions {
+            code,
+            on_duplicate,
+            verify_upload,
+            follow_symlinks,
+            max_depth,
+            sort_files,
+            on_url,
+            progress,
+        } = options;
+        let token = self.token()?;
+
+        let Plan {
+            entries,
+            skipped,
+            skipped_links,
+            total_size,
+            encrypted_size,
+        } = plan(
+            paths,
+            on_duplicate,
+            Walk {
+                follow_symlinks,
+                max_depth,
+                sort_files,
+            },
+        )?;
+
+        // Over a websocket the server picks the code and encrypts.
+        let ws = if self.protocol.is_websocket() {
+            if code.is_some() {
+                anyhow::bail!(
+                    "The server picks the code for {}:// uploads, don't give one.",
+                    self.protocol
+                );
+            }
+            Some(ws::WsUpload::connect(self.protocol, &self.host, token)?)
+        } else {
+            None
+        };
+        let code = match (&ws, code) {
+            (Some(ws), _) => ws.code.clone(),
+            (None, Some(code)) => code,
+            (None, None) => TarPassword::generate(),
+        };
+        let url = self.raw_url(&code);
+        let share_url = self.share_url(&code);
+
+        let (mut writer, reader, plaintext_hash): (Box<dyn Write>, _, _) = if ws.is_some() {
+            let (writer, reader) = common::create_pipe();
+            (Box::new(writer), reader, None)
+        } else {
+            let (writer, reader, hash) =
+                common::create_encrypted_pipe_with_hash(code.to_string().as_bytes());
+            (Box::new(writer), reader, Some(hash))
+        };
+
+        let mut sent_files = vec![];
+        let mut sent_bytes = 0;
+        let mut tracker = Tracker::new(total_size, progress);
+
+        let (response, upload_id) = std::thread::scope(|s| {
+            let handle_a = s.spawn(|| {
+                if let Some(ws) = ws {
+                    return Ok((ws.send(reader)?, None));
+                }
+                let response = self
+                    .agent
+                    .post(&url)
+                    .set("Authorization", &format!("Bearer {}", token))
+                    .set("Content-Length", &encrypted_size.to_string())
+                    .set("Accept", "application/json")
+                    .send(reader)
+                    .map_err(status_error)
+                    .context("Failed to send request.")?;
+                let upload_id = response.header("X-Upload-Id").map(str::to_string);
+                Ok::<_, anyhow::Error>((response.into_string()?, upload_id))
+            });
+
+            if let Some(on_url) = on_url {
+                on_url(&share_url);
+            }
+
+            let mut tar = tar::Builder::new(&mut writer);
+            for (src_path, p, size, is_dir) in entries {
+                let mut header = tar::Header::new_gnu();
+                header.set_path(&p)?;
+
+                let display = src_path.display().to_string();
+                tracker.add(TAR_HEADER_SIZE as _, &display);
+                if is_dir {
+                    // Extracted as a file otherwise, and unwritable without a mode.
+                    let mode = std::fs::metadata(&src_path)?.permissions().mode();
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_mode(mode);
+                    header.set_size(0);
+                    header.set_cksum();
+                    tar.append(&header, std::io::empty())?;
+                } else {
+                    let file = std::fs::File::open(&src_path)?;
+                    let mode = file.metadata()?.permissions().mode();
+                    let time = file.metadata()?.modified()?;
+                    header.set_size(size as u64);
+                    header.set_mode(mode);
+                    header.set_mtime(time.duration_since(std::time::UNIX_EPOCH)?.as_secs());
+                    header.set_cksum();
+                    let reader = TrackedReader {
+                        tracker: &mut tracker,
+                        path: &display,
+                        inner: file,
+                    };
+                    tar.append(&header, reader)?;
+                    sent_files.push(p);
+                    sent_bytes += size as u64;
+                }
+            }
+            tar.finish()?;
+
+            drop(tar);
+            drop(writer);
+            handle_a.join().unwrap()
+        })?;
+
+        if verify_upload {
+            self.verify_stored(&url, encrypted_size)?;
+        }
+
+        Ok(SendResult {
+            code,
+            url: share_url,
+            bytes: sent_bytes,
+            files: sent_files,
+            skipped,
+            skipped_links,
+            expires_at: expires_at(&response),
+            upload_id,
+            plaintext_hash: plaintext_hash.and_then(|hash| hash.hash()),
+        })
+    }
+
+    /// The server may answer an upload with success and still have stored less,
+    /// e.g. when its disk ran full.
+    fn verify_stored(&self, url: &str, expected: u64) -> anyhow::Result<()> {
+        let response = self
+            .agent
+            .head(url)
+            .call()
+            .context("Failed to check the upload.")?;
+        let stored = response
+            .header("X-Toc-Stored-Length")
+            .or_else(|| response.header("Content-Length"))
+            .and_then(|v| v.parse::<u64>().ok())
+            .ok_or_else(|| anyhow::anyhow!("Server did not report the stored size."))?;
+        check_stored(stored, expected)
+    }
+}
+
+/// What goes into the archive, decided before sending since the length of the
+/// archive depends on it.
+pub(crate) struct Plan {
+    /// Source, path in the archive, size and whether it is a directory.
+    pub entries: Vec<(PathBuf, String, usize, bool)>,
+    pub skipped: Vec<PathBuf>,
+    pub skipped_links: Vec<PathBuf>,
+    /// The files and their headers, what progress counts.
+    pub total_size: u64,
+    pub encrypted_size: u64,
+}
+
+/// How `plan` walks the given directories.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Walk {
+    pub follow_symlinks: bool,
+    pub max_depth: Option<usize>,
+    pub sort_files: bool,
+}
+
+pub(crate) fn plan(
+    paths: &[PathBuf],
+    on_duplicate: OnDuplicate,
+    walk: Walk,
+) -> anyhow::Result<Plan> {
+    let mut walker = Walker {
+        walk,
+        ancestors: HashSet::new(),
+        found: vec![],
+        skipped_links: vec![],
+    };
+    for file in paths {
+        walker.collect(file, 0)?;
+    }
+    let Walker {
+        found: files_found,
+        skipped_links,
+        ..
+    } = walker;
+
+    let base = if paths.len() == 1 {
+        if paths[0].is_dir() {
+            Some(paths[0].to_path_buf())
+        } else if paths[0].is_file() {
+            Some(paths[0].parent().unwrap().to_path_buf())
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+
+    let mut entries = vec![];
+    let mut skipped = vec![];
+    let mut seen = HashSet::new();
+    for (src_path, size, is_dir) in files_found {
+        let p = tar_path(base.as_deref(), &src_path, is_dir);
+        if p.is_empty() {
+            continue;
+        }
+        if !seen.insert(p.clone()) {
+            // The same directory twice is harmless.
+            if is_dir {
+                continue;
+            }
+            match on_duplicate {
+                OnDuplicate::Error => anyhow::bail!(
+                    "{} would be stored as {}, which is already in the archive.",
+                    src_path.display(),
+                    p
+                ),
+                OnDuplicate::Skip => {
+                    skipped.push(src_path);
+                    continue;
+                }
+            }
+        }
+        entries.push((src_path, p, size, is_dir));
+    }
+
+    let total_size = entries
+        .iter()
+        .map(|(_, _, s, _)| *s + TAR_HEADER_SIZE)
+        .sum::<usize>();
+
+    // Exact length of the tar stream: one header per entry, contents padded to
+    // full blocks and two zero blocks at the end.
+    let tar_size = entries
+        .iter()
+        .map(|(_, _, s, _)| TAR_HEADER_SIZE + s.div_ceil(TAR_HEADER_SIZE) * TAR_HEADER_SIZE)
+        .sum::<usize>()
+        + 2 * TAR_HEADER_SIZE;
+
+    Ok(Plan {
+        entries,
+        skipped,
+        skipped_links,
+        total_size: total_size as u64,
+        encrypted_size: common::encrypted_size(tar_size as u64),
+    })
+}
+
+/// From servers answering uploads with JSON.
+pub(crate) fn expires_at(response: &str) -> Option<i64> {
+    serde_json::from_str::<serde_json::Value>(response)
+        .ok()
+        .and_then(|v| v["expires_at"].as_i64())
+}
+
+pub(crate) fn check_stored(stored: u64, expected: u64) -> anyhow::Result<()> {
+    if stored != expected {
+        anyhow::bail!("Upload is incomplete, the server stored {stored} of {expected} bytes.");
+    }
+    Ok(())
+}
+
+/// Path of `src_path` in the archive, relative to `base` and without `.` and
+/// `..` components. Empty for the base itself.
+fn tar_path(base: Option<&Path>, src_path: &Path, is_dir: bool) -> String {
+    let relative = match base {
+        Some(base) => src_path.strip_prefix(base).unwrap(),
+        None => src_path,
+    };
+    let mut parts: Vec<String> = vec![];
+    for component in relative.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if parts.last().is_some_and(|p| p != "..") => {
+                parts.pop();
+            }
+            // Left for the tar header to reject.
+            component => parts.push(component.as_os_str().to_string_lossy().to_string()),
+        }
+    }
+    let mut p = parts.join("/");
+    if p.is_empty() {
+        return p;
+    }
+
+    if is_dir {
+        p += "/";
+    }
+
+    if p.len() > 100 {
+        p = p[..50].to_string() + &p[p.len() - 50..];
+        eprint!("Warning: Path {} is too long. Triming.", p);
+    }
+    p
+}
+
+struct Walker {
+    walk: Walk,
+    /// Device and inode of the directories above, to notice links looping back.
+    ancestors: HashSet<(u64, u64)>,
+    /// Path, size and whether it is a directory.
+    found: Vec<(PathBuf, usize, bool)>,
+    skipped_links: Vec<PathBuf>,
+}
+
+impl Walker {
+    fn collect(&mut self, path: &Path, depth: usize) -> anyhow::Result<()> {
+        // The given paths themselves are always followed, like `find -H`.
+        if depth > 0 && !self.walk.follow_symlinks && path.is_symlink() {
+            self.skipped_links.push(path.to_path_buf());
+            return Ok(());
+        }
+        let metadata =
+            std::fs::metadata(path).with_context(|| format!("Invalid path: {}", path.display()))?;
+
+        if metadata.is_dir() {
+            let id = (metadata.dev(), metadata.ino());
+            if self.ancestors.contains(&id) {
+                self.skipped_links.push(path.to_path_buf());
+                return Ok(());
+            }
+            self.found.push((path.to_path_buf(), 0, true));
+            if self.walk.max_depth.is_some_and(|max| depth >= max) {
+                return Ok(());
+            }
+
+            self.ancestors.insert(id);
+            let mut children = std::fs::read_dir(path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            if self.walk.sort_files {
+                children.sort();
+            }
+            for child in children {
+                self.collect(&child, depth + 1)?;
+            }
+            self.ancestors.remove(&id);
+            Ok(())
+        } else if metadata.is_file() {
+            self.found
+                .push((path.to_path_buf(), metadata.len() as usize, false));
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Invalid path: {}", path.display()))
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::{io::Read, net::TcpStream, str::FromStr};
+
+use anyhow::Context;
+use common::TarPassword;
+use tungstenite::{client::IntoClientRequest, stream::MaybeTlsStream, Message, WebSocket};
+
+use crate::Protocol;
+
+const FRAME_SIZE: usize = 64 * 1024;
+
+/// An upload through `GET /upload` upgraded to a websocket, for proxies that
+/// let websockets through but not large POSTs. The server picks the code
+/// and encrypts, so on the way the archive is only protected by TLS.
+pub struct WsUpload {
+    socket: WebSocket<MaybeTlsStream<TcpStream>>,
+    pub code: TarPassword,
+}
+
+impl WsUpload {
+    pub fn connect(protocol: Protocol, host: &str, token: &str) -> anyhow::Result<Self> {
+        let mut request = format!("{protocol}://{host}/upload").into_client_request()?;
+        request
+            .headers_mut()
+            .insert("Authorization", format!("Bearer {token}").parse()?);
+        let (mut socket, _) = tungstenite::connect(request).context("Failed to open websocket.")?;
+
+        // The server greets with the url of the new upload.
+        let url = match socket.read_message()? {
+            Message::Text(url) => url,
+            other => anyhow::bail!("Unexpected greeting from server: {:?}", other),
+        };
+        let code = url
+            .trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .and_then(|code| TarPassword::from_str(code).ok())
+            .ok_or_else(|| anyhow::anyhow!("Server greeted without a code: {}", url))?;
+        Ok(Self { socket, code })
+    }
+
+    /// Sends all of `reader` and waits until the server stored it, returns
+    /// its last message.
+    pub fn send(mut self, mut reader: impl Read) -> anyhow::Result<String> {
+        let mut buffer = vec![0; FRAME_SIZE];
+        loop {
+            let n = reader.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            self.socket
+                .write_message(Message::Binary(buffer[..n].to_vec()))
+                .context("Failed to send data.")?;
+        }
+        let finish = serde_json::json!({ "finish": true });
+        self.socket
+            .write_message(Message::Text(finish.to_string()))?;
+
+        loop {
+            match self.socket.read_message()? {
+                Message::Text(text) => {
+                    let message: serde_json::Value =
+                        serde_json::from_str(&text).unwrap_or_default();
+                    match message["type"].as_str() {
+                        Some("finished") => return Ok(text),
+                        Some("error") => anyhow::bail!("Server error: {}", message["error"]),
+                        // Acks, nothing to resume here.
+                        _ => {}
+                    }
+                }
+                Message::Close(_) => {
+                    anyhow::bail!("Server closed the connection before the upload finished.")
+                }
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,233 @@
+#![cfg(feature = "async")]
+
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use common::TarHash;
+use filetime::FileTime;
+use piper_client::{AsyncClient, AsyncReceiveOptions, AsyncSendOptions, Protocol};
+
+mod server;
+use server::{temp_dir, TestServer, TOKEN};
+
+fn client(server: &TestServer) -> AsyncClient {
+    AsyncClient::new(&server.host, Protocol::Http, Some(TOKEN.to_string()))
+}
+
+/// A directory with a file at the top and one in a subdirectory.
+fn sample_dir() -> std::path::PathBuf {
+    let dir = temp_dir("async-send");
+    std::fs::write(dir.join("hello.txt"), "hello\n").unwrap();
+    std::fs::create_dir(dir.join("docs")).unwrap();
+    std::fs::write(dir.join("docs/readme.md"), vec![b'x'; 100_000]).unwrap();
+    dir
+}
+
+fn read(path: &Path) -> Vec<u8> {
+    std::fs::read(path).unwrap()
+}
+
+#[tokio::test]
+async fn test_send_and_receive() {
+    let server = TestServer::start();
+    let client = client(&server);
+    let dir = sample_dir();
+
+    let last_progress = Arc::new(Mutex::new((0, 0)));
+    let options = AsyncSendOptions {
+        verify_upload: true,
+        ..AsyncSendOptions::default()
+    }
+    .with_progress({
+        let last_progress = last_progress.clone();
+        move |p| *last_progress.lock().unwrap() = (p.done, p.total)
+    });
+    let sent = client
+        .send(std::slice::from_ref(&dir), options)
+        .await
+        .unwrap();
+
+    assert_eq!(sent.url, format!("http://{}/{}/", server.host, sent.code));
+    assert_eq!(sent.bytes, 100_006);
+    let mut files = sent.files.clone();
+    files.sort();
+    assert_eq!(files, ["docs/readme.md", "hello.txt"]);
+    assert!(sent.expires_at.is_some());
+    let hash = TarHash::from_tarid(&sent.code, &server.host);
+    assert_eq!(sent.upload_id, Some(hash.to_string()));
+    assert!(sent.plaintext_hash.is_some());
+    let (done, total) = *last_progress.lock().unwrap();
+    assert_eq!(done, total);
+    assert_eq!(server.upload_count(), 1);
+
+    let out = temp_dir("async-receive");
+    let options = AsyncReceiveOptions {
+        verify: true,
+        ..AsyncReceiveOptions::default()
+    };
+    let received = client.receive(&sent.code, &out, options).await.unwrap();
+    assert_eq!(received.bytes, 100_006);
+    assert_eq!(received.verified, 2);
+    assert_eq!(read(&out.join("hello.txt")), b"hello\n");
+    assert_eq!(
+        read(&out.join("docs/readme.md")),
+        read(&dir.join("docs/readme.md"))
+    );
+}
+
+#[tokio::test]
+async fn test_blocking_interop() {
+    let server = TestServer::start();
+    let blocking = piper_client::Client::new(&server.host, Protocol::Http, Some(TOKEN.into()));
+    let dir = sample_dir();
+
+    let sent = client(&server)
+        .send(std::slice::from_ref(&dir), AsyncSendOptions::default())
+        .await
+        .unwrap();
+    let out = temp_dir("async-interop");
+    let received = tokio::task::spawn_blocking(move || {
+        blocking.receive(&sent.code, &out, Default::default())?;
+        anyhow::Ok(out)
+    })
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(read(&received.join("hello.txt")), b"hello\n");
+}
+
+#[tokio::test]
+async fn test_receive_existing_files() {
+    let server = TestServer::start();
+    let client = client(&server);
+    let sent = client
+        .send(&[sample_dir()], AsyncSendOptions::default())
+        .await
+        .unwrap();
+
+    let out = temp_dir("async-existing");
+    std::fs::write(out.join("hello.txt"), "mine\n").unwrap();
+    let received = client
+        .receive(&sent.code, &out, AsyncReceiveOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(received.skipped, ["hello.txt"]);
+    assert_eq!(read(&out.join("hello.txt")), b"mine\n");
+
+    let options = AsyncReceiveOptions {
+        overwrite: true,
+        ..AsyncReceiveOptions::default()
+    };
+    let received = client.receive(&sent.code, &out, options).await.unwrap();
+    assert!(received.skipped.is_empty());
+    assert_eq!(read(&out.join("hello.txt")), b"hello\n");
+}
+
+#[tokio::test]
+async fn test_preserve_mtime() {
+    let server = TestServer::start();
+    let client = client(&server);
+    let dir = sample_dir();
+    let mtime = FileTime::from_unix_time(1_600_000_000, 0);
+    filetime::set_file_mtime(dir.join("hello.txt"), mtime).unwrap();
+    let sent = client
+        .send(&[dir], AsyncSendOptions::default())
+        .await
+        .unwrap();
+
+    let modified = |path: &Path| FileTime::from_last_modification_time(&path.metadata().unwrap());
+
+    let out = temp_dir("async-mtime");
+    client
+        .receive(&sent.code, &out, AsyncReceiveOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(modified(&out.join("hello.txt")), mtime);
+
+    let out = temp_dir("async-no-mtime");
+    let options = AsyncReceiveOptions {
+        preserve_mtime: false,
+        ..AsyncReceiveOptions::default()
+    };
+    client.receive(&sent.code, &out, options).await.unwrap();
+    assert!(modified(&out.join("hello.txt")) > mtime);
+}
+
+#[tokio::test]
+async fn test_duplicate_paths() {
+    let server = TestServer::start();
+    let client = client(&server);
+    let dir = sample_dir();
+
+    let duplicate = [dir.join("hello.txt"), dir.join("docs/../hello.txt")];
+    let err = client
+        .send(&duplicate, AsyncSendOptions::default())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("already in the archive"), "{err}");
+    assert_eq!(server.upload_count(), 0);
+}
+
+#[tokio::test]
+async fn test_delete_and_info() {
+    let server = TestServer::start();
+    let client = client(&server);
+    let sent = client
+        .send(&[sample_dir()], AsyncSendOptions::default())
+        .await
+        .unwrap();
+
+    let info = client.info(&sent.code).await.unwrap();
+    assert!(info.exists && info.finished);
+
+    let anonymous = AsyncClient::new(&server.host, Protocol::Http, None);
+    assert!(anonymous.delete(&sent.code).await.is_err());
+
+    client.delete(&sent.code).await.unwrap();
+    assert_eq!(server.upload_count(), 0);
+    assert!(!client.info(&sent.code).await.unwrap().exists);
+    let out = temp_dir("async-deleted");
+    let err = client
+        .receive(&sent.code, &out, AsyncReceiveOptions::default())
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Upload not found.");
+}
+
+#[tokio::test]
+async fn test_send_needs_token() {
+    let server = TestServer::start();
+    let client = AsyncClient::new(&server.host, Protocol::Http, None);
+    let err = client
+        .send(&[sample_dir()], AsyncSendOptions::default())
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "No token specified.");
+}
+
+#[tokio::test]
+async fn test_cancel_send() {
+    let server = TestServer::start();
+    let client = client(&server);
+    let dir = temp_dir("async-cancel");
+    std::fs::write(dir.join("big"), vec![0u8; 8_000_000]).unwrap();
+
+    // Slows the upload down after the first megabyte, so it is still running.
+    let options = AsyncSendOptions::default().with_progress(|p| {
+        if p.done > 1_000_000 {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    });
+    let paths = [dir];
+    let send = client.send(&paths, options);
+    assert!(tokio::time::timeout(Duration::from_millis(200), send)
+        .await
+        .is_err());
+
+    // The server sees the connection close and drops the partial upload.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(server.upload_count(), 0);
+}
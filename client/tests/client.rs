@@ -0,0 +1,335 @@
+use std::path::Path;
+
+use common::{TarHash, TarPassword};
+use filetime::FileTime;
+use piper_client::{Client, Protocol, ReceiveOptions, SendOptions};
+
+mod server;
+use server::{temp_dir, TestServer, TOKEN};
+
+fn client(server: &TestServer) -> Client {
+    Client::new(&server.host, Protocol::Http, Some(TOKEN.to_string()))
+}
+
+/// A directory with a file at the top and one in a subdirectory.
+fn sample_dir() -> std::path::PathBuf {
+    let dir = temp_dir("send");
+    std::fs::write(dir.join("hello.txt"), "hello\n").unwrap();
+    std::fs::create_dir(dir.join("docs")).unwrap();
+    std::fs::write(dir.join("docs/readme.md"), vec![b'x'; 100_000]).unwrap();
+    dir
+}
+
+fn read(path: &Path) -> Vec<u8> {
+    std::fs::read(path).unwrap()
+}
+
+#[test]
+fn test_send_and_receive() {
+    let server = TestServer::start();
+    let client = client(&server);
+    let dir = sample_dir();
+
+    let mut shared_url = None;
+    let mut last_progress = (0, 0);
+    let sent = client
+        .send(
+            std::slice::from_ref(&dir),
+            SendOptions {
+                verify_upload: true,
+                on_url: Some(&mut |url| shared_url = Some(url.to_string())),
+                progress: Some(&mut |p| last_progress = (p.done, p.total)),
+                ..SendOptions::default()
+            },
+        )
+        .unwrap();
+
+    assert_eq!(shared_url.as_deref(), Some(sent.url.as_str()));
+    assert_eq!(sent.url, format!("http://{}/{}/", server.host, sent.code));
+    assert_eq!(sent.bytes, 100_006);
+    let mut files = sent.files.clone();
+    files.sort();
+    assert_eq!(files, ["docs/readme.md", "hello.txt"]);
+    assert!(sent.expires_at.is_some());
+    let hash = TarHash::from_tarid(&sent.code, &server.host);
+    assert_eq!(sent.upload_id, Some(hash.to_string()));
+    assert!(sent.plaintext_hash.is_some());
+    assert_eq!(last_progress.0, last_progress.1);
+    assert_eq!(server.upload_count(), 1);
+
+    let out = temp_dir("receive");
+    let received = client
+        .receive(
+            &sent.code,
+            &out,
+            ReceiveOptions {
+                verify: true,
+                ..ReceiveOptions::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(received.bytes, 100_006);
+    assert_eq!(received.verified, 2);
+    assert_eq!(read(&out.join("hello.txt")), b"hello\n");
+    assert_eq!(
+        read(&out.join("docs/readme.md")),
+        read(&dir.join("docs/readme.md"))
+    );
+}
+
+#[test]
+fn test_receive_existing_files() {
+    let server = TestServer::start();
+    let client = client(&server);
+    let dir = sample_dir();
+    let sent = client.send(&[dir], SendOptions::default()).unwrap();
+
+    let out = temp_dir("existing");
+    std::fs::write(out.join("hello.txt"), "mine\n").unwrap();
+    let received = client
+        .receive(&sent.code, &out, ReceiveOptions::default())
+        .unwrap();
+    assert_eq!(received.skipped, ["hello.txt"]);
+    assert_eq!(read(&out.join("hello.txt")), b"mine\n");
+
+    let options = ReceiveOptions {
+        overwrite: true,
+        ..ReceiveOptions::default()
+    };
+    let received = client.receive(&sent.code, &out, options).unwrap();
+    assert!(received.skipped.is_empty());
+    assert_eq!(read(&out.join("hello.txt")), b"hello\n");
+}
+
+#[test]
+fn test_verify_catches_changed_files() {
+    let server = TestServer::start();
+    let client = client(&server);
+    let sent = client
+        .send(&[sample_dir()], SendOptions::default())
+        .unwrap();
+    let verify = || ReceiveOptions {
+        verify: true,
+        ..ReceiveOptions::default()
+    };
+
+    // A skipped file that differs from the upload is reported.
+    let out = temp_dir("verify-changed");
+    std::fs::write(out.join("hello.txt"), "hellO\n").unwrap();
+    let err = client.receive(&sent.code, &out, verify()).unwrap_err();
+    assert!(
+        err.to_string().contains("hello.txt (checksum mismatch)"),
+        "{err}"
+    );
+
+    // Paths in the list stay inside the destination.
+    let out = temp_dir("verify-escape");
+    let hello = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+    server.set_checksums(
+        &sent.code,
+        &format!("{hello}  hello.txt\n{hello}  ../hello.txt\n"),
+    );
+    let err = client.receive(&sent.code, &out, verify()).unwrap_err();
+    assert!(err.to_string().contains("Invalid path"), "{err}");
+}
+
+#[test]
+fn test_preserve_mtime() {
+    let server = TestServer::start();
+    let client = client(&server);
+    let dir = sample_dir();
+    let mtime = FileTime::from_unix_time(1_600_000_000, 0);
+    filetime::set_file_mtime(dir.join("hello.txt"), mtime).unwrap();
+    let sent = client.send(&[dir], SendOptions::default()).unwrap();
+
+    let modified = |path: &Path| FileTime::from_last_modification_time(&path.metadata().unwrap());
+
+    let out = temp_dir("mtime");
+    client
+        .receive(&sent.code, &out, ReceiveOptions::default())
+        .unwrap();
+    assert_eq!(modified(&out.join("hello.txt")), mtime);
+
+    let out = temp_dir("no-mtime");
+    let options = ReceiveOptions {
+        preserve_mtime: false,
+        ..ReceiveOptions::default()
+    };
+    client.receive(&sent.code, &out, options).unwrap();
+    assert!(modified(&out.join("hello.txt")) > mtime);
+}
+
+#[test]
+fn test_given_code() {
+    let server = TestServer::start();
+    let client = client(&server);
+    let code = TarPassword::generate();
+    let options = SendOptions {
+        code: Some(code.clone()),
+        ..SendOptions::default()
+    };
+    let sent = client.send(&[sample_dir()], options).unwrap();
+    assert_eq!(sent.code.to_string(), code.to_string());
+    assert!(client.download(&code).is_ok());
+}
+
+#[test]
+fn test_duplicate_paths() {
+    let server = TestServer::start();
+    let client = client(&server);
+    let dir = sample_dir();
+
+    let duplicate = [dir.join("hello.txt"), dir.join("docs/../hello.txt")];
+    let err = client.send(&duplicate, SendOptions::default()).unwrap_err();
+    assert!(err.to_string().contains("already in the archive"), "{err}");
+    assert_eq!(server.upload_count(), 0);
+}
+
+#[test]
+fn test_symlinks() {
+    let server = TestServer::start();
+    let client = client(&server);
+    let dir = sample_dir();
+    std::os::unix::fs::symlink(dir.join("hello.txt"), dir.join("link.txt")).unwrap();
+    std::os::unix::fs::symlink(&dir, dir.join("docs/loop")).unwrap();
+
+    let sent = client
+        .send(std::slice::from_ref(&dir), SendOptions::default())
+        .unwrap();
+    let mut files = sent.files.clone();
+    files.sort();
+    assert_eq!(files, ["docs/readme.md", "hello.txt"]);
+    assert_eq!(sent.skipped_links.len(), 2);
+
+    let options = SendOptions {
+        follow_symlinks: true,
+        ..SendOptions::default()
+    };
+    let sent = client.send(std::slice::from_ref(&dir), options).unwrap();
+    let mut files = sent.files.clone();
+    files.sort();
+    assert_eq!(files, ["docs/readme.md", "hello.txt", "link.txt"]);
+    assert_eq!(sent.skipped_links, [dir.join("docs/loop")]);
+
+    let options = SendOptions {
+        max_depth: Some(1),
+        ..SendOptions::default()
+    };
+    let sent = client.send(&[dir], options).unwrap();
+    assert_eq!(sent.files, ["hello.txt"]);
+}
+
+#[test]
+fn test_sort_files() {
+    let server = TestServer::start();
+    let client = client(&server);
+    let dir = sample_dir();
+    for name in ["zeta", "b.txt", "alpha", "c", "a.txt"] {
+        std::fs::write(dir.join(name), name).unwrap();
+    }
+
+    let options = SendOptions {
+        sort_files: true,
+        ..SendOptions::default()
+    };
+    let sent = client.send(std::slice::from_ref(&dir), options).unwrap();
+    assert_eq!(
+        sent.files,
+        [
+            "a.txt",
+            "alpha",
+            "b.txt",
+            "c",
+            "docs/readme.md",
+            "hello.txt",
+            "zeta"
+        ]
+    );
+}
+
+#[test]
+fn test_delete() {
+    let server = TestServer::start();
+    let client = client(&server);
+    let sent = client
+        .send(&[sample_dir()], SendOptions::default())
+        .unwrap();
+
+    let anonymous = Client::new(&server.host, Protocol::Http, None);
+    assert!(anonymous.delete(&sent.code).is_err());
+
+    client.delete(&sent.code).unwrap();
+    assert_eq!(server.upload_count(), 0);
+    let err = client.download(&sent.code).err().unwrap();
+    assert_eq!(err.to_string(), "Upload not found.");
+    assert!(client.delete(&sent.code).is_err());
+}
+
+#[test]
+fn test_send_needs_token() {
+    let server = TestServer::start();
+    let client = Client::new(&server.host, Protocol::Http, None);
+    let err = client
+        .send(&[sample_dir()], SendOptions::default())
+        .unwrap_err();
+    assert_eq!(err.to_string(), "No token specified.");
+}
+
+#[test]
+fn test_encrypt_stream() {
+    let code = TarPassword::generate();
+    let data = vec![7u8; 200_000];
+
+    let mut encrypted = vec![];
+    Client::encrypt_stream(&data[..], &mut encrypted, &code).unwrap();
+    assert_eq!(
+        encrypted.len() as u64,
+        common::encrypted_size(data.len() as u64)
+    );
+
+    let mut decrypted = vec![];
+    Client::decrypt_stream(&encrypted[..], &mut decrypted, &code).unwrap();
+    assert_eq!(decrypted, data);
+}
+
+#[test]
+fn test_info() {
+    let server = TestServer::start();
+    let client = client(&server);
+    let sent = client
+        .send(&[sample_dir()], SendOptions::default())
+        .unwrap();
+
+    let info = client.info(&sent.code).unwrap();
+    assert!(info.exists && info.finished);
+    assert_eq!(info.size_bytes, server.stored_size(&sent.code));
+    assert_eq!(info.file_count, Some(sent.files.len() as u64));
+    assert_eq!(info.content_bytes, Some(sent.bytes));
+
+    let info = client.info(&TarPassword::generate()).unwrap();
+    assert!(!info.exists);
+}
+
+#[test]
+fn test_alias() {
+    let server = TestServer::start();
+    let client = client(&server);
+    let sent = client
+        .send(&[sample_dir()], SendOptions::default())
+        .unwrap();
+
+    let url = client.alias(&sent.code, None).unwrap();
+    assert!(
+        url.starts_with(&format!("http://{}/s/", server.host)),
+        "{url}"
+    );
+    let url = client.alias(&sent.code, Some("notes")).unwrap();
+    assert_eq!(url, format!("http://{}/s/notes", server.host));
+
+    let other = client
+        .send(&[sample_dir()], SendOptions::default())
+        .unwrap();
+    let error = client.alias(&other.code, Some("notes")).unwrap_err();
+    assert!(error.to_string().contains("409"), "{error}");
+    assert!(client.alias(&TarPassword::generate(), None).is_err());
+}
@@ -0,0 +1,14 @@
+//! The tests run against the real tarcloud, see `tarcloud::testing`.
+
+use std::path::PathBuf;
+
+use common::TarPassword;
+
+pub use tarcloud::testing::{TestServer, TOKEN};
+
+/// A fresh directory under the system temp dir.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("piper-client-{name}-{}", TarPassword::generate()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
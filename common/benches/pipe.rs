@@ -0,0 +1,65 @@
+use std::io::{Read, Write};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+const TOTAL: usize = 16 * 1024 * 1024;
+
+/// Writes `TOTAL` bytes in `write_size` pieces from another thread and reads
+/// them in `read_size` pieces.
+fn transfer(write_size: usize, read_size: usize) {
+    let (mut writer, mut reader) = common::create_pipe();
+    let handle = std::thread::spawn(move || {
+        let data = vec![7u8; write_size];
+        for _ in 0..TOTAL / write_size {
+            writer.write_all(&data).unwrap();
+        }
+    });
+
+    let mut buf = vec![0u8; read_size];
+    let mut read = 0;
+    loop {
+        match reader.read(&mut buf).unwrap() {
+            0 => break,
+            n => read += n,
+        }
+    }
+    handle.join().unwrap();
+    assert_eq!(read, TOTAL);
+}
+
+fn bench_write_size(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pipe_write_size");
+    group.throughput(Throughput::Bytes(TOTAL as u64));
+    group.sample_size(10);
+    for write_size in [1024, 8 * 1024, 64 * 1024, 1024 * 1024] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(write_size),
+            &write_size,
+            |b, &write_size| b.iter(|| transfer(write_size, 64 * 1024)),
+        );
+    }
+    group.finish();
+}
+
+/// Small reads against large writes and the other way round, e.g. `tar`
+/// reading headers from the pipe or `ureq` sending the body.
+fn bench_read_size(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pipe_read_size");
+    group.throughput(Throughput::Bytes(TOTAL as u64));
+    group.sample_size(10);
+    for (write_size, read_size) in [
+        (1024 * 1024, 512),
+        (1024 * 1024, 8 * 1024),
+        (512, 1024 * 1024),
+    ] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{write_size}/{read_size}")),
+            &(write_size, read_size),
+            |b, &(write_size, read_size)| b.iter(|| transfer(write_size, read_size)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_write_size, bench_read_size);
+criterion_main!(benches);
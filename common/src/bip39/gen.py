@@ -2,7 +2,7 @@
 with open('bip39.txt', 'r') as f:
     words = f.readlines()
 
-    print('pub static WORDS: [&str; 2048] = [')
+    print('pub(crate) static WORDS: [&str; 2048] = [')
     for word in words:
         print(f'    "{word.strip()}",')
     print('];') 
\ No newline at end of file
@@ -0,0 +1,88 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use tokio::io::{AsyncRead, ReadBuf};
+
+use super::{reader::StreamTracker, EncryptedFileError, BLOCK_SIZE, HEADER_SIZE, PAYLOAD_SIZE};
+
+/// Async counterpart of [`super::EncryptedReader`], without seeking.
+pub struct AsyncEncryptedReader<R> {
+    inner: R,
+    tracker: StreamTracker,
+
+    current_chunk_position: usize,
+    /// Data in the current block, less than `PAYLOAD_SIZE` in a last block.
+    current_chunk_len: usize,
+    current_chunk: Box<[u8; BLOCK_SIZE]>,
+    /// Bytes of the next block already read from `inner`.
+    filled: usize,
+
+    global_position: u64,
+}
+
+impl<R> AsyncEncryptedReader<R> {
+    pub fn new(inner: R, passphrase: &[u8]) -> Self {
+        Self {
+            inner,
+            tracker: StreamTracker::new(passphrase),
+            current_chunk_position: PAYLOAD_SIZE,
+            current_chunk_len: PAYLOAD_SIZE,
+            current_chunk: Box::new([0; BLOCK_SIZE]),
+            filled: 0,
+            global_position: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncEncryptedReader<R> {
+    /// Reads the next block, returns false on a clean EOF.
+    fn poll_read_chunk(&mut self, cx: &mut Context<'_>) -> Poll<Result<bool, EncryptedFileError>> {
+        while self.filled < BLOCK_SIZE {
+            let mut buf = ReadBuf::new(&mut self.current_chunk[self.filled..]);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf))?;
+            match buf.filled().len() {
+                0 if self.filled == 0 => return Poll::Ready(Ok(false)),
+                0 => return Poll::Ready(Err(EncryptedFileError::InvalidChunk)),
+                n => self.filled += n,
+            }
+        }
+        self.filled = 0;
+
+        self.current_chunk_len = self
+            .tracker
+            .open_block(&mut self.current_chunk, self.global_position)?;
+        self.current_chunk_position = 0;
+        Poll::Ready(Ok(true))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncEncryptedReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.current_chunk_position == this.current_chunk_len
+            && !ready!(this.poll_read_chunk(cx))?
+        {
+            return Poll::Ready(Ok(()));
+        }
+
+        let to_read = std::cmp::min(
+            buf.remaining(),
+            this.current_chunk_len - this.current_chunk_position,
+        );
+        buf.put_slice(&this.current_chunk[HEADER_SIZE + this.current_chunk_position..][..to_read]);
+        this.current_chunk_position += to_read;
+        this.global_position += to_read as u64;
+        Poll::Ready(Ok(()))
+    }
+}
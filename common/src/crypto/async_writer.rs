@@ -0,0 +1,119 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use chacha20poly1305::ChaCha20Poly1305;
+use tokio::io::AsyncWrite;
+
+use super::{
+    writer::new_stream_header, Header, BLOCK_SIZE, HEADER_SIZE, PAYLOAD_SIZE,
+    VARIANT_ARGON_CHACHA20_POLY_LAST,
+};
+
+/// Async counterpart of [`super::EncryptedWriter`].
+///
+/// Unlike the sync writer, the last block can not be written on drop,
+/// so the writer has to be shut down (`AsyncWriteExt::shutdown`) to finish the stream.
+pub struct AsyncEncryptedWriter<W> {
+    inner: W,
+
+    cipher: ChaCha20Poly1305,
+    current_header: Header,
+
+    current_chunk_position: usize,
+    current_chunk: Box<[u8; BLOCK_SIZE]>,
+    /// Bytes of a sealed block already passed to `inner`, `None` while filling.
+    sealed_written: Option<usize>,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncEncryptedWriter<W> {
+    pub fn new(inner: W, passphrase: &[u8]) -> Self {
+        let header = new_stream_header();
+        let key = super::generate_key(passphrase, &header);
+
+        Self {
+            inner,
+
+            cipher: super::stream_cipher(&key),
+            current_header: header,
+            current_chunk_position: 0,
+            current_chunk: Box::new([0; BLOCK_SIZE]),
+            sealed_written: None,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    fn seal_chunk(&mut self) {
+        super::seal_block(
+            &self.cipher,
+            &self.current_header,
+            &mut self.current_chunk,
+            self.current_chunk_position,
+        );
+        self.current_chunk_position = 0;
+        self.sealed_written = Some(0);
+    }
+
+    /// Writes out a sealed block, if there is one.
+    fn poll_write_chunk(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some(written) = self.sealed_written {
+            if written == BLOCK_SIZE {
+                self.sealed_written = None;
+                self.current_header.advance()?;
+                break;
+            }
+
+            let n =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.current_chunk[written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.sealed_written = Some(written + n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for AsyncEncryptedWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_chunk(cx))?;
+
+        let left = PAYLOAD_SIZE - this.current_chunk_position;
+        let to_write = std::cmp::min(left, buf.len());
+        this.current_chunk[HEADER_SIZE + this.current_chunk_position..][..to_write]
+            .copy_from_slice(&buf[..to_write]);
+        this.current_chunk_position += to_write;
+
+        if this.current_chunk_position == PAYLOAD_SIZE {
+            this.seal_chunk();
+        }
+
+        Poll::Ready(Ok(to_write))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_chunk(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.sealed_written.is_none() && this.current_chunk_position > 0 {
+            this.current_header.variant = VARIANT_ARGON_CHACHA20_POLY_LAST;
+            this.seal_chunk();
+        }
+        ready!(this.poll_write_chunk(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
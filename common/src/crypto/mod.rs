@@ -1,17 +1,35 @@
+use chacha20poly1305::{
+    aead::{generic_array::GenericArray, AeadInPlace},
+    ChaCha20Poly1305, KeyInit,
+};
 use std::fmt::{Display, Formatter};
 
 mod reader;
 pub use reader::EncryptedReader;
 
 mod writer;
-pub use writer::EncryptedWriter;
+pub use writer::{EncryptedWriter, HashReader};
+
+mod validator;
+pub use validator::{InvalidStream, StreamValidator};
+
+#[cfg(feature = "tokio")]
+mod async_reader;
+#[cfg(feature = "tokio")]
+pub use async_reader::AsyncEncryptedReader;
+
+#[cfg(feature = "tokio")]
+mod async_writer;
+#[cfg(feature = "tokio")]
+pub use async_writer::AsyncEncryptedWriter;
 
 pub(crate) const HEADER_SIZE: usize = 1 /*magic*/ + 1 /*version */ + 4 /*blockcounter*/ + 10 /*salt*/;
 pub(crate) const POLY_TAG_SIZE: usize = 16;
 
-pub(crate) const PAYLOAD_SIZE: usize = 512;
-pub(crate) const BLOCK_SIZE: usize = HEADER_SIZE + PAYLOAD_SIZE + POLY_TAG_SIZE;
+pub const PAYLOAD_SIZE: usize = 512;
+pub const BLOCK_SIZE: usize = HEADER_SIZE + PAYLOAD_SIZE + POLY_TAG_SIZE;
 
+#[cfg(not(fuzzing))]
 pub(crate) const ARGON2_PARAMS: argon2::Config = argon2::Config {
     variant: argon2::Variant::Argon2i,
     version: argon2::Version::Version13,
@@ -24,8 +42,24 @@ pub(crate) const ARGON2_PARAMS: argon2::Config = argon2::Config {
     hash_length: 32,
 };
 
+/// Fuzzing would spend nearly all its time deriving keys otherwise.
+#[cfg(fuzzing)]
+pub(crate) const ARGON2_PARAMS: argon2::Config = argon2::Config {
+    variant: argon2::Variant::Argon2i,
+    version: argon2::Version::Version13,
+    mem_cost: 8,
+    time_cost: 1,
+    lanes: 1,
+    thread_mode: argon2::ThreadMode::Sequential,
+    secret: &[],
+    ad: &[],
+    hash_length: 32,
+};
+
 const VERSION_0: u8 = 0;
 const VARIANT_ARGON_CHACHA20_POLY: u8 = 1;
+/// The last block of a stream that ends inside it, see `set_last_len`.
+const VARIANT_ARGON_CHACHA20_POLY_LAST: u8 = 2;
 
 const COUNTER_HINT: u32 = u32::from_be_bytes([b'5', b'4', b'4', b'b']);
 
@@ -64,7 +98,7 @@ impl From<[u8; HEADER_SIZE]> for Header {
 impl From<Header> for [u8; HEADER_SIZE] {
     fn from(header: Header) -> Self {
         let mut data = [0u8; HEADER_SIZE];
-        data[0] = MAGIC[header.blockcounter as usize % MAGIC.len()];
+        data[0] = MAGIC[(header.blockcounter % MAGIC.len() as u32) as usize];
         data[1] = (header.version << 4) | header.variant;
         data[2..6].copy_from_slice(&(header.blockcounter ^ COUNTER_HINT).to_be_bytes());
         data[6..].copy_from_slice(&header.salt);
@@ -78,16 +112,39 @@ impl Header {
     }
 
     fn magic_ok(&self) -> bool {
-        self.blockcounter >= 16 || self.magic == MAGIC[self.blockcounter as usize % MAGIC.len()]
+        self.blockcounter >= 16 || self.magic == MAGIC[self.blockcounter as usize]
+    }
+
+    fn variant_ok(&self) -> bool {
+        self.version == VERSION_0
+            && matches!(
+                self.variant,
+                VARIANT_ARGON_CHACHA20_POLY | VARIANT_ARGON_CHACHA20_POLY_LAST
+            )
+    }
+
+    pub(crate) fn is_last(&self) -> bool {
+        self.variant == VARIANT_ARGON_CHACHA20_POLY_LAST
+    }
+
+    /// Moves on to the next block of the stream.
+    pub(crate) fn advance(&mut self) -> std::io::Result<()> {
+        self.blockcounter = self
+            .blockcounter
+            .checked_add(1)
+            .ok_or_else(|| std::io::Error::other("Reached maximum bytes in stream"))?;
+        Ok(())
     }
 }
 
-enum EncryptedFileError {
+pub(crate) enum EncryptedFileError {
     Io(std::io::Error),
     InvalidHeader,
     InvalidChunk,
     UnsupportedVariant,
     InvalidBlockCounter,
+    TooManyStreams,
+    DataAfterLastBlock,
     KeyError,
 }
 
@@ -100,6 +157,8 @@ impl Display for EncryptedFileError {
             EncryptedFileError::KeyError => write!(f, "Key Error"),
             EncryptedFileError::InvalidChunk => write!(f, "Invalid Chunk"),
             EncryptedFileError::InvalidBlockCounter => write!(f, "Invalid Block Counter"),
+            EncryptedFileError::TooManyStreams => write!(f, "Too Many Streams"),
+            EncryptedFileError::DataAfterLastBlock => write!(f, "Data After Last Block"),
         }
     }
 }
@@ -123,6 +182,12 @@ impl From<EncryptedFileError> for std::io::Error {
             EncryptedFileError::InvalidBlockCounter => {
                 std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid Block Counter")
             }
+            EncryptedFileError::TooManyStreams => {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, "Too Many Streams")
+            }
+            EncryptedFileError::DataAfterLastBlock => {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, "Data After Last Block")
+            }
         }
     }
 }
@@ -143,7 +208,34 @@ impl From<chacha20poly1305::aead::Error> for EncryptedFileError {
     }
 }
 
+/// Size of the encrypted stream for `plain_size` bytes of input.
+/// The last block is padded to full size, so every started block counts fully.
+pub fn encrypted_size(plain_size: u64) -> u64 {
+    plain_size.div_ceil(PAYLOAD_SIZE as u64) * BLOCK_SIZE as u64
+}
+
+#[cfg(not(test))]
+pub(crate) fn generate_key(passphrase: &[u8], header: &Header) -> [u8; 32] {
+    derive_key(passphrase, header)
+}
+
+/// Tests derive keys for the same few salts over and over, see `proptests`.
+#[cfg(test)]
 pub(crate) fn generate_key(passphrase: &[u8], header: &Header) -> [u8; 32] {
+    use std::{collections::BTreeMap, sync::Mutex};
+    type Keys = BTreeMap<(Vec<u8>, [u8; 10]), [u8; 32]>;
+    static KEYS: Mutex<Keys> = Mutex::new(BTreeMap::new());
+
+    let id = (passphrase.to_vec(), header.salt);
+    if let Some(key) = KEYS.lock().unwrap().get(&id) {
+        return *key;
+    }
+    let key = derive_key(passphrase, header);
+    KEYS.lock().unwrap().insert(id, key);
+    key
+}
+
+fn derive_key(passphrase: &[u8], header: &Header) -> [u8; 32] {
     let mut salt = [0u8; 14];
     salt[0..10].copy_from_slice(&header.salt);
     salt[10..].copy_from_slice(b"#toc");
@@ -161,6 +253,94 @@ pub(crate) fn payload_nonce(h: &Header) -> [u8; 12] {
     nonce
 }
 
+/// A last block holds the length of its data in the last two bytes of the
+/// payload, little endian. With 511 bytes only the last byte is free, it is
+/// `0xFF` then, which the high byte of a shorter length never is.
+fn set_last_len(payload: &mut [u8], len: usize) {
+    debug_assert!((1..PAYLOAD_SIZE).contains(&len));
+    if len == PAYLOAD_SIZE - 1 {
+        payload[PAYLOAD_SIZE - 1] = 0xFF;
+    } else {
+        payload[PAYLOAD_SIZE - 2..].copy_from_slice(&(len as u16).to_le_bytes());
+    }
+}
+
+fn last_len(payload: &[u8]) -> Option<usize> {
+    if payload[PAYLOAD_SIZE - 1] == 0xFF {
+        return Some(PAYLOAD_SIZE - 1);
+    }
+    let len = u16::from_le_bytes([payload[PAYLOAD_SIZE - 2], payload[PAYLOAD_SIZE - 1]]);
+    Some(len as usize).filter(|len| (1..PAYLOAD_SIZE - 1).contains(len))
+}
+
+/// The variant of last blocks is authenticated, so a full block can't be
+/// passed off as one or the other way around.
+fn associated_data(header: &Header) -> &'static [u8] {
+    if header.is_last() {
+        b"last"
+    } else {
+        b""
+    }
+}
+
+/// The cipher of a stream, built once per writer.
+pub(crate) fn stream_cipher(key: &[u8; 32]) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(GenericArray::from_slice(&key[..]))
+}
+
+/// Fills in header and tag of `block` and encrypts its payload in place.
+/// Payload bytes after `payload_len` are zeroed first, a last block gets
+/// its length.
+pub(crate) fn seal_block(
+    cipher: &ChaCha20Poly1305,
+    header: &Header,
+    block: &mut [u8; BLOCK_SIZE],
+    payload_len: usize,
+) {
+    block[0..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+    block[HEADER_SIZE + payload_len..HEADER_SIZE + PAYLOAD_SIZE].fill(0);
+    if header.is_last() {
+        set_last_len(&mut block[HEADER_SIZE..][..PAYLOAD_SIZE], payload_len);
+    }
+
+    let nonce = payload_nonce(header);
+    let poly_tag = cipher
+        .encrypt_in_place_detached(
+            GenericArray::from_slice(&nonce[..]),
+            associated_data(header),
+            &mut block[HEADER_SIZE..][..PAYLOAD_SIZE],
+        )
+        .unwrap();
+    block[HEADER_SIZE + PAYLOAD_SIZE..].copy_from_slice(&poly_tag[..]);
+}
+
+/// Checks the tag and decrypts the payload in place. Returns the length of
+/// the data in it.
+pub(crate) fn open_payload(
+    key: &[u8; 32],
+    header: &Header,
+    payload: &mut [u8; PAYLOAD_SIZE],
+    tag: &[u8; POLY_TAG_SIZE],
+) -> Result<usize, EncryptedFileError> {
+    let nonce = payload_nonce(header);
+    let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(&key[..]));
+
+    cipher.decrypt_in_place_detached(
+        GenericArray::from_slice(&nonce),
+        associated_data(header),
+        &mut payload[..],
+        GenericArray::from_slice(tag),
+    )?;
+    if header.is_last() {
+        last_len(payload).ok_or(EncryptedFileError::InvalidChunk)
+    } else {
+        Ok(PAYLOAD_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod proptests;
+
 #[cfg(test)]
 mod tests {
     use rand::{Rng, RngCore};
@@ -215,6 +395,123 @@ mod tests {
         assert_eq!(original, decrypt_all(&encoded, "test").unwrap());
     }
 
+    /// Hands out a few bytes per call and is interrupted every other time.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        calls: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.calls += 1;
+            if self.calls.is_multiple_of(2) {
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+            let n = buf.len().min(self.data.len()).min(self.calls % 7 + 1);
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_read_in_small_pieces() {
+        let original = generate_data(5000);
+        let encoded = encrypt_all(&original, "test");
+        let read = |data: &[u8]| {
+            let mut out = Vec::new();
+            EncryptedReader::new(Trickle { data, calls: 0 }, b"test")
+                .read_to_end(&mut out)
+                .map(|_| out)
+        };
+
+        assert_eq!(read(&encoded).unwrap()[..original.len()], original[..]);
+        let error = read(&encoded[..encoded.len() - 1]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_skip_blocks() {
+        let original = generate_data(10 * PAYLOAD_SIZE + 100);
+        let encoded = encrypt_all(&original, "test");
+
+        let mut dec = EncryptedReader::new(&encoded[..], b"test");
+        let mut buf = [0; 10];
+        dec.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, original[..10]);
+
+        // Drops the rest of the first block, too.
+        dec.skip_blocks(3).unwrap();
+        dec.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, original[4 * PAYLOAD_SIZE..][..10]);
+
+        dec.skip_blocks(0).unwrap();
+        let mut rest = Vec::new();
+        dec.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest[..5 * PAYLOAD_SIZE + 100], original[5 * PAYLOAD_SIZE..]);
+
+        let mut dec = EncryptedReader::new(&encoded[..], b"test");
+        dec.skip_blocks(100).unwrap();
+        assert_eq!(dec.read(&mut buf).unwrap(), 0);
+
+        let mut dec = EncryptedReader::new(&encoded[..encoded.len() - 1], b"test");
+        let error = dec.skip_blocks(100).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_encrypted_size() {
+        for len in [0, 1, 511, 512, 513, 4096, 100_000] {
+            let original = generate_data(len);
+            let encoded = encrypt_all(&original, "test");
+            assert_eq!(encoded.len() as u64, encrypted_size(len as u64));
+        }
+    }
+
+    #[test]
+    fn test_plaintext_position() {
+        let mut out = Vec::new();
+        let mut enc = EncryptedWriter::new(&mut out, "test".as_bytes());
+        assert_eq!(enc.current_plaintext_position(), 0);
+        enc.write_all(&generate_data(2 * PAYLOAD_SIZE + 10))
+            .unwrap();
+        assert_eq!(
+            enc.current_plaintext_position(),
+            2 * PAYLOAD_SIZE as u64 + 10
+        );
+        assert_eq!(enc.blocks_written(), 2);
+    }
+
+    #[test]
+    fn test_plaintext_hash() {
+        let original = generate_data(2 * PAYLOAD_SIZE + 10);
+        let mut out = Vec::new();
+        let (mut enc, hash) = EncryptedWriter::new_with_hash(&mut out, "test".as_bytes());
+        enc.write_all(&original).unwrap();
+        assert_eq!(hash.hash(), None);
+        drop(enc);
+
+        assert_eq!(hash.hash(), Some(*blake3::hash(&original).as_bytes()));
+        let decoded = decrypt_all(&out, "test").unwrap();
+        assert_eq!(hash.hash(), Some(*blake3::hash(&decoded).as_bytes()));
+    }
+
+    #[test]
+    fn test_last_block_length() {
+        for len in [1, 100, 255, 256, 510, 511, 512, 513, 1023] {
+            let original = generate_data(len);
+            let decoded = decrypt_all(&encrypt_all(&original, "test"), "test").unwrap();
+            assert_eq!(decoded, original, "{len}");
+        }
+
+        // A stream is over after its last block.
+        let short = encrypt_all(&generate_data(100), "test");
+        let full = encrypt_all(&generate_data(PAYLOAD_SIZE), "test");
+        let err = decrypt_all(&[&short[..], &full[..]].concat(), "test").unwrap_err();
+        assert_eq!(err.to_string(), "Data After Last Block");
+        assert!(decrypt_all(&[&full[..], &short[..]].concat(), "test").is_ok());
+    }
+
     #[test]
     fn test_encryption_is_salted() {
         let original = generate_data(TWO_MB);
@@ -241,6 +538,143 @@ mod tests {
         assert_eq!(original, decoded);
     }
 
+    #[test]
+    fn test_append() {
+        let original = generate_data(10 * PAYLOAD_SIZE + 100);
+        let mut file = Cursor::new(encrypt_all(&original[..4 * PAYLOAD_SIZE], "test"));
+
+        // A wrong passphrase doesn't match the existing blocks.
+        assert!(EncryptedWriter::append(&mut file, b"wrong", 4).is_err());
+
+        let mut writer = EncryptedWriter::append(&mut file, b"test", 4).unwrap();
+        writer.write_all(&original[4 * PAYLOAD_SIZE..]).unwrap();
+        drop(writer);
+
+        // Still a single stream.
+        let encoded = file.into_inner();
+        let mut dec = EncryptedReader::new_strict(&encoded[..], b"test");
+        let mut decoded = Vec::new();
+        dec.read_to_end(&mut decoded).unwrap();
+        assert_eq!(&decoded[..original.len()], &original[..]);
+
+        // Overwrites what follows the given blocks.
+        let mut file = Cursor::new(encoded);
+        let mut writer = EncryptedWriter::append(&mut file, b"test", 2).unwrap();
+        writer.write_all(&[7; PAYLOAD_SIZE]).unwrap();
+        drop(writer);
+        let mut file = file.into_inner();
+        file.truncate(3 * BLOCK_SIZE);
+        let decoded = decrypt_all(&file, "test").unwrap();
+        assert_eq!(&decoded[..2 * PAYLOAD_SIZE], &original[..2 * PAYLOAD_SIZE]);
+        assert_eq!(&decoded[2 * PAYLOAD_SIZE..], &[7; PAYLOAD_SIZE]);
+
+        let mut empty = Cursor::new(vec![]);
+        EncryptedWriter::append(&mut empty, b"test", 0)
+            .unwrap()
+            .write_all(b"new")
+            .unwrap();
+        assert_eq!(
+            decrypt_all(&empty.into_inner(), "test").unwrap()[..3],
+            *b"new"
+        );
+    }
+
+    #[test]
+    fn test_strict_rejects_concat() {
+        let original = generate_data(4096);
+        let single = encrypt_all(&original, "test");
+        let concat = [
+            encrypt_all(&original[..1024], "test"),
+            encrypt_all(&original[1024..], "test"),
+        ]
+        .concat();
+
+        let strict_decrypt = |data: &[u8]| {
+            let mut out = vec![];
+            EncryptedReader::new_strict(data, b"test")
+                .read_to_end(&mut out)
+                .map(|_| out)
+        };
+
+        assert_eq!(strict_decrypt(&single).unwrap()[..4096], original[..]);
+        let err = strict_decrypt(&concat).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "Invalid Header");
+        assert!(decrypt_all(&concat, "test").is_ok());
+    }
+
+    fn validate(data: &[u8]) -> std::io::Result<()> {
+        let mut validator = StreamValidator::new(std::io::sink());
+        // Odd write sizes to cross header boundaries.
+        for chunk in data.chunks(100) {
+            validator.write_all(chunk)?;
+        }
+        validator.finish().map(|_| ())
+    }
+
+    fn validation_error(data: &[u8]) -> &'static str {
+        let e = validate(data).unwrap_err();
+        InvalidStream::from_io(&e).unwrap().reason
+    }
+
+    #[test]
+    fn test_validator() {
+        let original = generate_data(100 * 1024);
+        let encrypted = encrypt_all(&original, "test");
+        validate(&encrypted).unwrap();
+
+        let concat = [
+            encrypt_all(&original[..1024], "a"),
+            encrypt_all(&original, "b"),
+        ]
+        .concat();
+        validate(&concat).unwrap();
+
+        assert_eq!(
+            validation_error(&encrypted[..encrypted.len() - 1]),
+            "Stream is not aligned to blocks"
+        );
+
+        let mut corrupted = encrypted.clone();
+        corrupted[0] ^= 0xFF;
+        assert_eq!(validation_error(&corrupted), "Invalid magic byte");
+
+        let mut corrupted = encrypted.clone();
+        corrupted[1] = 0x21;
+        assert_eq!(
+            validation_error(&corrupted),
+            "Unsupported version or variant"
+        );
+
+        let mut swapped = encrypted.clone();
+        swapped[BLOCK_SIZE * 20..][..BLOCK_SIZE]
+            .copy_from_slice(&encrypted[BLOCK_SIZE * 21..][..BLOCK_SIZE]);
+        assert_eq!(validation_error(&swapped), "Block counter out of order");
+
+        let interleaved = [
+            &concat[..BLOCK_SIZE],
+            &concat[BLOCK_SIZE * 2..BLOCK_SIZE * 3],
+            &concat[BLOCK_SIZE..BLOCK_SIZE * 2],
+        ]
+        .concat();
+        assert_eq!(
+            validation_error(&interleaved),
+            "Stream continues after another one"
+        );
+
+        assert_eq!(
+            validation_error(&encrypted[BLOCK_SIZE * 20..]),
+            "Block counter before start of data"
+        );
+
+        // Resuming in the middle of a header skips it.
+        let mut validator = StreamValidator::resume(std::io::sink(), BLOCK_SIZE as u64 * 3 + 5);
+        validator
+            .write_all(&encrypted[BLOCK_SIZE * 3 + 5..])
+            .unwrap();
+        validator.finish().unwrap();
+    }
+
     #[test]
     fn fail_on_ordering_has_been_changed() {
         let original = generate_data(TWO_MB);
@@ -293,8 +727,8 @@ mod tests {
                 _ => unreachable!(),
             };
 
-            let last_postion_a = reader.seek(SeekFrom::Current(0)).unwrap();
-            let last_postion_b = decrypted_reader.seek(SeekFrom::Current(0)).unwrap();
+            let last_postion_a = reader.stream_position().unwrap();
+            let last_postion_b = decrypted_reader.stream_position().unwrap();
 
             assert_eq!(last_postion_a, last_postion_b);
             dbg!(seek, last_postion_a, length);
@@ -305,8 +739,8 @@ mod tests {
             dbg!(&p1, &p2);
 
             assert_eq!(p1.is_ok(), p2.is_ok());
-            if p1.is_ok() {
-                assert_eq!(p1.unwrap(), p2.unwrap());
+            if let (Ok(p1), Ok(p2)) = (p1, p2) {
+                assert_eq!(p1, p2);
             } else {
                 // Invalid state
                 reader.seek(SeekFrom::Start(0)).unwrap();
@@ -321,8 +755,8 @@ mod tests {
 
             if ok1 {
                 assert_eq!(
-                    reader.seek(SeekFrom::Current(0)).unwrap(),
-                    decrypted_reader.seek(SeekFrom::Current(0)).unwrap()
+                    reader.stream_position().unwrap(),
+                    decrypted_reader.stream_position().unwrap()
                 );
                 assert_eq!(encrypted, decrypted);
             } else {
@@ -333,18 +767,50 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_read_on_after_error() {
+        let a = encrypt_all(&[1; PAYLOAD_SIZE], "test");
+        let b = encrypt_all(&[2; 10 * PAYLOAD_SIZE], "test");
+        let c = encrypt_all(&[3; PAYLOAD_SIZE], "test");
+        // The block of `b` claims to be the sixth of its stream.
+        let data = [&a[..], &b[5 * BLOCK_SIZE..][..BLOCK_SIZE], &c[..]].concat();
+
+        let mut reader = EncryptedReader::new(&data[..], b"test");
+        let mut buf = [0; PAYLOAD_SIZE];
+        assert_eq!(reader.read(&mut buf).unwrap(), PAYLOAD_SIZE);
+        assert!(reader.read(&mut buf).is_err());
+        // The rejected block is dropped, the stream after it still reads.
+        assert_eq!(reader.read(&mut buf).unwrap(), PAYLOAD_SIZE);
+        assert_eq!(buf, [3; PAYLOAD_SIZE]);
+    }
+
+    #[test]
+    fn test_seek_out_of_range() {
+        let encoded = encrypt_all(&generate_data(4096), "test");
+        let mut reader = EncryptedReader::new(Cursor::new(encoded), b"test");
+
+        for seek in [SeekFrom::Start(u64::MAX), SeekFrom::Current(i64::MIN)] {
+            let err = reader.seek(seek).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        }
+        // Skipping past the end is the end of the stream.
+        reader.skip_blocks(u64::MAX).unwrap();
+        assert_eq!(reader.read(&mut [0; 16]).unwrap(), 0);
+    }
+
     #[bench]
     fn bench_encrypt(b: &mut test::Bencher) {
         let data = generate_data(10 * 1024 * 1024);
 
         let mut encrypted = Vec::new();
         let writer = EncryptedWriter::new(vec![], b"test");
+        let key = generate_key(b"test", &writer.current_header);
         b.iter(|| {
             encrypted.clear();
             let mut writer = EncryptedWriter::new_from_salt_and_key(
                 &mut encrypted,
                 writer.current_header.salt,
-                writer.key,
+                key,
                 0,
             );
             writer.write_all(&data).unwrap();
@@ -352,6 +818,30 @@ mod tests {
         });
     }
 
+    #[bench]
+    fn bench_copy(b: &mut test::Bencher) {
+        let encrypted = encrypt_all(&generate_data(10 * 1024 * 1024), "test");
+
+        let mut out = Vec::with_capacity(encrypted.len());
+        b.iter(|| {
+            out.clear();
+            std::io::copy(&mut &encrypted[..], &mut out).unwrap();
+        });
+    }
+
+    #[bench]
+    fn bench_validate(b: &mut test::Bencher) {
+        let encrypted = encrypt_all(&generate_data(10 * 1024 * 1024), "test");
+
+        let mut out = Vec::with_capacity(encrypted.len());
+        b.iter(|| {
+            out.clear();
+            let mut validator = StreamValidator::new(&mut out);
+            std::io::copy(&mut &encrypted[..], &mut validator).unwrap();
+            validator.finish().unwrap();
+        });
+    }
+
     #[bench]
     fn bench_decrypt(b: &mut test::Bencher) {
         let data = generate_data(10 * 1024 * 1024);
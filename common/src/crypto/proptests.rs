@@ -0,0 +1,142 @@
+//! Property tests of the stream format. Streams use a few fixed salts, so the
+//! keys are only derived once, see `generate_key`.
+
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+use proptest::prelude::*;
+
+use super::*;
+
+const PASSPHRASE: &[u8] = b"test";
+const SALTS: [[u8; 10]; 4] = [[1; 10], [2; 10], [3; 10], [4; 10]];
+
+/// At least 16 blocks, where the magic byte stops being checked.
+const MAX_LEN: usize = 20 * PAYLOAD_SIZE;
+
+fn config() -> ProptestConfig {
+    ProptestConfig::with_cases(48)
+}
+
+fn encrypt(data: &[u8], salt: [u8; 10]) -> Vec<u8> {
+    let header = Header {
+        magic: 0,
+        version: VERSION_0,
+        variant: VARIANT_ARGON_CHACHA20_POLY,
+        blockcounter: 0,
+        salt,
+    };
+    let key = generate_key(PASSPHRASE, &header);
+
+    let mut out = Vec::new();
+    let mut writer = EncryptedWriter::new_from_salt_and_key(&mut out, salt, key, 0);
+    writer.write_all(data).unwrap();
+    drop(writer);
+    out
+}
+
+fn decrypt(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    EncryptedReader::new(data, PASSPHRASE).read_to_end(&mut out)?;
+    Ok(out)
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    Seek(SeekFrom),
+    Read(usize),
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let len = MAX_LEN as i64;
+    prop_oneof![
+        (0..len as u64 + 600).prop_map(|n| Op::Seek(SeekFrom::Start(n))),
+        (-len..len).prop_map(|n| Op::Seek(SeekFrom::Current(n))),
+        (-len..600).prop_map(|n| Op::Seek(SeekFrom::End(n))),
+        (0..2 * PAYLOAD_SIZE).prop_map(Op::Read),
+    ]
+}
+
+proptest! {
+    #![proptest_config(config())]
+
+    #[test]
+    fn roundtrip(data in prop::collection::vec(any::<u8>(), 0..MAX_LEN)) {
+        let encrypted = encrypt(&data, SALTS[0]);
+        prop_assert_eq!(encrypted.len() as u64, encrypted_size(data.len() as u64));
+        prop_assert_eq!(decrypt(&encrypted).unwrap(), data);
+    }
+
+    #[test]
+    fn seek_like_plaintext(
+        data in prop::collection::vec(any::<u8>(), 0..MAX_LEN),
+        ops in prop::collection::vec(op(), 1..16),
+    ) {
+        let mut reader = EncryptedReader::new(Cursor::new(encrypt(&data, SALTS[0])), PASSPHRASE);
+        let mut plain = Cursor::new(data);
+
+        for op in ops {
+            match op {
+                Op::Seek(seek) => {
+                    let a = reader.seek(seek);
+                    let b = plain.seek(seek);
+                    prop_assert_eq!(a.is_ok(), b.is_ok(), "{:?}", seek);
+                    if let (Ok(a), Ok(b)) = (a, b) {
+                        prop_assert_eq!(a, b);
+                    }
+                }
+                Op::Read(len) => {
+                    let (mut a, mut b) = (vec![0; len], vec![0; len]);
+                    let a = reader.read(&mut a).map(|n| a[..n].to_vec()).unwrap();
+                    let b = plain.read(&mut b).map(|n| b[..n].to_vec()).unwrap();
+                    // A read may stop at the end of a block.
+                    prop_assert!(b.starts_with(&a));
+                    prop_assert!(!a.is_empty() || b.is_empty());
+                    plain.seek(SeekFrom::Current(a.len() as i64 - b.len() as i64)).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn concatenated_segments(
+        data in prop::collection::vec(any::<u8>(), 0..MAX_LEN),
+        splits in prop::collection::vec(any::<prop::sample::Index>(), 0..SALTS.len()),
+    ) {
+        // Only the last segment may end inside a block.
+        let blocks = data.len() / PAYLOAD_SIZE;
+        let mut splits: Vec<usize> = splits
+            .iter()
+            .map(|i| i.index(blocks + 1) * PAYLOAD_SIZE)
+            .collect();
+        splits.sort();
+        splits.push(data.len());
+
+        let mut encrypted = vec![];
+        let mut start = 0;
+        for (end, salt) in splits.into_iter().zip(SALTS) {
+            encrypted.extend(encrypt(&data[start..end], salt));
+            start = end;
+        }
+        prop_assert_eq!(decrypt(&encrypted).unwrap(), data);
+    }
+
+    #[test]
+    fn corruption_is_detected(
+        data in prop::collection::vec(any::<u8>(), 1..MAX_LEN),
+        position in any::<prop::sample::Index>(),
+        flip in 1..=255u8,
+    ) {
+        let mut encrypted = encrypt(&data, SALTS[0]);
+        let position = position.index(encrypted.len());
+        encrypted[position] ^= flip;
+
+        // The format leaves the magic byte open after the 16th block, it is
+        // not authenticated either.
+        let block = position / BLOCK_SIZE;
+        if position % BLOCK_SIZE == 0 && block >= 16 {
+            prop_assert_eq!(decrypt(&encrypted).unwrap(), data);
+        } else {
+            prop_assert!(decrypt(&encrypted).is_err());
+        }
+    }
+}
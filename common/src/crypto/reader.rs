@@ -1,21 +1,20 @@
-use chacha20poly1305::{aead::generic_array::GenericArray, AeadInPlace, ChaCha20Poly1305, KeyInit};
 use std::{
     collections::BTreeMap,
     io::{Read, Seek, SeekFrom},
 };
 
-use super::{
-    EncryptedFileError, Header, BLOCK_SIZE, HEADER_SIZE, PAYLOAD_SIZE, POLY_TAG_SIZE,
-    VARIANT_ARGON_CHACHA20_POLY, VERSION_0,
-};
+use super::{EncryptedFileError, Header, BLOCK_SIZE, HEADER_SIZE, PAYLOAD_SIZE};
+
+/// Concatenated streams in one reader, far more than appending ever creates.
+const MAX_STREAMS: usize = 1024;
 
 pub struct EncryptedReader<R> {
     inner: R,
-    passphrase: Vec<u8>,
-    stream_state: BTreeMap<[u8; 10], StreamState>,
-    last_stream: Option<[u8; 10]>,
+    tracker: StreamTracker,
 
     current_chunk_position: usize,
+    /// Data in the current block, less than `PAYLOAD_SIZE` in a last block.
+    current_chunk_len: usize,
     current_chunk: Box<[u8; BLOCK_SIZE]>,
 
     global_position: u64,
@@ -28,66 +27,53 @@ struct StreamState {
     next_stream_block: Option<i64>,
 }
 
-impl<R> EncryptedReader<R> {
-    pub fn new(inner: R, passphrase: &[u8]) -> Self {
+/// Keys and block positions of all streams seen so far.
+/// Independent of the underlying io, so it is shared with the async reader.
+#[derive(Clone)]
+pub(crate) struct StreamTracker {
+    passphrase: Vec<u8>,
+    stream_state: BTreeMap<[u8; 10], StreamState>,
+    last_stream: Option<[u8; 10]>,
+    /// Reject blocks of any stream but the first one.
+    strict: bool,
+}
+
+impl StreamTracker {
+    pub(crate) fn new(passphrase: &[u8]) -> Self {
         Self {
-            inner,
             passphrase: passphrase.to_vec(),
             stream_state: BTreeMap::new(),
             last_stream: None,
-            current_chunk_position: PAYLOAD_SIZE,
-            current_chunk: Box::new([0; BLOCK_SIZE]),
-            global_position: 0,
-        }
-    }
-
-    #[allow(dead_code)] // used in tests
-    /// Creates a new EncryptedReader, but inherits cached keys from self.
-    pub(crate) fn clone_with<O>(&self, inner: O) -> EncryptedReader<O> {
-        EncryptedReader {
-            inner,
-            passphrase: self.passphrase.clone(),
-            stream_state: self.stream_state.clone(),
-            last_stream: None,
-            current_chunk_position: PAYLOAD_SIZE,
-            current_chunk: Box::new([0; BLOCK_SIZE]),
-            global_position: 0,
+            strict: false,
         }
     }
 
-    fn payload_bytes(&self) -> &[u8; PAYLOAD_SIZE] {
-        self.current_chunk[HEADER_SIZE..][..PAYLOAD_SIZE]
-            .try_into()
-            .unwrap()
+    /// Forget the previous block, needed after seeking.
+    pub(crate) fn reset_position(&mut self) {
+        self.last_stream = None;
     }
 
-    fn header_bytes(&self) -> &[u8; HEADER_SIZE] {
-        self.current_chunk[..HEADER_SIZE].try_into().unwrap()
-    }
-
-    fn poly_tag_bytes(&self) -> &[u8; POLY_TAG_SIZE] {
-        self.current_chunk[HEADER_SIZE + PAYLOAD_SIZE..]
-            .try_into()
-            .unwrap()
-    }
+    fn get_state(
+        &mut self,
+        header: &Header,
+        global_position: u64,
+    ) -> Result<StreamState, EncryptedFileError> {
+        let current_block = i64::try_from(global_position / PAYLOAD_SIZE as u64)
+            .map_err(|_| EncryptedFileError::InvalidBlockCounter)?;
 
-    fn payload_bytes_mut(&mut self) -> &mut [u8; PAYLOAD_SIZE] {
-        (&mut self.current_chunk[HEADER_SIZE..][..PAYLOAD_SIZE])
-            .try_into()
-            .unwrap()
-    }
+        if self.strict
+            && !self.stream_state.is_empty()
+            && !self.stream_state.contains_key(&header.salt)
+        {
+            return Err(EncryptedFileError::InvalidHeader);
+        }
 
-    fn get_state(&mut self, header: &Header) -> Result<StreamState, EncryptedFileError> {
-        let current_block = self.global_position as i64 / PAYLOAD_SIZE as i64;
-
-        // Update last block
-        if self.last_stream.is_some() && self.last_stream != Some(header.salt) {
-            dbg!("Updating last block");
-            let mut last_state = self
-                .stream_state
-                .get_mut(&self.last_stream.unwrap())
-                .unwrap();
-            last_state.next_stream_block = Some(current_block);
+        // Update last block. The last stream may have been rejected before
+        // it was stored, if reading went on after an error.
+        if let Some(last) = self.last_stream.filter(|last| *last != header.salt) {
+            if let Some(last_state) = self.stream_state.get_mut(&last) {
+                last_state.next_stream_block = Some(current_block);
+            }
         }
         // Remember last stream
         self.last_stream = Some(header.salt);
@@ -107,11 +93,15 @@ impl<R> EncryptedReader<R> {
             };
         }
 
-        let key = super::generate_key(&self.passphrase, header);
         let first_stream_chunk = current_block - header.blockcounter as i64;
         if first_stream_chunk < 0 {
             return Err(EncryptedFileError::InvalidBlockCounter);
         }
+        // Every stream costs a key derivation.
+        if self.stream_state.len() >= MAX_STREAMS {
+            return Err(EncryptedFileError::TooManyStreams);
+        }
+        let key = super::generate_key(&self.passphrase, header);
 
         let state = StreamState {
             key,
@@ -121,53 +111,132 @@ impl<R> EncryptedReader<R> {
         self.stream_state.insert(header.salt, state);
         Ok(state)
     }
-}
 
-impl<R: Read> EncryptedReader<R> {
-    fn read_chunk(&mut self) -> Result<bool, EncryptedFileError> {
-        self.current_chunk_position = PAYLOAD_SIZE;
-        match self.inner.read(&mut self.current_chunk[..])? {
-            0 => {
-                return Ok(false);
-            }
-            BLOCK_SIZE => (),
-            n => {
-                self.inner.read_exact(&mut self.current_chunk[n..])?;
-            }
+    /// Checks the header of a block read at `global_position` and decrypts its payload in place.
+    /// Returns the length of the data in it.
+    pub(crate) fn open_block(
+        &mut self,
+        block: &mut [u8; BLOCK_SIZE],
+        global_position: u64,
+    ) -> Result<usize, EncryptedFileError> {
+        // Only the end of a last block is not on a block boundary.
+        if !global_position.is_multiple_of(PAYLOAD_SIZE as u64) {
+            return Err(EncryptedFileError::DataAfterLastBlock);
         }
-
-        let header = Header::from(*self.header_bytes());
+        let header = Header::from(<[u8; HEADER_SIZE]>::try_from(&block[..HEADER_SIZE]).unwrap());
         if !header.magic_ok() {
             return Err(EncryptedFileError::InvalidHeader);
         }
-        if header.version != VERSION_0 || header.variant != VARIANT_ARGON_CHACHA20_POLY {
+        if !header.variant_ok() {
             return Err(EncryptedFileError::UnsupportedVariant);
         }
 
-        let key = self.get_state(&header)?;
-        let nonce = super::payload_nonce(&header);
+        let state = self.get_state(&header, global_position)?;
+
+        let (payload, tag) = block[HEADER_SIZE..].split_at_mut(PAYLOAD_SIZE);
+        super::open_payload(
+            &state.key,
+            &header,
+            payload.try_into().unwrap(),
+            (&*tag).try_into().unwrap(),
+        )
+    }
+}
+
+impl<R> EncryptedReader<R> {
+    pub fn new(inner: R, passphrase: &[u8]) -> Self {
+        Self {
+            inner,
+            tracker: StreamTracker::new(passphrase),
+            current_chunk_position: PAYLOAD_SIZE,
+            current_chunk_len: PAYLOAD_SIZE,
+            current_chunk: Box::new([0; BLOCK_SIZE]),
+            global_position: 0,
+        }
+    }
+
+    /// Like `new`, but fails on concatenated streams.
+    /// A block with a different salt inside a single stream hints at tampering.
+    pub fn new_strict(inner: R, passphrase: &[u8]) -> Self {
+        let mut reader = Self::new(inner, passphrase);
+        reader.tracker.strict = true;
+        reader
+    }
+
+    #[allow(dead_code)] // used in tests
+    /// Creates a new EncryptedReader, but inherits cached keys from self.
+    pub(crate) fn clone_with<O>(&self, inner: O) -> EncryptedReader<O> {
+        let mut tracker = self.tracker.clone();
+        tracker.reset_position();
+        EncryptedReader {
+            inner,
+            tracker,
+            current_chunk_position: PAYLOAD_SIZE,
+            current_chunk_len: PAYLOAD_SIZE,
+            current_chunk: Box::new([0; BLOCK_SIZE]),
+            global_position: 0,
+        }
+    }
+
+    fn payload_bytes(&self) -> &[u8; PAYLOAD_SIZE] {
+        self.current_chunk[HEADER_SIZE..][..PAYLOAD_SIZE]
+            .try_into()
+            .unwrap()
+    }
+}
 
-        let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(&key.key[..]));
-        let tag = GenericArray::from_slice(self.poly_tag_bytes()).to_owned();
+impl<R: Read> EncryptedReader<R> {
+    /// Reads a whole block, `false` at the end of the stream. `read_exact`
+    /// can't tell an empty stream from a truncated one, hence the loop.
+    fn read_chunk(&mut self) -> Result<bool, EncryptedFileError> {
+        self.current_chunk_position = PAYLOAD_SIZE;
+        self.current_chunk_len = PAYLOAD_SIZE;
+        let mut filled = 0;
+        while filled < BLOCK_SIZE {
+            match self.inner.read(&mut self.current_chunk[filled..]) {
+                Ok(0) if filled == 0 => return Ok(false),
+                Ok(0) => return Err(EncryptedFileError::InvalidChunk),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
 
-        cipher.decrypt_in_place_detached(
-            GenericArray::from_slice(&nonce),
-            &[], // no additional data
-            &mut self.payload_bytes_mut()[..],
-            &tag,
-        )?;
+        self.current_chunk_len = self
+            .tracker
+            .open_block(&mut self.current_chunk, self.global_position)?;
         self.current_chunk_position = 0;
         Ok(true)
     }
+
+    /// Drops the rest of the current block and the next `n` blocks without
+    /// decrypting them. Forward seeking for readers that aren't `Seek`.
+    pub fn skip_blocks(&mut self, n: u64) -> std::io::Result<()> {
+        self.global_position += (self.current_chunk_len - self.current_chunk_position) as u64;
+        self.current_chunk_position = PAYLOAD_SIZE;
+        self.current_chunk_len = PAYLOAD_SIZE;
+        self.tracker.reset_position();
+
+        let len = n.saturating_mul(BLOCK_SIZE as u64);
+        let skipped = std::io::copy(&mut (&mut self.inner).take(len), &mut std::io::sink())?;
+        self.global_position += skipped / BLOCK_SIZE as u64 * PAYLOAD_SIZE as u64;
+        if skipped % BLOCK_SIZE as u64 != 0 {
+            return Err(EncryptedFileError::InvalidChunk.into());
+        }
+        Ok(())
+    }
 }
 
 impl<R: Read> Read for EncryptedReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
-        if self.current_chunk_position == PAYLOAD_SIZE && !self.read_chunk()? {
+        if self.current_chunk_position == self.current_chunk_len && !self.read_chunk()? {
             return Ok(0);
         }
 
-        let to_read = std::cmp::min(buf.len(), PAYLOAD_SIZE - self.current_chunk_position);
+        let to_read = std::cmp::min(
+            buf.len(),
+            self.current_chunk_len - self.current_chunk_position,
+        );
         buf[..to_read]
             .copy_from_slice(&self.payload_bytes()[self.current_chunk_position..][..to_read]);
         self.current_chunk_position += to_read;
@@ -182,15 +251,18 @@ impl<R: Read + Seek> Seek for EncryptedReader<R> {
             SeekFrom::Start(n) => {
                 let block = n / PAYLOAD_SIZE as u64;
                 let offset = n % PAYLOAD_SIZE as u64;
-                self.inner
-                    .seek(SeekFrom::Start(block * BLOCK_SIZE as u64))?;
+                let inner_position = block
+                    .checked_mul(BLOCK_SIZE as u64)
+                    .ok_or_else(seek_out_of_range)?;
+                self.inner.seek(SeekFrom::Start(inner_position))?;
 
-                self.last_stream = None;
+                self.tracker.reset_position();
                 self.global_position = block * PAYLOAD_SIZE as u64;
 
                 if self.read_chunk()? {
-                    // if not at EOF
-                    self.current_chunk_position = offset as usize;
+                    // if not at EOF. Past the end of a last block reads
+                    // nothing, like past the end of the stream.
+                    self.current_chunk_position = (offset as usize).min(self.current_chunk_len);
                 }
 
                 self.global_position += offset;
@@ -198,28 +270,41 @@ impl<R: Read + Seek> Seek for EncryptedReader<R> {
                 Ok(self.global_position)
             }
             SeekFrom::Current(n) => {
-                let new_pos = self.global_position as i64 + n;
-                if new_pos < 0 {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidInput,
-                        "Seek before start of file",
-                    ));
-                }
-                self.seek(SeekFrom::Start(new_pos as u64))
+                let new_pos = self
+                    .global_position
+                    .checked_add_signed(n)
+                    .ok_or_else(seek_out_of_range)?;
+                self.seek(SeekFrom::Start(new_pos))
             }
             SeekFrom::End(n) => {
-                let end = self.inner.seek(SeekFrom::End(0))?;
-                let blocks = end / BLOCK_SIZE as u64;
-
-                let new_pos = blocks as i64 * PAYLOAD_SIZE as i64 + n;
-                if new_pos < 0 {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidInput,
-                        "Seek before start of file",
-                    ));
+                let position = self.global_position;
+                let blocks = self.inner.seek(SeekFrom::End(0))? / BLOCK_SIZE as u64;
+                // The last block may end early, it has to be read to know.
+                let end = match blocks.checked_sub(1) {
+                    Some(last) => {
+                        self.seek(SeekFrom::Start(last * PAYLOAD_SIZE as u64))?;
+                        last * PAYLOAD_SIZE as u64 + self.current_chunk_len as u64
+                    }
+                    None => 0,
+                };
+
+                match end.checked_add_signed(n) {
+                    Some(new_pos) => self.seek(SeekFrom::Start(new_pos)),
+                    None => {
+                        // Stay where we were.
+                        self.seek(SeekFrom::Start(position))?;
+                        Err(seek_out_of_range())
+                    }
                 }
-                self.seek(SeekFrom::Start(new_pos as u64))
             }
         }
     }
 }
+
+/// Offsets come from requests, e.g. a `Range` header.
+fn seek_out_of_range() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "Seek before start of file or out of range",
+    )
+}
@@ -0,0 +1,150 @@
+use std::{
+    collections::BTreeSet,
+    fmt::{Display, Formatter},
+    io::Write,
+};
+
+use super::{Header, BLOCK_SIZE, HEADER_SIZE};
+
+/// Why an encrypted stream was rejected, carried inside the `io::Error`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidStream {
+    pub position: u64,
+    pub reason: &'static str,
+}
+
+impl Display for InvalidStream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at byte {}", self.reason, self.position)
+    }
+}
+
+impl std::error::Error for InvalidStream {}
+
+impl InvalidStream {
+    /// The validation error inside `e`, if it is one.
+    pub fn from_io(e: &std::io::Error) -> Option<&InvalidStream> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+/// Passes data through to `inner` while checking the block structure of the
+/// encrypted stream. Only headers are looked at, so no passphrase is needed.
+pub struct StreamValidator<W> {
+    inner: W,
+    position: u64,
+    header: [u8; HEADER_SIZE],
+    /// Set when starting in the middle of a header, which can't be checked.
+    skip_header: bool,
+    current: Option<([u8; 10], u32)>,
+    ended: BTreeSet<[u8; 10]>,
+    /// Nothing may follow a last block.
+    after_last: bool,
+}
+
+impl<W: Write> StreamValidator<W> {
+    pub fn new(inner: W) -> Self {
+        Self::resume(inner, 0)
+    }
+
+    /// Validates data appended at `offset` of an existing stream.
+    pub fn resume(inner: W, offset: u64) -> Self {
+        Self {
+            inner,
+            position: offset,
+            header: [0; HEADER_SIZE],
+            skip_header: (1..HEADER_SIZE as u64).contains(&(offset % BLOCK_SIZE as u64)),
+            current: None,
+            ended: BTreeSet::new(),
+            after_last: false,
+        }
+    }
+
+    /// Checks that the stream ends on a block boundary.
+    pub fn finish(self) -> std::io::Result<W> {
+        if !self.position.is_multiple_of(BLOCK_SIZE as u64) {
+            return Err(self.invalid("Stream is not aligned to blocks"));
+        }
+        Ok(self.inner)
+    }
+
+    fn invalid(&self, reason: &'static str) -> std::io::Error {
+        let e = InvalidStream {
+            position: self.position,
+            reason,
+        };
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+
+    fn check(&mut self, mut data: &[u8]) -> std::io::Result<()> {
+        while !data.is_empty() {
+            let in_block = (self.position % BLOCK_SIZE as u64) as usize;
+            let n = if in_block < HEADER_SIZE {
+                let n = std::cmp::min(HEADER_SIZE - in_block, data.len());
+                self.header[in_block..][..n].copy_from_slice(&data[..n]);
+                n
+            } else {
+                std::cmp::min(BLOCK_SIZE - in_block, data.len())
+            };
+            self.position += n as u64;
+            data = &data[n..];
+
+            if in_block < HEADER_SIZE && in_block + n == HEADER_SIZE {
+                if self.skip_header {
+                    self.skip_header = false;
+                } else {
+                    self.check_header()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_header(&mut self) -> std::io::Result<()> {
+        let block = self.position / BLOCK_SIZE as u64;
+        let header = Header::from(self.header);
+        if !header.magic_ok() {
+            return Err(self.invalid("Invalid magic byte"));
+        }
+        if !header.variant_ok() {
+            return Err(self.invalid("Unsupported version or variant"));
+        }
+        if self.after_last {
+            return Err(self.invalid("Data after the last block"));
+        }
+        self.after_last = header.is_last();
+
+        match self.current {
+            Some((salt, counter)) if salt == header.salt => {
+                if counter.checked_add(1) != Some(header.blockcounter) {
+                    return Err(self.invalid("Block counter out of order"));
+                }
+            }
+            current => {
+                if self.ended.contains(&header.salt) {
+                    return Err(self.invalid("Stream continues after another one"));
+                }
+                if header.blockcounter as u64 > block {
+                    return Err(self.invalid("Block counter before start of data"));
+                }
+                if let Some((salt, _)) = current {
+                    self.ended.insert(salt);
+                }
+            }
+        }
+        self.current = Some((header.salt, header.blockcounter));
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for StreamValidator<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.check(buf)?;
+        self.inner.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
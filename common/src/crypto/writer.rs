@@ -1,51 +1,79 @@
-use std::io::Write;
-
-use chacha20poly1305::{
-    aead::{generic_array::GenericArray, AeadMutInPlace},
-    ChaCha20Poly1305, KeyInit,
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    sync::{Arc, OnceLock},
 };
+
+use chacha20poly1305::ChaCha20Poly1305;
 use rand::{RngCore, SeedableRng};
 
 use super::{
-    Header, BLOCK_SIZE, HEADER_SIZE, PAYLOAD_SIZE, VARIANT_ARGON_CHACHA20_POLY, VERSION_0,
+    EncryptedFileError, Header, BLOCK_SIZE, HEADER_SIZE, PAYLOAD_SIZE, VARIANT_ARGON_CHACHA20_POLY,
+    VARIANT_ARGON_CHACHA20_POLY_LAST, VERSION_0,
 };
 
+/// Header of the first block of a new stream with a random salt.
+pub(crate) fn new_stream_header() -> Header {
+    let mut salt = [0; 10];
+    let mut rng = rand::rngs::StdRng::from_entropy();
+    rng.fill_bytes(&mut salt);
+
+    Header {
+        magic: 0,
+        version: 0,
+        variant: 1,
+        blockcounter: 0,
+        salt,
+    }
+}
+
 pub struct EncryptedWriter<W: Write> {
     inner: W,
 
-    pub(crate) key: [u8; 32],
+    cipher: ChaCha20Poly1305,
     pub(crate) current_header: Header,
 
     current_chunk_position: usize,
     current_chunk: Box<[u8; BLOCK_SIZE]>,
+
+    hasher: Option<(blake3::Hasher, Arc<OnceLock<[u8; 32]>>)>,
 }
 
-impl<W: Write> EncryptedWriter<W> {
-    pub fn new(inner: W, passphrase: &[u8]) -> Self {
-        let mut salt = [0; 10];
-        let mut rng = rand::rngs::StdRng::from_entropy();
-        rng.fill_bytes(&mut salt);
+/// The BLAKE3 hash of the plaintext of an `EncryptedWriter`, see
+/// `EncryptedWriter::new_with_hash`.
+#[derive(Clone)]
+pub struct HashReader(Arc<OnceLock<[u8; 32]>>);
 
-        let header = Header {
-            magic: 0,
-            version: 0,
-            variant: 1,
-            blockcounter: 0,
-            salt,
-        };
+impl HashReader {
+    /// `None` until the writer is dropped.
+    pub fn hash(&self) -> Option<[u8; 32]> {
+        self.0.get().copied()
+    }
+}
 
+impl<W: Write> EncryptedWriter<W> {
+    pub fn new(inner: W, passphrase: &[u8]) -> Self {
+        let header = new_stream_header();
         let key = super::generate_key(passphrase, &header);
 
         Self {
             inner,
 
-            key,
+            cipher: super::stream_cipher(&key),
             current_header: header,
             current_chunk_position: 0,
             current_chunk: Box::new([0; BLOCK_SIZE]),
+            hasher: None,
         }
     }
 
+    /// Also hashes the plaintext, e.g. as a fingerprint of the content.
+    pub fn new_with_hash(inner: W, passphrase: &[u8]) -> (Self, HashReader) {
+        let hash = Arc::new(OnceLock::new());
+        let mut writer = Self::new(inner, passphrase);
+        writer.hasher = Some((blake3::Hasher::new(), hash.clone()));
+        (writer, HashReader(hash))
+    }
+
     #[allow(dead_code)] // used in tests
     pub(crate) fn new_from_salt_and_key(
         inner: W,
@@ -64,40 +92,93 @@ impl<W: Write> EncryptedWriter<W> {
         Self {
             inner,
 
-            key,
+            cipher: super::stream_cipher(&key),
             current_header: header,
             current_chunk_position: 0,
             current_chunk: Box::new([0; BLOCK_SIZE]),
+            hasher: None,
         }
     }
 
+    /// Plaintext bytes in the stream so far, including appended-to blocks.
+    pub fn current_plaintext_position(&self) -> u64 {
+        self.current_header.blockcounter as u64 * PAYLOAD_SIZE as u64
+            + self.current_chunk_position as u64
+    }
+
+    /// Full blocks in the stream so far, the partial one is not counted.
+    pub fn blocks_written(&self) -> u32 {
+        self.current_header.blockcounter
+    }
+
     fn write_chunk(&mut self) -> std::io::Result<()> {
-        self.current_chunk[0..HEADER_SIZE].copy_from_slice(&self.current_header.to_bytes());
-        for i in self.current_chunk_position..PAYLOAD_SIZE {
-            self.current_chunk[i] = 0;
+        if let Some((hasher, _)) = &mut self.hasher {
+            hasher.update(&self.current_chunk[HEADER_SIZE..][..self.current_chunk_position]);
         }
-        let nonce = super::payload_nonce(&self.current_header);
-        let mut cipher = ChaCha20Poly1305::new(GenericArray::from_slice(&self.key[..]));
-        let poly_tag = cipher
-            .encrypt_in_place_detached(
-                GenericArray::from_slice(&nonce[..]),
-                b"",
-                &mut self.current_chunk[HEADER_SIZE..][..PAYLOAD_SIZE],
-            )
-            .unwrap();
-        self.current_chunk[HEADER_SIZE + PAYLOAD_SIZE..].copy_from_slice(&poly_tag[..]);
-
+        super::seal_block(
+            &self.cipher,
+            &self.current_header,
+            &mut self.current_chunk,
+            self.current_chunk_position,
+        );
         self.inner.write_all(&self.current_chunk[..])?;
+        self.current_header.advance()
+    }
+}
+
+impl<W: Read + Write + Seek> EncryptedWriter<W> {
+    /// Continues the stream in `inner` after its first `existing_block_count`
+    /// blocks with the same salt, so it reads like one uninterrupted stream.
+    ///
+    /// The last of these blocks is checked against the passphrase and its
+    /// counter is continued. It has to be a full one, a stream ends with a
+    /// shorter block. Anything after it is overwritten, the caller truncates
+    /// if needed. Without existing blocks a new stream starts.
+    pub fn append(
+        mut inner: W,
+        passphrase: &[u8],
+        existing_block_count: u32,
+    ) -> std::io::Result<Self> {
+        if existing_block_count == 0 {
+            inner.seek(SeekFrom::Start(0))?;
+            return Ok(Self::new(inner, passphrase));
+        }
 
-        self.current_header.blockcounter = self
-            .current_header
-            .blockcounter
-            .checked_add(1)
-            .ok_or_else(|| {
-                std::io::Error::new(std::io::ErrorKind::Other, "Reached maximum bytes in stream")
-            })?;
+        let last = (existing_block_count as u64 - 1) * BLOCK_SIZE as u64;
+        inner.seek(SeekFrom::Start(last))?;
+        let mut block = [0u8; BLOCK_SIZE];
+        inner
+            .read_exact(&mut block)
+            .map_err(EncryptedFileError::from)?;
+
+        let mut header =
+            Header::from(<[u8; HEADER_SIZE]>::try_from(&block[..HEADER_SIZE]).unwrap());
+        if !header.magic_ok() {
+            return Err(EncryptedFileError::InvalidHeader.into());
+        }
+        if header.version != VERSION_0 || header.variant != VARIANT_ARGON_CHACHA20_POLY {
+            return Err(EncryptedFileError::UnsupportedVariant.into());
+        }
+
+        let key = super::generate_key(passphrase, &header);
+        let (payload, tag) = block[HEADER_SIZE..].split_at_mut(PAYLOAD_SIZE);
+        super::open_payload(
+            &key,
+            &header,
+            payload.try_into().unwrap(),
+            (&*tag).try_into().unwrap(),
+        )?;
+        header.advance()?;
+
+        Ok(Self {
+            inner,
 
-        Ok(())
+            cipher: super::stream_cipher(&key),
+            current_header: header,
+            current_chunk_position: 0,
+            current_chunk: Box::new([0; BLOCK_SIZE]),
+            hasher: None,
+        })
     }
 }
 
@@ -111,8 +192,10 @@ impl<W: Write> Write for EncryptedWriter<W> {
         self.current_chunk_position += to_write;
 
         if self.current_chunk_position == PAYLOAD_SIZE {
-            self.write_chunk()?;
+            let result = self.write_chunk();
+            // A block that failed to write is not retried on drop.
             self.current_chunk_position = 0;
+            result?;
         }
 
         Ok(to_write)
@@ -125,8 +208,13 @@ impl<W: Write> Write for EncryptedWriter<W> {
 
 impl<W: Write> Drop for EncryptedWriter<W> {
     fn drop(&mut self) {
+        // Marked, so the reader knows where the data ends.
         if self.current_chunk_position > 0 {
+            self.current_header.variant = VARIANT_ARGON_CHACHA20_POLY_LAST;
             self.write_chunk().unwrap();
         }
+        if let Some((hasher, hash)) = self.hasher.take() {
+            let _ = hash.set(*hasher.finalize().as_bytes());
+        }
     }
 }
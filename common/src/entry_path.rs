@@ -0,0 +1,48 @@
+/// Path of an archive entry as it is listed and put into zips: relative and
+/// `/` separated, without `.`, drive letters and control characters. Empty
+/// for the root, `None` if it climbs out with `..`.
+pub fn sanitize_entry_path(path: &str) -> Option<String> {
+    let path = path.replace('\\', "/");
+    let mut components = vec![];
+    for (i, component) in path.split('/').enumerate() {
+        let component = match component.as_bytes() {
+            [drive, b':', ..] if i == 0 && drive.is_ascii_alphabetic() => &component[2..],
+            _ => component,
+        };
+        let component: String = component.chars().filter(|c| !c.is_control()).collect();
+        match component.as_str() {
+            "" | "." => continue,
+            ".." => return None,
+            _ => components.push(component),
+        }
+    }
+    let mut out = components.join("/");
+    if !out.is_empty() && path.ends_with('/') {
+        out.push('/');
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_path() {
+        assert_eq!(sanitize_entry_path("./a/b.txt").as_deref(), Some("a/b.txt"));
+        assert_eq!(
+            sanitize_entry_path("/etc/passwd").as_deref(),
+            Some("etc/passwd")
+        );
+        assert_eq!(sanitize_entry_path("C:\\x\\y").as_deref(), Some("x/y"));
+        assert_eq!(
+            sanitize_entry_path("dir//sub/").as_deref(),
+            Some("dir/sub/")
+        );
+        assert_eq!(sanitize_entry_path("a\r\n.txt").as_deref(), Some("a.txt"));
+        assert_eq!(sanitize_entry_path("./").as_deref(), Some(""));
+        assert_eq!(sanitize_entry_path("../../evil"), None);
+        assert_eq!(sanitize_entry_path("a/../../b"), None);
+        assert_eq!(sanitize_entry_path("a\\..\\b"), None);
+    }
+}
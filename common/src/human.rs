@@ -0,0 +1,59 @@
+/// Sizes for people, in the unit that keeps the number below 4096.
+pub fn format_bytes(mut size: u64) -> String {
+    let prefix = ["b", "K", "M", "G", "T", "P", "E", "Z", "Y"];
+    for i in prefix {
+        if size < 4096 {
+            return format!("{size} {i}");
+        }
+        size /= 1024;
+    }
+    format!("{size}x∞")
+}
+
+/// Transfer rates, as shown by the progress bar.
+pub fn format_speed(bytes_per_sec: f64) -> String {
+    if bytes_per_sec > 1024.0 * 1024.0 {
+        format!("{:.2} MB/s", bytes_per_sec / 1024.0 / 1024.0)
+    } else if bytes_per_sec > 1024.0 {
+        format!("{:.2} KB/s", bytes_per_sec / 1024.0)
+    } else {
+        format!("{:.2} B/s", bytes_per_sec)
+    }
+}
+
+pub fn human_duration(secs: u64) -> String {
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    if days > 0 {
+        format!("{days}d {hours}h")
+    } else if hours > 0 {
+        format!("{hours}h {minutes}m")
+    } else {
+        format!("{minutes}m")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(615), "615 b");
+        assert_eq!(format_bytes(1_200_000), "1171 K");
+        assert_eq!(format_bytes(5 << 30), "5 G");
+    }
+
+    #[test]
+    fn test_format_speed() {
+        assert_eq!(format_speed(10.0), "10.00 B/s");
+        assert_eq!(format_speed(2048.0), "2.00 KB/s");
+        assert_eq!(format_speed(3.5 * 1024.0 * 1024.0), "3.50 MB/s");
+    }
+
+    #[test]
+    fn test_human_duration() {
+        assert_eq!(human_duration(7 * 86400), "7d 0h");
+        assert_eq!(human_duration(3 * 3600 + 120), "3h 2m");
+        assert_eq!(human_duration(59), "0m");
+    }
+}
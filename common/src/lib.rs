@@ -3,11 +3,22 @@ extern crate test;
 
 mod bip39;
 mod crypto;
+mod entry_path;
+mod human;
+mod limit;
 mod pipe;
+mod progress;
 mod tar_hash;
+mod tar_index;
 mod tar_password;
 
+pub use bip39::{word_at, word_index, WORD_COUNT};
 pub use crypto::*;
+pub use entry_path::*;
+pub use human::*;
+pub use limit::*;
 pub use pipe::*;
+pub use progress::*;
 pub use tar_hash::*;
+pub use tar_index::*;
 pub use tar_password::*;
@@ -0,0 +1,142 @@
+use std::{
+    fmt::{Display, Formatter},
+    io::{Read, Write},
+};
+
+/// The error inside the `io::Error` of a `SizeLimitedReader` or `SizeLimitedWriter`.
+#[derive(Debug)]
+pub struct SizeLimitExceeded {
+    pub limit: u64,
+}
+
+impl Display for SizeLimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "size limit exceeded, at most {} bytes", self.limit)
+    }
+}
+
+impl std::error::Error for SizeLimitExceeded {}
+
+impl SizeLimitExceeded {
+    /// The limit error inside `e`, if it is one.
+    pub fn from_io(e: &std::io::Error) -> Option<&SizeLimitExceeded> {
+        e.get_ref()?.downcast_ref()
+    }
+
+    fn io(limit: u64) -> std::io::Error {
+        std::io::Error::other(SizeLimitExceeded { limit })
+    }
+}
+
+/// Fails once more than `limit` bytes would be read. A stream of exactly
+/// `limit` bytes reads to the end.
+pub struct SizeLimitedReader<R> {
+    inner: R,
+    remaining: u64,
+    limit: u64,
+}
+
+impl<R> SizeLimitedReader<R> {
+    pub fn new(inner: R, limit: u64) -> Self {
+        Self {
+            inner,
+            remaining: limit,
+            limit,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for SizeLimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            // Only an error if there is more.
+            return match self.inner.read(&mut [0])? {
+                0 => Ok(0),
+                _ => Err(SizeLimitExceeded::io(self.limit)),
+            };
+        }
+        let len = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..len])?;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Fails writes past `limit` bytes, the part that fits is written.
+pub struct SizeLimitedWriter<W> {
+    inner: W,
+    remaining: u64,
+    limit: u64,
+}
+
+impl<W> SizeLimitedWriter<W> {
+    pub fn new(inner: W, limit: u64) -> Self {
+        Self {
+            inner,
+            remaining: limit,
+            limit,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for SizeLimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            return Err(SizeLimitExceeded::io(self.limit));
+        }
+        let len = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        let n = self.inner.write(&buf[..len])?;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reader() {
+        let mut out = vec![];
+        let mut reader = SizeLimitedReader::new(&[1u8; 100][..], 100);
+        assert_eq!(reader.read_to_end(&mut out).unwrap(), 100);
+
+        let mut reader = SizeLimitedReader::new(&[1u8; 101][..], 100);
+        let err = reader.read_to_end(&mut out).unwrap_err();
+        assert_eq!(SizeLimitExceeded::from_io(&err).unwrap().limit, 100);
+    }
+
+    #[test]
+    fn test_writer() {
+        let mut writer = SizeLimitedWriter::new(vec![], 10);
+        writer.write_all(b"0123456789").unwrap();
+        let err = writer.write_all(b"a").unwrap_err();
+        assert!(SizeLimitExceeded::from_io(&err).is_some());
+
+        let mut writer = SizeLimitedWriter::new(vec![], 10);
+        assert!(writer.write_all(b"0123456789a").is_err());
+        assert_eq!(writer.into_inner(), b"0123456789");
+    }
+}
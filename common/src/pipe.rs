@@ -1,60 +1,263 @@
-use std::io::Read;
+use std::{
+    io::Read,
+    sync::{Arc, Condvar, Mutex},
+};
 
-// TODO: Optimize this
+use crate::{EncryptedWriter, HashReader};
+
+pub type EncryptedPipeWriter = EncryptedWriter<PipeWriter>;
+
+/// Bytes the writer may be ahead of what the reader took, before it blocks.
+const PIPE_CAPACITY: usize = 256 * 1024;
+
+/// Smaller writes are collected by the writer until there is this much, or
+/// until `flush`, so they don't take the lock and wake the reader each time.
+const BATCH_SIZE: usize = 32 * 1024;
+
+/// A blocking, in-memory pipe to hand a stream to another thread, e.g. the
+/// body of a request.
 pub fn create_pipe() -> (PipeWriter, PipeReader) {
-    let (sender, receiver) = std::sync::mpsc::sync_channel(64);
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            buffer: vec![],
+            writer_closed: false,
+            reader_closed: false,
+            reader_waiting: false,
+            writer_waiting: false,
+        }),
+        readable: Condvar::new(),
+        writable: Condvar::new(),
+    });
     (
-        PipeWriter { written: 0, sender },
+        PipeWriter {
+            written: 0,
+            batch: Vec::with_capacity(BATCH_SIZE),
+            shared: shared.clone(),
+        },
         PipeReader {
+            shared,
             buffer: vec![],
-            receiver,
+            position: 0,
         },
     )
 }
 
+/// A pipe whose reader gets what was written encrypted with `passphrase`.
+pub fn create_encrypted_pipe(passphrase: &[u8]) -> (EncryptedPipeWriter, PipeReader) {
+    let (writer, reader) = create_pipe();
+    (EncryptedWriter::new(writer, passphrase), reader)
+}
+
+/// Like `create_encrypted_pipe`, also hashing the plaintext, see
+/// `EncryptedWriter::new_with_hash`.
+pub fn create_encrypted_pipe_with_hash(
+    passphrase: &[u8],
+) -> (EncryptedPipeWriter, PipeReader, HashReader) {
+    let (writer, reader) = create_pipe();
+    let (writer, hash) = EncryptedWriter::new_with_hash(writer, passphrase);
+    (writer, reader, hash)
+}
+
+struct State {
+    /// Filled by the writer, swapped with the empty buffer of the reader.
+    buffer: Vec<u8>,
+    writer_closed: bool,
+    reader_closed: bool,
+    // Waking the other side costs a syscall, so only when it waits.
+    reader_waiting: bool,
+    writer_waiting: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    readable: Condvar,
+    writable: Condvar,
+}
+
 pub struct PipeReader {
+    shared: Arc<Shared>,
     buffer: Vec<u8>,
-    receiver: std::sync::mpsc::Receiver<Vec<u8>>,
+    /// Read up to here, the rest of `buffer` is still to be read.
+    position: usize,
 }
 
 pub struct PipeWriter {
     written: u64,
-    sender: std::sync::mpsc::SyncSender<Vec<u8>>,
+    batch: Vec<u8>,
+    shared: Arc<Shared>,
 }
 
 impl PipeWriter {
     pub fn written(&self) -> u64 {
         self.written
     }
+
+    /// Blocks until all of `data` is in the pipe.
+    fn send(shared: &Shared, mut data: &[u8]) -> std::io::Result<()> {
+        let mut state = shared.state.lock().unwrap();
+        while !data.is_empty() {
+            if state.reader_closed {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "Pipe reader closed",
+                ));
+            }
+            let free = PIPE_CAPACITY.saturating_sub(state.buffer.len());
+            if free == 0 {
+                state.writer_waiting = true;
+                state = shared.writable.wait(state).unwrap();
+                state.writer_waiting = false;
+                continue;
+            }
+            let n = free.min(data.len());
+            state.buffer.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if state.reader_waiting {
+                shared.readable.notify_one();
+            }
+        }
+        Ok(())
+    }
+
+    fn send_batch(&mut self) -> std::io::Result<()> {
+        Self::send(&self.shared, &self.batch)?;
+        self.batch.clear();
+        Ok(())
+    }
 }
 
 impl Read for PipeReader {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        if self.buffer.is_empty() {
-            match self.receiver.recv() {
-                Ok(v) => {
-                    self.buffer = v;
+        if self.position == self.buffer.len() && !buf.is_empty() {
+            let mut state = self.shared.state.lock().unwrap();
+            while state.buffer.is_empty() {
+                if state.writer_closed {
+                    return Ok(0);
                 }
-                Err(_) => return Ok(0),
-            };
+                state.reader_waiting = true;
+                state = self.shared.readable.wait(state).unwrap();
+                state.reader_waiting = false;
+            }
+            self.buffer.clear();
+            std::mem::swap(&mut self.buffer, &mut state.buffer);
+            self.position = 0;
+            if state.writer_waiting {
+                self.shared.writable.notify_one();
+            }
         }
-        let n = std::cmp::min(buf.len(), self.buffer.len());
-        buf[..n].copy_from_slice(&self.buffer[..n]);
-        self.buffer.drain(..n);
+
+        let n = buf.len().min(self.buffer.len() - self.position);
+        buf[..n].copy_from_slice(&self.buffer[self.position..self.position + n]);
+        self.position += n;
         Ok(n)
     }
 }
 
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().reader_closed = true;
+        self.shared.writable.notify_one();
+    }
+}
+
 impl std::io::Write for PipeWriter {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.sender
-            .send(buf.to_vec())
-            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "send error"))?;
+        if self.batch.len() + buf.len() > BATCH_SIZE {
+            self.send_batch()?;
+        }
+        if buf.len() >= BATCH_SIZE {
+            Self::send(&self.shared, buf)?;
+        } else {
+            self.batch.extend_from_slice(buf);
+        }
         self.written += buf.len() as u64;
         Ok(buf.len())
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        Ok(())
+        self.send_batch()
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        // Nobody to tell if the reader is gone.
+        let _ = self.send_batch();
+        self.shared.state.lock().unwrap().writer_closed = true;
+        self.shared.readable.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EncryptedReader;
+    use std::io::Write;
+
+    #[test]
+    fn test_encrypted_pipe() {
+        let (mut writer, mut reader) = create_encrypted_pipe(b"secret");
+        let handle = std::thread::spawn(move || {
+            writer.write_all(b"hello pipe").unwrap();
+        });
+
+        let mut encrypted = vec![];
+        reader.read_to_end(&mut encrypted).unwrap();
+        handle.join().unwrap();
+        assert!(!encrypted.windows(4).any(|w| w == b"pipe"));
+
+        let mut plain = vec![];
+        EncryptedReader::new(std::io::Cursor::new(encrypted), b"secret")
+            .read_to_end(&mut plain)
+            .unwrap();
+        assert_eq!(&plain[..10], b"hello pipe");
+    }
+
+    #[test]
+    fn test_encrypted_pipe_with_hash() {
+        let (mut writer, mut reader, hash) = create_encrypted_pipe_with_hash(b"secret");
+        let handle = std::thread::spawn(move || {
+            writer.write_all(b"hello pipe").unwrap();
+        });
+        reader.read_to_end(&mut vec![]).unwrap();
+        handle.join().unwrap();
+        assert_eq!(hash.hash(), Some(*blake3::hash(b"hello pipe").as_bytes()));
+    }
+
+    #[test]
+    fn test_pipe_sizes() {
+        let data: Vec<u8> = (0..3 * PIPE_CAPACITY + 17).map(|i| i as u8).collect();
+        let (mut writer, mut reader) = create_pipe();
+        let sent = data.clone();
+        let handle = std::thread::spawn(move || {
+            // Odd sizes, and one larger than the pipe.
+            let (small, large) = sent.split_at(1000);
+            for chunk in small.chunks(7) {
+                writer.write_all(chunk).unwrap();
+            }
+            writer.write_all(large).unwrap();
+            writer.written()
+        });
+
+        let mut received = vec![];
+        let mut buf = [0u8; 333];
+        loop {
+            match reader.read(&mut buf).unwrap() {
+                0 => break,
+                n => received.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert_eq!(handle.join().unwrap(), data.len() as u64);
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn test_reader_dropped() {
+        let (mut writer, reader) = create_pipe();
+        drop(reader);
+        // Small writes are only sent with the next batch.
+        writer.write_all(b"nobody listens").unwrap();
+        let err = writer.flush().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
     }
 }
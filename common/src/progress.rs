@@ -0,0 +1,197 @@
+use std::{
+    fmt::Display,
+    io::{Read, Write},
+    time::{Duration, Instant},
+};
+
+use crate::{format_bytes, format_speed};
+
+/// Clears the current terminal line and returns to its start.
+pub const DELETE_LINE: &str = "\x1B[2K\r";
+
+/// Time between two redraws.
+const UPDATE_INTERVAL: f32 = 0.5;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProgressStyle {
+    /// Redraws one line of the terminal.
+    #[default]
+    Bar,
+    /// A line per update without escape codes, for logs.
+    Plain,
+}
+
+/// Progress of a transfer on stdout, with speed and, if the total is known,
+/// percentage and remaining time.
+pub struct ProgressBar {
+    visible: bool,
+    style: ProgressStyle,
+    started: Instant,
+    last_update: Instant,
+    current: u64,
+    last_progress: u64,
+    total: Option<u64>,
+}
+
+/// Counts what is read through it, see `ProgressBar::reader`.
+pub struct ProgressReader<'a, D, R> {
+    bar: &'a mut ProgressBar,
+    display: D,
+    inner: R,
+}
+
+impl<D: Display, R: Read> Read for ProgressReader<'_, D, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bar.update(n as u64, &self.display);
+        Ok(n)
+    }
+}
+
+impl ProgressBar {
+    /// Without a total only the amount and speed are shown.
+    pub fn new(total: Option<u64>) -> Self {
+        Self {
+            visible: true,
+            style: ProgressStyle::default(),
+            started: Instant::now(),
+            last_update: Instant::now(),
+            current: 0,
+            last_progress: 0,
+            total,
+        }
+    }
+
+    pub fn with_style(mut self, style: ProgressStyle) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// Still counts when hidden, e.g. for the elapsed time and speed.
+    pub fn with_visible(mut self, visible: bool) -> Self {
+        self.visible = visible;
+        self
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn current(&self) -> u64 {
+        self.current
+    }
+
+    pub fn reader<D: Display, R: Read>(
+        &mut self,
+        display: D,
+        inner: R,
+    ) -> ProgressReader<'_, D, R> {
+        ProgressReader {
+            bar: self,
+            display,
+            inner,
+        }
+    }
+
+    /// For callbacks that report the amount so far instead of steps.
+    pub fn set<D: Display>(&mut self, done: u64, total: Option<u64>, message: D) {
+        self.total = total;
+        self.update(done.saturating_sub(self.current), message);
+    }
+
+    pub fn update<D: Display>(&mut self, progress: u64, message: D) {
+        self.current += progress;
+        if !self.visible {
+            return;
+        }
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_update).as_secs_f32();
+        if elapsed < UPDATE_INTERVAL {
+            return;
+        }
+
+        let speed = (self.current - self.last_progress) as f32 / (elapsed + 0.0001);
+        self.last_progress = self.current;
+        self.last_update = now;
+
+        match self.style {
+            ProgressStyle::Bar => {
+                print!("{DELETE_LINE}{}", self.line(speed, message));
+                let _ = std::io::stdout().flush();
+            }
+            ProgressStyle::Plain => println!("{}", self.line(speed, message)),
+        }
+    }
+
+    fn line<D: Display>(&self, speed: f32, message: D) -> String {
+        let speed_text = format_speed(speed as f64);
+
+        let total = match self.total {
+            Some(total) if total > 0 => total,
+            _ => {
+                let done = format_bytes(self.current);
+                return format!("{done:>8}  {speed_text:10} - {message}");
+            }
+        };
+
+        let percent = if self.current < total {
+            (self.current as f64 / total as f64) * 100.0
+        } else {
+            100.0
+        };
+        let eta = if self.current < total && speed > 0.0 {
+            let remaining = total - self.current;
+            remaining as f32 / speed
+        } else {
+            0.0
+        };
+
+        let eta = if eta > 60.0 * 60.0 {
+            format!("{:.2} h", eta / 60.0 / 60.0)
+        } else if eta > 60.0 {
+            format!("{:.2} m", eta / 60.0)
+        } else {
+            format!("{:.2} s", eta)
+        };
+
+        match self.style {
+            ProgressStyle::Bar => {
+                let bar = (0..((percent / 5.0) as isize))
+                    .map(|_| "=")
+                    .collect::<String>();
+                format!("|{bar:20}|  {percent:02.0}%  {speed_text:10}  eta {eta:9} - {message}")
+            }
+            ProgressStyle::Plain => {
+                format!("{percent:3.0}%  {speed_text:10}  eta {eta:9} - {message}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line() {
+        let mut bar = ProgressBar::new(Some(1000)).with_visible(false);
+        bar.update(500, "");
+        assert_eq!(
+            bar.line(2048.0, "a.txt"),
+            "|==========          |  50%  2.00 KB/s   eta 0.24 s    - a.txt"
+        );
+
+        let bar = bar.with_style(ProgressStyle::Plain);
+        assert_eq!(
+            bar.line(2048.0, "a.txt"),
+            " 50%  2.00 KB/s   eta 0.24 s    - a.txt"
+        );
+
+        // Unknown total.
+        let mut bar = ProgressBar::new(None).with_visible(false);
+        bar.set(2000, None, "b");
+        assert_eq!(bar.current(), 2000);
+        assert_eq!(bar.line(10.0, "b"), "  2000 b  10.00 B/s  - b");
+    }
+}
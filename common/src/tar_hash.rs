@@ -5,15 +5,15 @@ use std::{
 
 use argon2::{Config, ThreadMode, Variant, Version};
 
-use crate::tar_password::TarPassword;
-
 #[derive(Hash, Eq, PartialEq, Debug, Clone)]
 pub struct TarHash {
     hash: [u8; 32],
 }
 
 impl TarHash {
-    pub fn from_tarid(id: &TarPassword, salt: &str) -> Self {
+    /// Hash of a code, e.g. a `TarPassword`, with the host name as salt.
+    /// Only the text of the code counts.
+    pub fn from_tarid<T: Display + ?Sized>(id: &T, salt: &str) -> Self {
         let password = id.to_string();
         let config = Config {
             variant: Variant::Argon2i,
@@ -66,3 +66,18 @@ impl FromStr for TarHash {
         Ok(TarHash { hash })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TarPassword;
+
+    #[test]
+    fn test_from_tarid_text() {
+        let code = TarPassword::generate();
+        assert_eq!(
+            TarHash::from_tarid(&code, "localhost"),
+            TarHash::from_tarid(code.to_string().as_str(), "localhost")
+        );
+    }
+}
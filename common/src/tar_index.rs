@@ -0,0 +1,71 @@
+use std::{
+    fmt::Display,
+    io::{Read, Seek},
+};
+
+/// An entry of a tar archive, without its contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TarIndexEntry {
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub mtime: u64,
+    /// Position of the contents in the tar stream.
+    pub offset: u64,
+}
+
+#[derive(Debug)]
+pub enum TarIndexError {
+    /// More than `max_entries`, the scan stops at the first one too many.
+    TooManyEntries,
+    /// A path longer than `max_path_length` bytes.
+    PathTooLong,
+    Io(std::io::Error),
+}
+
+impl Display for TarIndexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TarIndexError::TooManyEntries => write!(f, "Too many entries"),
+            TarIndexError::PathTooLong => write!(f, "Path too long"),
+            TarIndexError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TarIndexError {}
+
+impl From<std::io::Error> for TarIndexError {
+    fn from(e: std::io::Error) -> Self {
+        TarIndexError::Io(e)
+    }
+}
+
+/// Lists the entries of an archive from an upload, seeking over the contents.
+/// The limits bound the work for archives made to be expensive to list.
+pub fn scan_tar_index<R: Read + Seek>(
+    reader: R,
+    max_entries: usize,
+    max_path_length: usize,
+) -> Result<Vec<TarIndexEntry>, TarIndexError> {
+    let mut index = Vec::new();
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries_with_seek()? {
+        if index.len() >= max_entries {
+            return Err(TarIndexError::TooManyEntries);
+        }
+        let entry = entry?;
+        let path = entry.path()?.to_string_lossy().to_string();
+        if path.len() > max_path_length {
+            return Err(TarIndexError::PathTooLong);
+        }
+        index.push(TarIndexEntry {
+            is_dir: entry.header().entry_type().is_dir() || path.ends_with('/'),
+            path,
+            size: entry.size(),
+            mtime: entry.header().mtime().unwrap_or(0),
+            offset: entry.raw_file_position(),
+        });
+    }
+    Ok(index)
+}
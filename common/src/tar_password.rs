@@ -1,5 +1,5 @@
-use crate::bip39::WORDS as BIP39_WORDS;
-use rand::{Rng, SeedableRng};
+use crate::bip39::{word_index, WORDS as BIP39_WORDS, WORD_COUNT};
+use rand::{Rng, RngCore, SeedableRng};
 use std::{fmt::Display, str::FromStr};
 
 #[derive(Debug, Clone)]
@@ -24,27 +24,37 @@ impl Display for TarPassword {
 
 impl TarPassword {
     pub fn generate() -> Self {
-        let mut rng = rand::rngs::StdRng::from_entropy();
+        Self::generate_from_rng(&mut rand::rngs::StdRng::from_entropy())
+    }
+
+    /// Same as `generate`, a seeded `rng` gives reproducible codes for tests.
+    pub fn generate_from_rng<R: RngCore>(rng: &mut R) -> Self {
         let prefix = rng.gen_range(0..10000);
         let words = [
-            rng.gen_range(0..2048),
-            rng.gen_range(0..2048),
-            rng.gen_range(0..2048),
-            rng.gen_range(0..2048),
+            rng.gen_range(0..WORD_COUNT as u16),
+            rng.gen_range(0..WORD_COUNT as u16),
+            rng.gen_range(0..WORD_COUNT as u16),
+            rng.gen_range(0..WORD_COUNT as u16),
         ];
         Self { prefix, words }
     }
 
     pub fn parse(input: &str) -> Option<Self> {
         let mut input = input.split('-');
-        let num = input.next()?.parse().ok()?;
+        // At most the four digits the code is shown with, `u16::from_str`
+        // would also take a sign and larger numbers.
+        let prefix = input.next()?;
+        if prefix.is_empty() || prefix.len() > 4 || !prefix.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let num = prefix.parse().ok()?;
 
         let mut words = [0; 4];
         for word in &mut words {
             let input_word = input.next()?;
-            match BIP39_WORDS.binary_search(&input_word) {
-                Ok(idx) => *word = idx as u16,
-                Err(_) if input_word.len() <= 10 && input_word.len() >= 2 => {
+            match word_index(input_word) {
+                Some(idx) => *word = idx,
+                None if input_word.len() <= 10 && input_word.len() >= 2 => {
                     let lower = input_word.to_lowercase();
                     let candidates: Vec<_> = BIP39_WORDS
                         .iter()
@@ -59,7 +69,7 @@ impl TarPassword {
                         return None;
                     }
                 }
-                Err(_) => {
+                None => {
                     return None;
                 }
             }
@@ -102,6 +112,35 @@ mod tests {
         assert_eq!(id.to_string(), "0005-abandon-ability-able-about")
     }
 
+    #[test]
+    fn test_generate_from_rng() {
+        let mut a = rand::rngs::StdRng::seed_from_u64(42);
+        let mut b = rand::rngs::StdRng::seed_from_u64(42);
+        let code = TarPassword::generate_from_rng(&mut a);
+        assert_eq!(
+            code.to_string(),
+            TarPassword::generate_from_rng(&mut b).to_string()
+        );
+        assert_ne!(
+            code.to_string(),
+            TarPassword::generate_from_rng(&mut a).to_string()
+        );
+        assert!(TarPassword::parse(&code.to_string()).is_some());
+    }
+
+    #[test]
+    fn test_parse_prefix() {
+        for code in [
+            "+005-abandon-ability-able-about",
+            "10000-abandon-ability-able-about",
+            "-abandon-ability-able-about",
+        ] {
+            assert!(TarPassword::parse(code).is_none(), "{code}");
+        }
+        let id = TarPassword::parse("5-abandon-ability-able-about").unwrap();
+        assert_eq!(id.to_string(), "0005-abandon-ability-able-about");
+    }
+
     #[test]
     fn test_parse_err() {
         let id = TarPassword::parse("0005-abondon-abilty-able-abou").unwrap();
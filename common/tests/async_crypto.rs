@@ -0,0 +1,99 @@
+#![cfg(feature = "tokio")]
+
+use common::{AsyncEncryptedReader, AsyncEncryptedWriter, EncryptedReader, EncryptedWriter};
+use rand::RngCore;
+use std::io::{Read, Write};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+fn generate_data(len: usize) -> Vec<u8> {
+    let mut data = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut data);
+    data
+}
+
+async fn encrypt_async(data: &[u8], passphrase: &str) -> Vec<u8> {
+    let mut writer = AsyncEncryptedWriter::new(Vec::new(), passphrase.as_bytes());
+    // odd sized writes to cross block boundaries
+    for chunk in data.chunks(1000) {
+        writer.write_all(chunk).await.unwrap();
+    }
+    writer.shutdown().await.unwrap();
+    writer.into_inner()
+}
+
+async fn decrypt_async(data: &[u8], passphrase: &str) -> std::io::Result<Vec<u8>> {
+    let mut reader = AsyncEncryptedReader::new(data, passphrase.as_bytes());
+    let mut out = Vec::new();
+    reader.read_to_end(&mut out).await?;
+    Ok(out)
+}
+
+#[tokio::test]
+async fn async_roundtrip() {
+    let original = generate_data(100 * 1024 + 17);
+    let encrypted = encrypt_async(&original, "test").await;
+    let decrypted = decrypt_async(&encrypted, "test").await.unwrap();
+
+    // the last block is padded with zeros
+    assert_eq!(&decrypted[..original.len()], &original[..]);
+    assert!(decrypted[original.len()..].iter().all(|b| *b == 0));
+    assert_eq!(
+        encrypted.len() as u64,
+        common::encrypted_size(original.len() as u64)
+    );
+}
+
+#[tokio::test]
+async fn async_writer_sync_reader() {
+    let original = generate_data(64 * 1024);
+    let encrypted = encrypt_async(&original, "test").await;
+
+    let mut reader = EncryptedReader::new(&encrypted[..], b"test");
+    let mut decrypted = Vec::new();
+    reader.read_to_end(&mut decrypted).unwrap();
+    assert_eq!(decrypted, original);
+}
+
+#[tokio::test]
+async fn sync_writer_async_reader() {
+    let original = generate_data(64 * 1024);
+    let mut encrypted = Vec::new();
+    let mut writer = EncryptedWriter::new(&mut encrypted, b"test");
+    writer.write_all(&original).unwrap();
+    drop(writer);
+
+    assert_eq!(decrypt_async(&encrypted, "test").await.unwrap(), original);
+}
+
+#[tokio::test]
+async fn async_reader_over_duplex() {
+    let original = generate_data(32 * 1024);
+    let encrypted = encrypt_async(&original, "test").await;
+
+    // small pipe capacity forces partial reads of blocks
+    let (mut tx, rx) = tokio::io::duplex(100);
+    let send = async move {
+        tx.write_all(&encrypted).await.unwrap();
+        tx.shutdown().await.unwrap();
+    };
+    let receive = async move {
+        let mut out = Vec::new();
+        AsyncEncryptedReader::new(rx, b"test")
+            .read_to_end(&mut out)
+            .await
+            .map(|_| out)
+    };
+    let (_, decrypted) = tokio::join!(send, receive);
+    assert_eq!(decrypted.unwrap(), original);
+}
+
+#[tokio::test]
+async fn async_errors() {
+    let original = generate_data(16 * 1024);
+    let encrypted = encrypt_async(&original, "test").await;
+
+    assert!(decrypt_async(&encrypted, "wrong").await.is_err());
+    assert!(decrypt_async(&encrypted[..encrypted.len() - 10], "test")
+        .await
+        .is_err());
+}
@@ -0,0 +1,40 @@
+#![no_main]
+
+use std::io::Read;
+
+use common::{EncryptedReader, BLOCK_SIZE, PAYLOAD_SIZE};
+use libfuzzer_sys::fuzz_target;
+
+// The first byte picks the strict reader, the rest is the stream.
+fuzz_target!(|data: &[u8]| {
+    let Some((&mode, data)) = data.split_first() else {
+        return;
+    };
+    let mut reader = if mode & 1 == 0 {
+        EncryptedReader::new(data, b"fuzz")
+    } else {
+        EncryptedReader::new_strict(data, b"fuzz")
+    };
+    let blocks = data.len() / BLOCK_SIZE;
+
+    let mut out = Vec::new();
+    if reader.read_to_end(&mut out).is_ok() {
+        assert!(out.len() <= blocks * PAYLOAD_SIZE);
+        return;
+    }
+
+    // Callers may go on reading after an error. Every call returns a block or
+    // drops one, so it has to end within that many calls.
+    let mut buf = [0; PAYLOAD_SIZE];
+    for _ in 0..=2 * blocks + 2 {
+        match reader.read(&mut buf) {
+            Ok(0) => {
+                assert!(out.len() <= blocks * PAYLOAD_SIZE);
+                return;
+            }
+            Ok(n) => out.extend_from_slice(&buf[..n]),
+            Err(_) => {}
+        }
+    }
+    panic!("Reader did not end after {} blocks", blocks);
+});
@@ -0,0 +1,22 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use common::scan_tar_index;
+use libfuzzer_sys::fuzz_target;
+
+/// Archives are decrypted before scanning, that is covered by `encrypted_reader`.
+const MAX_SIZE: usize = 1024 * 1024;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() > MAX_SIZE {
+        return;
+    }
+    if let Ok(index) = scan_tar_index(Cursor::new(data), 1000, 4096) {
+        // Every entry has at least a header block.
+        assert!(index.len() <= data.len() / 512);
+        for entry in index {
+            assert!(entry.path.len() <= 4096);
+        }
+    }
+});
@@ -0,0 +1,16 @@
+#![no_main]
+
+use common::TarPassword;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(input) = std::str::from_utf8(data) else {
+        return;
+    };
+    // Also takes near misses, but what it shows has to parse to the same code.
+    if let Some(code) = TarPassword::parse(input) {
+        let shown = code.to_string();
+        let again = TarPassword::parse(&shown).expect("shown code parses");
+        assert_eq!(again.to_string(), shown);
+    }
+});
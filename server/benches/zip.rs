@@ -0,0 +1,86 @@
+use std::{
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    time::Duration,
+};
+
+use common::{EncryptedReader, EncryptedWriter};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use tarcloud::zip_stream::{tar_to_zip, zip_time};
+
+const FILES: usize = 100;
+const FILE_SIZE: usize = 1024 * 1024;
+const CODE: &[u8] = b"bench";
+
+/// A tar of `FILES` files, encrypted as it is stored.
+fn archive() -> Vec<u8> {
+    let mut tar = tar::Builder::new(vec![]);
+    let data = vec![7u8; FILE_SIZE];
+    for i in 0..FILES {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(FILE_SIZE as u64);
+        header.set_mode(0o644);
+        header.set_mtime(1_600_000_000);
+        tar.append_data(&mut header, format!("dir/file-{i}.bin"), &data[..])
+            .unwrap();
+    }
+
+    let mut encrypted = vec![];
+    let mut writer = EncryptedWriter::new(&mut encrypted, CODE);
+    writer.write_all(&tar.into_inner().unwrap()).unwrap();
+    drop(writer);
+    encrypted
+}
+
+fn decrypted(encrypted: &[u8]) -> EncryptedReader<Cursor<&[u8]>> {
+    let mut reader = EncryptedReader::new(Cursor::new(encrypted), CODE);
+    // Derives the key, it would be measured too otherwise.
+    reader.read_exact(&mut [0]).unwrap();
+    reader.seek(SeekFrom::Start(0)).unwrap();
+    reader
+}
+
+/// The conversion before `tar_to_zip`, reading and writing in one thread.
+fn sequential<R: Read + Seek, W: Write>(reader: R, out: W) -> anyhow::Result<W> {
+    let mut archive = tar::Archive::new(reader);
+    let mut zip = streaming_zip::Archive::new(out);
+    for entry in archive.entries_with_seek()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().to_string();
+        let mtime = entry.header().mtime().unwrap_or(0);
+        zip.add_file(
+            path.into(),
+            zip_time(mtime),
+            streaming_zip::CompressionMode::Store,
+            &mut entry,
+            true,
+        )?;
+    }
+    Ok(zip.finish()?)
+}
+
+fn bench_tar_to_zip(c: &mut Criterion) {
+    let encrypted = archive();
+    let mut group = c.benchmark_group("tar_to_zip");
+    group.throughput(Throughput::Bytes((FILES * FILE_SIZE) as u64));
+    group.sample_size(10);
+    // A conversion takes about a second.
+    group.measurement_time(Duration::from_secs(15));
+    group.bench_function("sequential", |b| {
+        b.iter_batched(
+            || decrypted(&encrypted),
+            |reader| sequential(reader, std::io::sink()).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("threaded", |b| {
+        b.iter_batched(
+            || decrypted(&encrypted),
+            |reader| tar_to_zip(reader, std::io::sink(), |path| Some(path.to_string())).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_tar_to_zip);
+criterion_main!(benches);
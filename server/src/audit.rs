@@ -0,0 +1,386 @@
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{BufWriter, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+};
+
+use common::TarHash;
+use serde::Serialize;
+
+use crate::{
+    config::{AuditConfig, GeneralConfig},
+    util::{client_ip, now_unix},
+};
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    UploadStart,
+    UploadFinish,
+    Delete,
+    /// Deleted by the GC.
+    Expire,
+    AuthFailed,
+}
+
+/// Who sent a request. Kept apart from the request, websocket uploads
+/// finish on their own thread.
+#[derive(Clone, Debug, Default)]
+pub struct AuditClient {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+impl AuditClient {
+    /// The IP is the one the per-IP limits use, see `util::client_ip`.
+    pub fn of_request(config: &GeneralConfig, request: &rouille::Request) -> Self {
+        Self {
+            ip: Some(client_ip(config, request)),
+            user_agent: request.header("User-Agent").map(str::to_string),
+        }
+    }
+}
+
+/// One line of the audit log. Uploads are named by their hash, the code
+/// never ends up in the log.
+#[derive(Serialize, Clone, Debug)]
+pub struct AuditRecord {
+    pub timestamp: u64,
+    pub event: AuditEvent,
+    pub hash: Option<String>,
+    pub owner: Option<String>,
+    pub ip: Option<IpAddr>,
+    /// Stored size of the upload.
+    pub bytes: Option<u64>,
+    pub user_agent: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(event: AuditEvent, client: &AuditClient) -> Self {
+        Self {
+            timestamp: now_unix(),
+            event,
+            hash: None,
+            owner: None,
+            ip: client.ip,
+            bytes: None,
+            user_agent: client.user_agent.clone(),
+        }
+    }
+
+    pub fn with_hash(mut self, hash: &TarHash) -> Self {
+        self.hash = Some(hash.to_string());
+        self
+    }
+
+    pub fn with_owner(mut self, owner: &str) -> Self {
+        self.owner = Some(owner.to_string());
+        self
+    }
+
+    pub fn with_bytes(mut self, bytes: Option<u64>) -> Self {
+        self.bytes = bytes;
+        self
+    }
+}
+
+enum Command {
+    Record(AuditRecord),
+    Flush(SyncSender<()>),
+}
+
+/// Appends records to `[audit] path` on a thread of its own, so requests
+/// never wait for the disk. When the queue is full, records are dropped
+/// rather than holding up the request.
+#[derive(Clone, Default)]
+pub struct AuditLog {
+    sender: Option<SyncSender<Command>>,
+}
+
+impl AuditLog {
+    /// Does nothing without `[audit]` or with `enabled = false`.
+    pub fn from_config(config: Option<&AuditConfig>) -> anyhow::Result<Self> {
+        match config {
+            Some(config) if config.enabled => Self::start(config),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    fn start(config: &AuditConfig) -> anyhow::Result<Self> {
+        let file = open(&config.path)?;
+        let (sender, receiver) = mpsc::sync_channel(config.queue_len);
+        let config = config.clone();
+        std::thread::spawn(move || write_records(&config, file, receiver));
+        Ok(Self {
+            sender: Some(sender),
+        })
+    }
+
+    pub fn record(&self, record: AuditRecord) {
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return,
+        };
+        match sender.try_send(Command::Record(record)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(Command::Record(record))) => {
+                println!("Audit log queue is full, dropped {:?}", record);
+            }
+            Err(_) => println!("Audit log writer is gone"),
+        }
+    }
+
+    /// Waits until everything recorded so far is on disk.
+    pub fn flush(&self) {
+        if let Some(sender) = &self.sender {
+            let (done, wait) = mpsc::sync_channel(1);
+            if sender.send(Command::Flush(done)).is_ok() {
+                let _ = wait.recv();
+            }
+        }
+    }
+}
+
+fn open(path: &Path) -> std::io::Result<BufWriter<File>> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    Ok(BufWriter::new(file))
+}
+
+/// `audit.log.1` is the newest of the rotated files.
+pub(crate) fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+fn rotate(config: &AuditConfig) -> std::io::Result<BufWriter<File>> {
+    if config.keep == 0 {
+        std::fs::remove_file(&config.path)?;
+    } else {
+        for n in (1..config.keep).rev() {
+            match std::fs::rename(rotated(&config.path, n), rotated(&config.path, n + 1)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        std::fs::rename(&config.path, rotated(&config.path, 1))?;
+    }
+    open(&config.path)
+}
+
+/// Flushes whenever the queue runs empty, so little is lost on a crash.
+fn write_records(config: &AuditConfig, mut file: BufWriter<File>, receiver: Receiver<Command>) {
+    let mut size = file.get_ref().metadata().map(|m| m.len()).unwrap_or(0);
+    let mut next = receiver.recv().ok();
+    while let Some(command) = next {
+        match command {
+            Command::Record(record) => {
+                let mut line = serde_json::to_string(&record).unwrap();
+                line.push('\n');
+                if let Err(e) = file.write_all(line.as_bytes()) {
+                    println!("Could not write audit log: {}", e);
+                }
+                size += line.len() as u64;
+                if size >= config.max_bytes {
+                    let _ = file.flush();
+                    match rotate(config) {
+                        Ok(new) => {
+                            file = new;
+                            size = 0;
+                        }
+                        Err(e) => println!("Could not rotate audit log: {}", e),
+                    }
+                }
+            }
+            Command::Flush(done) => {
+                let _ = file.flush();
+                let _ = done.send(());
+            }
+        }
+
+        next = match receiver.try_recv() {
+            Ok(command) => Some(command),
+            Err(_) => {
+                let _ = file.flush();
+                receiver.recv().ok()
+            }
+        };
+    }
+    let _ = file.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{handle, test_state};
+    use common::TarPassword;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("tarcloud-audit-{}", TarPassword::generate()))
+    }
+
+    fn config(path: &Path) -> AuditConfig {
+        toml::from_str(&format!("path = {:?}", path)).unwrap()
+    }
+
+    fn read_records(path: &Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn request(method: &str, url: &str, token: &str, body: Vec<u8>) -> rouille::Request {
+        let headers = vec![
+            ("User-Agent".to_string(), "toc/test".to_string()),
+            ("Authorization".to_string(), format!("Bearer {token}")),
+        ];
+        rouille::Request::fake_http(method, url, headers, body)
+    }
+
+    #[test]
+    fn test_requests_are_recorded() {
+        let path = temp_path();
+        let mut state = test_state();
+        state.audit = AuditLog::from_config(Some(&config(&path))).unwrap();
+
+        let code = TarPassword::generate();
+        let hash = TarHash::from_tarid(&code, "localhost");
+        let data = crate::test_encrypt(code.to_string().as_bytes(), b"hello");
+        let raw = format!("/raw/{hash}/");
+        let response = handle(&state, &request("POST", &raw, "wrong", data.clone()));
+        assert_eq!(response.status_code, 401);
+        let response = handle(&state, &request("POST", &raw, "secret", data.clone()));
+        assert_eq!(response.status_code, 200);
+        let response = handle(
+            &state,
+            &request("DELETE", &format!("/{code}/"), "secret", vec![]),
+        );
+        assert_eq!(response.status_code, 200);
+
+        let expiring = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+        let raw = format!("/raw/{expiring}/");
+        let response = handle(&state, &request("POST", &raw, "secret", data.clone()));
+        assert_eq!(response.status_code, 200);
+        let mut meta = state.meta.get(&expiring).unwrap().unwrap();
+        meta.delete_at_unix = 0;
+        state.meta.set(&expiring, &meta).unwrap();
+        crate::collect_garbage(&state).unwrap();
+
+        state.audit.flush();
+        let records = read_records(&path);
+        let events: Vec<_> = records
+            .iter()
+            .map(|r| r["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "auth_failed",
+                "upload_start",
+                "upload_finish",
+                "delete",
+                "upload_start",
+                "upload_finish",
+                "expire"
+            ]
+        );
+
+        assert_eq!(records[0]["hash"], serde_json::Value::Null);
+        assert_eq!(records[0]["user_agent"], "toc/test");
+        for record in &records[1..4] {
+            assert_eq!(record["hash"], hash.to_string());
+            assert_eq!(record["owner"], "test");
+            assert!(record["ip"].is_string());
+        }
+        assert_eq!(records[2]["bytes"], data.len());
+        assert_eq!(records[3]["bytes"], data.len());
+        assert_eq!(records[6]["hash"], expiring.to_string());
+        assert_eq!(records[6]["owner"], "test");
+        assert_eq!(records[6]["ip"], serde_json::Value::Null);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert!(!log.contains(&code.to_string()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_client_behind_proxy() {
+        let path = temp_path();
+        let mut state = test_state();
+        state.audit = AuditLog::from_config(Some(&config(&path))).unwrap();
+        state.config.update(|config| {
+            config.general.trusted_proxies = vec!["127.0.0.1".parse().unwrap()];
+        });
+
+        let hash = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+        let headers = vec![
+            ("Authorization".to_string(), "Bearer wrong".to_string()),
+            ("X-Forwarded-For".to_string(), "203.0.113.7".to_string()),
+        ];
+        let request = rouille::Request::fake_http("POST", format!("/raw/{hash}/"), headers, vec![]);
+        assert_eq!(handle(&state, &request).status_code, 401);
+
+        state.audit.flush();
+        let records = read_records(&path);
+        assert_eq!(records[0]["event"], "auth_failed");
+        assert_eq!(records[0]["ip"], "203.0.113.7");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rotation() {
+        let path = temp_path();
+        let config = AuditConfig {
+            max_bytes: 1,
+            keep: 2,
+            ..config(&path)
+        };
+        let log = AuditLog::from_config(Some(&config)).unwrap();
+        for event in [
+            AuditEvent::UploadStart,
+            AuditEvent::UploadFinish,
+            AuditEvent::Delete,
+        ] {
+            log.record(AuditRecord::new(event, &AuditClient::default()));
+        }
+        log.flush();
+
+        // Every record goes over the limit, the oldest rotated file is gone.
+        assert_eq!(read_records(&path), Vec::<serde_json::Value>::new());
+        assert_eq!(read_records(&rotated(&path, 1))[0]["event"], "delete");
+        assert_eq!(
+            read_records(&rotated(&path, 2))[0]["event"],
+            "upload_finish"
+        );
+        assert!(!rotated(&path, 3).exists());
+
+        for n in 1..=2 {
+            std::fs::remove_file(rotated(&path, n)).unwrap();
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_disabled() {
+        let path = temp_path();
+        let config = AuditConfig {
+            enabled: false,
+            ..config(&path)
+        };
+        let log = AuditLog::from_config(Some(&config)).unwrap();
+        log.record(AuditRecord::new(
+            AuditEvent::Delete,
+            &AuditClient::default(),
+        ));
+        log.flush();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,163 @@
+use std::io::Read;
+
+use flate2::{write::GzEncoder, Compression};
+use rouille::{Request, Response, ResponseBody};
+
+/// Smaller bodies barely shrink, the gzip header alone is 18 bytes.
+const MIN_SIZE: usize = 1024;
+/// Buffered bodies are compressed in memory.
+const MAX_SIZE: usize = 16 * 1024 * 1024;
+
+fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+    response
+        .headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| &**v)
+}
+
+/// Pages and API answers. Downloads, inline ones included, are sent as stored,
+/// as are partial responses.
+fn compressible(response: &Response) -> bool {
+    let content_type = header(response, "Content-Type").unwrap_or("");
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    response.status_code == 200
+        && matches!(mime, "text/html" | "application/json" | "text/plain")
+        && header(response, "Content-Encoding").is_none()
+        && header(response, "Content-Disposition").is_none()
+}
+
+fn accepts_gzip(request: &Request) -> bool {
+    let Some(accept) = request.header("Accept-Encoding") else {
+        return false;
+    };
+    accept.split(',').any(|coding| {
+        let mut params = coding.split(';');
+        let name = params.next().unwrap_or("").trim();
+        (name.eq_ignore_ascii_case("gzip") || name == "*")
+            && !params.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    == Some(0.0)
+            })
+    })
+}
+
+fn gzip(mut reader: impl Read) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::new(5));
+    std::io::copy(&mut reader, &mut encoder)?;
+    encoder.finish()
+}
+
+/// Compresses the body with gzip if the client accepts it.
+pub fn apply(request: &Request, response: Response) -> Response {
+    if !compressible(&response) {
+        return response;
+    }
+    let (reader, size) = response.data.into_reader_and_size();
+    let response = Response {
+        data: ResponseBody::empty(),
+        ..response
+    };
+    let size = match size {
+        Some(size) if (MIN_SIZE..=MAX_SIZE).contains(&size) => size,
+        Some(size) => {
+            return Response {
+                data: ResponseBody::from_reader_and_size(reader, size),
+                ..response
+            }
+        }
+        None => {
+            return Response {
+                data: ResponseBody::from_reader(reader),
+                ..response
+            }
+        }
+    };
+
+    let response = response.with_additional_header("Vary", "Accept-Encoding");
+    if !accepts_gzip(request) {
+        return Response {
+            data: ResponseBody::from_reader_and_size(reader, size),
+            ..response
+        };
+    }
+    match gzip(reader) {
+        Ok(data) => Response {
+            data: ResponseBody::from_data(data),
+            ..response
+        }
+        .with_additional_header("Content-Encoding", "gzip"),
+        Err(e) => {
+            println!("Error: {:?}", e);
+            Response::text("Internal Server Error").with_status_code(500)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(accept_encoding: &str) -> Request {
+        let headers = vec![("Accept-Encoding".to_string(), accept_encoding.to_string())];
+        Request::fake_http("GET", "/", headers, vec![])
+    }
+
+    fn body(response: Response) -> Vec<u8> {
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_compress() {
+        let html = "<tr><td>file.txt</td><td>1 KiB</td></tr>\n".repeat(500);
+        let response = apply(&request("gzip, br"), Response::html(html.clone()));
+        assert_eq!(header(&response, "Content-Encoding"), Some("gzip"));
+        assert_eq!(header(&response, "Vary"), Some("Accept-Encoding"));
+        let compressed = body(response);
+        assert!(compressed.len() < html.len() / 10);
+
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, html);
+
+        // Still varies, caches must not hand it to gzip clients.
+        for accept in ["identity", "gzip;q=0", "br"] {
+            let response = apply(&request(accept), Response::html(html.clone()));
+            assert_eq!(header(&response, "Content-Encoding"), None);
+            assert_eq!(header(&response, "Vary"), Some("Accept-Encoding"));
+            assert_eq!(body(response).len(), html.len());
+        }
+    }
+
+    #[test]
+    fn test_not_compressed() {
+        let text = "a".repeat(10_000);
+        let small = apply(&request("gzip"), Response::text("small"));
+        assert_eq!(header(&small, "Vary"), None);
+        assert_eq!(body(small), b"small");
+
+        let responses = [
+            Response::from_data("application/octet-stream", text.clone()),
+            Response::text(text.clone()).with_status_code(206),
+            Response::text(text.clone())
+                .with_additional_header("Content-Disposition", "inline; filename=\"a.txt\""),
+            Response {
+                data: ResponseBody::from_reader(std::io::Cursor::new(text.clone())),
+                ..Response::text("")
+            },
+        ];
+        for response in responses {
+            let response = apply(&request("gzip"), response);
+            assert_eq!(header(&response, "Content-Encoding"), None);
+            assert_eq!(body(response).len(), text.len());
+        }
+    }
+}
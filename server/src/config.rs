@@ -1,37 +1,611 @@
-use serde::Deserialize;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fmt::Debug,
+    net::{IpAddr, ToSocketAddrs},
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    listener::Listen,
+    util::{constant_time_eq, to_hex},
+};
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct Config {
     pub general: GeneralConfig,
     pub users: Vec<UserConfig>,
+    #[serde(default)]
+    pub cors: CorsConfig,
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    pub audit: Option<AuditConfig>,
+    #[serde(default)]
+    pub anonymous: AnonymousConfig,
 }
 
+/// Longest `gc_interval_s`, expired uploads would stay around for days otherwise.
+const MAX_GC_INTERVAL_S: u64 = 24 * 60 * 60;
+
+/// Owner of uploads without a token, see `general.allow_anonymous`.
+pub const ANONYMOUS: &str = "anonymous";
+
 impl Config {
     pub fn load(path: &str) -> anyhow::Result<Config> {
-        let config = std::fs::read_to_string(path)?;
+        let config = std::fs::read_to_string(path).with_context(|| format!("Can't read {path}"))?;
         let config = toml::from_str(&config)?;
         Ok(config)
     }
+
+    /// Mistakes that would otherwise show up later, or not at all. The error
+    /// has every problem found, one per line.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let problems = self.problems();
+        if !problems.is_empty() {
+            anyhow::bail!("{}", problems.join("\n"));
+        }
+        Ok(())
+    }
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let general = &self.general;
+        match Listen::parse(&general.listen) {
+            Listen::Tcp(addr) => {
+                if addr.to_socket_addrs().is_err() {
+                    problems.push(format!("listen '{addr}' is not a host:port address"));
+                }
+            }
+            Listen::Unix(path) => {
+                if path.as_os_str().is_empty() {
+                    problems.push("listen 'unix:' has no socket path".to_string());
+                }
+            }
+        }
+        if general.hostname.is_empty() {
+            problems.push("hostname is empty".to_string());
+        }
+        problems.extend(data_dir_problem(&general.data_dir));
+        if general.gc_interval_s < 60 {
+            problems.push(format!(
+                "gc_interval_s is {}, it has to be at least 60",
+                general.gc_interval_s
+            ));
+        } else if general.gc_interval_s > MAX_GC_INTERVAL_S {
+            problems.push(format!(
+                "gc_interval_s is {}, it has to be at most {MAX_GC_INTERVAL_S}",
+                general.gc_interval_s
+            ));
+        }
+
+        if !(1..=22).contains(&general.zstd_level) {
+            problems.push(format!(
+                "zstd_level is {}, it has to be between 1 and 22",
+                general.zstd_level
+            ));
+        }
+        if general.upload_idle_timeout_s > general.upload_max_duration_s {
+            problems.push(format!(
+                "upload_idle_timeout_s is {}, more than upload_max_duration_s {}",
+                general.upload_idle_timeout_s, general.upload_max_duration_s
+            ));
+        }
+
+        let mut names = HashSet::new();
+        let mut tokens = HashSet::new();
+        for user in &self.users {
+            if !names.insert(&user.username) {
+                problems.push(format!("user '{}' is configured twice", user.username));
+            }
+            if let Some(hex) = &user.token_sha256 {
+                if !user.token.is_empty() {
+                    problems.push(format!(
+                        "user '{}' has both token and token_sha256",
+                        user.username
+                    ));
+                }
+                if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    problems.push(format!(
+                        "user '{}' has a token_sha256 that is not 64 hex digits",
+                        user.username
+                    ));
+                }
+            }
+            match user.expected_sha256() {
+                Some(expected) => {
+                    if !tokens.insert(expected) {
+                        problems.push(format!(
+                            "user '{}' has the token of another user",
+                            user.username
+                        ));
+                    }
+                }
+                None => problems.push(format!("user '{}' has empty token", user.username)),
+            }
+            // The general default is capped at a lower maximum on purpose,
+            // both set for one user is a mistake.
+            if let (Some(default), Some(max)) = (user.default_expire_s, user.max_expire_s) {
+                if default > max {
+                    problems.push(format!(
+                        "user '{}' has default_expire_s {default}, more than max_expire_s {max}",
+                        user.username
+                    ));
+                }
+            }
+        }
+
+        if general.allow_anonymous && self.users.iter().any(|user| user.username == ANONYMOUS) {
+            problems.push(format!(
+                "user '{ANONYMOUS}' is taken by uploads without a token"
+            ));
+        }
+
+        if let Some(audit) = self.audit.as_ref().filter(|audit| audit.enabled) {
+            if audit.path.as_os_str().is_empty() {
+                problems.push("[audit] path is empty".to_string());
+            }
+            if audit.queue_len == 0 {
+                problems.push("[audit] queue_len has to be at least 1".to_string());
+            }
+        }
+        problems
+    }
+
+    /// Names of the settings that differ, like `general.max_expire_s` or
+    /// `users.alice added`. Tokens never show up, only the users they
+    /// belong to.
+    pub fn diff(&self, new: &Config) -> Vec<String> {
+        let mut changes = vec![];
+        for (section, old, new) in [
+            (
+                "general",
+                serde_json::to_value(&self.general).unwrap(),
+                serde_json::to_value(&new.general).unwrap(),
+            ),
+            (
+                "anonymous",
+                serde_json::to_value(&self.anonymous).unwrap(),
+                serde_json::to_value(&new.anonymous).unwrap(),
+            ),
+        ] {
+            for (key, value) in old.as_object().unwrap() {
+                if new[key] != *value {
+                    changes.push(format!("{section}.{key}"));
+                }
+            }
+        }
+
+        fn differs(a: &impl Debug, b: &impl Debug) -> bool {
+            format!("{a:?}") != format!("{b:?}")
+        }
+        for (name, changed) in [
+            ("cors", differs(&self.cors, &new.cors)),
+            ("tls", differs(&self.tls, &new.tls)),
+            ("storage", differs(&self.storage, &new.storage)),
+            ("audit", differs(&self.audit, &new.audit)),
+        ] {
+            if changed {
+                changes.push(name.to_string());
+            }
+        }
+
+        let find = |users: &[UserConfig], name: &str| {
+            users.iter().find(|user| user.username == name).cloned()
+        };
+        for user in &new.users {
+            match find(&self.users, &user.username) {
+                None => changes.push(format!("users.{} added", user.username)),
+                Some(old) if differs(&old, user) => {
+                    changes.push(format!("users.{} changed", user.username))
+                }
+                Some(_) => {}
+            }
+        }
+        for user in &self.users {
+            if find(&new.users, &user.username).is_none() {
+                changes.push(format!("users.{} removed", user.username));
+            }
+        }
+        changes
+    }
+
+    /// Who uploads without a token are from, `None` unless
+    /// `general.allow_anonymous` is set. Their expiry is capped by both
+    /// `max_expire_s`.
+    pub fn anonymous_user(&self) -> Option<UserConfig> {
+        let max_expire_s = match self.general.max_expire_s {
+            Some(max) => max.min(self.anonymous.max_expire_s),
+            None => self.anonymous.max_expire_s,
+        };
+        self.general.allow_anonymous.then(|| UserConfig {
+            username: ANONYMOUS.to_string(),
+            scopes: Some(vec![Scope::Upload]),
+            max_expire_s: Some(max_expire_s),
+            ..Default::default()
+        })
+    }
 }
 
-#[derive(Deserialize, Clone, Debug)]
+/// Settings only read at startup, a reload that changes them is rejected.
+const FIXED_SETTINGS: [&str; 12] = [
+    "general.hostname",
+    "general.listen",
+    "general.socket_mode",
+    "general.data_dir",
+    "general.meta_list_cache_s",
+    "general.allowed_tokens_file",
+    "general.rate_limit_per_minute",
+    "general.rate_limit_clients",
+    "anonymous.rate_limit_per_minute",
+    "tls",
+    "storage",
+    "audit",
+];
+
+/// The config as it is right now. A reload swaps it as a whole, so a
+/// request that took it sees one version from start to end.
+#[derive(Clone)]
+pub struct SharedConfig {
+    current: Arc<RwLock<Arc<Config>>>,
+    /// Where `reload` reads from.
+    path: Option<String>,
+}
+
+impl SharedConfig {
+    pub fn new(config: Config, path: Option<String>) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(Arc::new(config))),
+            path,
+        }
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.current.read().unwrap().clone()
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    pub fn update(&self, f: impl FnOnce(&mut Config)) {
+        let mut current = self.current.write().unwrap();
+        let mut config = Config::clone(&current);
+        f(&mut config);
+        *current = Arc::new(config);
+    }
+
+    /// Reads the file again and swaps it in if it is valid and leaves the
+    /// fixed settings alone. Returns what changed.
+    pub fn reload(&self) -> anyhow::Result<Vec<String>> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => anyhow::bail!("the config was not loaded from a file"),
+        };
+        let config = Config::load(path)?;
+        config.validate()?;
+
+        let mut current = self.current.write().unwrap();
+        let changes = current.diff(&config);
+        let fixed: Vec<&str> = changes
+            .iter()
+            .map(String::as_str)
+            .filter(|change| FIXED_SETTINGS.contains(change))
+            .collect();
+        if !fixed.is_empty() {
+            anyhow::bail!("{} can't change without a restart", fixed.join(", "));
+        }
+        *current = Arc::new(config);
+        Ok(changes)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct GeneralConfig {
     #[serde(default = "default_servername")]
     pub hostname: String,
+    /// `host:port`, or `unix:/path/to.sock`
     #[serde(default = "default_listen")]
     pub listen: String,
+    /// Permissions of a unix socket in octal, like `660`. The proxy in front of
+    /// the socket is trusted with `X-Forwarded-For`.
+    pub socket_mode: Option<String>,
     #[serde(default = "default_protocol")]
     pub protocol: String,
     #[serde(default = "default_data_dir")]
     pub data_dir: String,
+    /// Seconds the list of uploads is kept in memory, for a data directory on
+    /// a bucket mounted with `s3fs` where listing is slow. Read every time if unset.
+    pub meta_list_cache_s: Option<u64>,
     #[serde(default = "default_gc_interval_s")]
     pub gc_interval_s: u64,
+    /// A JSON line with the counts of each GC run is appended here, for
+    /// monitoring.
+    pub gc_metrics_file: Option<PathBuf>,
+    /// The metrics file is moved to `path.1` once it grows past this.
+    #[serde(default = "default_gc_metrics_max_bytes")]
+    pub gc_metrics_max_bytes: u64,
+    /// Check the block structure of raw uploads.
+    #[serde(default = "default_validate_uploads")]
+    pub validate_uploads: bool,
+    /// Reserve the `Content-Length` of raw uploads on disk before storing them.
+    #[serde(default = "default_preallocate")]
+    pub preallocate: bool,
+    /// Largest raw upload, unlimited if unset.
+    pub max_upload_size_bytes: Option<u64>,
+    /// Additional `username:token` lines, re-read while running.
+    pub allowed_tokens_file: Option<PathBuf>,
+    /// Uploads are aborted when the client sends nothing for this long.
+    #[serde(default = "default_upload_idle_timeout_s")]
+    pub upload_idle_timeout_s: u64,
+    #[serde(default = "default_upload_max_duration_s")]
+    pub upload_max_duration_s: u64,
+    /// Seconds until uploads are deleted, unless the client asks for less.
+    #[serde(default = "default_expire_s")]
+    pub default_expire_s: u64,
+    /// Longest expiry a client may ask for with `X-Toc-Expire-In`, unlimited if unset.
+    pub max_expire_s: Option<u64>,
+    /// Former host names. Codes are hashed with the host name, so after a rename
+    /// these are tried too, one more hash each for codes that don't exist.
+    #[serde(default)]
+    pub accepted_hostnames: Vec<String>,
+    /// Reverse proxies whose `X-Forwarded-Proto` and `X-Forwarded-Host` are used
+    /// for links, and whose last `X-Forwarded-For` entry is the client IP for
+    /// per-IP limits. Codes stay tied to `hostname`, so `toc` must still be
+    /// pointed at that name.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Requests per minute for each client IP or token, unlimited if unset.
+    pub rate_limit_per_minute: Option<u32>,
+    /// Number of clients tracked by the rate limiter.
+    #[serde(default = "default_rate_limit_clients")]
+    pub rate_limit_clients: usize,
+    /// Paths not counted by the rate limiter.
+    #[serde(default = "default_rate_limit_exempt")]
+    pub rate_limit_exempt: Vec<String>,
+    /// Simultaneous downloads of one upload, unlimited if unset.
+    pub max_downloads_per_upload: Option<usize>,
+    /// Simultaneous downloads of one client IP, unlimited if unset.
+    pub max_downloads_per_ip: Option<usize>,
+    /// Entries listed on the index page, the archive downloads have all of them.
+    #[serde(default = "default_max_index_entries")]
+    pub max_index_entries: usize,
+    /// Archives with more entries get no index page or zip download.
+    #[serde(default = "default_max_archive_entries")]
+    pub max_archive_entries: usize,
+    /// Archives with longer paths get no index page or zip download.
+    #[serde(default = "default_max_path_length")]
+    pub max_path_length: usize,
+    /// How long running transfers may take after SIGTERM, before the
+    /// server exits anyway.
+    #[serde(default = "default_shutdown_grace_s")]
+    pub shutdown_grace_s: u64,
+    /// Compression level of the `tar.zst` download, 1 to 22.
+    #[serde(default = "default_zstd_level")]
+    pub zstd_level: i32,
+    /// Show the download counts on the index page only to the owner, who
+    /// has to send their token. Anyone with the code sees them otherwise.
+    #[serde(default = "default_private_stats")]
+    pub private_stats: bool,
+    /// Accept uploads without a token, within the limits of `[anonymous]`.
+    /// They can't be deleted or resumed, only expire.
+    #[serde(default)]
+    pub allow_anonymous: bool,
+}
+
+/// Limits of uploads without a token, stricter than the general ones.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct AnonymousConfig {
+    /// Largest upload of any kind.
+    #[serde(default = "default_anonymous_max_upload_size_bytes")]
+    pub max_upload_size_bytes: u64,
+    #[serde(default = "default_anonymous_max_expire_s")]
+    pub max_expire_s: u64,
+    /// Uploads per minute for each client IP.
+    #[serde(default = "default_anonymous_rate_limit_per_minute")]
+    pub rate_limit_per_minute: u32,
+}
+
+impl Default for AnonymousConfig {
+    fn default() -> Self {
+        Self {
+            max_upload_size_bytes: default_anonymous_max_upload_size_bytes(),
+            max_expire_s: default_anonymous_max_expire_s(),
+            rate_limit_per_minute: default_anonymous_rate_limit_per_minute(),
+        }
+    }
 }
 
+/// Cross origin access for browser clients, off without allowed origins.
 #[derive(Deserialize, Clone, Debug)]
+pub struct CorsConfig {
+    /// Origins allowed to call the server, `*` allows any.
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "default_cors_methods")]
+    pub allowed_methods: Vec<String>,
+    #[serde(default = "default_cors_max_age_s")]
+    pub max_age_s: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec![],
+            allowed_methods: default_cors_methods(),
+            max_age_s: default_cors_max_age_s(),
+        }
+    }
+}
+
+/// Serve https directly, needs the `tls` feature.
+#[derive(Deserialize, Clone, Debug)]
+pub struct TlsConfig {
+    /// PEM files, reloaded when they change.
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    #[serde(default = "default_tls_reload_interval_s")]
+    pub reload_interval_s: u64,
+}
+
+/// Where uploads are kept, metadata always stays in the data dir.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    #[default]
+    Fs,
+    /// Needs the `s3` feature. Resumable uploads, rewrites and downloads of
+    /// unfinished uploads only work with `fs`.
+    S3(S3Config),
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct S3Config {
+    /// Like `https://s3.eu-central-1.amazonaws.com` or `http://minio:9000`.
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Put in front of the object names, like `uploads/`.
+    #[serde(default)]
+    pub prefix: String,
+}
+
+/// Append-only JSON lines of uploads, deletions and failed logins.
+#[derive(Deserialize, Clone, Debug)]
+pub struct AuditConfig {
+    /// Switch to keep the section but not write anything.
+    #[serde(default = "default_audit_enabled")]
+    pub enabled: bool,
+    pub path: PathBuf,
+    /// The file is rotated to `path.1`, `path.2`, .. once it grows past this.
+    #[serde(default = "default_audit_max_bytes")]
+    pub max_bytes: u64,
+    /// Rotated files kept, older ones are deleted.
+    #[serde(default = "default_audit_keep")]
+    pub keep: usize,
+    /// Records waiting to be written, more are dropped.
+    #[serde(default = "default_audit_queue_len")]
+    pub queue_len: usize,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
 pub struct UserConfig {
     pub username: String,
+    /// Either this or `token_sha256`.
+    #[serde(default)]
     pub token: String,
+    /// Hex SHA-256 of the token, see `tarcloud hash-token`.
+    pub token_sha256: Option<String>,
+    /// What the token may do, everything but `admin` if unset.
+    pub scopes: Option<Vec<Scope>>,
+    /// Override `general.default_expire_s` for this user.
+    pub default_expire_s: Option<u64>,
+    /// Override `general.max_expire_s` for this user.
+    pub max_expire_s: Option<u64>,
+}
+
+/// `admin` includes the other scopes.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Upload,
+    Delete,
+    List,
+    Admin,
+}
+
+impl Scope {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scope::Upload => "upload",
+            Scope::Delete => "delete",
+            Scope::List => "list",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl UserConfig {
+    pub fn scopes(&self) -> Vec<Scope> {
+        match &self.scopes {
+            Some(scopes) => scopes.clone(),
+            None => vec![Scope::Upload, Scope::Delete, Scope::List],
+        }
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        let scopes = self.scopes();
+        scopes.contains(&scope) || scopes.contains(&Scope::Admin)
+    }
+
+    /// Compares hashes in constant time, for both ways to configure the token.
+    pub fn matches_token(&self, token: &str) -> bool {
+        match self.expected_sha256() {
+            Some(expected) => constant_time_eq(hash_token(token).as_bytes(), expected.as_bytes()),
+            None => false,
+        }
+    }
+
+    fn expected_sha256(&self) -> Option<String> {
+        match &self.token_sha256 {
+            Some(hex) => Some(hex.to_ascii_lowercase()),
+            None if !self.token.is_empty() => Some(hash_token(&self.token)),
+            None => None,
+        }
+    }
+
+    /// Seconds until an upload of this user is deleted. What the client
+    /// `requested` and the default are both capped at the maximum.
+    pub fn expire_s(&self, general: &GeneralConfig, requested: Option<u64>) -> u64 {
+        let expire_s = requested
+            .or(self.default_expire_s)
+            .unwrap_or(general.default_expire_s);
+        match self.expire_limit_s(general) {
+            Some(max) => expire_s.min(max),
+            None => expire_s,
+        }
+    }
+
+    pub fn expire_limit_s(&self, general: &GeneralConfig) -> Option<u64> {
+        self.max_expire_s.or(general.max_expire_s)
+    }
+
+    /// The stand-in of `Config::anonymous_user`, users from the config
+    /// always have a token.
+    pub fn is_anonymous(&self) -> bool {
+        self.username == ANONYMOUS && self.expected_sha256().is_none()
+    }
+}
+
+/// A data directory that exists has to take new files, a missing one is
+/// created at startup.
+fn data_dir_problem(data_dir: &str) -> Option<String> {
+    if data_dir.is_empty() {
+        return Some("data_dir is empty".to_string());
+    }
+    let path = std::path::Path::new(data_dir);
+    if !path.exists() {
+        return None;
+    }
+    if !path.is_dir() {
+        return Some(format!("data_dir '{data_dir}' is not a directory"));
+    }
+    let probe = path.join(format!(".write-test-{}", std::process::id()));
+    match std::fs::File::create(&probe) {
+        Ok(_) => {
+            let _ = std::fs::remove_file(probe);
+            None
+        }
+        Err(e) => Some(format!("data_dir '{data_dir}' is not writable: {e}")),
+    }
+}
+
+/// What goes into `token_sha256`.
+pub fn hash_token(token: &str) -> String {
+    use sha2::Digest;
+    to_hex(&sha2::Sha256::digest(token.as_bytes()))
 }
 
 fn default_protocol() -> String {
@@ -54,3 +628,366 @@ fn default_gc_interval_s() -> u64 {
 fn default_data_dir() -> String {
     "./data".to_string()
 }
+
+fn default_validate_uploads() -> bool {
+    true
+}
+
+fn default_preallocate() -> bool {
+    true
+}
+
+fn default_upload_idle_timeout_s() -> u64 {
+    60
+}
+
+fn default_upload_max_duration_s() -> u64 {
+    // 6h
+    6 * 60 * 60
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_expire_s() -> u64 {
+    // 7 days
+    60 * 60 * 24 * 7
+}
+
+fn default_rate_limit_clients() -> usize {
+    10_000
+}
+
+fn default_rate_limit_exempt() -> Vec<String> {
+    ["/health", "/metrics"].map(String::from).to_vec()
+}
+
+fn default_max_index_entries() -> usize {
+    5_000
+}
+
+fn default_max_archive_entries() -> usize {
+    100_000
+}
+
+fn default_max_path_length() -> usize {
+    4096
+}
+
+fn default_shutdown_grace_s() -> u64 {
+    30
+}
+
+fn default_zstd_level() -> i32 {
+    3
+}
+
+fn default_private_stats() -> bool {
+    true
+}
+
+fn default_anonymous_max_upload_size_bytes() -> u64 {
+    // 100MB
+    100 * 1024 * 1024
+}
+
+fn default_anonymous_max_expire_s() -> u64 {
+    // 1 day
+    60 * 60 * 24
+}
+
+fn default_anonymous_rate_limit_per_minute() -> u32 {
+    2
+}
+
+fn default_gc_metrics_max_bytes() -> u64 {
+    // 10MB
+    10 * 1024 * 1024
+}
+
+fn default_audit_enabled() -> bool {
+    true
+}
+
+fn default_audit_max_bytes() -> u64 {
+    // 100MB
+    100 * 1024 * 1024
+}
+
+fn default_audit_keep() -> usize {
+    10
+}
+
+fn default_audit_queue_len() -> usize {
+    10_000
+}
+
+fn default_tls_reload_interval_s() -> u64 {
+    60
+}
+
+fn default_cors_methods() -> Vec<String> {
+    ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn default_cors_max_age_s() -> u64 {
+    // 10min
+    10 * 60
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(config: &str) -> Result<(), String> {
+        let config: Config = toml::from_str(config).unwrap();
+        config.validate().map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_validate() {
+        let users = r#"
+            [[users]]
+            username = "alice"
+            token = "a"
+            [[users]]
+            username = "bob"
+            token = "b"
+        "#;
+        assert_eq!(validate(&format!("[general]\n{users}")), Ok(()));
+        assert_eq!(
+            validate(&format!(
+                "[general]\nlisten = \"unix:/run/toc.sock\"\n{users}"
+            )),
+            Ok(())
+        );
+
+        let error =
+            |general: &str| validate(&format!("[general]\n{general}\n{users}")).unwrap_err();
+        assert_eq!(
+            error("listen = \"8000\""),
+            "listen '8000' is not a host:port address"
+        );
+        assert_eq!(
+            error("listen = \"unix:\""),
+            "listen 'unix:' has no socket path"
+        );
+        assert_eq!(error("hostname = \"\""), "hostname is empty");
+        assert_eq!(
+            error("gc_interval_s = 10"),
+            "gc_interval_s is 10, it has to be at least 60"
+        );
+
+        let users = |bob: &str| {
+            validate(&format!(
+                "[general]\n[[users]]\nusername = \"alice\"\ntoken = \"a\"\n\
+                 [[users]]\nusername = \"bob\"\ntoken = \"{bob}\""
+            ))
+        };
+        assert_eq!(users(""), Err("user 'bob' has empty token".to_string()));
+        assert_eq!(
+            users("a"),
+            Err("user 'bob' has the token of another user".to_string())
+        );
+
+        let hashed = |bob: &str| {
+            validate(&format!(
+                "[general]\n[[users]]\nusername = \"alice\"\ntoken = \"a\"\n\
+                 [[users]]\nusername = \"bob\"\n{bob}"
+            ))
+        };
+        let b = hash_token("b");
+        assert_eq!(hashed(&format!("token_sha256 = \"{b}\"")), Ok(()));
+        assert_eq!(
+            hashed(&format!("token_sha256 = \"{}\"", hash_token("a"))),
+            Err("user 'bob' has the token of another user".to_string())
+        );
+        assert_eq!(
+            hashed(&format!("token = \"b\"\ntoken_sha256 = \"{b}\"")),
+            Err("user 'bob' has both token and token_sha256".to_string())
+        );
+        assert_eq!(
+            hashed("token_sha256 = \"abc\""),
+            Err("user 'bob' has a token_sha256 that is not 64 hex digits".to_string())
+        );
+
+        let audit = |audit: &str| {
+            validate(&format!(
+                "[general]\n[[users]]\nusername = \"alice\"\ntoken = \"a\"\n[audit]\n{audit}"
+            ))
+        };
+        assert_eq!(audit("path = \"audit.log\""), Ok(()));
+        assert_eq!(
+            audit("path = \"\""),
+            Err("[audit] path is empty".to_string())
+        );
+        assert_eq!(audit("path = \"\"\nenabled = false"), Ok(()));
+
+        let anonymous = |allow: bool| {
+            validate(&format!(
+                "[general]\nallow_anonymous = {allow}\n\
+                 [[users]]\nusername = \"anonymous\"\ntoken = \"a\""
+            ))
+        };
+        assert_eq!(anonymous(false), Ok(()));
+        assert_eq!(
+            anonymous(true),
+            Err("user 'anonymous' is taken by uploads without a token".to_string())
+        );
+        assert_eq!(
+            audit("path = \"audit.log\"\nqueue_len = 0"),
+            Err("[audit] queue_len has to be at least 1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_validate_all_problems() {
+        let config = r#"
+            [general]
+            listen = "8000"
+            hostname = ""
+            gc_interval_s = 0
+            upload_idle_timeout_s = 600
+            upload_max_duration_s = 60
+            [[users]]
+            username = "alice"
+            token = "a"
+            [[users]]
+            username = "alice"
+            token = ""
+            default_expire_s = 120
+            max_expire_s = 60
+        "#;
+        let problems: Vec<String> = validate(config)
+            .unwrap_err()
+            .lines()
+            .map(String::from)
+            .collect();
+        assert_eq!(
+            problems,
+            [
+                "listen '8000' is not a host:port address",
+                "hostname is empty",
+                "gc_interval_s is 0, it has to be at least 60",
+                "upload_idle_timeout_s is 600, more than upload_max_duration_s 60",
+                "user 'alice' is configured twice",
+                "user 'alice' has empty token",
+                "user 'alice' has default_expire_s 120, more than max_expire_s 60",
+            ]
+        );
+
+        assert_eq!(
+            validate(
+                "[general]\ngc_interval_s = 172800\n[[users]]\nusername = \"a\"\ntoken = \"a\""
+            ),
+            Err("gc_interval_s is 172800, it has to be at most 86400".to_string())
+        );
+    }
+
+    #[test]
+    fn test_data_dir_problem() {
+        assert_eq!(data_dir_problem(""), Some("data_dir is empty".to_string()));
+        let dir = std::env::temp_dir().join(format!("tarcloud-data-{}", std::process::id()));
+        assert_eq!(data_dir_problem(dir.to_str().unwrap()), None);
+
+        std::fs::write(&dir, "").unwrap();
+        assert_eq!(
+            data_dir_problem(dir.to_str().unwrap()),
+            Some(format!("data_dir '{}' is not a directory", dir.display()))
+        );
+        std::fs::remove_file(&dir).unwrap();
+
+        std::fs::create_dir(&dir).unwrap();
+        assert_eq!(data_dir_problem(dir.to_str().unwrap()), None);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_diff() {
+        let config = |general: &str, users: &[(&str, &str)]| -> Config {
+            let users: String = users
+                .iter()
+                .map(|(name, token)| {
+                    format!("[[users]]\nusername = \"{name}\"\ntoken = \"{token}\"\n")
+                })
+                .collect();
+            toml::from_str(&format!("[general]\n{general}\n{users}")).unwrap()
+        };
+        let old = config("", &[("alice", "a"), ("bob", "b")]);
+        assert_eq!(old.diff(&old.clone()), Vec::<String>::new());
+
+        let new = config(
+            "max_expire_s = 60\nlisten = \"[::]:80\"",
+            &[("alice", "rotated"), ("carol", "c")],
+        );
+        let changes = old.diff(&new);
+        assert_eq!(
+            changes,
+            [
+                "general.listen",
+                "general.max_expire_s",
+                "users.alice changed",
+                "users.carol added",
+                "users.bob removed",
+            ]
+        );
+        assert!(changes.iter().all(|change| !change.contains("rotated")));
+
+        let mut anonymous = old.clone();
+        anonymous.anonymous.max_expire_s = 60;
+        assert_eq!(old.diff(&anonymous), ["anonymous.max_expire_s"]);
+    }
+
+    #[test]
+    fn test_anonymous_user() {
+        let config = |general: &str| -> Config {
+            toml::from_str(&format!(
+                "[general]\n{general}\n[anonymous]\nmax_expire_s = 3600\n\
+                 [[users]]\nusername = \"anonymous\"\ntoken = \"a\""
+            ))
+            .unwrap()
+        };
+        assert!(config("").anonymous_user().is_none());
+        assert!(!config("").users[0].is_anonymous());
+
+        let user = config("allow_anonymous = true").anonymous_user().unwrap();
+        assert!(user.is_anonymous());
+        assert_eq!(user.scopes(), [Scope::Upload]);
+        assert!(!user.matches_token(""));
+        let general = config("allow_anonymous = true").general;
+        assert_eq!(user.expire_s(&general, None), 3600);
+        assert_eq!(user.expire_s(&general, Some(60)), 60);
+
+        let config = config("allow_anonymous = true\nmax_expire_s = 60");
+        let user = config.anonymous_user().unwrap();
+        assert_eq!(user.expire_s(&config.general, None), 60);
+    }
+
+    #[test]
+    fn test_matches_token() {
+        let plain = UserConfig {
+            token: "coopighohfoNai6a".to_string(),
+            ..Default::default()
+        };
+        let hashed = UserConfig {
+            token_sha256: Some(hash_token("coopighohfoNai6a").to_uppercase()),
+            ..Default::default()
+        };
+        for user in [plain, hashed] {
+            assert!(user.matches_token("coopighohfoNai6a"));
+            for near_miss in [
+                "coopighohfoNai6",
+                "coopighohfoNai6a ",
+                "coopighohfonai6a",
+                "",
+            ] {
+                assert!(!user.matches_token(near_miss));
+            }
+        }
+        assert!(!UserConfig::default().matches_token(""));
+    }
+}
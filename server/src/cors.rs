@@ -0,0 +1,149 @@
+use rouille::{Request, Response};
+
+use crate::{config::CorsConfig, route_table};
+
+/// Request headers browser clients may send.
+const ALLOWED_HEADERS: &str = "Authorization, Accept, Content-Type, Content-Range, Range, \
+    If-Range, If-Match, If-None-Match, If-Modified-Since, \
+    X-Toc-Resumable, X-Toc-Finish, X-Toc-Allow-Rewrite, X-Toc-Expire-In";
+
+/// Response headers browser clients may read.
+const EXPOSED_HEADERS: &str = "Content-Disposition, ETag, Last-Modified, Content-Range, \
+    Accept-Ranges, Retry-After, X-Toc-Stored-Length, X-Toc-Finished, \
+    X-Toc-Block-Count, X-Toc-Format, X-Upload-Id";
+
+fn allowed_origin<'a>(config: &CorsConfig, request: &'a Request) -> Option<&'a str> {
+    let origin = request.header("Origin")?;
+    config
+        .allowed_origins
+        .iter()
+        .any(|allowed| allowed == "*" || allowed == origin)
+        .then_some(origin)
+}
+
+/// Answers preflight requests from allowed origins, everything else is left to the router.
+pub fn preflight(config: &CorsConfig, request: &Request) -> Option<Response> {
+    if request.method() != "OPTIONS" {
+        return None;
+    }
+    let allow = route_table::allow(&request.url())?;
+    let origin = allowed_origin(config, request)?;
+
+    Some(
+        Response::empty_204()
+            .with_additional_header("Access-Control-Allow-Origin", origin.to_string())
+            .with_additional_header(
+                "Access-Control-Allow-Methods",
+                config.allowed_methods.join(", "),
+            )
+            .with_additional_header("Access-Control-Allow-Headers", ALLOWED_HEADERS)
+            .with_additional_header("Access-Control-Max-Age", config.max_age_s.to_string())
+            .with_additional_header("Allow", allow)
+            .with_additional_header("Vary", "Origin"),
+    )
+}
+
+pub fn add_headers(config: &CorsConfig, request: &Request, response: Response) -> Response {
+    match allowed_origin(config, request) {
+        Some(origin) => response
+            .with_additional_header("Access-Control-Allow-Origin", origin.to_string())
+            .with_additional_header("Access-Control-Expose-Headers", EXPOSED_HEADERS)
+            .with_additional_header("Vary", "Origin"),
+        None => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CorsConfig {
+        CorsConfig {
+            allowed_origins: vec!["https://app.example".to_string()],
+            ..CorsConfig::default()
+        }
+    }
+
+    fn cors_request(method: &str, url: &str, origin: &str) -> Request {
+        let headers = vec![("Origin".to_string(), origin.to_string())];
+        Request::fake_http(method, url, headers, vec![])
+    }
+
+    fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| &**v)
+    }
+
+    #[test]
+    fn test_preflight() {
+        let request = cors_request("OPTIONS", "/raw/abc/", "https://app.example");
+        let response = preflight(&config(), &request).unwrap();
+        assert_eq!(response.status_code, 204);
+        assert_eq!(
+            header(&response, "Access-Control-Allow-Origin"),
+            Some("https://app.example")
+        );
+        assert!(header(&response, "Access-Control-Allow-Methods")
+            .unwrap()
+            .contains("PUT"));
+        assert!(header(&response, "Access-Control-Allow-Headers")
+            .unwrap()
+            .contains("Authorization"));
+        assert_eq!(header(&response, "Access-Control-Max-Age"), Some("600"));
+        assert_eq!(
+            header(&response, "Allow"),
+            Some("OPTIONS, GET, HEAD, POST, PUT, PATCH")
+        );
+
+        for url in ["/metrics", "/0005-abandon-ability-able-about"] {
+            let request = cors_request("OPTIONS", url, "https://app.example");
+            assert!(preflight(&config(), &request).is_some());
+        }
+
+        let unknown = cors_request("OPTIONS", "/nope/x/y", "https://app.example");
+        assert!(preflight(&config(), &unknown).is_none());
+        let get = cors_request("GET", "/upload", "https://app.example");
+        assert!(preflight(&config(), &get).is_none());
+    }
+
+    #[test]
+    fn test_allowed_origin() {
+        let request = cors_request("GET", "/abc/pipe", "https://app.example");
+        let response = add_headers(&config(), &request, Response::text("data"));
+        assert_eq!(
+            header(&response, "Access-Control-Allow-Origin"),
+            Some("https://app.example")
+        );
+        let exposed = header(&response, "Access-Control-Expose-Headers").unwrap();
+        assert!(exposed.contains("Content-Disposition") && exposed.contains("ETag"));
+        assert!(exposed.contains("X-Upload-Id"));
+
+        let any = CorsConfig {
+            allowed_origins: vec!["*".to_string()],
+            ..CorsConfig::default()
+        };
+        let request = cors_request("GET", "/abc/pipe", "https://other.example");
+        let response = add_headers(&any, &request, Response::text("data"));
+        assert!(header(&response, "Access-Control-Allow-Origin").is_some());
+    }
+
+    #[test]
+    fn test_disallowed_origin() {
+        let request = cors_request("OPTIONS", "/upload", "https://evil.example");
+        assert!(preflight(&config(), &request).is_none());
+
+        let request = cors_request("GET", "/upload", "https://evil.example");
+        let response = add_headers(&config(), &request, Response::text("data"));
+        assert!(response
+            .headers
+            .iter()
+            .all(|(k, _)| !k.starts_with("Access-Control")));
+
+        // No configured origins means CORS is off.
+        let request = cors_request("OPTIONS", "/upload", "https://app.example");
+        assert!(preflight(&CorsConfig::default(), &request).is_none());
+    }
+}
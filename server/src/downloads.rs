@@ -0,0 +1,135 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    io::Read,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use common::TarHash;
+use rouille::{Response, ResponseBody};
+
+use crate::{config::GeneralConfig, responses::ErrorResponse};
+
+#[derive(Default)]
+struct Active {
+    uploads: HashMap<TarHash, usize>,
+    ips: HashMap<IpAddr, usize>,
+    total: usize,
+}
+
+/// Downloads being sent, per upload and per client IP. Each one holds a key
+/// derived with Argon2 and a file, unfinished ones also poll the metadata.
+#[derive(Clone, Default)]
+pub struct DownloadTracker {
+    active: Arc<Mutex<Active>>,
+}
+
+/// Counts a download until it is dropped, see `attach`.
+pub struct DownloadGuard {
+    active: Arc<Mutex<Active>>,
+    hash: TarHash,
+    ip: IpAddr,
+    on_complete: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl DownloadTracker {
+    /// Fails with 429 when the upload or the client is at its limit.
+    pub fn start(
+        &self,
+        config: &GeneralConfig,
+        hash: &TarHash,
+        ip: IpAddr,
+    ) -> Result<DownloadGuard, ErrorResponse> {
+        let mut active = self.active.lock().unwrap();
+        let at_limit = |count: Option<&usize>, limit: Option<usize>| {
+            limit.is_some_and(|limit| count.copied().unwrap_or(0) >= limit)
+        };
+        if at_limit(active.uploads.get(hash), config.max_downloads_per_upload)
+            || at_limit(active.ips.get(&ip), config.max_downloads_per_ip)
+        {
+            return Err(ErrorResponse::too_many_requests().with_code("too_many_downloads"));
+        }
+
+        *active.uploads.entry(hash.clone()).or_default() += 1;
+        *active.ips.entry(ip).or_default() += 1;
+        active.total += 1;
+        Ok(DownloadGuard {
+            active: self.active.clone(),
+            hash: hash.clone(),
+            ip,
+            on_complete: None,
+        })
+    }
+
+    /// Running downloads, uploads and clients with at least one of them.
+    pub fn counts(&self) -> (usize, usize, usize) {
+        let active = self.active.lock().unwrap();
+        (active.total, active.uploads.len(), active.ips.len())
+    }
+}
+
+impl DownloadGuard {
+    /// Runs `f` once the whole body was sent, not if the client went away.
+    pub fn on_complete(mut self, f: impl FnOnce() + Send + 'static) -> Self {
+        self.on_complete = Some(Box::new(f));
+        self
+    }
+
+    /// For downloads not sent as a response body, e.g. over a websocket.
+    pub fn complete(mut self) {
+        if let Some(f) = self.on_complete.take() {
+            f();
+        }
+    }
+
+    /// Keeps counting until the body was sent or the client went away.
+    pub fn attach(self, response: Response) -> Response {
+        let (reader, size) = response.data.into_reader_and_size();
+        let reader = GuardedReader {
+            inner: reader,
+            guard: Some(self),
+        };
+        let data = match size {
+            Some(size) => ResponseBody::from_reader_and_size(reader, size),
+            None => ResponseBody::from_reader(reader),
+        };
+        Response { data, ..response }
+    }
+}
+
+fn release<K: Eq + Hash>(counts: &mut HashMap<K, usize>, key: &K) {
+    if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+impl Drop for DownloadGuard {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap();
+        release(&mut active.uploads, &self.hash);
+        release(&mut active.ips, &self.ip);
+        active.total -= 1;
+    }
+}
+
+struct GuardedReader<R> {
+    inner: R,
+    guard: Option<DownloadGuard>,
+}
+
+impl<R: Read> Read for GuardedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        // Done once everything was read, even if the body is dropped later.
+        if n == 0 && !buf.is_empty() {
+            if let Some(guard) = self.guard.take() {
+                guard.complete();
+            }
+        }
+        Ok(n)
+    }
+}
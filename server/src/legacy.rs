@@ -0,0 +1,59 @@
+//! Uploads of the old server are `.tar.age` files with the code as the
+//! passphrase. They are still served, until they expire.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use age::{secrecy::SecretString, stream::StreamReader};
+use common::{EncryptedReader, TarPassword};
+
+const AGE_MAGIC: &[u8] = b"age-encryption.org/v1\n";
+
+/// Looks at the start of the blob and seeks back.
+pub fn is_age<R: Read + Seek>(reader: &mut R) -> std::io::Result<bool> {
+    let mut start = Vec::with_capacity(AGE_MAGIC.len());
+    reader
+        .by_ref()
+        .take(AGE_MAGIC.len() as u64)
+        .read_to_end(&mut start)?;
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(start == AGE_MAGIC)
+}
+
+/// The plain archive of either format.
+pub enum Decrypted<R: Read + Seek> {
+    Blocks(EncryptedReader<R>),
+    Age(StreamReader<R>),
+}
+
+impl<R: Read + Seek> Decrypted<R> {
+    pub fn open(mut reader: R, id: &TarPassword) -> anyhow::Result<Self> {
+        if !is_age(&mut reader)? {
+            return Ok(Decrypted::Blocks(EncryptedReader::new(
+                reader,
+                id.to_string().as_bytes(),
+            )));
+        }
+        let identity = age::scrypt::Identity::new(SecretString::from(id.to_string()));
+        let decryptor = age::Decryptor::new(reader)?;
+        let reader = decryptor.decrypt(std::iter::once(&identity as &dyn age::Identity))?;
+        Ok(Decrypted::Age(reader))
+    }
+}
+
+impl<R: Read + Seek> Read for Decrypted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Decrypted::Blocks(reader) => reader.read(buf),
+            Decrypted::Age(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl<R: Read + Seek> Seek for Decrypted<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Decrypted::Blocks(reader) => reader.seek(pos),
+            Decrypted::Age(reader) => reader.seek(pos),
+        }
+    }
+}
@@ -0,0 +1,680 @@
+use common::{TarHash, TarPassword};
+use rouille::Response;
+use std::{io::Write, sync::Arc};
+
+use crate::{
+    audit::{AuditClient, AuditEvent, AuditRecord},
+    responses::ErrorResponse,
+};
+
+mod audit;
+mod compress;
+mod config;
+mod cors;
+mod downloads;
+mod legacy;
+mod listener;
+mod meta;
+mod ratelimit;
+mod responses;
+mod route_table;
+mod routes;
+mod shutdown;
+mod storage;
+mod templates;
+mod timeout;
+mod tokens;
+mod uploads;
+mod util;
+pub mod zip_stream;
+
+#[cfg(feature = "testing")]
+pub mod testing;
+
+#[macro_use]
+extern crate rouille;
+
+#[derive(Clone)]
+pub struct AppState {
+    pub config: config::SharedConfig,
+    pub meta: meta::MetaStore,
+    pub storage: Arc<dyn storage::Storage>,
+    pub tokens: Option<tokens::TokenFile>,
+    pub limiter: Option<ratelimit::RateLimiter>,
+    /// Uploads without a token per client IP.
+    pub anonymous_uploads: ratelimit::RateLimiter,
+    pub downloads: downloads::DownloadTracker,
+    pub uploads: uploads::UploadTracker,
+    pub audit: audit::AuditLog,
+    pub shutdown: shutdown::Shutdown,
+}
+
+impl AppState {
+    /// The stores and limits `config` asks for, `shared` is the same config
+    /// for reloading.
+    fn new(
+        config: &config::Config,
+        shared: config::SharedConfig,
+        shutdown: shutdown::Shutdown,
+    ) -> anyhow::Result<Self> {
+        let data_dir = &config.general.data_dir;
+        let meta = match config.general.meta_list_cache_s {
+            Some(secs) => {
+                meta::MetaStore::new_with_cache(data_dir, std::time::Duration::from_secs(secs))
+            }
+            None => meta::MetaStore::new(data_dir),
+        }?;
+        Ok(AppState {
+            config: shared,
+            storage: storage::from_config(&config.storage, &meta)?,
+            meta,
+            tokens: config
+                .general
+                .allowed_tokens_file
+                .clone()
+                .map(tokens::TokenFile::new),
+            limiter: config.general.rate_limit_per_minute.map(|per_minute| {
+                ratelimit::RateLimiter::new(per_minute, config.general.rate_limit_clients)
+            }),
+            anonymous_uploads: ratelimit::RateLimiter::new(
+                config.anonymous.rate_limit_per_minute,
+                config.general.rate_limit_clients,
+            ),
+            downloads: Default::default(),
+            uploads: Default::default(),
+            audit: audit::AuditLog::from_config(config.audit.as_ref())?,
+            shutdown,
+        })
+    }
+
+    /// Take it once per request, a reload may swap it in between.
+    pub fn config(&self) -> Arc<config::Config> {
+        self.config.get()
+    }
+}
+
+/// The `tarcloud` binary.
+pub fn run() {
+    if std::env::args().nth(1).as_deref() == Some("hash-token") {
+        hash_token();
+        return;
+    }
+
+    // `tarcloud CONFIG` as the old server took it, else `CONFIG_FILE`.
+    let config_file = std::env::args()
+        .nth(1)
+        .or_else(|| std::env::var("CONFIG_FILE").ok())
+        .unwrap_or_else(|| "config.toml".to_string());
+    println!("Loading config from {}", config_file);
+
+    let config = config::Config::load(&config_file).and_then(|config| {
+        config.validate()?;
+        Ok(config)
+    });
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Config errors in {config_file}:");
+            for problem in format!("{e:#}").lines() {
+                eprintln!("  {problem}");
+            }
+            std::process::exit(1);
+        }
+    };
+
+    // Before any thread is started, see `handle_signals`.
+    let shutdown = shutdown::Shutdown::default();
+    let shared = config::SharedConfig::new(config.clone(), Some(config_file));
+    shutdown::handle_signals(&shutdown, &shared).unwrap();
+
+    let state = AppState::new(&config, shared, shutdown.clone()).unwrap();
+
+    let gc = std::thread::spawn({
+        let state = state.clone();
+        move || {
+            run_gc(state);
+        }
+    });
+
+    std::thread::spawn({
+        let meta = state.meta.clone();
+        move || match meta.migrate() {
+            Ok(0) => {}
+            Ok(moved) => println!("=== Moved {moved} files into shard directories"),
+            Err(e) => println!("== Error moving files into shard directories: {:?}", e),
+        }
+    });
+
+    let result = listener::serve(&config, &shutdown, {
+        let state = state.clone();
+        move |request| handle(&state, request)
+    });
+    if result.is_ok() {
+        let grace = std::time::Duration::from_secs(state.config().general.shutdown_grace_s);
+        let expired = shutdown::drain(&state, grace);
+        if expired > 0 {
+            println!("=== Set {expired} unfinished uploads to expire");
+        }
+        let _ = gc.join();
+    }
+    state.audit.flush();
+    result.unwrap();
+}
+
+/// `tarcloud hash-token [TOKEN]` prints the `token_sha256` for the config.
+/// Without an argument the token is read from stdin, to keep it out of the
+/// shell history.
+fn hash_token() {
+    let token = match std::env::args().nth(2) {
+        Some(token) => token,
+        None => {
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line).unwrap();
+            line
+        }
+    };
+    println!("{}", config::hash_token(token.trim()));
+}
+
+fn handle(state: &AppState, request: &rouille::Request) -> Response {
+    let config = state.config();
+    if let Some(res) = cors::preflight(&config.cors, request) {
+        return res;
+    }
+    if let Some(res) = route_table::check(request) {
+        return cors::add_headers(&config.cors, request, res);
+    }
+    if let Some(res) = ratelimit::check(state, request) {
+        return cors::add_headers(&config.cors, request, res);
+    }
+
+    let is_browser = request
+        .header("Accept")
+        .map(|v| v.starts_with("text/html"))
+        .unwrap_or(false);
+
+    // New routes also go into `route_table::ROUTES`.
+    let res: anyhow::Result<Response> = router!(request,
+        (POST) ["/upload"] => {
+            routes::post_upload(state, request)
+        },
+        (GET) ["/upload"] => {
+            routes::ws_upload(state, request)
+        },
+        (POST) ["/upload/form"] => {
+            routes::post_upload_form(state, request)
+        },
+        (GET) ["/{id}/", id : TarPassword] => {
+            if is_browser {
+                routes::get_ui_index(state, request, id)
+            } else {
+                routes::get_download(state, request, id)
+            }
+        },
+        (DELETE) ["/{id}/", id : TarPassword] => {
+            routes::delete(state, request, id)
+        },
+        (GET) ["/{id}/pipe", id : TarPassword] => {
+            routes::get_download(state, request, id)
+        },
+        (GET) ["/{id}/stream", id : TarPassword] => {
+            routes::get_stream(state, request, id)
+        },
+        (GET) ["/{id}/ws", id : TarPassword] => {
+            routes::ws_download(state, request, id)
+        },
+        (GET) ["/{id}/thumbnail", id : TarPassword] => {
+            routes::get_thumbnail(state, request, id)
+        },
+        (GET) ["/{id}/sha256", id : TarPassword] => {
+            routes::get_checksums(state, request, id)
+        },
+        (GET) ["/{id}/zip", id : TarPassword] => {
+            routes::get_tar_to_zip(state, request, id)
+        },
+        (GET) ["/{id}/tar.zst", id : TarPassword] => {
+            routes::get_tar_zst(state, request, id)
+        },
+        (GET) ["/raw/{id}/", id : TarHash] => {
+            routes::get_download_raw(state, request, id)
+        },
+        (POST) ["/raw/{id}/", id : TarHash] => {
+            routes::post_upload_raw(state, request, id)
+        },
+        (PUT) ["/raw/{id}/", id : TarHash] => {
+            routes::put_upload_raw(state, request, id)
+        },
+        (PATCH) ["/raw/{id}/", id : TarHash] => {
+            routes::post_upload_raw(state, request, id)
+        },
+        (HEAD) ["/raw/{id}/", id : TarHash] => {
+            routes::head_upload_raw(state, request, id)
+        },
+        (GET) ["/api/v1/status/{id}/", id : TarPassword] => {
+            routes::get_status(state, request, id)
+        },
+        (GET) ["/api/v1/uploads"] => {
+            routes::get_uploads(state, request)
+        },
+        (POST) ["/api/uploads/{hash}/alias", hash : TarHash] => {
+            routes::post_alias(state, request, hash)
+        },
+        (GET) ["/api/uploads/{hash}/status", hash : TarHash] => {
+            routes::get_upload_status(state, request, hash)
+        },
+        (GET) ["/s/{slug}", slug : String] => {
+            routes::get_alias(state, request, slug)
+        },
+        (GET) ["/whoami"] => {
+            routes::get_whoami(state, request)
+        },
+        (GET) ["/metrics"] => {
+            routes::get_metrics(state, request)
+        },
+        (POST) ["/api/admin/reload"] => {
+            routes::post_reload(state, request)
+        },
+        (DELETE) ["/api/admin/uploads"] => {
+            routes::delete_admin_uploads(state, request)
+        },
+        (GET) ["/favicon.ico"] => {
+            routes::get_favicon(state, request)
+        },
+        (GET) ["/protocol"] => {
+            routes::get_protocol(state, request)
+        },
+        (GET) ["/{id}", id : TarPassword] => {
+            routes::redirect_index(state, request, id)
+        },
+        (GET) ["/"] => {
+            routes::get_upload_ui(state, request)
+        },
+        _ => {
+            let res = rouille::match_assets(request, "./static");
+
+            if res.is_success() {
+                Ok(res)
+            } else {
+                Err(ErrorResponse::not_found().into())
+            }
+        }
+    );
+
+    let res = match res {
+        Ok(r) => r,
+        Err(e) => match e.downcast::<ErrorResponse>() {
+            Ok(res) => res.to_response(request),
+            Err(e) => {
+                println!("Error: {:?}", e);
+                rouille::Response::text("Internal Server Error").with_status_code(500)
+            }
+        },
+    };
+    let res = compress::apply(request, res);
+    ratelimit::record(state, request, &res);
+    cors::add_headers(&config.cors, request, res)
+}
+
+/// Reloads the config file and logs the outcome, for SIGHUP and the admin route.
+fn reload_config(config: &config::SharedConfig) -> anyhow::Result<Vec<String>> {
+    let result = config.reload();
+    match &result {
+        Ok(changes) if changes.is_empty() => println!("=== Reloaded config, nothing changed"),
+        Ok(changes) => println!("=== Reloaded config, changed {}", changes.join(", ")),
+        Err(e) => println!("== Config not reloaded: {e:#}"),
+    }
+    result
+}
+
+#[cfg(test)]
+pub(crate) fn test_state() -> AppState {
+    let dir = std::env::temp_dir().join(format!("tarcloud-test-{}", TarPassword::generate()));
+    let config = toml::from_str(
+        r#"
+        [general]
+        hostname = "localhost"
+        protocol = "http"
+
+        [[users]]
+        username = "test"
+        token = "secret"
+        "#,
+    )
+    .unwrap();
+
+    let meta = meta::MetaStore::new(dir).unwrap();
+    AppState {
+        config: config::SharedConfig::new(config, None),
+        storage: Arc::new(storage::FsStorage::new(meta.clone())),
+        meta,
+        tokens: None,
+        limiter: None,
+        anonymous_uploads: ratelimit::RateLimiter::new(2, 100),
+        downloads: Default::default(),
+        uploads: Default::default(),
+        audit: Default::default(),
+        shutdown: Default::default(),
+    }
+}
+
+/// Encrypts `data` the way toc does before uploading it.
+#[cfg(test)]
+pub(crate) fn test_encrypt(password: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut encrypted = vec![];
+    let mut writer = common::EncryptedWriter::new(&mut encrypted, password);
+    writer.write_all(data).unwrap();
+    drop(writer);
+    encrypted
+}
+
+/// What a GC run did, a line of `general.gc_metrics_file`.
+#[derive(serde::Serialize)]
+struct GcMetrics {
+    timestamp: u64,
+    deleted: usize,
+    total: usize,
+    errors: usize,
+    duration_ms: u64,
+}
+
+/// Deletes expired uploads.
+fn collect_garbage(state: &AppState) -> anyhow::Result<GcMetrics> {
+    let started = std::time::Instant::now();
+    let mut count = 0;
+    let mut total = 0;
+    let mut errors = 0;
+
+    let now = util::now_unix();
+    for (k, v) in state.meta.list()?.into_iter() {
+        let delete = v.delete_at_unix < now;
+
+        if delete {
+            let size = state.storage.size(&k).ok();
+            match state
+                .storage
+                .delete(&k)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| state.meta.delete(&k))
+            {
+                Err(e) => {
+                    println!("Error deleting {}: {:?}", k, e);
+                    errors += 1;
+                }
+                Ok(_) => {
+                    let record = AuditRecord::new(AuditEvent::Expire, &AuditClient::default());
+                    state
+                        .audit
+                        .record(record.with_hash(&k).with_owner(&v.owner).with_bytes(size));
+                    count += 1;
+                }
+            }
+        }
+
+        total += 1;
+    }
+
+    println!("== GC: {count} / {total}, {errors} Errors");
+    Ok(GcMetrics {
+        timestamp: now,
+        deleted: count,
+        total,
+        errors,
+        duration_ms: started.elapsed().as_millis() as u64,
+    })
+}
+
+fn write_gc_metrics(
+    path: &std::path::Path,
+    max_bytes: u64,
+    metrics: &GcMetrics,
+) -> anyhow::Result<()> {
+    let line = serde_json::to_string(metrics)? + "\n";
+    let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    if size > 0 && size + line.len() as u64 > max_bytes {
+        std::fs::rename(path, audit::rotated(path, 1))?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Returns once a shutdown is requested, a running pass is finished first.
+fn run_gc(state: AppState) {
+    // Read every time, a reload may change it.
+    let interval = || std::time::Duration::from_secs(state.config().general.gc_interval_s);
+    if state.shutdown.sleep(interval() / 10) {
+        return;
+    }
+
+    loop {
+        if state.shutdown.sleep(interval()) {
+            return;
+        }
+        println!("=== Running GC");
+        match collect_garbage(&state) {
+            Ok(metrics) => {
+                println!("=== Finished GC");
+                let general = state.config().general.clone();
+                if let Some(path) = &general.gc_metrics_file {
+                    if let Err(e) = write_gc_metrics(path, general.gc_metrics_max_bytes, &metrics) {
+                        println!(
+                            "== Could not write GC metrics to {}: {:?}",
+                            path.display(),
+                            e
+                        );
+                    }
+                }
+            }
+            Err(e) => {
+                println!("== Error: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn get(state: &AppState, url: &str) -> Response {
+        handle(
+            state,
+            &rouille::Request::fake_http("GET", url, vec![], vec![]),
+        )
+    }
+
+    /// Stores `data` encrypted under a new code, through the raw upload route.
+    fn upload_encrypted(state: &AppState, data: &[u8]) -> (TarHash, TarPassword) {
+        let code = TarPassword::generate();
+        (upload_encrypted_as(state, &code, data), code)
+    }
+
+    fn upload_encrypted_as(state: &AppState, code: &TarPassword, data: &[u8]) -> TarHash {
+        let hash = TarHash::from_tarid(code, "localhost");
+        let encrypted = test_encrypt(code.to_string().as_bytes(), data);
+        let headers = vec![("Authorization".to_string(), "Bearer secret".to_string())];
+        let request =
+            rouille::Request::fake_http("POST", format!("/raw/{hash}/"), headers, encrypted);
+        assert_eq!(handle(state, &request).status_code, 200);
+        hash
+    }
+
+    fn location(response: &Response) -> Option<&str> {
+        response
+            .headers
+            .iter()
+            .find(|(k, _)| k == "Location")
+            .map(|(_, v)| v.as_ref())
+    }
+
+    #[test]
+    fn test_gc_metrics() {
+        let state = test_state();
+        let (hash, _) = upload_encrypted(&state, b"hello");
+        let mut meta = state.meta.get(&hash).unwrap().unwrap();
+        meta.delete_at_unix = 1;
+        state.meta.set(&hash, &meta).unwrap();
+
+        let metrics = collect_garbage(&state).unwrap();
+        assert_eq!((metrics.deleted, metrics.total, metrics.errors), (1, 1, 0));
+
+        let path = state.meta.file_path(&hash).with_file_name("gc.jsonl");
+        write_gc_metrics(&path, 1000, &metrics).unwrap();
+        let line: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(line["deleted"], 1);
+        assert_eq!(line["timestamp"], metrics.timestamp);
+
+        // Over the limit with the next line, it goes to a new file.
+        write_gc_metrics(&path, 100, &metrics).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+        assert!(audit::rotated(&path, 1).exists());
+    }
+
+    #[test]
+    fn test_options_and_allow() {
+        let state = test_state();
+        let request = |method: &str, url: &str| {
+            let headers = vec![("Accept".to_string(), "application/json".to_string())];
+            handle(
+                &state,
+                &rouille::Request::fake_http(method, url, headers, vec![]),
+            )
+        };
+        let allow = |response: &Response| {
+            response
+                .headers
+                .iter()
+                .find(|(k, _)| k == "Allow")
+                .map(|(_, v)| v.to_string())
+        };
+
+        let response = request("OPTIONS", "/upload");
+        assert_eq!(response.status_code, 204);
+        assert_eq!(allow(&response).as_deref(), Some("OPTIONS, GET, POST"));
+
+        let response = request("POST", "/metrics");
+        assert_eq!(response.status_code, 405);
+        assert_eq!(allow(&response).as_deref(), Some("OPTIONS, GET"));
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut body = String::new();
+        reader.read_to_string(&mut body).unwrap();
+        assert!(body.contains("method_not_allowed"), "{body}");
+
+        assert_eq!(request("GET", "/protocol").status_code, 200);
+        assert_eq!(request("OPTIONS", "/nope/x/y").status_code, 404);
+    }
+
+    #[test]
+    fn test_alias_redirect() {
+        let state = test_state();
+        let (hash, code) = upload_encrypted(&state, b"hello");
+
+        let headers = vec![("Authorization".to_string(), "Bearer secret".to_string())];
+        let body = serde_json::json!({ "code": code.to_string(), "slug": "holiday" });
+        let request = rouille::Request::fake_http(
+            "POST",
+            format!("/api/uploads/{hash}/alias"),
+            headers,
+            body.to_string().into_bytes(),
+        );
+        assert_eq!(handle(&state, &request).status_code, 200);
+
+        let response = get(&state, "/s/holiday");
+        assert_eq!(response.status_code, 302);
+        assert_eq!(location(&response), Some(&*format!("/{code}/")));
+        assert_eq!(get(&state, "/s/unknown").status_code, 404);
+
+        // Expires with the upload.
+        let mut meta = state.meta.get(&hash).unwrap().unwrap();
+        meta.delete_at_unix = 1;
+        state.meta.set(&hash, &meta).unwrap();
+        collect_garbage(&state).unwrap();
+        assert!(state.meta.get_alias("holiday").unwrap().is_none());
+        assert_eq!(get(&state, "/s/holiday").status_code, 404);
+    }
+
+    #[test]
+    fn test_gc_stops_on_shutdown() {
+        let state = test_state();
+        state.shutdown.request();
+        // Would sleep for 6 minutes otherwise.
+        run_gc(state);
+    }
+
+    #[test]
+    fn test_code_routes() {
+        let state = test_state();
+        let code = "0005-abandon-ability-able-about";
+        upload_encrypted_as(&state, &code.parse().unwrap(), b"hello");
+
+        let response = get(&state, &format!("/{code}"));
+        assert_eq!(response.status_code, 301);
+        assert_eq!(location(&response), Some(format!("/{code}/").as_str()));
+
+        // One mistyped word is corrected, the redirect goes to the real code.
+        let response = get(&state, "/0005-abandon-abilty-able-about?sort=size");
+        assert_eq!(response.status_code, 301);
+        assert_eq!(
+            location(&response),
+            Some(format!("/{code}/?sort=size").as_str())
+        );
+
+        let response = get(&state, "/0005-abandonn-ability-able-about/");
+        assert_eq!(response.status_code, 200);
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut body = vec![];
+        reader.read_to_end(&mut body).unwrap();
+        assert!(body.starts_with(b"hello"));
+
+        // "bax" is as close to "bag" as to "bar" and "box".
+        let ambiguous = "0005-abandon-ability-bax-about";
+        assert_eq!(get(&state, &format!("/{ambiguous}")).status_code, 404);
+        assert_eq!(get(&state, &format!("/{ambiguous}/")).status_code, 404);
+        assert_eq!(get(&state, &format!("/{code}/")).status_code, 200);
+    }
+
+    #[test]
+    fn test_compression() {
+        let state = test_state();
+        let get_gzip = |url: &str| {
+            let headers = vec![("Accept-Encoding".to_string(), "gzip".to_string())];
+            let response = handle(
+                &state,
+                &rouille::Request::fake_http("GET", url, headers, vec![]),
+            );
+            let encoding = response
+                .headers
+                .iter()
+                .find(|(k, _)| k == "Content-Encoding")
+                .map(|(_, v)| v.to_string());
+            let (mut reader, _) = response.data.into_reader_and_size();
+            let mut body = vec![];
+            reader.read_to_end(&mut body).unwrap();
+            (encoding, body)
+        };
+
+        let (encoding, compressed) = get_gzip("/protocol");
+        assert_eq!(encoding.as_deref(), Some("gzip"));
+        let (mut reader, _) = get(&state, "/protocol").data.into_reader_and_size();
+        let mut plain = vec![];
+        reader.read_to_end(&mut plain).unwrap();
+        assert!(compressed.len() < plain.len() / 2);
+
+        // Downloads stay as stored.
+        let (_, code) = upload_encrypted(&state, &b"text ".repeat(1000));
+
+        for url in [
+            format!("/{code}/pipe"),
+            format!("/{code}/pipe?disposition=inline&name=a.txt"),
+        ] {
+            let (encoding, body) = get_gzip(&url);
+            assert_eq!(encoding, None);
+            assert_eq!(body, b"text ".repeat(1000));
+        }
+    }
+}
@@ -0,0 +1,340 @@
+use std::{
+    collections::HashSet,
+    fs::Permissions,
+    net::{SocketAddr, TcpStream},
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use rouille::{Request, Response};
+
+use crate::{
+    config::{Config, TlsConfig},
+    responses::ErrorResponse,
+    shutdown::Shutdown,
+};
+
+/// Where the server accepts connections, from `general.listen`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Listen {
+    Tcp(String),
+    /// `unix:/path/to.sock`
+    Unix(PathBuf),
+}
+
+impl Listen {
+    pub fn parse(listen: &str) -> Self {
+        match listen.strip_prefix("unix:") {
+            Some(path) => Listen::Unix(PathBuf::from(path)),
+            None => Listen::Tcp(listen.to_string()),
+        }
+    }
+}
+
+/// Runs the server on the configured listener until `shutdown` is requested.
+/// Requests that already arrived keep running after it returns.
+/// Share urls always use `general.protocol`, whatever is listened on.
+pub fn serve<F>(config: &Config, shutdown: &Shutdown, handler: F) -> anyhow::Result<()>
+where
+    F: Send + Sync + 'static + Fn(&Request) -> Response,
+{
+    match (Listen::parse(&config.general.listen), &config.tls) {
+        (Listen::Tcp(addr), None) => {
+            let server = rouille::Server::new(&addr, handler)
+                .map_err(|e| anyhow::anyhow!("Could not start server: {}", e))?;
+            println!("Listening on http://{}", addr);
+            let (running, stop) = server.stoppable();
+            shutdown.wait();
+            let _ = stop.send(());
+            let _ = running.join();
+            Ok(())
+        }
+        (Listen::Tcp(addr), Some(tls)) => serve_tls(&addr, tls, shutdown, handler),
+        (Listen::Unix(path), None) => {
+            let mode = config.general.socket_mode.as_deref();
+            serve_unix(&path, mode, shutdown, handler)
+        }
+        (Listen::Unix(_), Some(_)) => anyhow::bail!("[tls] can't be used with a unix socket"),
+    }
+}
+
+/// Serves with the certificate from `tls`. Certificates are usually renewed
+/// in place, so the files are watched and the server restarts with the new
+/// ones. If they don't load, the last working ones are used again.
+#[cfg(feature = "tls")]
+fn serve_tls<F>(addr: &str, tls: &TlsConfig, shutdown: &Shutdown, handler: F) -> anyhow::Result<()>
+where
+    F: Send + Sync + 'static + Fn(&Request) -> Response,
+{
+    let handler = std::sync::Arc::new(handler);
+    let start = |(cert, key): (Vec<u8>, Vec<u8>)| {
+        let handler = handler.clone();
+        rouille::Server::new_ssl(addr, move |request| handler(request), cert, key)
+            .map_err(|e| anyhow::anyhow!("Could not start TLS server: {}", e))
+    };
+
+    let mut working = None;
+    loop {
+        let loaded = modified(tls)?;
+        let files = (std::fs::read(&tls.cert)?, std::fs::read(&tls.key)?);
+        let server = match (start(files.clone()), &working) {
+            (Ok(server), _) => {
+                working = Some(files);
+                server
+            }
+            (Err(e), Some(working)) => {
+                println!("{:?}, keeping the previous certificate", e);
+                start(working.clone())?
+            }
+            (Err(e), None) => return Err(e),
+        };
+
+        println!("Listening on https://{}", addr);
+        let (running, stop) = server.stoppable();
+        let interval = std::time::Duration::from_secs(tls.reload_interval_s);
+        let mut stopping = false;
+        while !stopping && modified(tls).map(|m| m == loaded).unwrap_or(true) {
+            stopping = shutdown.sleep(interval);
+        }
+
+        if !stopping {
+            println!("Certificate changed, restarting");
+        }
+        let _ = stop.send(());
+        let _ = running.join();
+        if stopping {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn serve_tls<F>(
+    _addr: &str,
+    _tls: &TlsConfig,
+    _shutdown: &Shutdown,
+    _handler: F,
+) -> anyhow::Result<()>
+where
+    F: Send + Sync + 'static + Fn(&Request) -> Response,
+{
+    anyhow::bail!("[tls] is configured, but the server was built without the `tls` feature")
+}
+
+#[cfg(feature = "tls")]
+fn modified(tls: &TlsConfig) -> std::io::Result<[std::time::SystemTime; 2]> {
+    Ok([
+        std::fs::metadata(&tls.cert)?.modified()?,
+        std::fs::metadata(&tls.key)?.modified()?,
+    ])
+}
+
+/// Source ports of the connections `forward` opened to the loopback server.
+type Forwarded = Arc<Mutex<HashSet<u16>>>;
+
+/// tiny_http only listens on TCP and rouille needs an IP for every request,
+/// so connections to the socket are passed on to the server on a loopback
+/// port. Only connections the forwarder opened are answered there, anyone
+/// else connecting to the port directly would get around `socket_mode`.
+/// Requests then come from 127.0.0.1, the proxy in front of the socket has
+/// to tell who the client is with `X-Forwarded-For`.
+///
+/// On shutdown the socket is removed and the server stops, connections that
+/// still make it through the proxy are refused.
+fn serve_unix<F>(
+    path: &Path,
+    mode: Option<&str>,
+    shutdown: &Shutdown,
+    handler: F,
+) -> anyhow::Result<()>
+where
+    F: Send + Sync + 'static + Fn(&Request) -> Response,
+{
+    let forwarded = Forwarded::default();
+    let allowed = forwarded.clone();
+    let server = rouille::Server::new("127.0.0.1:0", move |request: &Request| {
+        if !allowed
+            .lock()
+            .unwrap()
+            .contains(&request.remote_addr().port())
+        {
+            return ErrorResponse::forbidden("Connect through the unix socket")
+                .to_response(request);
+        }
+        handler(request)
+    })
+    .map_err(|e| anyhow::anyhow!("Could not start server: {}", e))?;
+    let backend = server.server_addr();
+    let listener = bind_unix(path, mode)?;
+    let (running, stop) = server.stoppable();
+
+    println!("Listening on unix:{}", path.display());
+    std::thread::spawn(move || forward_unix(listener, backend, forwarded));
+    shutdown.wait();
+    let _ = std::fs::remove_file(path);
+    let _ = stop.send(());
+    let _ = running.join();
+    Ok(())
+}
+
+/// `mode` are the permissions of the socket in octal, like `660`.
+fn bind_unix(path: &Path, mode: Option<&str>) -> anyhow::Result<UnixListener> {
+    let mode = mode
+        .map(|mode| u32::from_str_radix(mode, 8))
+        .transpose()
+        .map_err(|_| anyhow::anyhow!("Invalid socket_mode, expected octal like 660"))?;
+
+    // Left over from the last run, anything else is not ours to remove.
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => anyhow::bail!("{} exists and is not a socket", path.display()),
+        Err(_) => (),
+    }
+
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+fn forward_unix(
+    listener: UnixListener,
+    backend: SocketAddr,
+    forwarded: Forwarded,
+) -> anyhow::Result<()> {
+    for client in listener.incoming() {
+        match client {
+            Ok(client) => {
+                let forwarded = forwarded.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = forward(client, backend, &forwarded) {
+                        println!("Error forwarding connection: {:?}", e);
+                    }
+                });
+            }
+            Err(e) => println!("Error accepting connection: {:?}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Forgets the source port once the connection is closed.
+struct ForwardedPort<'a>(&'a Forwarded, u16);
+
+impl Drop for ForwardedPort<'_> {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().remove(&self.1);
+    }
+}
+
+fn forward(client: UnixStream, backend: SocketAddr, forwarded: &Forwarded) -> std::io::Result<()> {
+    let server = TcpStream::connect(backend)?;
+    // Registered before anything is sent, so the first request already counts.
+    let port = server.local_addr()?.port();
+    forwarded.lock().unwrap().insert(port);
+    let _port = ForwardedPort(forwarded, port);
+
+    let (mut from_client, mut to_server) = (client.try_clone()?, server.try_clone()?);
+    let upstream = std::thread::spawn(move || {
+        let _ = std::io::copy(&mut from_client, &mut to_server);
+        let _ = to_server.shutdown(std::net::Shutdown::Write);
+    });
+
+    let (mut from_server, mut to_client) = (server, client);
+    let result = std::io::copy(&mut from_server, &mut to_client);
+    let _ = to_client.shutdown(std::net::Shutdown::Write);
+    let _ = upstream.join();
+    result.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_parse_listen() {
+        assert_eq!(
+            Listen::parse("[::1]:8000"),
+            Listen::Tcp("[::1]:8000".to_string())
+        );
+        assert_eq!(
+            Listen::parse("unix:/run/piper.sock"),
+            Listen::Unix(PathBuf::from("/run/piper.sock"))
+        );
+    }
+
+    #[test]
+    fn test_tls_config() {
+        let config: Config = toml::from_str(
+            r#"
+            users = []
+
+            [general]
+            listen = "0.0.0.0:443"
+
+            [tls]
+            cert = "/etc/piper/cert.pem"
+            key = "/etc/piper/key.pem"
+            "#,
+        )
+        .unwrap();
+        let tls = config.tls.unwrap();
+        assert_eq!(tls.cert, PathBuf::from("/etc/piper/cert.pem"));
+        assert_eq!(tls.reload_interval_s, 60);
+
+        let config: Config = toml::from_str("users = []\n[general]\n").unwrap();
+        assert!(config.tls.is_none());
+    }
+
+    #[test]
+    fn test_unix_socket() {
+        let backend = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let forwarded = Forwarded::default();
+        let tracked = forwarded.clone();
+        std::thread::spawn(move || {
+            let (mut stream, from) = backend.accept().unwrap();
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).unwrap();
+            assert!(tracked.lock().unwrap().contains(&from.port()));
+            stream.write_all(b"pong").unwrap();
+        });
+
+        let path =
+            std::env::temp_dir().join(format!("tarcloud-{}.sock", common::TarPassword::generate()));
+        // A stale socket from an earlier run is replaced.
+        drop(UnixListener::bind(&path).unwrap());
+        let listener = bind_unix(&path, Some("600")).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let ports = forwarded.clone();
+        std::thread::spawn(move || forward_unix(listener, backend_addr, ports));
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut answer = String::new();
+        client.read_to_string(&mut answer).unwrap();
+        assert_eq!(answer, "pong");
+        // The port is forgotten once the connection is done.
+        drop(client);
+        for _ in 0..100 {
+            if forwarded.lock().unwrap().is_empty() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(forwarded.lock().unwrap().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "data").unwrap();
+        assert!(bind_unix(&path, None).is_err());
+        assert!(bind_unix(Path::new("/tmp/x.sock"), Some("999")).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
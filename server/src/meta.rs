@@ -1,15 +1,44 @@
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
     collections::HashMap,
+    io::{Read, Write},
     path::{Path, PathBuf},
     str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-use common::TarHash;
+use common::{TarHash, TarIndexEntry, TarPassword};
+
+use crate::util::to_hex;
+
+const META_EXT: &str = "meta.json";
+const DATA_EXT: &str = "tar.age";
+/// Directory of the alias records, not a shard with its two letters.
+const ALIAS_DIR: &str = "aliases";
 
 #[derive(Clone)]
 pub struct MetaStore {
     path: PathBuf,
+    list_cache: Option<Arc<ListCache>>,
+}
+
+/// `list` reads every metadata file, which is a request each on a bucket
+/// mounted with `s3fs`. The result is kept for `ttl`, changes made through
+/// the store are applied to it, others show up after `ttl`.
+struct ListCache {
+    ttl: Duration,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    list: Option<(Instant, HashMap<TarHash, MetaData>)>,
+    /// Counts the changes, a rebuild replays those after the one it started at.
+    generation: u64,
+    rebuilds: usize,
+    /// Kept while a rebuild runs, `None` for deleted uploads.
+    changes: Vec<(u64, TarHash, Option<MetaData>)>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -20,6 +49,206 @@ pub struct MetaData {
     pub allow_write: bool,
     pub allow_rewrite: bool,
     pub finished: bool,
+    /// Partial data is kept on failure and can be appended to.
+    #[serde(default)]
+    pub resumable: bool,
+    /// Set when the code was found with one of `general.accepted_hostnames`.
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// Entries of the archive, see `tar_index`.
+    #[serde(default)]
+    pub tar_index: Option<String>,
+    /// Hex SHA-256 of the stored blob, set once it is complete.
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// Checksums of the archived files, see `checksums`.
+    #[serde(default)]
+    pub checksums: Option<String>,
+    /// BLAKE3 of the archive, for uploads the server encrypted itself.
+    #[serde(default)]
+    pub plaintext_hash: Option<[u8; 32]>,
+    #[serde(default)]
+    pub download_stats: DownloadStats,
+    /// Slug of the short link, removed with the upload.
+    #[serde(default)]
+    pub alias: Option<String>,
+}
+
+/// Record behind a short link `/s/{slug}`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Alias {
+    pub hash: String,
+    /// In the clear, to redirect to the page of the upload. Anyone who can
+    /// read the data directory can open the upload then.
+    pub code: String,
+}
+
+/// Completed downloads of an upload, by route. Aborted ones don't count.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DownloadStats {
+    /// The encrypted blob from `/raw/{hash}/`.
+    pub raw: u64,
+    /// The whole archive, as tar, tar.zst or over the websocket.
+    pub decrypted: u64,
+    pub zip: u64,
+    /// Single files, linked on the index page.
+    pub single_file: u64,
+    pub last_download_at_unix: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DownloadKind {
+    Raw,
+    Decrypted,
+    Zip,
+    SingleFile,
+}
+
+impl DownloadStats {
+    pub fn record(&mut self, kind: DownloadKind, now_unix: u64) {
+        let count = match kind {
+            DownloadKind::Raw => &mut self.raw,
+            DownloadKind::Decrypted => &mut self.decrypted,
+            DownloadKind::Zip => &mut self.zip,
+            DownloadKind::SingleFile => &mut self.single_file,
+        };
+        *count += 1;
+        self.last_download_at_unix = Some(now_unix);
+    }
+
+    pub fn total(&self) -> u64 {
+        self.raw + self.decrypted + self.zip + self.single_file
+    }
+}
+
+/// Archive entry as cached in the metadata, so listing an upload does not
+/// have to read all of it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SerializedTarEntry {
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub mtime: u64,
+    /// Position of the contents in the tar stream.
+    pub offset: u64,
+}
+
+impl From<TarIndexEntry> for SerializedTarEntry {
+    fn from(entry: TarIndexEntry) -> Self {
+        Self {
+            path: entry.path,
+            is_dir: entry.is_dir,
+            size: entry.size,
+            mtime: entry.mtime,
+            offset: entry.offset,
+        }
+    }
+}
+
+impl MetaData {
+    /// The cached index, if there is one and it was stored with `id`.
+    pub fn tar_index(&self, id: &TarPassword) -> Option<Vec<SerializedTarEntry>> {
+        decrypt_json(id, self.tar_index.as_ref()?)
+    }
+
+    /// File names are as private as the contents, so the index is encrypted
+    /// with the code as well.
+    pub fn set_tar_index(
+        &mut self,
+        id: &TarPassword,
+        index: &[SerializedTarEntry],
+    ) -> anyhow::Result<()> {
+        self.tar_index = Some(encrypt_json(id, index)?);
+        Ok(())
+    }
+
+    /// The cached `sha256sum` list of the archived files, encrypted like the index.
+    pub fn checksums(&self, id: &TarPassword) -> Option<String> {
+        decrypt_json(id, self.checksums.as_ref()?)
+    }
+
+    pub fn set_checksums(&mut self, id: &TarPassword, checksums: &str) -> anyhow::Result<()> {
+        self.checksums = Some(encrypt_json(id, checksums)?);
+        Ok(())
+    }
+}
+
+fn encrypt_json<T: Serialize + ?Sized>(id: &TarPassword, value: &T) -> anyhow::Result<String> {
+    let mut encrypted = vec![];
+    let mut writer = common::EncryptedWriter::new(&mut encrypted, id.to_string().as_bytes());
+    writer.write_all(&serde_json::to_vec(value)?)?;
+    drop(writer);
+    Ok(to_hex(&encrypted))
+}
+
+fn decrypt_json<T: DeserializeOwned>(id: &TarPassword, hex: &str) -> Option<T> {
+    let encrypted = from_hex(hex)?;
+    let mut json = vec![];
+    common::EncryptedReader::new(&encrypted[..], id.to_string().as_bytes())
+        .read_to_end(&mut json)
+        .ok()?;
+    // Written before the last block knew its length, it may be zero padded.
+    let len = json.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    serde_json::from_slice(&json[..len]).ok()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Fills the cache with the list from `read`. It isn't locked meanwhile,
+/// `set` and `delete` shouldn't wait for this, so their changes are replayed
+/// on the list afterwards.
+fn rebuild_cache(
+    cache: &ListCache,
+    read: impl FnOnce() -> anyhow::Result<HashMap<TarHash, MetaData>>,
+) -> anyhow::Result<HashMap<TarHash, MetaData>> {
+    let started = {
+        let mut state = cache.state.lock().unwrap();
+        state.rebuilds += 1;
+        state.generation
+    };
+    let read = read();
+
+    let mut state = cache.state.lock().unwrap();
+    state.rebuilds -= 1;
+    let result = read.map(|mut list| {
+        for (_, id, meta) in state.changes.iter().filter(|(g, _, _)| *g > started) {
+            apply_change(&mut list, id, meta.as_ref());
+        }
+        state.list = Some((Instant::now(), list.clone()));
+        list
+    });
+    if state.rebuilds == 0 {
+        state.changes.clear();
+    }
+    result
+}
+
+fn apply_change(list: &mut HashMap<TarHash, MetaData>, id: &TarHash, meta: Option<&MetaData>) {
+    match meta {
+        Some(meta) => list.insert(id.clone(), meta.clone()),
+        None => list.remove(id),
+    };
+}
+
+/// Counts the stored uploads each time it is formatted.
+impl std::fmt::Debug for MetaStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut debug = f.debug_struct("MetaStore");
+        debug.field("path", &self.path);
+        match self.files(META_EXT) {
+            Ok(files) => debug.field("count", &files.len()),
+            Err(e) => debug.field("count", &format_args!("<{e}>")),
+        };
+        debug.finish()
+    }
 }
 
 impl MetaStore {
@@ -29,11 +258,40 @@ impl MetaStore {
             std::fs::create_dir(path.clone())?;
         }
 
-        Ok(Self { path })
+        Ok(Self {
+            path,
+            list_cache: None,
+        })
+    }
+
+    pub fn new_with_cache<P: AsRef<Path>>(path: P, cache_ttl: Duration) -> std::io::Result<Self> {
+        let mut store = Self::new(path)?;
+        store.list_cache = Some(Arc::new(ListCache {
+            ttl: cache_ttl,
+            state: Mutex::default(),
+        }));
+        Ok(store)
+    }
+
+    /// Applies a change to the cached list, if there is one, and keeps it
+    /// for the rebuilds that are running.
+    fn update_cache(&self, id: &TarHash, meta: Option<&MetaData>) {
+        let Some(cache) = &self.list_cache else {
+            return;
+        };
+        let mut state = cache.state.lock().unwrap();
+        state.generation += 1;
+        if let Some((_, list)) = state.list.as_mut() {
+            apply_change(list, id, meta);
+        }
+        if state.rebuilds > 0 {
+            let generation = state.generation;
+            state.changes.push((generation, id.clone(), meta.cloned()));
+        }
     }
 
     pub fn get(&self, id: &TarHash) -> anyhow::Result<Option<MetaData>> {
-        let path = self.path.join(&format!("{}.meta.json", id));
+        let path = self.resolve(id, META_EXT);
         if !path.exists() {
             return Ok(None);
         }
@@ -43,56 +301,323 @@ impl MetaStore {
         Ok(Some(meta))
     }
 
+    /// Where the data of `id` is, or goes when there is none yet.
     pub fn file_path(&self, id: &TarHash) -> PathBuf {
-        self.path.join(&format!("{}.tar.age", id))
+        self.create_path(id, DATA_EXT)
     }
 
     pub fn set(&self, id: &TarHash, meta: &MetaData) -> anyhow::Result<()> {
-        let path = self.path.join(&format!("{}.meta.json", id));
+        let path = self.create_path(id, META_EXT);
         let data = serde_json::to_string(meta)?;
         std::fs::write(path, data)?;
+        self.update_cache(id, Some(meta));
         Ok(())
     }
 
     pub fn delete(&self, id: &TarHash) -> anyhow::Result<()> {
-        let path = self.path.join(&format!("{}.meta.json", id));
-        if !path.exists() {
-            return Ok(());
+        if let Some(slug) = self.get(id).ok().flatten().and_then(|m| m.alias) {
+            self.delete_alias(&slug)?;
+        }
+        for path in [self.sharded(id, META_EXT), self.legacy(id, META_EXT)] {
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
         }
-        std::fs::remove_file(path)?;
+        self.update_cache(id, None);
         Ok(())
     }
 
     pub fn list(&self) -> anyhow::Result<HashMap<TarHash, MetaData>> {
+        let cache = match &self.list_cache {
+            Some(cache) => cache,
+            None => return self.read_list(),
+        };
+        if let Some((read_at, list)) = cache.state.lock().unwrap().list.as_ref() {
+            if read_at.elapsed() < cache.ttl {
+                return Ok(list.clone());
+            }
+        }
+        rebuild_cache(cache, || self.read_list())
+    }
+
+    fn read_list(&self) -> anyhow::Result<HashMap<TarHash, MetaData>> {
         let mut map = HashMap::new();
-        for entry in std::fs::read_dir(&self.path)? {
-            let entry = entry?;
-            let path = entry.path();
+        for (id, path) in self.files(META_EXT)? {
+            let data = std::fs::read_to_string(path)?;
+            let meta: MetaData = serde_json::from_str(&data)?;
+            map.insert(id, meta);
+        }
+        Ok(map)
+    }
 
-            let file_name = path
+    fn alias_path(&self, slug: &str) -> PathBuf {
+        self.path.join(ALIAS_DIR).join(format!("{slug}.json"))
+    }
+
+    /// Stores `alias` under `slug`, `false` if the slug is taken. The slug
+    /// has to be a valid file name.
+    pub fn create_alias(&self, slug: &str, alias: &Alias) -> anyhow::Result<bool> {
+        let path = self.alias_path(slug);
+        std::fs::create_dir_all(self.path.join(ALIAS_DIR))?;
+        let mut file = match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        file.write_all(serde_json::to_string(alias)?.as_bytes())?;
+        Ok(true)
+    }
+
+    pub fn get_alias(&self, slug: &str) -> anyhow::Result<Option<Alias>> {
+        match std::fs::read_to_string(self.alias_path(slug)) {
+            Ok(data) => Ok(Some(serde_json::from_str(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn delete_alias(&self, slug: &str) -> anyhow::Result<()> {
+        match std::fs::remove_file(self.alias_path(slug)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Moves files of the flat layout into their shards, returns how many.
+    /// Open files stay valid, lookups find either location meanwhile.
+    pub fn migrate(&self) -> anyhow::Result<usize> {
+        let mut moved = 0;
+        for ext in [DATA_EXT, META_EXT] {
+            for (id, path) in Self::files_in(&self.path, ext)? {
+                let sharded = self.sharded(&id, ext);
+                if sharded.exists() {
+                    continue;
+                }
+                std::fs::create_dir_all(sharded.parent().unwrap())?;
+                std::fs::rename(path, sharded)?;
+                moved += 1;
+            }
+        }
+        Ok(moved)
+    }
+
+    /// `data/ab/cd/abcd….ext`, so no directory holds too many files.
+    fn sharded(&self, id: &TarHash, ext: &str) -> PathBuf {
+        let name = id.to_string();
+        self.path
+            .join(&name[..2])
+            .join(&name[2..4])
+            .join(format!("{name}.{ext}"))
+    }
+
+    /// All files used to be directly in the data directory.
+    fn legacy(&self, id: &TarHash, ext: &str) -> PathBuf {
+        self.path.join(format!("{id}.{ext}"))
+    }
+
+    fn resolve(&self, id: &TarHash, ext: &str) -> PathBuf {
+        let sharded = self.sharded(id, ext);
+        if sharded.exists() {
+            return sharded;
+        }
+        let legacy = self.legacy(id, ext);
+        if legacy.exists() {
+            return legacy;
+        }
+        sharded
+    }
+
+    /// Like `resolve`, but creates the shard directory for a new file.
+    fn create_path(&self, id: &TarHash, ext: &str) -> PathBuf {
+        let path = self.resolve(id, ext);
+        if let Some(dir) = path.parent() {
+            // Writing to the path fails anyway if this did.
+            let _ = std::fs::create_dir_all(dir);
+        }
+        path
+    }
+
+    /// Files with `ext` in both layouts.
+    fn files(&self, ext: &str) -> anyhow::Result<Vec<(TarHash, PathBuf)>> {
+        let mut files = Self::files_in(&self.path, ext)?;
+        for dir in Self::shard_dirs(&self.path)? {
+            for dir in Self::shard_dirs(&dir)? {
+                files.extend(Self::files_in(&dir, ext)?);
+            }
+        }
+        Ok(files)
+    }
+
+    fn shard_dirs(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        let mut dirs = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path
                 .file_name()
                 .unwrap_or_default()
                 .to_str()
                 .unwrap_or_default();
-            if !file_name.ends_with(".meta.json") {
-                continue;
+            if path.is_dir() && name.len() == 2 {
+                dirs.push(path);
             }
-            match TarHash::from_str(
-                file_name
-                    .split_once('.')
-                    .expect("file has meta.json but no '.'.")
-                    .0,
-            )
-            .ok()
-            {
-                Some(id) => {
-                    let data = std::fs::read_to_string(path)?;
-                    let meta: MetaData = serde_json::from_str(&data)?;
-                    map.insert(id, meta);
-                }
-                None => continue,
+        }
+        Ok(dirs)
+    }
+
+    fn files_in(dir: &Path, ext: &str) -> anyhow::Result<Vec<(TarHash, PathBuf)>> {
+        let mut files = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let file_name = path
+                .file_name()
+                .unwrap_or_default()
+                .to_str()
+                .unwrap_or_default();
+            let id = file_name
+                .strip_suffix(ext)
+                .and_then(|name| name.strip_suffix('.'))
+                .and_then(|name| TarHash::from_str(name).ok());
+            if let Some(id) = id {
+                files.push((id, path));
             }
         }
-        Ok(map)
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta() -> MetaData {
+        MetaData {
+            owner: "test".to_string(),
+            delete_at_unix: 0,
+            created_at_unix: 0,
+            allow_write: false,
+            allow_rewrite: false,
+            finished: true,
+            resumable: false,
+            hostname: None,
+            tar_index: None,
+            sha256: None,
+            checksums: None,
+            plaintext_hash: None,
+            download_stats: DownloadStats::default(),
+            alias: None,
+        }
+    }
+
+    fn store() -> MetaStore {
+        let dir = std::env::temp_dir().join(format!("tarcloud-test-{}", TarPassword::generate()));
+        MetaStore::new(dir).unwrap()
+    }
+
+    fn hash() -> TarHash {
+        TarHash::from_tarid(&TarPassword::generate(), "localhost")
+    }
+
+    /// Writes `id` the way older versions did.
+    fn set_legacy(store: &MetaStore, id: &TarHash, data: &[u8]) {
+        let json = serde_json::to_string(&meta()).unwrap();
+        std::fs::write(store.legacy(id, META_EXT), json).unwrap();
+        std::fs::write(store.legacy(id, DATA_EXT), data).unwrap();
+    }
+
+    #[test]
+    fn test_mixed_layouts() {
+        let store = store();
+        let (old, new) = (hash(), hash());
+        set_legacy(&store, &old, b"old");
+        store.set(&new, &meta()).unwrap();
+        std::fs::write(store.file_path(&new), b"new").unwrap();
+
+        let name = new.to_string();
+        let expected = store.path.join(&name[..2]).join(&name[2..4]);
+        assert_eq!(store.file_path(&new).parent(), Some(expected.as_path()));
+        assert_eq!(store.file_path(&old), store.legacy(&old, DATA_EXT));
+        assert_eq!(std::fs::read(store.file_path(&old)).unwrap(), b"old");
+
+        let list = store.list().unwrap();
+        assert_eq!(list.len(), 2);
+        let debug = format!("{store:?}");
+        assert_eq!(
+            debug,
+            format!("MetaStore {{ path: {:?}, count: 2 }}", store.path)
+        );
+        assert!(list.contains_key(&old) && list.contains_key(&new));
+        assert!(store.get(&old).unwrap().is_some());
+
+        // Updates stay where the file is.
+        store.set(&old, &meta()).unwrap();
+        assert!(!store.sharded(&old, META_EXT).exists());
+
+        store.delete(&old).unwrap();
+        store.delete(&new).unwrap();
+        assert!(store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_migrate() {
+        let store = store();
+        let (old, new) = (hash(), hash());
+        set_legacy(&store, &old, b"old");
+        store.set(&new, &meta()).unwrap();
+
+        assert_eq!(store.migrate().unwrap(), 2);
+        assert!(!store.legacy(&old, META_EXT).exists());
+        assert!(!store.legacy(&old, DATA_EXT).exists());
+        assert_eq!(store.file_path(&old), store.sharded(&old, DATA_EXT));
+        assert_eq!(std::fs::read(store.file_path(&old)).unwrap(), b"old");
+        assert_eq!(store.list().unwrap().len(), 2);
+
+        assert_eq!(store.migrate().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_list_cache() {
+        let uncached = store();
+        let store = MetaStore::new_with_cache(&uncached.path, Duration::from_secs(3600)).unwrap();
+        let (a, b, c) = (hash(), hash(), hash());
+        store.set(&a, &meta()).unwrap();
+        assert_eq!(store.list().unwrap().len(), 1);
+
+        // Changes through the store are seen right away, others not.
+        store.set(&b, &meta()).unwrap();
+        uncached.set(&c, &meta()).unwrap();
+        store.delete(&a).unwrap();
+        let list = store.list().unwrap();
+        assert_eq!(list.len(), 1);
+        assert!(list.contains_key(&b));
+        assert_eq!(uncached.list().unwrap().len(), 2);
+
+        let store = MetaStore::new_with_cache(&uncached.path, Duration::ZERO).unwrap();
+        store.list().unwrap();
+        uncached.delete(&c).unwrap();
+        assert_eq!(store.list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_change_during_rebuild() {
+        let store = MetaStore::new_with_cache(store().path, Duration::from_secs(3600)).unwrap();
+        let cache = store.list_cache.as_ref().unwrap();
+        let (a, b) = (hash(), hash());
+        store.set(&a, &meta()).unwrap();
+
+        // Read before `b` is stored and `a` deleted.
+        let list = rebuild_cache(cache, || {
+            let list = store.read_list();
+            store.set(&b, &meta()).unwrap();
+            store.delete(&a).unwrap();
+            list
+        })
+        .unwrap();
+        assert_eq!(list.keys().collect::<Vec<_>>(), [&b]);
+        assert_eq!(store.list().unwrap().keys().collect::<Vec<_>>(), [&b]);
+        assert!(cache.state.lock().unwrap().changes.is_empty());
     }
 }
@@ -0,0 +1,242 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rouille::{Request, Response};
+
+use crate::{responses::ErrorResponse, util::client_ip, AppState};
+
+/// Who a request is counted against.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Client {
+    Ip(IpAddr),
+    Token(String),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket per client, refilled with `per_minute` requests per minute.
+/// At most `max_clients` buckets are kept, the least recently used go first.
+#[derive(Clone)]
+pub struct RateLimiter {
+    per_minute: u32,
+    max_clients: usize,
+    buckets: Arc<Mutex<HashMap<Client, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32, max_clients: usize) -> Self {
+        Self {
+            per_minute,
+            max_clients,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn capacity(&self) -> f64 {
+        self.per_minute as f64
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.capacity() / 60.0).min(self.capacity());
+        bucket.updated = now;
+    }
+
+    /// Takes `cost` requests from the bucket of `client`, or returns how long
+    /// to wait until one is available again.
+    fn take(&self, client: Client, cost: f64, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if !buckets.contains_key(&client) && buckets.len() >= self.max_clients {
+            self.evict(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.capacity(),
+            updated: now,
+        });
+        self.refill(bucket, now);
+
+        if bucket.tokens < 1.0 {
+            let missing = 1.0 - bucket.tokens;
+            return Err(Duration::from_secs_f64(missing * 60.0 / self.capacity()));
+        }
+        bucket.tokens = (bucket.tokens - cost).max(0.0);
+        Ok(())
+    }
+
+    /// Full buckets are the same as new ones and can go first.
+    fn evict(&self, buckets: &mut HashMap<Client, Bucket>, now: Instant) {
+        buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * self.capacity() / 60.0 < self.capacity()
+        });
+
+        if buckets.len() >= self.max_clients {
+            let oldest = buckets
+                .iter()
+                .min_by_key(|(_, bucket)| bucket.updated)
+                .map(|(client, _)| client.clone());
+            if let Some(client) = oldest {
+                buckets.remove(&client);
+            }
+        }
+    }
+}
+
+/// Requests with a valid token are counted per token, everything else per IP.
+fn client(state: &AppState, request: &Request) -> Client {
+    match crate::routes::request_token(request) {
+        Some(token) if crate::routes::find_user(state, token).is_some() => {
+            Client::Token(token.to_string())
+        }
+        _ => Client::Ip(client_ip(&state.config().general, request)),
+    }
+}
+
+fn is_exempt(state: &AppState, request: &Request) -> bool {
+    state
+        .config()
+        .general
+        .rate_limit_exempt
+        .contains(&request.url())
+}
+
+/// Answers with 429 once the client is over its limit, `None` lets the request through.
+pub fn check(state: &AppState, request: &Request) -> Option<Response> {
+    let limiter = state.limiter.as_ref()?;
+    if is_exempt(state, request) {
+        return None;
+    }
+
+    let retry_after = limiter
+        .take(client(state, request), 1.0, Instant::now())
+        .err()?;
+    let retry_after = retry_after.as_secs_f64().ceil() as u64;
+    Some(
+        ErrorResponse::too_many_requests()
+            .to_response(request)
+            .with_additional_header("Retry-After", retry_after.max(1).to_string()),
+    )
+}
+
+/// Counts an upload without a token against the client IP, 429 once it is
+/// over `anonymous.rate_limit_per_minute`.
+pub fn take_anonymous_upload(state: &AppState, request: &Request) -> Result<(), ErrorResponse> {
+    let client = Client::Ip(client_ip(&state.config().general, request));
+    state
+        .anonymous_uploads
+        .take(client, 1.0, Instant::now())
+        .map_err(|_| ErrorResponse::too_many_requests().with_code("too_many_uploads"))
+}
+
+/// Failed logins count double, so guessing tokens runs into the limit sooner.
+pub fn record(state: &AppState, request: &Request, response: &Response) {
+    let limiter = match &state.limiter {
+        Some(limiter) => limiter,
+        None => return,
+    };
+    if response.status_code == 401 && !is_exempt(state, request) {
+        let _ = limiter.take(client(state, request), 1.0, Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(n: u8) -> Client {
+        Client::Ip(IpAddr::from([10, 0, 0, n]))
+    }
+
+    fn limited_state(per_minute: u32) -> AppState {
+        let mut state = crate::test_state();
+        state.limiter = Some(RateLimiter::new(per_minute, 100));
+        state
+    }
+
+    fn from(n: u8, url: &str, headers: Vec<(String, String)>) -> Request {
+        let addr = std::net::SocketAddr::from(([10, 0, 0, n], 4000));
+        Request::fake_http_from(addr, "GET", url, headers, vec![])
+    }
+
+    #[test]
+    fn test_limit_and_recovery() {
+        let limiter = RateLimiter::new(60, 100);
+        let now = Instant::now();
+
+        for _ in 0..60 {
+            assert!(limiter.take(ip(1), 1.0, now).is_ok());
+        }
+        let retry_after = limiter.take(ip(1), 1.0, now).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(1));
+
+        // Other clients have their own bucket.
+        assert!(limiter.take(ip(2), 1.0, now).is_ok());
+
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.take(ip(1), 1.0, later).is_ok());
+        assert!(limiter.take(ip(1), 1.0, later).is_err());
+
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..60 {
+            assert!(limiter.take(ip(1), 1.0, much_later).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_bounded_clients() {
+        let limiter = RateLimiter::new(10, 3);
+        let now = Instant::now();
+        for n in 0..10 {
+            limiter
+                .take(ip(n), 1.0, now + Duration::from_millis(n as u64))
+                .unwrap();
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), 3);
+        assert!(buckets.contains_key(&ip(9)));
+        assert!(!buckets.contains_key(&ip(0)));
+    }
+
+    #[test]
+    fn test_check_request() {
+        let state = limited_state(2);
+
+        assert!(check(&state, &from(1, "/raw/abc/", vec![])).is_none());
+        assert!(check(&state, &from(1, "/raw/abc/", vec![])).is_none());
+        let response = check(&state, &from(1, "/raw/abc/", vec![])).unwrap();
+        assert_eq!(response.status_code, 429);
+        let retry_after = response
+            .headers
+            .iter()
+            .find(|(k, _)| k == "Retry-After")
+            .map(|(_, v)| v.to_string());
+        assert_eq!(retry_after.as_deref(), Some("30"));
+
+        // Valid tokens get their own bucket, exempt paths are not counted.
+        let auth = vec![("Authorization".to_string(), "Bearer secret".to_string())];
+        assert!(check(&state, &from(1, "/upload", auth)).is_none());
+        assert!(check(&state, &from(1, "/health", vec![])).is_none());
+    }
+
+    #[test]
+    fn test_failed_auth_counts_double() {
+        let state = limited_state(4);
+        let auth = vec![("Authorization".to_string(), "Bearer wrong".to_string())];
+
+        for _ in 0..2 {
+            let request = from(3, "/upload", auth.clone());
+            assert!(check(&state, &request).is_none());
+            record(&state, &request, &ErrorResponse::unauthorized().into());
+        }
+        let response = check(&state, &from(3, "/upload", auth)).unwrap();
+        assert_eq!(response.status_code, 429);
+    }
+}
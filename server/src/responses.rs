@@ -4,12 +4,17 @@ use std::{
     fmt::{Display, Formatter},
 };
 
+use askama::Template;
 use rouille::Response;
 
+use crate::templates::ErrorPage;
+
 #[derive(Clone, Debug)]
 pub struct ErrorResponse {
     status: u16,
     error: Cow<'static, str>,
+    /// Machine readable reason for JSON clients.
+    code: Option<&'static str>,
 }
 
 impl Error for ErrorResponse {}
@@ -19,13 +24,88 @@ impl ErrorResponse {
         Self {
             status: 401,
             error: "Unauthorized".into(),
+            code: Some("unauthorized"),
+        }
+    }
+
+    pub fn bad_request<E: Into<Cow<'static, str>>>(error: E) -> Self {
+        Self {
+            status: 400,
+            error: error.into(),
+            code: None,
+        }
+    }
+
+    pub fn forbidden<E: Into<Cow<'static, str>>>(error: E) -> Self {
+        Self {
+            status: 403,
+            error: error.into(),
+            code: Some("forbidden"),
         }
     }
 
-    pub fn unimplemented() -> Self {
+    pub fn conflict<E: Into<Cow<'static, str>>>(error: E) -> Self {
+        Self {
+            status: 409,
+            error: error.into(),
+            code: Some("conflict"),
+        }
+    }
+
+    pub fn unprocessable<E: Into<Cow<'static, str>>>(error: E) -> Self {
+        Self {
+            status: 422,
+            error: error.into(),
+            code: Some("invalid_stream"),
+        }
+    }
+
+    pub fn request_timeout<E: Into<Cow<'static, str>>>(error: E) -> Self {
+        Self {
+            status: 408,
+            error: error.into(),
+            code: Some("timeout"),
+        }
+    }
+
+    pub fn payload_too_large<E: Into<Cow<'static, str>>>(error: E) -> Self {
+        Self {
+            status: 413,
+            error: error.into(),
+            code: Some("too_large"),
+        }
+    }
+
+    pub fn too_many_requests() -> Self {
+        Self {
+            status: 429,
+            error: "Too many requests".into(),
+            code: Some("rate_limited"),
+        }
+    }
+
+    pub fn not_implemented<E: Into<Cow<'static, str>>>(error: E) -> Self {
         Self {
             status: 501,
-            error: "Not implemented yet :/".into(),
+            error: error.into(),
+            code: Some("not_implemented"),
+        }
+    }
+
+    pub fn insufficient_storage<E: Into<Cow<'static, str>>>(error: E) -> Self {
+        Self {
+            status: 507,
+            error: error.into(),
+            code: Some("insufficient_storage"),
+        }
+    }
+
+    /// The path exists, but not for this method. The `Allow` header is up to the caller.
+    pub fn method_not_allowed() -> Self {
+        Self {
+            status: 405,
+            error: "Method not allowed".into(),
+            code: Some("method_not_allowed"),
         }
     }
 
@@ -33,7 +113,40 @@ impl ErrorResponse {
         Self {
             status: 404,
             error: "404 - Not found :/".into(),
+            code: Some("not_found"),
+        }
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    /// JSON for clients asking for it, a page for browsers, plain text otherwise.
+    pub fn to_response(&self, request: &rouille::Request) -> Response {
+        if crate::util::accepts_json(request) {
+            let body = serde_json::json!({ "error": self.error, "code": self.code });
+            return Response::json(&body).with_status_code(self.status);
+        }
+        if crate::util::accepts_html(request) {
+            let status = self.status.to_string();
+            let page = ErrorPage {
+                status: self.status,
+                title: if self.error.starts_with(&status) {
+                    self.error.to_string()
+                } else {
+                    format!("{status} - {}", self.error)
+                },
+            };
+            if let Ok(html) = page.render() {
+                return Response::html(html).with_status_code(self.status);
+            }
         }
+        self.clone().into()
     }
 }
 
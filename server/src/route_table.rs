@@ -0,0 +1,127 @@
+use rouille::{Request, Response};
+
+use crate::responses::ErrorResponse;
+
+/// Paths answered by the router in lib.rs and their methods, `{id}` matches
+/// any segment. The first matching path counts, so fixed ones come first.
+const ROUTES: [(&str, &[&str]); 24] = [
+    ("/", &["GET"]),
+    ("/protocol", &["GET"]),
+    ("/whoami", &["GET"]),
+    ("/metrics", &["GET"]),
+    ("/favicon.ico", &["GET"]),
+    ("/upload", &["GET", "POST"]),
+    ("/upload/form", &["POST"]),
+    ("/raw/{id}/", &["GET", "HEAD", "POST", "PUT", "PATCH"]),
+    ("/api/v1/status/{id}/", &["GET"]),
+    ("/api/v1/uploads", &["GET"]),
+    ("/api/uploads/{id}/alias", &["POST"]),
+    ("/api/uploads/{id}/status", &["GET"]),
+    ("/api/admin/reload", &["POST"]),
+    ("/api/admin/uploads", &["DELETE"]),
+    ("/s/{id}", &["GET"]),
+    ("/{id}", &["GET"]),
+    ("/{id}/", &["GET", "DELETE"]),
+    ("/{id}/pipe", &["GET"]),
+    ("/{id}/stream", &["GET"]),
+    ("/{id}/ws", &["GET"]),
+    ("/{id}/zip", &["GET"]),
+    ("/{id}/tar.zst", &["GET"]),
+    ("/{id}/sha256", &["GET"]),
+    ("/{id}/thumbnail", &["GET"]),
+];
+
+fn matches(route: &str, url: &str) -> bool {
+    let (mut route, mut url) = (route.split('/'), url.split('/'));
+    loop {
+        match (route.next(), url.next()) {
+            (None, None) => return true,
+            (Some("{id}"), Some(segment)) if !segment.is_empty() => (),
+            (Some(a), Some(b)) if a == b => (),
+            _ => return false,
+        }
+    }
+}
+
+/// Methods answered for `url`, `None` for unknown paths.
+pub fn methods(url: &str) -> Option<&'static [&'static str]> {
+    ROUTES
+        .iter()
+        .find(|(route, _)| matches(route, url))
+        .map(|(_, methods)| *methods)
+}
+
+/// Value of the `Allow` header for `url`.
+pub fn allow(url: &str) -> Option<String> {
+    methods(url).map(|methods| {
+        ["OPTIONS"]
+            .iter()
+            .chain(methods)
+            .copied()
+            .collect::<Vec<_>>()
+            .join(", ")
+    })
+}
+
+/// Answers OPTIONS on known paths with 204 and other methods they don't
+/// support with 405, both with `Allow`. Everything else is left to the router.
+pub fn check(request: &Request) -> Option<Response> {
+    let url = request.url();
+    let methods = methods(&url)?;
+    let method = request.method();
+    let response = if method == "OPTIONS" {
+        Response::empty_204()
+    } else if !methods.contains(&method) {
+        ErrorResponse::method_not_allowed().to_response(request)
+    } else {
+        return None;
+    };
+    Some(response.with_additional_header("Allow", allow(&url)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_methods() {
+        assert_eq!(methods("/upload"), Some(&["GET", "POST"][..]));
+        assert_eq!(
+            methods("/0005-abandon-ability-able-about/"),
+            Some(&["GET", "DELETE"][..])
+        );
+        assert_eq!(methods("/raw/abc/").unwrap().len(), 5);
+        assert_eq!(methods("/s/team-notes"), Some(&["GET"][..]));
+        assert_eq!(methods("/nope/x/y"), None);
+        assert_eq!(methods("//"), None);
+        assert_eq!(allow("/upload/form").unwrap(), "OPTIONS, POST");
+    }
+
+    #[test]
+    fn test_check() {
+        let request = |method: &str, url: &str| Request::fake_http(method, url, vec![], vec![]);
+        let allow = |response: &Response| {
+            response
+                .headers
+                .iter()
+                .find(|(k, _)| k == "Allow")
+                .map(|(_, v)| v.to_string())
+        };
+
+        let response = check(&request("OPTIONS", "/abc/")).unwrap();
+        assert_eq!(response.status_code, 204);
+        assert_eq!(allow(&response).unwrap(), "OPTIONS, GET, DELETE");
+
+        let response = check(&request("POST", "/whoami")).unwrap();
+        assert_eq!(response.status_code, 405);
+        assert_eq!(allow(&response).unwrap(), "OPTIONS, GET");
+        let response = check(&request("PUT", "/upload")).unwrap();
+        assert_eq!(response.status_code, 405);
+
+        assert!(check(&request("POST", "/upload")).is_none());
+        assert!(check(&request("HEAD", "/raw/abc/")).is_none());
+        // Unknown paths stay a 404 of the router.
+        assert!(check(&request("OPTIONS", "/nope/x/y")).is_none());
+        assert!(check(&request("PUT", "/nope/x/y")).is_none());
+    }
+}
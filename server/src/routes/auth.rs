@@ -1,17 +1,47 @@
-use common::{TarHash, TarPassword};
-use std::io::Read;
+use common::{
+    InvalidStream, SizeLimitExceeded, SizeLimitedReader, StreamValidator, TarHash, TarPassword,
+    BLOCK_SIZE, PAYLOAD_SIZE,
+};
+use multipart::server::{Multipart, ReadEntryResult};
+use rand::Rng;
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
 
 use rouille::{
-    websocket::{self, Websocket},
+    websocket::{self, Message, Websocket},
     Response,
 };
 
 use crate::{
-    config::UserConfig, meta::MetaData, responses::ErrorResponse, util::now_unix, AppState,
+    audit::{AuditClient, AuditEvent, AuditRecord},
+    config::{Scope, UserConfig, ANONYMOUS},
+    meta::{Alias, DownloadStats, MetaData},
+    ratelimit,
+    responses::ErrorResponse,
+    routes::find_upload,
+    storage::{self, BlobWriter},
+    timeout::{TimedOut, TimeoutReader, UploadTimer},
+    util::{accepts_json, fallocate, format_rfc3339, now_unix, Origin, Sha256Writer},
+    AppState,
 };
 
+/// Stored bytes after which an ack is sent at the latest.
+const WS_ACK_BYTES: u64 = 1024 * 1024;
+const WS_ACK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Upload over a websocket.
+///
+/// The server greets with the url of a fresh code. The client sends binary
+/// frames, optionally preceded by `{"resume": true, "code": .., "offset": N}`
+/// to continue an unfinished upload of its own. Acks `{"type": "ack", "received": N}`
+/// report how much is stored, which is where a later resume can continue.
+/// Only `{"finish": true}` marks the upload finished, a dropped connection
+/// leaves it resumable. Failures are sent as `{"type": "error", ..}` before closing.
 pub fn ws_upload(state: &AppState, request: &rouille::Request) -> anyhow::Result<Response> {
-    let user = check_token(request, state)?.clone();
+    let user = upload_user(request, state)?;
 
     let (resp, websocket) = match websocket::start(request, None as Option<&'static str>) {
         Ok(a) => a,
@@ -20,90 +50,499 @@ pub fn ws_upload(state: &AppState, request: &rouille::Request) -> anyhow::Result
         }
     };
 
-    let id = TarPassword::generate();
-    let id_str = id.to_string();
-    let hash = TarHash::from_tarid(&id, &state.config.general.hostname);
-
     let state = state.clone();
+    let origin = Origin::of_request(&state.config().general, request);
+    let client = AuditClient::of_request(&state.config().general, request);
     std::thread::spawn(move || {
-        let mut ws = websocket.recv().unwrap();
+        let mut ws = match websocket.recv() {
+            Ok(ws) => ws,
+            Err(_) => return,
+        };
+        run_ws_upload(
+            &state,
+            &user,
+            &origin,
+            &client,
+            &mut ws,
+            TarPassword::generate(),
+        );
+    });
 
-        let _ = ws.send_text(&format!(
-            "{}://{}/{}/",
-            &state.config.general.protocol, &state.config.general.hostname, id_str
-        ));
+    Ok(resp)
+}
 
-        struct WSReader<'a> {
-            buffer: Vec<u8>,
-            inner: &'a mut Websocket,
-        }
-
-        impl<'a> Read for WSReader<'a> {
-            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-                if self.buffer.is_empty() {
-                    match self.inner.next() {
-                        Some(rouille::websocket::Message::Binary(b)) => {
-                            self.buffer = b;
-                        }
-                        Some(_) => {
-                            return Err(std::io::Error::new(
-                                std::io::ErrorKind::Other,
-                                "Unexpected message",
-                            ));
-                        }
-                        None => return Ok(0),
-                    }
-                }
-                let n = std::cmp::min(self.buffer.len(), buf.len());
-                buf[..n].copy_from_slice(&self.buffer[..n]);
-                self.buffer.drain(..n);
+/// What the upload needs from a websocket, so the protocol can be driven in tests.
+trait FrameSocket {
+    fn next_message(&mut self) -> Option<Message>;
+    fn send_text(&mut self, text: &str) -> std::io::Result<()>;
+}
+
+impl FrameSocket for Websocket {
+    fn next_message(&mut self) -> Option<Message> {
+        self.next()
+    }
+
+    fn send_text(&mut self, text: &str) -> std::io::Result<()> {
+        Websocket::send_text(self, text).map_err(|e| match e {
+            rouille::websocket::SendError::IoError(e) => e,
+            rouille::websocket::SendError::Closed => std::io::ErrorKind::BrokenPipe.into(),
+        })
+    }
+}
+
+fn run_ws_upload<S: FrameSocket>(
+    state: &AppState,
+    user: &UserConfig,
+    origin: &Origin,
+    client: &AuditClient,
+    ws: &mut S,
+    new_id: TarPassword,
+) {
+    if ws.send_text(&upload_url(origin, &new_id)).is_err() {
+        return;
+    }
+
+    // Browsers can't set headers on websockets, these get the default.
+    let expire_s = user.expire_s(&state.config().general, None);
+    let mut timer = UploadTimer::from_config(&state.config().general);
+    let first = match timer.wait(|| ws.next_message()) {
+        Ok(Some(first)) => first,
+        Ok(None) => return,
+        Err(e) => {
+            let error = serde_json::json!({ "type": "error", "error": e.to_string() });
+            let _ = ws.send_text(&error.to_string());
+            return;
+        }
+    };
+
+    let resume = match &first {
+        Message::Text(text) => serde_json::from_str::<serde_json::Value>(text)
+            .ok()
+            .filter(|v| v["resume"] == true),
+        Message::Binary(_) => None,
+    };
 
-                Ok(n)
+    let result = match resume {
+        Some(resume) => resume_ws_upload(state, user, &resume).and_then(|(id, file, offset)| {
+            let url = upload_url(origin, &id);
+            let resumed = serde_json::json!({ "type": "resumed", "url": url, "offset": offset });
+            if ws.send_text(&resumed.to_string()).is_err() {
+                return Ok(());
             }
+            receive_ws_upload(state, user, client, ws, &mut timer, &id, file, offset, None)
+        }),
+        None => {
+            let hash = TarHash::from_tarid(&new_id, &state.config().general.hostname);
+            state
+                .meta
+                .set(&hash, &upload_meta(user, expire_s))
+                .and_then(|_| Ok(state.storage.create_writer(&hash)?))
+                .and_then(|file| {
+                    audit_upload(
+                        state,
+                        client,
+                        AuditEvent::UploadStart,
+                        &hash,
+                        &user.username,
+                    );
+                    let first = Some(first);
+                    receive_ws_upload(state, user, client, ws, &mut timer, &new_id, file, 0, first)
+                })
         }
+    };
 
-        let _ = with_update_metadata(&hash, &state, &user, || {
-            let mut file = std::fs::File::create(state.meta.file_path(&hash))?;
-            let mut encryptor = common::EncryptedWriter::new(&mut file, id_str.as_bytes());
+    if let Err(e) = result {
+        let error = serde_json::json!({ "type": "error", "error": e.to_string() });
+        let _ = ws.send_text(&error.to_string());
+    }
+}
 
-            std::io::copy(
-                &mut WSReader {
-                    buffer: vec![],
-                    inner: &mut ws,
-                },
-                &mut encryptor,
-            )?;
-            Ok(())
-        });
+fn upload_url(origin: &Origin, id: &TarPassword) -> String {
+    origin.url(&format!("/{id}/"))
+}
 
-        let _ = ws.send_text("\nDone\n");
-    });
+/// Checks a resume request and cuts the stored data back to the requested offset.
+fn resume_ws_upload(
+    state: &AppState,
+    user: &UserConfig,
+    resume: &serde_json::Value,
+) -> anyhow::Result<(TarPassword, Box<dyn BlobWriter>, u64)> {
+    refuse_anonymous_resume(user)?;
+    let id = resume["code"]
+        .as_str()
+        .and_then(TarPassword::parse)
+        .ok_or_else(|| ErrorResponse::bad_request("Invalid code"))?;
+    let offset = resume["offset"]
+        .as_u64()
+        .ok_or_else(|| ErrorResponse::bad_request("Invalid offset"))?;
 
-    Ok(resp)
+    let hash = TarHash::from_tarid(&id, &state.config().general.hostname);
+    let meta = state
+        .meta
+        .get(&hash)?
+        .ok_or_else(ErrorResponse::not_found)?;
+    if meta.owner != user.username {
+        return Err(ErrorResponse::unauthorized().into());
+    }
+    if meta.finished {
+        return Err(ErrorResponse::bad_request("Upload already finished").into());
+    }
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(storage::local_path(&*state.storage, &hash)?)?;
+    let stored = file.metadata()?.len() / BLOCK_SIZE as u64 * PAYLOAD_SIZE as u64;
+    if offset % PAYLOAD_SIZE as u64 != 0 || offset > stored {
+        return Err(ErrorResponse::bad_request(format!(
+            "Cannot resume at {offset}, {stored} bytes are stored"
+        ))
+        .into());
+    }
+
+    // Continues with a new stream, the reader handles concatenated streams.
+    file.set_len(common::encrypted_size(offset))?;
+    file.seek(SeekFrom::End(0))?;
+    Ok((id, Box::new(file), offset))
+}
+
+/// Stores binary frames until the client finishes or goes away.
+/// Only whole blocks are written before the finish, so a dropped connection
+/// never leaves a short last block in the middle of the data.
+/// A client that is too slow is treated like one that went away.
+#[allow(clippy::too_many_arguments)]
+fn receive_ws_upload<S: FrameSocket>(
+    state: &AppState,
+    user: &UserConfig,
+    client: &AuditClient,
+    ws: &mut S,
+    timer: &mut UploadTimer,
+    id: &TarPassword,
+    mut file: Box<dyn BlobWriter>,
+    offset: u64,
+    first: Option<Message>,
+) -> anyhow::Result<()> {
+    let hash = TarHash::from_tarid(id, &state.config().general.hostname);
+    let _upload = state.uploads.start(&hash);
+    let limit = anonymous_limit(state, user);
+    let mut encryptor = common::EncryptedWriter::new(&mut file, id.to_string().as_bytes());
+
+    let mut pending = vec![];
+    let mut received = offset;
+    let mut last_ack = (received, Instant::now());
+
+    let mut message = match first {
+        Some(first) => Some(first),
+        None => timer.wait(|| ws.next_message())?,
+    };
+    while let Some(m) = message {
+        match m {
+            Message::Binary(data) => {
+                pending.extend(data);
+                if let Some(limit) = limit.filter(|&limit| received + pending.len() as u64 > limit)
+                {
+                    // Can't be resumed, there is no point in keeping it.
+                    drop(encryptor);
+                    let _ = state.storage.delete(&hash);
+                    let _ = state.meta.delete(&hash);
+                    return Err(too_large(limit).into());
+                }
+                let whole = pending.len() / PAYLOAD_SIZE * PAYLOAD_SIZE;
+                encryptor.write_all(&pending[..whole])?;
+                pending.drain(..whole);
+                received += whole as u64;
+            }
+            Message::Text(text) => {
+                let finish = text == "finish"
+                    || serde_json::from_str::<serde_json::Value>(&text)
+                        .map(|v| v["finish"] == true)
+                        .unwrap_or(false);
+                if !finish {
+                    return Err(ErrorResponse::bad_request("Unexpected message").into());
+                }
+
+                if !pending.is_empty() {
+                    received += pending.len() as u64;
+                    encryptor.write_all(&pending)?;
+                }
+                drop(encryptor);
+                file.finish()?;
+
+                let mut meta = state.meta.get(&hash)?.unwrap_or_else(|| {
+                    upload_meta(user, user.expire_s(&state.config().general, None))
+                });
+                meta.finished = true;
+                // Resumed uploads were written in parts.
+                meta.sha256 = Some(stored_sha256(state, &hash)?);
+                state.meta.set(&hash, &meta)?;
+                audit_upload(
+                    state,
+                    client,
+                    AuditEvent::UploadFinish,
+                    &hash,
+                    &user.username,
+                );
+
+                let done = serde_json::json!({ "type": "finished", "received": received });
+                let _ = ws.send_text(&done.to_string());
+                return Ok(());
+            }
+        }
+
+        if received - last_ack.0 >= WS_ACK_BYTES || last_ack.1.elapsed() >= WS_ACK_INTERVAL {
+            let ack = serde_json::json!({ "type": "ack", "received": received });
+            if ws.send_text(&ack.to_string()).is_err() {
+                break;
+            }
+            last_ack = (received, Instant::now());
+        }
+        message = timer.wait(|| ws.next_message())?;
+    }
+
+    // Gone without finishing, the stored blocks stay for a resume.
+    Ok(())
 }
 
 pub fn post_upload(state: &AppState, request: &rouille::Request) -> anyhow::Result<Response> {
-    let user = check_token(request, state)?;
+    let user = &upload_user(request, state)?;
+    let limit = anonymous_limit(state, user);
+    let meta = upload_meta(user, expire_s(state, request, user)?);
+
+    let id = TarPassword::generate();
+    let id_str = id.to_string();
+
+    let hash = TarHash::from_tarid(&id, &state.config().general.hostname);
+    let _upload = state.uploads.start(&hash);
+
+    let is_multipart = request
+        .header("Content-Type")
+        .map(|v| v.trim_start().starts_with("multipart/form-data"))
+        .unwrap_or(false);
+
+    if is_multipart {
+        // Plain html forms, the archive is sent as the field `file`.
+        let boundary = multipart_boundary(request)
+            .ok_or_else(|| ErrorResponse::bad_request("Invalid multipart/form-data"))?;
+        let mut entry = Multipart::with_body(request_body(state, request)?, boundary).into_entry();
+        // Owns the rest of the body, for `store_encrypted`.
+        let field = loop {
+            match entry {
+                ReadEntryResult::Entry(field) if &*field.headers.name == "file" => break field,
+                ReadEntryResult::Entry(field) => entry = field.next_entry(),
+                _ => return Err(ErrorResponse::bad_request("Missing field 'file'").into()),
+            }
+        };
+        store_encrypted(
+            state, request, meta, &hash, &id_str, field.data, None, limit,
+        )?;
+    } else {
+        let expected_len = content_length(request);
+        let body = request_body(state, request)?;
+        store_encrypted(
+            state,
+            request,
+            meta,
+            &hash,
+            &id_str,
+            body,
+            expected_len,
+            limit,
+        )?;
+    }
+
+    let response = if accepts_json(request) {
+        upload_json(state, request, &hash, Some(&id))?
+    } else {
+        let url = upload_url(&Origin::of_request(&state.config().general, request), &id);
+        rouille::Response::text(format!(
+            "===\n\n{url}\n\n===\n\ncurl '{url}' | tar -xkvf -\n\n===\n"
+        ))
+    };
+    Ok(with_upload_id(response, &hash))
+}
+
+/// The hash names the files in the data directory, for matching them to uploads.
+fn with_upload_id(response: Response, hash: &TarHash) -> Response {
+    response.with_additional_header("X-Upload-Id", hash.to_string())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn store_encrypted<R: Read + Send + 'static>(
+    state: &AppState,
+    request: &rouille::Request,
+    meta: MetaData,
+    hash: &TarHash,
+    id_str: &str,
+    body: R,
+    expected_len: Option<u64>,
+    limit: Option<u64>,
+) -> anyhow::Result<()> {
+    if let Some(limit) = limit.filter(|&limit| expected_len.is_some_and(|len| len > limit)) {
+        return Err(too_large(limit).into());
+    }
+    let mut body = SizeLimitedReader::new(body, limit.unwrap_or(u64::MAX));
+    let store = {
+        let (storage, hash, id_str) = (state.storage.clone(), hash.clone(), id_str.to_string());
+        move || {
+            let mut file = Sha256Writer::new(storage.create_writer(&hash)?);
+            let (mut encryptor, plaintext_hash) =
+                common::EncryptedWriter::new_with_hash(&mut file, id_str.as_bytes());
+
+            let written = std::io::copy(&mut body, &mut encryptor).map_err(upload_error)?;
+            check_length(written, expected_len)?;
+            drop(encryptor);
+            let (file, sha256) = file.into_parts();
+            file.finish()?;
+            Ok((sha256, plaintext_hash.hash()))
+        }
+    };
+    with_update_metadata(hash, state, request, meta, Box::new(store))
+}
+
+/// Upload from the browser form, the files are packed into a tar on the server.
+/// Each file is spooled to disk first, because the tar header needs its size.
+pub fn post_upload_form(state: &AppState, request: &rouille::Request) -> anyhow::Result<Response> {
+    // Browsers can't set the header and send the field `token` before the files.
+    let mut user = match request_token(request) {
+        Some(_) => Some(check_token(request, state, Scope::Upload)?),
+        None => None,
+    };
+    let boundary = multipart_boundary(request)
+        .ok_or_else(|| ErrorResponse::bad_request("Expected multipart/form-data"))?;
+    let limit = state.config().general.max_upload_size_bytes;
+    if let Some(limit) =
+        limit.filter(|&limit| content_length(request).is_some_and(|len| len > limit))
+    {
+        return Err(too_large(limit).into());
+    }
+    let body = SizeLimitedReader::new(request_body(state, request)?, limit.unwrap_or(u64::MAX));
+    let mut multipart = Multipart::with_body(body, boundary);
 
     let id = TarPassword::generate();
     let id_str = id.to_string();
+    let hash = TarHash::from_tarid(&id, &state.config().general.hostname);
+    let _upload = state.uploads.start(&hash);
+    let spool = Spool(state.meta.file_path(&hash).with_extension("part"));
+
+    // Fields before the first file, the token has to be among them.
+    let mut first_file = None;
+    while let Some(mut field) = multipart.read_entry().map_err(upload_error)? {
+        match field.headers.filename.clone() {
+            Some(name) if !name.is_empty() => {
+                // Nothing is written to disk for unknown tokens.
+                let found = user.take().ok_or_else(|| auth_failed(state, request))?;
+                user = Some(require_scope(found, Scope::Upload)?);
+                first_file = Some((name, spool.write(&mut field.data)?));
+                break;
+            }
+            Some(_) => continue,
+            None if &*field.headers.name == "token" => {
+                let mut token = String::new();
+                (&mut field.data)
+                    .take(1024)
+                    .read_to_string(&mut token)
+                    .map_err(upload_error)?;
+                if let Some(u) = find_user(state, token.trim()) {
+                    user = Some(u);
+                }
+            }
+            None => continue,
+        }
+    }
+
+    let user = match user {
+        Some(user) => require_scope(user, Scope::Upload)?,
+        None => return Err(auth_failed(state, request)),
+    };
+    let first_file = first_file.ok_or_else(|| ErrorResponse::bad_request("No files"))?;
+
+    let meta = upload_meta(&user, expire_s(state, request, &user)?);
+    let store = {
+        let (storage, hash, id_str) = (state.storage.clone(), hash.clone(), id_str.clone());
+        move || {
+            let mut file = Sha256Writer::new(storage.create_writer(&hash)?);
+            let (mut encryptor, plaintext_hash) =
+                common::EncryptedWriter::new_with_hash(&mut file, id_str.as_bytes());
+            let mut tar = tar::Builder::new(&mut encryptor);
+
+            let (name, (spooled, size)) = first_file;
+            append_spooled(&mut tar, &name, spooled, size)?;
+
+            while let Some(mut field) = multipart.read_entry().map_err(upload_error)? {
+                match field.headers.filename.clone() {
+                    Some(name) if !name.is_empty() => {
+                        let (spooled, size) = spool.write(&mut field.data)?;
+                        append_spooled(&mut tar, &name, spooled, size)?;
+                    }
+                    _ => continue,
+                }
+            }
+            tar.finish()?;
+            drop(tar);
+            drop(encryptor);
+            let (file, sha256) = file.into_parts();
+            file.finish()?;
+            Ok((sha256, plaintext_hash.hash()))
+        }
+    };
+    with_update_metadata(&hash, state, request, meta, Box::new(store))?;
+
+    Ok(Response::redirect_303(format!("/{id_str}/?uploaded=1")))
+}
+
+/// The boundary of a `multipart/form-data` body.
+fn multipart_boundary(request: &rouille::Request) -> Option<String> {
+    let (mime, params) = request.header("Content-Type")?.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params.split(';').find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        (key.trim() == "boundary").then(|| value.trim().trim_matches('"').to_string())
+    })
+}
 
-    let hash = TarHash::from_tarid(&id, &state.config.general.hostname);
+/// File the form upload keeps one file in at a time, removed however the
+/// upload ends.
+struct Spool(std::path::PathBuf);
+
+impl Spool {
+    fn write<R: Read>(&self, data: &mut R) -> anyhow::Result<(std::fs::File, u64)> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.0)?;
+        let size = std::io::copy(data, &mut file).map_err(upload_error)?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok((file, size))
+    }
+}
 
-    let mut body = request.data().ok_or_else(|| anyhow::anyhow!("No body"))?;
-    with_update_metadata(&hash, state, user, || {
-        let mut file = std::fs::File::create(state.meta.file_path(&hash))?;
-        let mut encryptor = common::EncryptedWriter::new(&mut file, id_str.as_bytes());
+impl Drop for Spool {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
 
-        std::io::copy(&mut body, &mut encryptor)?;
-        Ok(())
-    })?;
+fn append_spooled<W: Write>(
+    tar: &mut tar::Builder<W>,
+    name: &str,
+    file: std::fs::File,
+    size: u64,
+) -> anyhow::Result<()> {
+    // Browsers only send the file name, but never trust it to be a plain name.
+    let name = Path::new(name)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .ok_or_else(|| ErrorResponse::bad_request("Invalid file name"))?;
 
-    let proto = &state.config.general.protocol;
-    let hostname = &state.config.general.hostname;
-    Ok(rouille::Response::text(format!(
-        "===\n\n{proto}://{hostname}/{id_str}/\n\n===\n\ncurl '{proto}://{hostname}/{id_str}/' | tar -xkvf -\n\n===\n"
-    )))
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(now_unix());
+    tar.append_data(&mut header, name, file)?;
+    Ok(())
 }
 
 pub fn post_upload_raw(
@@ -111,104 +550,2289 @@ pub fn post_upload_raw(
     request: &rouille::Request,
     id: TarHash,
 ) -> anyhow::Result<Response> {
-    let user = check_token(request, state)?;
+    let user = &upload_user(request, state)?;
+    if request.header("Content-Range").is_some() || header_flag(request, "X-Toc-Resumable") {
+        refuse_anonymous_resume(user)?;
+    }
+    let _upload = state.uploads.start(&id);
+    let meta = MetaData {
+        allow_rewrite: header_flag(request, "X-Toc-Allow-Rewrite"),
+        ..upload_meta(user, expire_s(state, request, user)?)
+    };
 
-    if state.meta.get(&id)?.is_some() {
+    if let Some(range) = request.header("Content-Range") {
+        resume_upload_raw(state, request, user, &id, range)?;
+    } else if state.meta.get(&id)?.is_some() {
         return Ok(Response::text("Already exists").with_status_code(403));
+    } else if header_flag(request, "X-Toc-Resumable") {
+        let meta = MetaData {
+            resumable: true,
+            ..meta
+        };
+        let file = std::fs::File::create(storage::local_path(&*state.storage, &id)?)?;
+        begin_upload(state, request, &id, &meta)?;
+        append_resumable(state, request, &id, meta, file)?;
+    } else {
+        let mut body = request_body(state, request)?;
+        let expected_len = content_length(request);
+        let limit = anonymous_limit(state, user);
+        let store = {
+            let (state, id) = (state.clone(), id.clone());
+            move || {
+                let mut file = Sha256Writer::new(state.storage.create_writer(&id)?);
+                if let Some(path) = state.storage.local_path(&id) {
+                    let file = std::fs::OpenOptions::new().write(true).open(path)?;
+                    preallocate(&state, expected_len, &file, 0)?;
+                }
+                copy_raw(&state, expected_len, &mut body, &mut file, 0, true, limit)?;
+                let (file, sha256) = file.into_parts();
+                file.finish()?;
+                // Encrypted by the client, the server can't hash the content.
+                Ok((sha256, None))
+            }
+        };
+        with_update_metadata(&id, state, request, meta, Box::new(store))?;
+    }
+
+    let response = if accepts_json(request) {
+        upload_json(state, request, &id, None)?
+    } else {
+        rouille::Response::text("ok")
+    };
+    let response = with_upload_id(response, &id);
+
+    // Where an interrupted resumable upload can be continued, see `EncryptedWriter::append`.
+    match state.meta.get(&id)? {
+        Some(meta) if meta.resumable => {
+            let blocks = state.storage.size(&id)? / BLOCK_SIZE as u64;
+            Ok(response.with_additional_header("X-Toc-Block-Count", blocks.to_string()))
+        }
+        _ => Ok(response),
+    }
+}
+
+/// Replaces a finished upload that was created with `X-Toc-Allow-Rewrite: true`.
+///
+/// The new data goes to a temporary file which is then renamed over the old one.
+/// Downloads that already opened the old file keep reading it until they are
+/// done, the old data is only freed once the last handle is closed.
+pub fn put_upload_raw(
+    state: &AppState,
+    request: &rouille::Request,
+    id: TarHash,
+) -> anyhow::Result<Response> {
+    let user = &check_token(request, state, Scope::Upload)?;
+    let _upload = state.uploads.start(&id);
+
+    let mut meta = state.meta.get(&id)?.ok_or_else(ErrorResponse::not_found)?;
+    if meta.owner != user.username {
+        return Err(ErrorResponse::unauthorized().into());
+    }
+    if !meta.allow_rewrite {
+        return Err(ErrorResponse::forbidden("Upload does not allow rewrites").into());
+    }
+    if !meta.finished {
+        return Err(ErrorResponse::conflict("Upload not finished yet").into());
+    }
+
+    let path = storage::local_path(&*state.storage, &id)?;
+    let tmp_path = path.with_extension("rewrite");
+    let mut body = request_body(state, request)?;
+    let client = AuditClient::of_request(&state.config().general, request);
+    audit_upload(state, &client, AuditEvent::UploadStart, &id, &user.username);
+    let result = std::fs::File::create(&tmp_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|file| {
+            let mut file = Sha256Writer::new(file);
+            copy_raw(
+                state,
+                content_length(request),
+                &mut body,
+                &mut file,
+                0,
+                true,
+                None,
+            )?;
+            let (file, sha256) = file.into_parts();
+            file.sync_all()?;
+            std::fs::rename(&tmp_path, &path)?;
+            Ok(sha256)
+        });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
     }
+    meta.sha256 = Some(result?);
 
-    let mut body = request.data().ok_or_else(|| anyhow::anyhow!("No body"))?;
-    with_update_metadata(&id, state, user, || {
-        let mut file = std::fs::File::create(state.meta.file_path(&id))?;
-        std::io::copy(&mut body, &mut file)?;
-        Ok(())
-    })?;
+    meta.created_at_unix = now_unix();
+    // Listed the old archive.
+    meta.tar_index = None;
+    meta.checksums = None;
+    state.meta.set(&id, &meta)?;
+    audit_upload(
+        state,
+        &client,
+        AuditEvent::UploadFinish,
+        &id,
+        &user.username,
+    );
 
+    if accepts_json(request) {
+        return upload_json(state, request, &id, None);
+    }
     Ok(rouille::Response::text("ok"))
 }
 
-fn check_token<'a>(
+/// Continues a resumable upload at the offset given by `Content-Range: bytes N-*/T`.
+fn resume_upload_raw(
+    state: &AppState,
     request: &rouille::Request,
-    state: &'a AppState,
-) -> anyhow::Result<&'a UserConfig> {
-    let token = request
-        .header("Authorization")
-        .map(|token| token.strip_prefix("Bearer ").unwrap_or(token));
-    let token = match token {
-        Some(token) => token,
-        None => return Err(ErrorResponse::unauthorized().into()),
+    user: &UserConfig,
+    id: &TarHash,
+    range: &str,
+) -> anyhow::Result<()> {
+    let (offset, total) = parse_content_range(range)
+        .ok_or_else(|| ErrorResponse::bad_request("Invalid Content-Range"))?;
+
+    let meta = state.meta.get(id)?.ok_or_else(ErrorResponse::not_found)?;
+    if meta.owner != user.username {
+        return Err(ErrorResponse::unauthorized().into());
+    }
+    if !meta.resumable {
+        return Err(ErrorResponse::bad_request("Upload is not resumable").into());
+    }
+    if meta.finished {
+        return Err(ErrorResponse::conflict("Upload already finished").into());
+    }
+
+    let file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(storage::local_path(&*state.storage, id)?)?;
+    let stored = file.metadata()?.len();
+    if offset != stored {
+        return Err(ErrorResponse::conflict(format!(
+            "Upload has {stored} bytes, cannot continue at {offset}"
+        ))
+        .into());
+    }
+    if let (Some(total), Some(len)) = (total, content_length(request)) {
+        if offset + len > total {
+            return Err(ErrorResponse::bad_request("Body exceeds Content-Range total").into());
+        }
+    }
+
+    append_resumable(state, request, id, meta, file)
+}
+
+/// Appends the body, whatever arrived stays stored if the transfer breaks off.
+fn append_resumable(
+    state: &AppState,
+    request: &rouille::Request,
+    id: &TarHash,
+    mut meta: MetaData,
+    mut file: std::fs::File,
+) -> anyhow::Result<()> {
+    let offset = file.metadata()?.len();
+    let finish = header_flag(request, "X-Toc-Finish");
+    preallocate(state, content_length(request), &file, offset)?;
+    let mut body = request_body(state, request)?;
+
+    let expected_len = content_length(request);
+    let result = copy_raw(
+        state,
+        expected_len,
+        &mut body,
+        &mut file,
+        offset,
+        finish,
+        None,
+    );
+    // Broken streams are dropped, but a broken connection can be resumed.
+    // Either way, space reserved for a body that didn't arrive is released.
+    let len = match &result {
+        Err(e) if e.downcast_ref::<ErrorResponse>().map(|e| e.status()) == Some(422) => offset,
+        _ => file.metadata()?.len(),
     };
+    file.set_len(len)?;
+    result?;
 
-    state
-        .config
-        .users
-        .iter()
-        .find(|user| user.token == token)
-        .ok_or_else(|| ErrorResponse::unauthorized().into())
+    if finish {
+        meta.finished = true;
+        meta.sha256 = Some(stored_sha256(state, id)?);
+        state.meta.set(id, &meta)?;
+        let client = AuditClient::of_request(&state.config().general, request);
+        audit_upload(state, &client, AuditEvent::UploadFinish, id, &meta.owner);
+    }
+    Ok(())
 }
 
-fn with_update_metadata<T, F: FnOnce() -> anyhow::Result<T>>(
-    hash: &TarHash,
+/// Stores a client encrypted body which starts at `offset` of the stream.
+/// Unless disabled, the stream structure is checked on the way, `finish`
+/// also requires it to end on a block boundary. `limit` is on top of
+/// `general.max_upload_size_bytes`.
+#[allow(clippy::too_many_arguments)]
+fn copy_raw<R: Read>(
     state: &AppState,
-    user: &UserConfig,
-    f: F,
-) -> anyhow::Result<T> {
-    let mut meta = MetaData {
-        owner: user.username.clone(),
-        finished: false,
-        created_at_unix: now_unix(),
-        delete_at_unix: now_unix() + SEVEN_DAYS,
-        allow_write: false,
-        allow_rewrite: false,
+    expected_len: Option<u64>,
+    body: &mut R,
+    file: &mut dyn Write,
+    offset: u64,
+    finish: bool,
+    limit: Option<u64>,
+) -> anyhow::Result<()> {
+    // The limit is on the whole stream, a resumed upload already has `offset`.
+    let config = state.config();
+    let max = config
+        .general
+        .max_upload_size_bytes
+        .into_iter()
+        .chain(limit)
+        .min();
+    let remaining = match max {
+        Some(max) => max.saturating_sub(offset),
+        None => u64::MAX,
     };
-    state.meta.set(hash, &meta)?;
+    if expected_len.is_some_and(|len| len > remaining) {
+        return Err(too_large(max.unwrap_or_default()).into());
+    }
+    let mut body = SizeLimitedReader::new(body, remaining);
 
-    let result = f();
+    if !config.general.validate_uploads {
+        let written = std::io::copy(&mut body, file).map_err(upload_error)?;
+        return check_length(written, expected_len);
+    }
 
-    meta.finished = true;
-    state.meta.set(hash, &meta)?;
+    let mut validator = StreamValidator::resume(file, offset);
+    let written = std::io::copy(&mut body, &mut validator).map_err(upload_error)?;
+    check_length(written, expected_len)?;
+    if finish {
+        validator.finish().map_err(upload_error)?;
+    }
+    Ok(())
+}
 
-    if result.is_err() {
-        let _ = std::fs::remove_file(state.meta.file_path(hash));
-        let _ = state.meta.delete(hash);
+/// Reserves the declared body length after `offset`, so a full disk refuses
+/// the upload right away instead of near its end.
+fn preallocate(
+    state: &AppState,
+    expected_len: Option<u64>,
+    file: &std::fs::File,
+    offset: u64,
+) -> anyhow::Result<()> {
+    let len = match expected_len {
+        Some(len) if len > 0 && state.config().general.preallocate => len,
+        _ => return Ok(()),
+    };
+    match fallocate(file, offset, len) {
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => Ok(()),
+        Err(e) => Err(ErrorResponse::insufficient_storage(format!(
+            "Cannot reserve {len} bytes: {e}"
+        ))
+        .into()),
+        Ok(()) => Ok(()),
     }
+}
 
-    result
+fn too_large(limit: u64) -> ErrorResponse {
+    ErrorResponse::payload_too_large(format!("Uploads are limited to {limit} bytes"))
 }
 
-pub fn delete_raw(
+/// The request body, cut off when the client is too slow. It is owned, so
+/// `with_update_metadata` can move it into the closure that stores it.
+fn request_body(
     state: &AppState,
     request: &rouille::Request,
-    hash: TarHash,
-) -> anyhow::Result<Response> {
-    let user = check_token(request, state)?.clone();
+) -> anyhow::Result<TimeoutReader<rouille::RequestBody<'static>>> {
+    let body = request.data().ok_or_else(|| anyhow::anyhow!("No body"))?;
+    // SAFETY: The lifetime is only a marker, `RequestBody` owns the
+    // `Box<dyn Read + Send>` that `data` took out of the request.
+    let body = unsafe {
+        std::mem::transmute::<rouille::RequestBody<'_>, rouille::RequestBody<'static>>(body)
+    };
+    Ok(UploadTimer::from_config(&state.config().general).reader(body))
+}
 
-    let m = if let Some(m) = state.meta.get(&hash)? {
-        m
-    } else {
-        return Ok(ErrorResponse::not_found().into());
+/// Turns rejected streams and slow clients into their responses.
+fn upload_error(e: std::io::Error) -> anyhow::Error {
+    if let Some(invalid) = InvalidStream::from_io(&e) {
+        return ErrorResponse::unprocessable(invalid.to_string()).into();
+    }
+    if let Some(timeout) = TimedOut::from_io(&e) {
+        return ErrorResponse::request_timeout(timeout.to_string()).into();
+    }
+    if let Some(exceeded) = SizeLimitExceeded::from_io(&e) {
+        return ErrorResponse::payload_too_large(exceeded.to_string()).into();
+    }
+    e.into()
+}
+
+/// Parses `bytes N-*/T`, also accepting an explicit end and an unknown total.
+fn parse_content_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let start = start.parse().ok()?;
+    if end != "*" {
+        end.parse::<u64>().ok().filter(|end| *end >= start)?;
+    }
+    let total = match total {
+        "*" => None,
+        total => Some(total.parse().ok()?),
     };
+    Some((start, total))
+}
 
-    if m.owner != user.username {
-        return Err(ErrorResponse::unauthorized().into());
+fn header_flag(request: &rouille::Request, name: &str) -> bool {
+    request
+        .header(name)
+        .map(|v| v.trim().eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+/// Where to find an upload, the code is only known if the server encrypted it.
+fn upload_json(
+    state: &AppState,
+    request: &rouille::Request,
+    hash: &TarHash,
+    id: Option<&TarPassword>,
+) -> anyhow::Result<Response> {
+    let meta = state.meta.get(hash)?.ok_or_else(ErrorResponse::not_found)?;
+    let origin = Origin::of_request(&state.config().general, request);
+
+    Ok(Response::json(&serde_json::json!({
+        "code": id.map(|id| id.to_string()),
+        "url": id.map(|id| upload_url(&origin, id)),
+        "raw_url": origin.url(&format!("/raw/{hash}/")),
+        "expires_at": meta.delete_at_unix,
+        "hash": hash.to_string(),
+    })))
+}
+
+fn content_length(request: &rouille::Request) -> Option<u64> {
+    request
+        .header("Content-Length")
+        .and_then(|v| v.trim().parse::<u64>().ok())
+}
+
+/// A body shorter than announced means the client went away mid-upload.
+fn check_length(written: u64, expected: Option<u64>) -> anyhow::Result<()> {
+    match expected {
+        Some(expected) if expected != written => Err(ErrorResponse::bad_request(format!(
+            "Body length {written} does not match Content-Length {expected}"
+        ))
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn request_token(request: &rouille::Request) -> Option<&str> {
+    request
+        .header("Authorization")
+        .map(|token| token.strip_prefix("Bearer ").unwrap_or(token))
+}
+
+/// The user of the token, 403 if it lacks `scope`.
+fn check_token(
+    request: &rouille::Request,
+    state: &AppState,
+    scope: Scope,
+) -> anyhow::Result<UserConfig> {
+    require_scope(authenticate(request, state)?, scope)
+}
+
+/// Like `check_token` for uploads, but without a token they are anonymous
+/// if `general.allow_anonymous` is set. A wrong token is refused either way.
+fn upload_user(request: &rouille::Request, state: &AppState) -> anyhow::Result<UserConfig> {
+    if request_token(request).is_none() {
+        if let Some(user) = state.config().anonymous_user() {
+            ratelimit::take_anonymous_upload(state, request)?;
+            return Ok(user);
+        }
     }
+    check_token(request, state, Scope::Upload)
+}
+
+/// Uploads without a token are limited to `anonymous.max_upload_size_bytes`.
+fn anonymous_limit(state: &AppState, user: &UserConfig) -> Option<u64> {
+    user.is_anonymous()
+        .then(|| state.config().anonymous.max_upload_size_bytes)
+}
 
-    let path = state.meta.file_path(&hash);
-    if path.exists() {
-        std::fs::remove_file(path)?;
+/// Anyone without a token could continue them, as the same owner.
+fn refuse_anonymous_resume(user: &UserConfig) -> anyhow::Result<()> {
+    if user.is_anonymous() {
+        return Err(ErrorResponse::forbidden("Uploads without a token can't be resumed").into());
     }
-    state.meta.delete(&hash)?;
+    Ok(())
+}
 
-    Ok(Response::text("Deleted"))
+fn authenticate(request: &rouille::Request, state: &AppState) -> anyhow::Result<UserConfig> {
+    request_token(request)
+        .and_then(|token| find_user(state, token))
+        .ok_or_else(|| auth_failed(state, request))
 }
 
-pub fn delete(
+/// Records the attempt, whether the token was wrong or missing.
+fn auth_failed(state: &AppState, request: &rouille::Request) -> anyhow::Error {
+    let client = AuditClient::of_request(&state.config().general, request);
+    state
+        .audit
+        .record(AuditRecord::new(AuditEvent::AuthFailed, &client));
+    ErrorResponse::unauthorized().into()
+}
+
+fn require_scope(user: UserConfig, scope: Scope) -> anyhow::Result<UserConfig> {
+    if !user.has_scope(scope) {
+        let error = format!("Token lacks the '{}' scope", scope.name());
+        return Err(ErrorResponse::forbidden(error)
+            .with_code("missing_scope")
+            .into());
+    }
+    Ok(user)
+}
+
+/// The user behind the token and how long their uploads are kept.
+pub fn get_whoami(state: &AppState, request: &rouille::Request) -> anyhow::Result<Response> {
+    let user = authenticate(request, state)?;
+    let config = state.config();
+    let general = &config.general;
+    Ok(Response::json(&serde_json::json!({
+        "username": user.username,
+        "scopes": user.scopes(),
+        "default_expire_s": user.expire_s(general, None),
+        "max_expire_s": user.expire_limit_s(general),
+    })))
+}
+
+/// Uploads of the user of the token, newest first, with their download
+/// counts. The server only knows their hashes, the codes are with the client.
+pub fn get_uploads(state: &AppState, request: &rouille::Request) -> anyhow::Result<Response> {
+    let user = check_token(request, state, Scope::List)?;
+    let mut uploads: Vec<_> = state
+        .meta
+        .list()?
+        .into_iter()
+        .filter(|(_, m)| m.owner == user.username)
+        .collect();
+    uploads.sort_by_key(|(_, m)| std::cmp::Reverse(m.created_at_unix));
+
+    let uploads: Vec<_> = uploads
+        .into_iter()
+        .map(|(hash, m)| {
+            serde_json::json!({
+                "hash": hash.to_string(),
+                "finished": m.finished,
+                "created_at": format_rfc3339(m.created_at_unix),
+                "expires_at": format_rfc3339(m.delete_at_unix),
+                "downloads": m.download_stats,
+            })
+        })
+        .collect();
+    Ok(Response::json(&serde_json::json!({ "uploads": uploads })))
+}
+
+/// Progress of an upload for its owner, to poll a running one or find
+/// where to resume. Only reads the metadata and the stored size.
+pub fn get_upload_status(
     state: &AppState,
     request: &rouille::Request,
-    id: TarPassword,
+    hash: TarHash,
 ) -> anyhow::Result<Response> {
-    let hash = TarHash::from_tarid(&id, &state.config.general.hostname);
-    delete_raw(state, request, hash)
+    let user = check_token(request, state, Scope::Upload)?;
+    let m = state
+        .meta
+        .get(&hash)?
+        .ok_or_else(ErrorResponse::not_found)?;
+    if m.owner != user.username {
+        return Err(ErrorResponse::unauthorized().into());
+    }
+    let size = match state.storage.size(&hash) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        size => size?,
+    };
+    // Like `X-Toc-Block-Count`, only resumable uploads keep partial blocks.
+    let blocks = m.resumable.then_some(size / BLOCK_SIZE as u64);
+
+    Ok(Response::json(&serde_json::json!({
+        "bytes_received": size,
+        "finished": m.finished,
+        "receiving": state.uploads.active().contains(&hash),
+        "block_count": blocks,
+        "created_at": format_rfc3339(m.created_at_unix),
+        "delete_at": format_rfc3339(m.delete_at_unix),
+    })))
+}
+
+/// Letters of generated slugs, without the ones easily mistaken for others.
+const SLUG_CHARS: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// `a-z`, `0-9` and inner dashes, also a safe file name.
+pub(crate) fn valid_slug(slug: &str) -> bool {
+    (3..=32).contains(&slug.len())
+        && slug
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        && !slug.starts_with('-')
+        && !slug.ends_with('-')
 }
 
-const SEVEN_DAYS: u64 = 60 * 60 * 24 * 7;
+fn random_slug() -> String {
+    let mut rng = rand::thread_rng();
+    (0..6)
+        .map(|_| SLUG_CHARS[rng.gen_range(0..SLUG_CHARS.len())] as char)
+        .collect()
+}
+
+/// Short link `/s/{slug}` to the page of an upload, a requested slug or a
+/// random one. The server has to keep the code for the redirect, so whoever
+/// can read its data directory can open the upload. Replaces an earlier alias
+/// of the upload.
+pub fn post_alias(
+    state: &AppState,
+    request: &rouille::Request,
+    hash: TarHash,
+) -> anyhow::Result<Response> {
+    #[derive(serde::Deserialize)]
+    struct Body {
+        code: String,
+        slug: Option<String>,
+    }
+
+    let user = check_token(request, state, Scope::Upload)?;
+    let mut body = String::new();
+    request_body(state, request)?
+        .take(4096)
+        .read_to_string(&mut body)?;
+    let body: Body = serde_json::from_str(&body)
+        .map_err(|_| ErrorResponse::bad_request("Expected {\"code\": ..., \"slug\": ...}"))?;
+    let code: TarPassword = body
+        .code
+        .parse()
+        .map_err(|_| ErrorResponse::bad_request("Invalid code"))?;
+
+    let (found, mut meta) = find_upload(state, &code)?;
+    if found != hash {
+        return Err(ErrorResponse::bad_request("Code does not belong to the upload").into());
+    }
+    if meta.owner != user.username {
+        return Err(ErrorResponse::unauthorized().into());
+    }
+
+    let alias = Alias {
+        hash: hash.to_string(),
+        code: code.to_string(),
+    };
+    let slug = match body.slug {
+        Some(slug) if !valid_slug(&slug) => {
+            return Err(ErrorResponse::bad_request(
+                "Aliases have 3 to 32 characters of a-z, 0-9 and inner dashes",
+            )
+            .with_code("invalid_alias")
+            .into());
+        }
+        Some(slug) if meta.alias.as_ref() == Some(&slug) => slug,
+        Some(slug) => {
+            if !state.meta.create_alias(&slug, &alias)? {
+                return Err(ErrorResponse::conflict("Alias is taken").into());
+            }
+            slug
+        }
+        None => loop {
+            let slug = random_slug();
+            if state.meta.create_alias(&slug, &alias)? {
+                break slug;
+            }
+        },
+    };
+
+    if let Some(previous) = meta.alias.replace(slug.clone()) {
+        if previous != slug {
+            state.meta.delete_alias(&previous)?;
+        }
+    }
+    state.meta.set(&hash, &meta)?;
+
+    let origin = Origin::of_request(&state.config().general, request);
+    Ok(Response::json(&serde_json::json!({
+        "alias": slug,
+        "url": origin.url(&format!("/s/{slug}")),
+    })))
+}
+
+/// Same as SIGHUP, answers with what changed. A rejected config leaves the
+/// running one in place.
+pub fn post_reload(state: &AppState, request: &rouille::Request) -> anyhow::Result<Response> {
+    check_token(request, state, Scope::Admin)?;
+    match crate::reload_config(&state.config) {
+        Ok(changes) => Ok(Response::json(&serde_json::json!({ "changed": changes }))),
+        Err(e) => Err(ErrorResponse::unprocessable(format!("{e:#}"))
+            .with_code("config_rejected")
+            .into()),
+    }
+}
+
+/// Users from the config come first, then the ones from `allowed_tokens_file`.
+pub(crate) fn find_user(state: &AppState, token: &str) -> Option<UserConfig> {
+    let config = state.config();
+    config
+        .users
+        .iter()
+        .find(|user| user.matches_token(token))
+        .cloned()
+        .or_else(|| state.tokens.as_ref()?.find(token))
+        // The uploads without a token would be theirs.
+        .filter(|user| !(config.general.allow_anonymous && user.username == ANONYMOUS))
+}
+
+/// What `with_update_metadata` records of a written blob: its SHA-256, see
+/// `Sha256Writer`, and if the server encrypted it, the hash of the plaintext.
+type Stored = (String, Option<[u8; 32]>);
+
+/// Runs `f`, which stores the blob, between `begin_upload` and
+/// `finish_upload`. `f` owns everything it writes, the body included, so it
+/// can be moved to a blocking task once handlers are async. It should only
+/// write the blob, callers must not do heavy async work inside it.
+fn with_update_metadata(
+    hash: &TarHash,
+    state: &AppState,
+    request: &rouille::Request,
+    meta: MetaData,
+    f: Box<dyn FnOnce() -> anyhow::Result<Stored> + Send + 'static>,
+) -> anyhow::Result<()> {
+    begin_upload(state, request, hash, &meta)?;
+    let result = f();
+    finish_upload(state, request, hash, meta, &result)?;
+    result.map(|_| ())
+}
+
+/// Records the unfinished upload before anything is written, so it is not
+/// taken twice and shows up as in progress.
+fn begin_upload(
+    state: &AppState,
+    request: &rouille::Request,
+    hash: &TarHash,
+    meta: &MetaData,
+) -> anyhow::Result<()> {
+    state.meta.set(hash, meta)?;
+    let client = AuditClient::of_request(&state.config().general, request);
+    audit_upload(state, &client, AuditEvent::UploadStart, hash, &meta.owner);
+    Ok(())
+}
+
+/// Marks the upload finished with the hashes of the blob, or removes it
+/// again when writing failed.
+fn finish_upload(
+    state: &AppState,
+    request: &rouille::Request,
+    hash: &TarHash,
+    mut meta: MetaData,
+    result: &anyhow::Result<Stored>,
+) -> anyhow::Result<()> {
+    meta.finished = true;
+    if let Ok((sha256, plaintext_hash)) = result {
+        meta.sha256 = Some(sha256.clone());
+        meta.plaintext_hash = *plaintext_hash;
+    }
+    state.meta.set(hash, &meta)?;
+
+    if result.is_err() {
+        let _ = state.storage.delete(hash);
+        let _ = state.meta.delete(hash);
+    } else {
+        let client = AuditClient::of_request(&state.config().general, request);
+        audit_upload(state, &client, AuditEvent::UploadFinish, hash, &meta.owner);
+    }
+    Ok(())
+}
+
+/// Finished uploads also record their stored size.
+fn audit_upload(
+    state: &AppState,
+    client: &AuditClient,
+    event: AuditEvent,
+    hash: &TarHash,
+    owner: &str,
+) {
+    let record = AuditRecord::new(event, client)
+        .with_hash(hash)
+        .with_owner(owner);
+    let record = match event {
+        AuditEvent::UploadFinish => record.with_bytes(state.storage.size(hash).ok()),
+        _ => record,
+    };
+    state.audit.record(record);
+}
+
+/// Hash of a blob that was written in several parts.
+fn stored_sha256(state: &AppState, hash: &TarHash) -> anyhow::Result<String> {
+    let mut hasher = Sha256Writer::new(std::io::sink());
+    std::io::copy(&mut state.storage.open_reader(hash)?, &mut hasher)?;
+    Ok(hasher.into_parts().1)
+}
+
+/// `X-Toc-Expire-In` in seconds, capped at the user's maximum.
+fn expire_s(
+    state: &AppState,
+    request: &rouille::Request,
+    user: &UserConfig,
+) -> anyhow::Result<u64> {
+    let requested = request
+        .header("X-Toc-Expire-In")
+        .map(|v| v.trim().parse::<u64>())
+        .transpose()
+        .map_err(|_| ErrorResponse::bad_request("Invalid X-Toc-Expire-In"))?;
+    Ok(user.expire_s(&state.config().general, requested))
+}
+
+pub(crate) fn upload_meta(user: &UserConfig, expire_s: u64) -> MetaData {
+    MetaData {
+        owner: user.username.clone(),
+        finished: false,
+        created_at_unix: now_unix(),
+        delete_at_unix: now_unix() + expire_s,
+        allow_write: false,
+        allow_rewrite: false,
+        resumable: false,
+        hostname: None,
+        tar_index: None,
+        sha256: None,
+        checksums: None,
+        plaintext_hash: None,
+        download_stats: DownloadStats::default(),
+        alias: None,
+    }
+}
+
+pub fn delete_raw(
+    state: &AppState,
+    request: &rouille::Request,
+    hash: TarHash,
+) -> anyhow::Result<Response> {
+    let user = check_token(request, state, Scope::Delete)?;
+
+    let m = if let Some(m) = state.meta.get(&hash)? {
+        m
+    } else {
+        return Err(ErrorResponse::not_found().into());
+    };
+
+    if m.owner != user.username {
+        return Err(ErrorResponse::unauthorized().into());
+    }
+
+    remove_upload(state, request, &hash, &m.owner)?;
+
+    if accepts_json(request) {
+        return Ok(Response::json(
+            &serde_json::json!({ "deleted": true, "hash": hash.to_string() }),
+        ));
+    }
+    Ok(Response::text("Deleted"))
+}
+
+/// Deletes the blob and metadata of an upload, audited as done by `request`.
+fn remove_upload(
+    state: &AppState,
+    request: &rouille::Request,
+    hash: &TarHash,
+    owner: &str,
+) -> anyhow::Result<()> {
+    let size = state.storage.size(hash).ok();
+    state.storage.delete(hash)?;
+    state.meta.delete(hash)?;
+    let record = AuditRecord::new(
+        AuditEvent::Delete,
+        &AuditClient::of_request(&state.config().general, request),
+    )
+    .with_hash(hash)
+    .with_owner(owner)
+    .with_bytes(size);
+    state.audit.record(record);
+    Ok(())
+}
+
+/// Deletes every upload of the user in the `owner` parameter, for removing
+/// accounts. `dry_run=true` only lists them.
+pub fn delete_admin_uploads(
+    state: &AppState,
+    request: &rouille::Request,
+) -> anyhow::Result<Response> {
+    check_token(request, state, Scope::Admin)?;
+    let owner = request
+        .get_param("owner")
+        .filter(|owner| !owner.is_empty())
+        .ok_or_else(|| ErrorResponse::bad_request("Missing owner"))?;
+    let dry_run = request.get_param("dry_run").as_deref() == Some("true");
+
+    let mut hashes: Vec<TarHash> = state
+        .meta
+        .list()?
+        .into_iter()
+        .filter(|(_, m)| m.owner == owner)
+        .map(|(hash, _)| hash)
+        .collect();
+    hashes.sort_by_key(|hash| hash.to_string());
+
+    if dry_run {
+        let hashes: Vec<String> = hashes.iter().map(|hash| hash.to_string()).collect();
+        return Ok(Response::json(&serde_json::json!({
+            "dry_run": true,
+            "uploads": hashes,
+        })));
+    }
+
+    let (mut deleted, mut errors) = (0, 0);
+    for hash in hashes {
+        match remove_upload(state, request, &hash, &owner) {
+            Ok(()) => deleted += 1,
+            Err(e) => {
+                println!("Error deleting {}: {:?}", hash, e);
+                errors += 1;
+            }
+        }
+    }
+    Ok(Response::json(
+        &serde_json::json!({ "deleted": deleted, "errors": errors }),
+    ))
+}
+
+pub fn delete(
+    state: &AppState,
+    request: &rouille::Request,
+    id: TarPassword,
+) -> anyhow::Result<Response> {
+    let (hash, _) = find_upload(state, &id)?;
+    delete_raw(state, request, hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn multipart_body(boundary: &str, fields: &[(&str, Option<&str>, &str)]) -> Vec<u8> {
+        let mut body = String::new();
+        for (name, filename, content) in fields {
+            body += &format!("--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"");
+            if let Some(filename) = filename {
+                body += &format!("; filename=\"{filename}\"\r\nContent-Type: text/plain");
+            }
+            body += &format!("\r\n\r\n{content}\r\n");
+        }
+        body += &format!("--{boundary}--\r\n");
+        body.into_bytes()
+    }
+
+    fn form_request(fields: &[(&str, Option<&str>, &str)]) -> rouille::Request {
+        rouille::Request::fake_http(
+            "POST",
+            "/upload/form",
+            vec![(
+                "Content-Type".to_string(),
+                "multipart/form-data; boundary=XyZ".to_string(),
+            )],
+            multipart_body("XyZ", fields),
+        )
+    }
+
+    #[test]
+    fn test_form_upload_roundtrip() {
+        let state = crate::test_state();
+        let request = form_request(&[
+            ("token", None, "secret"),
+            ("files", Some("a.txt"), "hello"),
+            ("files", Some("dir/b.txt"), "world!"),
+        ]);
+
+        let response = post_upload_form(&state, &request).unwrap();
+        assert_eq!(response.status_code, 303);
+        let location = response
+            .headers
+            .iter()
+            .find(|(k, _)| k == "Location")
+            .unwrap()
+            .1
+            .to_string();
+        let code = TarPassword::parse(location.split('/').nth(1).unwrap()).unwrap();
+
+        let archive = download(&state, code);
+        let mut files = vec![];
+        for entry in tar::Archive::new(&archive[..]).entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            files.push((entry.path().unwrap().display().to_string(), content));
+        }
+        assert_eq!(
+            files,
+            [
+                ("a.txt".to_string(), "hello".to_string()),
+                ("b.txt".to_string(), "world!".to_string())
+            ]
+        );
+    }
+
+    fn download(state: &AppState, code: TarPassword) -> Vec<u8> {
+        let request = rouille::Request::fake_http("GET", "/pipe", vec![], vec![]);
+        let response = crate::routes::get_download(state, &request, code).unwrap();
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut out = vec![];
+        reader.read_to_end(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_upload_multipart_file_field() {
+        let state = crate::test_state();
+        let request = rouille::Request::fake_http(
+            "POST",
+            "/upload",
+            vec![
+                ("Authorization".to_string(), "Bearer secret".to_string()),
+                (
+                    "Content-Type".to_string(),
+                    "multipart/form-data; boundary=XyZ".to_string(),
+                ),
+            ],
+            multipart_body(
+                "XyZ",
+                &[
+                    ("other", None, "ignored"),
+                    ("file", Some("a.tar"), "archive"),
+                ],
+            ),
+        );
+
+        let response = post_upload(&state, &request).unwrap();
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut text = String::new();
+        reader.read_to_string(&mut text).unwrap();
+        let code = text.split('/').find_map(TarPassword::parse).unwrap();
+
+        assert!(download(&state, code).starts_with(b"archive"));
+    }
+
+    #[test]
+    fn test_upload_multipart_without_file_field() {
+        let state = crate::test_state();
+        let request = rouille::Request::fake_http(
+            "POST",
+            "/upload",
+            vec![
+                ("Authorization".to_string(), "Bearer secret".to_string()),
+                (
+                    "Content-Type".to_string(),
+                    "multipart/form-data; boundary=XyZ".to_string(),
+                ),
+            ],
+            multipart_body("XyZ", &[("other", None, "ignored")]),
+        );
+
+        assert!(post_upload(&state, &request).is_err());
+        assert!(state.meta.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_form_upload_requires_token() {
+        let state = crate::test_state();
+
+        let request = form_request(&[("files", Some("a.txt"), "hello")]);
+        let err = post_upload_form(&state, &request).unwrap_err();
+        assert!(err.downcast_ref::<ErrorResponse>().is_some());
+
+        let request = form_request(&[("token", None, "wrong"), ("files", Some("a.txt"), "hello")]);
+        assert!(post_upload_form(&state, &request).is_err());
+        assert!(state.meta.list().unwrap().is_empty());
+        assert!(spooled_files(&state).is_empty());
+
+        // A wrong header is refused before the body is read.
+        let headers = vec![
+            ("Authorization".to_string(), "Bearer wrong".to_string()),
+            (
+                "Content-Type".to_string(),
+                "multipart/form-data; boundary=XyZ".to_string(),
+            ),
+        ];
+        let body = multipart_body(
+            "XyZ",
+            &[("token", None, "secret"), ("files", Some("a.txt"), "hi")],
+        );
+        let request = rouille::Request::fake_http("POST", "/upload/form", headers, body);
+        let err = post_upload_form(&state, &request).unwrap_err();
+        assert_eq!(err.downcast_ref::<ErrorResponse>().unwrap().status(), 401);
+        assert!(state.meta.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_form_upload_limit() {
+        let state = crate::test_state();
+        state
+            .config
+            .update(|config| config.general.max_upload_size_bytes = Some(300));
+
+        let request = form_request(&[
+            ("token", None, "secret"),
+            ("files", Some("a.txt"), &"x".repeat(1000)),
+        ]);
+        let err = post_upload_form(&state, &request).unwrap_err();
+        assert_eq!(err.downcast_ref::<ErrorResponse>().unwrap().status(), 413);
+        assert!(state.meta.list().unwrap().is_empty());
+        assert!(spooled_files(&state).is_empty());
+
+        let request = form_request(&[("token", None, "secret"), ("files", Some("a.txt"), "x")]);
+        assert_eq!(post_upload_form(&state, &request).unwrap().status_code, 303);
+        assert!(spooled_files(&state).is_empty());
+    }
+
+    /// `.part` files of form uploads left in the data directory.
+    fn spooled_files(state: &AppState) -> Vec<std::path::PathBuf> {
+        let any = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+        // `file_path` is `root/ab/cd/hash.data`.
+        let root = state
+            .meta
+            .file_path(&any)
+            .ancestors()
+            .nth(3)
+            .unwrap()
+            .to_path_buf();
+        let mut dirs = vec![root];
+        let mut found = vec![];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if path.extension().is_some_and(|ext| ext == "part") {
+                    found.push(path);
+                }
+            }
+        }
+        found
+    }
+
+    /// Plays back scripted frames, `None` is the client going away.
+    struct FakeSocket {
+        incoming: std::collections::VecDeque<Message>,
+        sent: Vec<String>,
+        /// How long the client stalls once all messages are sent.
+        stall: Duration,
+    }
+
+    impl FakeSocket {
+        fn new(incoming: Vec<Message>) -> Self {
+            Self {
+                incoming: incoming.into(),
+                sent: vec![],
+                stall: Duration::ZERO,
+            }
+        }
+
+        fn sent_json(&self) -> Vec<serde_json::Value> {
+            // The first frame is the plain url greeting.
+            self.sent[1..]
+                .iter()
+                .map(|s| serde_json::from_str(s).unwrap())
+                .collect()
+        }
+    }
+
+    impl FrameSocket for FakeSocket {
+        fn next_message(&mut self) -> Option<Message> {
+            if self.incoming.is_empty() {
+                std::thread::sleep(self.stall);
+            }
+            self.incoming.pop_front()
+        }
+
+        fn send_text(&mut self, text: &str) -> std::io::Result<()> {
+            self.sent.push(text.to_string());
+            Ok(())
+        }
+    }
+
+    fn origin(state: &AppState) -> Origin {
+        Origin::from_config(&state.config().general)
+    }
+
+    fn test_user(state: &AppState) -> UserConfig {
+        state.config().users[0].clone()
+    }
+
+    #[test]
+    fn test_ws_upload_resume() {
+        let state = crate::test_state();
+        let user = test_user(&state);
+        let data: Vec<u8> = (0..1_500_000).map(|i| (i % 251) as u8).collect();
+        let chunks: Vec<_> = data.chunks(64 * 1024).collect();
+
+        let id = TarPassword::generate();
+        let hash = TarHash::from_tarid(&id, &state.config().general.hostname);
+        let mut ws = FakeSocket::new(
+            chunks[..20]
+                .iter()
+                .map(|c| Message::Binary(c.to_vec()))
+                .collect(),
+        );
+        run_ws_upload(
+            &state,
+            &user,
+            &origin(&state),
+            &AuditClient::default(),
+            &mut ws,
+            id.clone(),
+        );
+
+        assert_eq!(ws.sent[0], upload_url(&origin(&state), &id));
+        let acks = ws.sent_json();
+        assert!(acks.iter().all(|a| a["type"] == "ack"));
+        let acked = acks.last().unwrap()["received"].as_u64().unwrap();
+        assert!(acked >= 1024 * 1024 && acked.is_multiple_of(PAYLOAD_SIZE as u64));
+        assert!(!state.meta.get(&hash).unwrap().unwrap().finished);
+
+        // Only whole blocks are resumable.
+        let resume = |offset: u64| {
+            Message::Text(
+                serde_json::json!({ "resume": true, "code": id.to_string(), "offset": offset })
+                    .to_string(),
+            )
+        };
+        let mut ws = FakeSocket::new(vec![resume(1000)]);
+        run_ws_upload(
+            &state,
+            &user,
+            &origin(&state),
+            &AuditClient::default(),
+            &mut ws,
+            TarPassword::generate(),
+        );
+        assert_eq!(ws.sent_json()[0]["type"], "error");
+
+        let mut incoming = vec![resume(acked)];
+        incoming.extend(
+            data[acked as usize..]
+                .chunks(64 * 1024)
+                .map(|c| Message::Binary(c.to_vec())),
+        );
+        incoming.push(Message::Text(r#"{"finish": true}"#.to_string()));
+        let mut ws = FakeSocket::new(incoming);
+        run_ws_upload(
+            &state,
+            &user,
+            &origin(&state),
+            &AuditClient::default(),
+            &mut ws,
+            TarPassword::generate(),
+        );
+
+        let frames = ws.sent_json();
+        assert_eq!(frames[0]["type"], "resumed");
+        assert_eq!(frames[0]["offset"], acked);
+        assert_eq!(frames[0]["url"], upload_url(&origin(&state), &id));
+        let finished = frames.last().unwrap();
+        assert_eq!(finished["type"], "finished");
+        assert_eq!(finished["received"], data.len() as u64);
+        assert!(state.meta.get(&hash).unwrap().unwrap().finished);
+
+        let downloaded = download(&state, id.clone());
+        assert_eq!(&downloaded[..data.len()], &data[..]);
+
+        // Finished uploads can't be resumed.
+        let mut ws = FakeSocket::new(vec![resume(acked)]);
+        run_ws_upload(
+            &state,
+            &user,
+            &origin(&state),
+            &AuditClient::default(),
+            &mut ws,
+            TarPassword::generate(),
+        );
+        assert_eq!(ws.sent_json()[0]["type"], "error");
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_ws_upload_disk_error() {
+        let state = crate::test_state();
+        let user = test_user(&state);
+
+        let id = TarPassword::generate();
+        let hash = TarHash::from_tarid(&id, &state.config().general.hostname);
+        std::os::unix::fs::symlink("/dev/full", state.meta.file_path(&hash)).unwrap();
+
+        let mut ws = FakeSocket::new(vec![
+            Message::Binary(vec![1; 4096]),
+            Message::Text("finish".to_string()),
+        ]);
+        run_ws_upload(
+            &state,
+            &user,
+            &origin(&state),
+            &AuditClient::default(),
+            &mut ws,
+            id,
+        );
+
+        let frames = ws.sent_json();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0]["type"], "error");
+        assert!(!state.meta.get(&hash).unwrap().unwrap().finished);
+    }
+
+    #[test]
+    fn test_ws_upload_timeout() {
+        let state = crate::test_state();
+        state
+            .config
+            .update(|config| config.general.upload_idle_timeout_s = 1);
+        let user = test_user(&state);
+
+        let id = TarPassword::generate();
+        let hash = TarHash::from_tarid(&id, &state.config().general.hostname);
+        let mut ws = FakeSocket::new(vec![Message::Binary(vec![1; 4096])]);
+        ws.stall = Duration::from_millis(1100);
+        run_ws_upload(
+            &state,
+            &user,
+            &origin(&state),
+            &AuditClient::default(),
+            &mut ws,
+            id,
+        );
+
+        let frames = ws.sent_json();
+        let last = frames.last().unwrap();
+        assert_eq!(last["type"], "error");
+        assert!(last["error"].as_str().unwrap().contains("No data"));
+
+        // Like a dropped connection, what arrived can be resumed.
+        assert!(!state.meta.get(&hash).unwrap().unwrap().finished);
+        assert_eq!(stored_length(&state, &hash), common::encrypted_size(4096));
+    }
+
+    #[test]
+    fn test_forwarded_upload_urls() {
+        let state = crate::test_state();
+        state
+            .config
+            .update(|config| config.general.trusted_proxies = vec!["127.0.0.1".parse().unwrap()]);
+
+        let headers = [
+            ("Authorization", "Bearer secret"),
+            ("Accept", "application/json"),
+            ("X-Forwarded-Proto", "https"),
+            ("X-Forwarded-Host", "files.example"),
+        ];
+        let headers = headers
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let request = rouille::Request::fake_http("POST", "/upload", headers, b"data".to_vec());
+        let json: serde_json::Value =
+            serde_json::from_str(&body(post_upload(&state, &request).unwrap())).unwrap();
+
+        // Links use the forwarded origin, the hash stays salted with the configured host.
+        let code = TarPassword::parse(json["code"].as_str().unwrap()).unwrap();
+        let hash = TarHash::from_tarid(&code, "localhost");
+        assert_eq!(json["url"], format!("https://files.example/{code}/"));
+        assert_eq!(
+            json["raw_url"],
+            format!("https://files.example/raw/{hash}/")
+        );
+        assert!(state.meta.get(&hash).unwrap().is_some());
+
+        let text = body(post_upload(&state, &upload_request(None, b"data")).unwrap());
+        assert!(text.contains("curl 'http://localhost/"));
+    }
+
+    fn upload_request(accept: Option<&str>, body: &[u8]) -> rouille::Request {
+        let mut headers = vec![("Authorization".to_string(), "Bearer secret".to_string())];
+        if let Some(accept) = accept {
+            headers.push(("Accept".to_string(), accept.to_string()));
+        }
+        rouille::Request::fake_http("POST", "/upload", headers, body.to_vec())
+    }
+
+    fn body(response: Response) -> String {
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut text = String::new();
+        reader.read_to_string(&mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn test_upload_response_negotiation() {
+        let state = crate::test_state();
+
+        let text = body(post_upload(&state, &upload_request(None, b"data")).unwrap());
+        assert!(text.contains("curl 'http://localhost/"));
+
+        let request = upload_request(Some("application/json"), b"data");
+        let response = post_upload(&state, &request).unwrap();
+        let upload_id = response
+            .headers
+            .iter()
+            .find(|(k, _)| k == "X-Upload-Id")
+            .map(|(_, v)| v.to_string());
+        let json: serde_json::Value = serde_json::from_str(&body(response)).unwrap();
+        let code = TarPassword::parse(json["code"].as_str().unwrap()).unwrap();
+        let hash = TarHash::from_tarid(&code, "localhost");
+        assert_eq!(upload_id, Some(hash.to_string()));
+        assert_eq!(json["url"], format!("http://localhost/{code}/"));
+        assert_eq!(json["raw_url"], format!("http://localhost/raw/{hash}/"));
+        assert_eq!(json["hash"], hash.to_string());
+        let meta = state.meta.get(&hash).unwrap().unwrap();
+        assert_eq!(json["expires_at"], meta.delete_at_unix);
+
+        let raw_hash = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+        let request = upload_request(
+            Some("application/json"),
+            &crate::test_encrypt(b"code", b"raw"),
+        );
+        let response = post_upload_raw(&state, &request, raw_hash.clone()).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&body(response)).unwrap();
+        assert_eq!(json["code"], serde_json::Value::Null);
+        assert_eq!(json["hash"], raw_hash.to_string());
+
+        let request = upload_request(Some("application/json"), b"");
+        let json: serde_json::Value =
+            serde_json::from_str(&body(delete_raw(&state, &request, raw_hash).unwrap())).unwrap();
+        assert_eq!(json["deleted"], true);
+
+        let request = upload_request(None, b"");
+        assert_eq!(body(delete(&state, &request, code).unwrap()), "Deleted");
+    }
+
+    #[test]
+    fn test_error_response_negotiation() {
+        let state = crate::test_state();
+        let request = rouille::Request::fake_http(
+            "POST",
+            "/upload",
+            vec![("Accept".to_string(), "application/json".to_string())],
+            b"data".to_vec(),
+        );
+        let err = post_upload(&state, &request).unwrap_err();
+        let err = err.downcast_ref::<ErrorResponse>().unwrap();
+
+        let response = err.to_response(&request);
+        assert_eq!(response.status_code, 401);
+        let json: serde_json::Value = serde_json::from_str(&body(response)).unwrap();
+        assert_eq!(json["code"], "unauthorized");
+        assert_eq!(json["error"], "Unauthorized");
+
+        let request = rouille::Request::fake_http("POST", "/upload", vec![], vec![]);
+        let response = err.to_response(&request);
+        assert_eq!(response.status_code, 401);
+        assert_eq!(body(response), "Unauthorized");
+
+        let accept = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+        let headers = vec![("Accept".to_string(), accept.to_string())];
+        let request = rouille::Request::fake_http("POST", "/upload", headers, vec![]);
+        let response = err.to_response(&request);
+        assert_eq!(response.status_code, 401);
+        let html = body(response);
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("401 - Unauthorized"));
+        assert!(html.contains("href=\"/\""));
+    }
+
+    fn raw_request(headers: &[(&str, &str)], body: &[u8]) -> rouille::Request {
+        let mut all = vec![("Authorization".to_string(), "Bearer secret".to_string())];
+        all.extend(headers.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+        rouille::Request::fake_http("POST", "/raw/x/", all, body.to_vec())
+    }
+
+    #[test]
+    fn test_raw_upload_validation() {
+        let state = crate::test_state();
+        let data = crate::test_encrypt(b"code", &[7; 5000]);
+        let upload = |body: &[u8]| {
+            let hash = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+            let request = raw_request(&[], body);
+            let result = post_upload_raw(&state, &request, hash.clone());
+            let status = match &result {
+                Ok(_) => 200,
+                Err(e) => e.downcast_ref::<ErrorResponse>().unwrap().status(),
+            };
+            (status, state.meta.get(&hash).unwrap().is_some())
+        };
+
+        assert_eq!(upload(&data), (200, true));
+
+        let mut corrupted = data.clone();
+        corrupted[BLOCK_SIZE * 2 + 1] = 0x77;
+        assert_eq!(upload(&corrupted), (422, false));
+
+        assert_eq!(upload(&data[..data.len() - 10]), (422, false));
+        assert_eq!(upload(&[0; 100]), (422, false));
+
+        state
+            .config
+            .update(|config| config.general.validate_uploads = false);
+        let hash = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+        let request = raw_request(&[], b"garbage");
+        post_upload_raw(&state, &request, hash).unwrap();
+    }
+
+    #[test]
+    fn test_max_upload_size() {
+        let state = crate::test_state();
+        let data = crate::test_encrypt(b"code", &[7; 5000]);
+        state.config.update(|config| {
+            config.general.max_upload_size_bytes = Some(data.len() as u64);
+        });
+        let upload = |headers: &[(&str, &str)], body: &[u8]| {
+            let hash = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+            match post_upload_raw(&state, &raw_request(headers, body), hash.clone()) {
+                Ok(_) => 200,
+                Err(e) => e.downcast_ref::<ErrorResponse>().unwrap().status(),
+            }
+        };
+
+        assert_eq!(upload(&[], &data), 200);
+        // Cut off while reading, or right away with a length.
+        let larger = crate::test_encrypt(b"code", &[7; 6000]);
+        assert_eq!(upload(&[], &larger), 413);
+        let length = larger.len().to_string();
+        assert_eq!(upload(&[("Content-Length", &length)], &larger), 413);
+    }
+
+    #[test]
+    fn test_anonymous_upload() {
+        let mut state = crate::test_state();
+        state.anonymous_uploads = crate::ratelimit::RateLimiter::new(100, 100);
+        let data = crate::test_encrypt(b"code", &[7; 5000]);
+        let anonymous = |headers: &[(&str, &str)], body: &[u8]| {
+            let headers = headers.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+            rouille::Request::fake_http("POST", "/raw/x/", headers.collect(), body.to_vec())
+        };
+        let status = |result: anyhow::Result<Response>| match result {
+            Ok(_) => 200,
+            Err(e) => e.downcast_ref::<ErrorResponse>().unwrap().status(),
+        };
+        let new_hash = || TarHash::from_tarid(&TarPassword::generate(), "localhost");
+
+        // Off by default.
+        let request = anonymous(&[], &data);
+        assert_eq!(status(post_upload_raw(&state, &request, new_hash())), 401);
+        assert_eq!(status(post_upload(&state, &anonymous(&[], b"data"))), 401);
+
+        state.config.update(|config| {
+            config.general.allow_anonymous = true;
+            config.general.max_expire_s = Some(7 * 24 * 3600);
+            config.anonymous.max_upload_size_bytes = data.len() as u64;
+            config.anonymous.max_expire_s = 3600;
+        });
+        let hash = new_hash();
+        let request = anonymous(&[("X-Toc-Expire-In", "86400")], &data);
+        assert_eq!(status(post_upload_raw(&state, &request, hash.clone())), 200);
+        let meta = state.meta.get(&hash).unwrap().unwrap();
+        assert_eq!(meta.owner, "anonymous");
+        assert_eq!(meta.delete_at_unix - meta.created_at_unix, 3600);
+
+        // Tighter than the general limit, which is unset.
+        let larger = crate::test_encrypt(b"code", &[7; 6000]);
+        let request = anonymous(&[], &larger);
+        assert_eq!(status(post_upload_raw(&state, &request, new_hash())), 413);
+        let request = anonymous(&[], &vec![7; data.len() + 1]);
+        assert_eq!(status(post_upload(&state, &request)), 413);
+        assert_eq!(status(post_upload(&state, &anonymous(&[], b"data"))), 200);
+        // Tokens still get the general limits.
+        let request = raw_request(&[], &larger);
+        assert_eq!(status(post_upload_raw(&state, &request, new_hash())), 200);
+
+        let request = anonymous(&[("X-Toc-Resumable", "true")], &data);
+        assert_eq!(status(post_upload_raw(&state, &request, new_hash())), 403);
+        let request = anonymous(&[("Authorization", "Bearer wrong")], &data);
+        assert_eq!(status(post_upload_raw(&state, &request, new_hash())), 401);
+
+        // Only expire, no token owns them.
+        let request = raw_request(&[], b"");
+        assert_eq!(status(delete_raw(&state, &request, hash.clone())), 401);
+        assert!(state.meta.get(&hash).unwrap().is_some());
+
+        let user = state.config().anonymous_user().unwrap();
+        let chunks = vec![
+            Message::Binary(vec![7; 4000]),
+            Message::Binary(vec![7; 4000]),
+        ];
+        let mut ws = FakeSocket::new(chunks);
+        let id = TarPassword::generate();
+        run_ws_upload(
+            &state,
+            &user,
+            &origin(&state),
+            &AuditClient::default(),
+            &mut ws,
+            id.clone(),
+        );
+        let sent = ws.sent_json();
+        assert_eq!(sent.last().unwrap()["type"], "error");
+        let hash = TarHash::from_tarid(&id, "localhost");
+        assert!(state.meta.get(&hash).unwrap().is_none());
+        assert!(!state.storage.exists(&hash).unwrap());
+    }
+
+    #[test]
+    fn test_anonymous_rate_limit() {
+        let mut state = crate::test_state();
+        state.anonymous_uploads = crate::ratelimit::RateLimiter::new(1, 100);
+        state
+            .config
+            .update(|config| config.general.allow_anonymous = true);
+        let request = || rouille::Request::fake_http("POST", "/upload", vec![], b"data".to_vec());
+        assert!(post_upload(&state, &request()).is_ok());
+        let error = post_upload(&state, &request()).err().unwrap();
+        assert_eq!(error.downcast_ref::<ErrorResponse>().unwrap().status(), 429);
+        // Uploads with a token are not counted.
+        assert!(post_upload(&state, &upload_request(None, b"data")).is_ok());
+    }
+
+    fn stored_length(state: &AppState, hash: &TarHash) -> u64 {
+        let request = raw_request(&[], b"");
+        let response = crate::routes::head_upload_raw(state, &request, hash.clone()).unwrap();
+        let (_, value) = response
+            .headers
+            .iter()
+            .find(|(k, _)| k == "X-Toc-Stored-Length")
+            .unwrap();
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_resume_raw_upload() {
+        const MB: usize = 1024 * 1024;
+        let state = crate::test_state();
+        let hash = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+        let plain: Vec<u8> = (0..3 * MB).map(|i| (i % 251) as u8).collect();
+        let data = crate::test_encrypt(b"code", &plain);
+        let total = data.len().to_string();
+
+        // The connection drops after the first megabyte.
+        let request = raw_request(
+            &[("X-Toc-Resumable", "true"), ("Content-Length", &total)],
+            &data[..MB],
+        );
+        assert!(post_upload_raw(&state, &request, hash.clone()).is_err());
+        assert_eq!(stored_length(&state, &hash), MB as u64);
+        assert!(!state.meta.get(&hash).unwrap().unwrap().finished);
+
+        let rest = &data[MB..];
+        let request = raw_request(&[("Content-Range", &format!("bytes 1000-*/{total}"))], rest);
+        let err = post_upload_raw(&state, &request, hash.clone()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ErrorResponse>()
+                .unwrap()
+                .to_response(&request)
+                .status_code,
+            409
+        );
+
+        let request = raw_request(
+            &[
+                ("Content-Range", &format!("bytes {MB}-*/{total}")),
+                ("Content-Length", &rest.len().to_string()),
+                ("X-Toc-Finish", "true"),
+            ],
+            rest,
+        );
+        let response = post_upload_raw(&state, &request, hash.clone()).unwrap();
+        let blocks = (data.len() / BLOCK_SIZE).to_string();
+        assert!(response
+            .headers
+            .iter()
+            .any(|(k, v)| k == "X-Toc-Block-Count" && *v == blocks));
+
+        assert!(state.meta.get(&hash).unwrap().unwrap().finished);
+        assert_eq!(std::fs::read(state.meta.file_path(&hash)).unwrap(), data);
+
+        let request = raw_request(&[("Content-Range", &format!("bytes {total}-*/*"))], b"x");
+        assert!(post_upload_raw(&state, &request, hash).is_err());
+    }
+
+    #[test]
+    fn test_upload_status() {
+        let state = crate::test_state();
+        let hash = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+        let data = crate::test_encrypt(b"code", &[7; 4 * PAYLOAD_SIZE]);
+        let total = data.len().to_string();
+        let status = || {
+            let headers = vec![("Authorization".to_string(), "Bearer secret".to_string())];
+            let url = format!("/api/uploads/{hash}/status");
+            let request = rouille::Request::fake_http("GET", url, headers, vec![]);
+            get_upload_status(&state, &request, hash.clone())
+                .map(|response| serde_json::from_str::<serde_json::Value>(&body(response)).unwrap())
+        };
+        assert_eq!(
+            status()
+                .unwrap_err()
+                .downcast::<ErrorResponse>()
+                .unwrap()
+                .status(),
+            404
+        );
+
+        // Two blocks arrived, the rest is still on its way.
+        let part = 2 * BLOCK_SIZE;
+        let request = raw_request(
+            &[("X-Toc-Resumable", "true"), ("Content-Length", &total)],
+            &data[..part],
+        );
+        assert!(post_upload_raw(&state, &request, hash.clone()).is_err());
+        let receiving = state.uploads.start(&hash);
+        let json = status().unwrap();
+        assert_eq!(json["bytes_received"], part);
+        assert_eq!(json["block_count"], 2);
+        assert_eq!(json["finished"], false);
+        assert_eq!(json["receiving"], true);
+        drop(receiving);
+
+        let rest = &data[part..];
+        let request = raw_request(
+            &[
+                ("Content-Range", &format!("bytes {part}-*/{total}")),
+                ("Content-Length", &rest.len().to_string()),
+                ("X-Toc-Finish", "true"),
+            ],
+            rest,
+        );
+        post_upload_raw(&state, &request, hash.clone()).unwrap();
+        let json = status().unwrap();
+        assert_eq!(json["bytes_received"], data.len());
+        assert_eq!(json["block_count"], data.len() / BLOCK_SIZE);
+        assert_eq!(json["finished"], true);
+        assert_eq!(json["receiving"], false);
+        assert!(json["delete_at"].is_string());
+    }
+
+    #[test]
+    fn test_failed_raw_upload_is_removed() {
+        let state = crate::test_state();
+        let hash = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+
+        let request = raw_request(&[("Content-Length", "100")], b"short");
+        assert!(post_upload_raw(&state, &request, hash.clone()).is_err());
+        assert!(state.meta.get(&hash).unwrap().is_none());
+        assert!(!state.meta.file_path(&hash).exists());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_preallocation() {
+        let state = crate::test_state();
+        let hash = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+        let data = crate::test_encrypt(b"code", &[7; 3000]);
+
+        // Less arrives than declared, the space reserved for the rest is released.
+        let request = raw_request(
+            &[("X-Toc-Resumable", "true"), ("Content-Length", "10000000")],
+            &data[..BLOCK_SIZE],
+        );
+        assert!(post_upload_raw(&state, &request, hash.clone()).is_err());
+        assert_eq!(stored_length(&state, &hash), BLOCK_SIZE as u64);
+
+        let rest = &data[BLOCK_SIZE..];
+        let request = raw_request(
+            &[
+                (
+                    "Content-Range",
+                    &format!("bytes {BLOCK_SIZE}-*/{}", data.len()),
+                ),
+                ("Content-Length", &rest.len().to_string()),
+                ("X-Toc-Finish", "true"),
+            ],
+            rest,
+        );
+        post_upload_raw(&state, &request, hash.clone()).unwrap();
+        assert_eq!(std::fs::read(state.meta.file_path(&hash)).unwrap(), data);
+
+        // More than any disk has.
+        let hash = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+        let request = raw_request(&[("Content-Length", &(1u64 << 62).to_string())], &data);
+        let status = post_upload_raw(&state, &request, hash.clone())
+            .unwrap_err()
+            .downcast_ref::<ErrorResponse>()
+            .map(|e| e.status());
+        assert_eq!(status, Some(507));
+        assert!(state.meta.get(&hash).unwrap().is_none());
+        assert!(!state.meta.file_path(&hash).exists());
+    }
+
+    /// Sends a byte now and then, slower than the idle timeout allows.
+    struct Stall;
+
+    impl Read for Stall {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            std::thread::sleep(Duration::from_millis(20));
+            buf[0] = 1;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn test_stalled_upload_is_removed() {
+        let state = crate::test_state();
+        let user = test_user(&state);
+        let id = TarPassword::generate();
+        let hash = TarHash::from_tarid(&id, "localhost");
+
+        let timer = UploadTimer::new(Duration::from_millis(10), Duration::from_secs(60));
+        let body = timer.reader(Stall);
+        let meta = upload_meta(&user, 60);
+        let request = rouille::Request::fake_http("POST", "/upload", vec![], vec![]);
+        let id = id.to_string();
+        let result = store_encrypted(&state, &request, meta, &hash, &id, body, None, None);
+        let status = result
+            .unwrap_err()
+            .downcast_ref::<ErrorResponse>()
+            .map(|e| e.status());
+        assert_eq!(status, Some(408));
+
+        assert!(state.meta.get(&hash).unwrap().is_none());
+        assert!(!state.meta.file_path(&hash).exists());
+    }
+
+    #[test]
+    fn test_anonymous_limit_without_length() {
+        let state = crate::test_state();
+        state
+            .config
+            .update(|config| config.general.allow_anonymous = true);
+        let user = state.config().anonymous_user().unwrap();
+        let code = TarPassword::generate();
+        let hash = TarHash::from_tarid(&code, "localhost");
+        let id = code.to_string();
+        let request = rouille::Request::fake_http("POST", "/upload", vec![], vec![]);
+
+        // No length up front, the stream is cut off at the limit.
+        let meta = upload_meta(&user, 60);
+        let result = store_encrypted(
+            &state,
+            &request,
+            meta,
+            &hash,
+            &id,
+            &[7; 100][..],
+            None,
+            Some(64),
+        );
+        let status = result
+            .unwrap_err()
+            .downcast_ref::<ErrorResponse>()
+            .map(|e| e.status());
+        assert_eq!(status, Some(413));
+        assert!(state.meta.get(&hash).unwrap().is_none());
+        assert!(!state.meta.file_path(&hash).exists());
+
+        let meta = upload_meta(&user, 60);
+        store_encrypted(
+            &state,
+            &request,
+            meta,
+            &hash,
+            &id,
+            &[7; 64][..],
+            None,
+            Some(64),
+        )
+        .unwrap();
+        assert!(state.meta.get(&hash).unwrap().unwrap().finished);
+    }
+
+    #[test]
+    fn test_plaintext_hash() {
+        let state = crate::test_state();
+        let user = test_user(&state);
+        let code = TarPassword::generate();
+        let hash = TarHash::from_tarid(&code, "localhost");
+        let id = code.to_string();
+
+        let request = rouille::Request::fake_http("POST", "/upload", vec![], vec![]);
+        let meta = upload_meta(&user, 60);
+        store_encrypted(&state, &request, meta, &hash, &id, &b"data"[..], None, None).unwrap();
+
+        // Of the plaintext, the code does not matter.
+        let (mut writer, expected) = common::EncryptedWriter::new_with_hash(vec![], b"other");
+        writer.write_all(b"data").unwrap();
+        drop(writer);
+        let meta = state.meta.get(&hash).unwrap().unwrap();
+        assert!(meta.plaintext_hash.is_some());
+        assert_eq!(meta.plaintext_hash, expected.hash());
+    }
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(parse_content_range("bytes 10-*/100"), Some((10, Some(100))));
+        assert_eq!(parse_content_range("bytes 10-19/*"), Some((10, None)));
+        assert_eq!(parse_content_range("bytes 10-5/100"), None);
+        assert_eq!(parse_content_range("10-*/100"), None);
+    }
+
+    fn put_request(token: &str, body: &[u8]) -> rouille::Request {
+        let headers = vec![("Authorization".to_string(), format!("Bearer {token}"))];
+        rouille::Request::fake_http("PUT", "/raw/x/", headers, body.to_vec())
+    }
+
+    fn error_status(result: anyhow::Result<Response>) -> u16 {
+        result
+            .unwrap_err()
+            .downcast_ref::<ErrorResponse>()
+            .unwrap()
+            .status()
+    }
+
+    #[test]
+    fn test_rewrite_permissions() {
+        let state = crate::test_state();
+        state.config.update(|config| {
+            config.users.push(UserConfig {
+                username: "other".to_string(),
+                token: "other".to_string(),
+                ..Default::default()
+            })
+        });
+        let (old, new) = (
+            crate::test_encrypt(b"code", b"old"),
+            crate::test_encrypt(b"code", b"new"),
+        );
+
+        let fixed = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+        post_upload_raw(&state, &raw_request(&[], &old), fixed.clone()).unwrap();
+        let result = put_upload_raw(&state, &put_request("secret", &new), fixed.clone());
+        assert_eq!(error_status(result), 403);
+
+        let hash = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+        let request = raw_request(&[("X-Toc-Allow-Rewrite", "true")], &old);
+        post_upload_raw(&state, &request, hash.clone()).unwrap();
+        let result = put_upload_raw(&state, &put_request("other", &new), hash.clone());
+        assert_eq!(error_status(result), 401);
+
+        // A broken stream leaves the old data in place.
+        let result = put_upload_raw(&state, &put_request("secret", b"garbage"), hash.clone());
+        assert_eq!(error_status(result), 422);
+        assert_eq!(std::fs::read(state.meta.file_path(&hash)).unwrap(), old);
+
+        put_upload_raw(&state, &put_request("secret", &new), hash.clone()).unwrap();
+        assert_eq!(std::fs::read(state.meta.file_path(&hash)).unwrap(), new);
+        assert!(!state
+            .meta
+            .file_path(&hash)
+            .with_extension("rewrite")
+            .exists());
+        assert!(state.meta.get(&hash).unwrap().unwrap().allow_rewrite);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_rewrite_during_download() {
+        let state = crate::test_state();
+        let (old, new) = (
+            crate::test_encrypt(b"code", &[1; 4000]),
+            crate::test_encrypt(b"code", &[2; 8000]),
+        );
+
+        let hash = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+        let request = raw_request(&[("X-Toc-Allow-Rewrite", "true")], &old);
+        post_upload_raw(&state, &request, hash.clone()).unwrap();
+
+        let get = rouille::Request::fake_http("GET", "/raw/x/", vec![], vec![]);
+        let running = crate::routes::get_download_raw(&state, &get, hash.clone()).unwrap();
+
+        put_upload_raw(&state, &put_request("secret", &new), hash.clone()).unwrap();
+
+        let (mut reader, _) = running.data.into_reader_and_size();
+        let mut downloaded = vec![];
+        reader.read_to_end(&mut downloaded).unwrap();
+        assert_eq!(downloaded, old);
+
+        let response = crate::routes::get_download_raw(&state, &get, hash).unwrap();
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut downloaded = vec![];
+        reader.read_to_end(&mut downloaded).unwrap();
+        assert_eq!(downloaded, new);
+    }
+
+    #[test]
+    fn test_token_file_users() {
+        let mut state = crate::test_state();
+        let path =
+            std::env::temp_dir().join(format!("tarcloud-tokens-{}", TarPassword::generate()));
+        std::fs::write(&path, "carol:rotated\n").unwrap();
+        state.tokens = Some(crate::tokens::TokenFile::new(path.clone()));
+
+        let hash = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+        let request = put_request("rotated", &crate::test_encrypt(b"code", b"data"));
+        post_upload_raw(&state, &request, hash.clone()).unwrap();
+        assert_eq!(state.meta.get(&hash).unwrap().unwrap().owner, "carol");
+
+        // Users from the config keep working.
+        assert_eq!(find_user(&state, "secret").unwrap().username, "test");
+        assert!(find_user(&state, "unknown").is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_scopes() {
+        let state = crate::test_state();
+        let user = |name: &str, scopes: Option<Vec<Scope>>| UserConfig {
+            username: name.to_string(),
+            token: name.to_string(),
+            scopes,
+            ..Default::default()
+        };
+        state.config.update(|config| {
+            config.users.extend([
+                user("ci", Some(vec![Scope::Upload])),
+                user("janitor", Some(vec![Scope::Delete])),
+                user("root", Some(vec![Scope::Admin])),
+            ])
+        });
+        let request = |method: &str, token: &str, body: &[u8]| {
+            let headers = vec![("Authorization".to_string(), format!("Bearer {token}"))];
+            rouille::Request::fake_http(method, "/raw/x/", headers, body.to_vec())
+        };
+        let raw_upload = |token: &str| {
+            let hash = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+            post_upload_raw(
+                &state,
+                &request("POST", token, &crate::test_encrypt(b"code", b"data")),
+                hash.clone(),
+            )
+            .map(|_| hash)
+        };
+        fn forbidden<T>(result: anyhow::Result<T>) -> bool {
+            let error = result.err().unwrap();
+            let error = error.downcast_ref::<ErrorResponse>().unwrap();
+            error.status() == 403 && error.to_string().contains("scope")
+        }
+
+        // Upload only: no deleting, not even its own uploads.
+        let hash = raw_upload("ci").unwrap();
+        assert!(post_upload(&state, &request("POST", "ci", b"data")).is_ok());
+        assert!(forbidden(delete_raw(
+            &state,
+            &request("DELETE", "ci", b""),
+            hash.clone()
+        )));
+
+        // Delete only.
+        assert!(forbidden(raw_upload("janitor")));
+        assert!(forbidden(post_upload(
+            &state,
+            &request("POST", "janitor", b"data")
+        )));
+        assert!(forbidden(put_upload_raw(
+            &state,
+            &request("PUT", "janitor", &crate::test_encrypt(b"code", b"data")),
+            hash.clone()
+        )));
+        assert!(forbidden(ws_upload(
+            &state,
+            &request("GET", "janitor", b"")
+        )));
+        let form = form_request(&[("token", None, "janitor"), ("files", Some("a.txt"), "a")]);
+        assert!(forbidden(post_upload_form(&state, &form)));
+
+        // Admin may do everything, so may tokens without scopes.
+        let hash = raw_upload("root").unwrap();
+        assert!(delete_raw(&state, &request("DELETE", "root", b""), hash).is_ok());
+        let hash = raw_upload("secret").unwrap();
+        assert!(delete_raw(&state, &request("DELETE", "secret", b""), hash).is_ok());
+
+        let scopes = |token: &str| {
+            let response = get_whoami(&state, &request("GET", token, b"")).unwrap();
+            let json: serde_json::Value = serde_json::from_str(&body(response)).unwrap();
+            json["scopes"].clone()
+        };
+        assert_eq!(scopes("janitor"), serde_json::json!(["delete"]));
+        assert_eq!(
+            scopes("secret"),
+            serde_json::json!(["upload", "delete", "list"])
+        );
+    }
+
+    #[test]
+    fn test_list_uploads() {
+        let state = crate::test_state();
+        state.config.update(|config| {
+            config.users.push(UserConfig {
+                username: "ci".to_string(),
+                token: "ci".to_string(),
+                scopes: Some(vec![Scope::Upload]),
+                ..Default::default()
+            })
+        });
+        let request = |token: &str| {
+            let headers = vec![("Authorization".to_string(), format!("Bearer {token}"))];
+            rouille::Request::fake_http("GET", "/api/v1/uploads", headers, vec![])
+        };
+        let hash = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+        let mut meta = upload_meta(&test_user(&state), 60);
+        meta.download_stats.zip = 2;
+        state.meta.set(&hash, &meta).unwrap();
+        let other = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+        state
+            .meta
+            .set(
+                &other,
+                &MetaData {
+                    owner: "ci".to_string(),
+                    ..meta
+                },
+            )
+            .unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_str(&body(get_uploads(&state, &request("secret")).unwrap())).unwrap();
+        let uploads = json["uploads"].as_array().unwrap();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(uploads[0]["hash"], hash.to_string());
+        assert_eq!(uploads[0]["downloads"]["zip"], 2);
+        assert_eq!(uploads[0]["downloads"]["raw"], 0);
+
+        let error = get_uploads(&state, &request("ci")).err().unwrap();
+        assert_eq!(error.downcast_ref::<ErrorResponse>().unwrap().status(), 403);
+    }
+
+    #[test]
+    fn test_alias() {
+        let state = crate::test_state();
+        let request = |hash: &TarHash, body: serde_json::Value| {
+            let headers = vec![("Authorization".to_string(), "Bearer secret".to_string())];
+            let url = format!("/api/uploads/{hash}/alias");
+            rouille::Request::fake_http("POST", url, headers, body.to_string().into_bytes())
+        };
+        let status = |result: anyhow::Result<Response>| {
+            result
+                .err()
+                .unwrap()
+                .downcast::<ErrorResponse>()
+                .unwrap()
+                .status()
+        };
+        let upload = || {
+            let code = TarPassword::generate();
+            let hash = TarHash::from_tarid(&code, "localhost");
+            let meta = upload_meta(&test_user(&state), 60);
+            state.meta.set(&hash, &meta).unwrap();
+            (code.to_string(), hash)
+        };
+        let (code, hash) = upload();
+        let (other_code, other) = upload();
+
+        let data = serde_json::json!({ "code": code, "slug": "team-notes" });
+        let json: serde_json::Value = serde_json::from_str(&body(
+            post_alias(&state, &request(&hash, data), hash.clone()).unwrap(),
+        ))
+        .unwrap();
+        assert_eq!(json["alias"], "team-notes");
+        assert_eq!(json["url"], "http://localhost/s/team-notes");
+        let alias = state.meta.get_alias("team-notes").unwrap().unwrap();
+        assert_eq!((alias.hash, alias.code), (hash.to_string(), code.clone()));
+
+        // Taken by another upload.
+        let data = serde_json::json!({ "code": other_code, "slug": "team-notes" });
+        assert_eq!(
+            status(post_alias(&state, &request(&other, data), other.clone())),
+            409
+        );
+        for slug in ["ab", "Team", "-notes", "../notes", "a".repeat(33).as_str()] {
+            let data = serde_json::json!({ "code": other_code, "slug": slug });
+            assert_eq!(
+                status(post_alias(&state, &request(&other, data), other.clone())),
+                400
+            );
+        }
+        // The code has to match the hash.
+        let data = serde_json::json!({ "code": code });
+        assert_eq!(
+            status(post_alias(&state, &request(&other, data), other.clone())),
+            400
+        );
+
+        // A new alias replaces the old one.
+        let data = serde_json::json!({ "code": code });
+        let json: serde_json::Value = serde_json::from_str(&body(
+            post_alias(&state, &request(&hash, data), hash.clone()).unwrap(),
+        ))
+        .unwrap();
+        let slug = json["alias"].as_str().unwrap();
+        assert_eq!(slug.len(), 6);
+        assert!(state.meta.get_alias("team-notes").unwrap().is_none());
+
+        let delete = rouille::Request::fake_http(
+            "DELETE",
+            format!("/raw/{hash}/"),
+            vec![("Authorization".to_string(), "Bearer secret".to_string())],
+            vec![],
+        );
+        delete_raw(&state, &delete, hash).unwrap();
+        assert!(state.meta.get_alias(slug).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reload() {
+        let path =
+            std::env::temp_dir().join(format!("tarcloud-config-{}", TarPassword::generate()));
+        let path = path.to_str().unwrap().to_string();
+        let write = |hostname: &str, users: &str| {
+            let config = format!(
+                "[general]\nhostname = \"{hostname}\"\n\
+                 [[users]]\nusername = \"test\"\ntoken = \"secret\"\nscopes = [\"admin\"]\n{users}"
+            );
+            std::fs::write(&path, config).unwrap();
+        };
+        write("localhost", "");
+        let mut state = crate::test_state();
+        let config = crate::config::Config::load(&path).unwrap();
+        state.config = crate::config::SharedConfig::new(config, Some(path.clone()));
+
+        let request = |method: &str, url: &str, token: &str| {
+            let headers = vec![("Authorization".to_string(), format!("Bearer {token}"))];
+            crate::handle(
+                &state,
+                &rouille::Request::fake_http(method, url, headers, vec![]),
+            )
+        };
+        let new_user = "[[users]]\nusername = \"new\"\ntoken = \"fresh\"\n";
+        write("localhost", new_user);
+        assert_eq!(request("GET", "/whoami", "fresh").status_code, 401);
+
+        let response = request("POST", "/api/admin/reload", "secret");
+        assert_eq!(response.status_code, 200);
+        let json: serde_json::Value = serde_json::from_str(&body(response)).unwrap();
+        assert_eq!(json["changed"], serde_json::json!(["users.new added"]));
+        let response = request("GET", "/whoami", "fresh");
+        assert_eq!(response.status_code, 200);
+        assert!(body(response).contains("\"new\""));
+        assert_eq!(
+            request("POST", "/api/admin/reload", "fresh").status_code,
+            403
+        );
+
+        // The host name is only read at startup, the running config stays.
+        write("other", new_user);
+        let response = request("POST", "/api/admin/reload", "secret");
+        assert_eq!(response.status_code, 422);
+        assert!(body(response).contains("general.hostname"));
+        assert_eq!(state.config().general.hostname, "localhost");
+        assert_eq!(request("GET", "/whoami", "fresh").status_code, 200);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_admin_delete_uploads() {
+        let state = crate::test_state();
+        let request = |url: &str| {
+            let headers = vec![("Authorization".to_string(), "Bearer secret".to_string())];
+            rouille::Request::fake_http("DELETE", url, headers, vec![])
+        };
+        let forbidden = delete_admin_uploads(&state, &request("/api/admin/uploads?owner=x"));
+        let error = forbidden
+            .err()
+            .unwrap()
+            .downcast::<ErrorResponse>()
+            .unwrap();
+        assert_eq!(error.status(), 403);
+        state
+            .config
+            .update(|config| config.users[0].scopes = Some(vec![Scope::Admin]));
+        let upload = |owner: &str| {
+            let hash = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+            let mut meta = upload_meta(&test_user(&state), 60);
+            meta.owner = owner.to_string();
+            state.meta.set(&hash, &meta).unwrap();
+            let mut writer = state.storage.create_writer(&hash).unwrap();
+            writer.write_all(b"data").unwrap();
+            writer.finish().unwrap();
+            hash
+        };
+        let gone = [upload("leaving"), upload("leaving")];
+        let kept = upload("test");
+
+        let response = delete_admin_uploads(
+            &state,
+            &request("/api/admin/uploads?owner=leaving&dry_run=true"),
+        );
+        let json: serde_json::Value = serde_json::from_str(&body(response.unwrap())).unwrap();
+        let mut expected: Vec<String> = gone.iter().map(|hash| hash.to_string()).collect();
+        expected.sort();
+        assert_eq!(json["uploads"], serde_json::json!(expected));
+        assert!(state.meta.get(&gone[0]).unwrap().is_some());
+
+        let response = delete_admin_uploads(&state, &request("/api/admin/uploads?owner=leaving"));
+        let json: serde_json::Value = serde_json::from_str(&body(response.unwrap())).unwrap();
+        assert_eq!(json, serde_json::json!({ "deleted": 2, "errors": 0 }));
+        for hash in &gone {
+            assert!(state.meta.get(hash).unwrap().is_none());
+            assert!(state.storage.size(hash).is_err());
+        }
+        assert!(state.meta.get(&kept).unwrap().is_some());
+
+        let missing = delete_admin_uploads(&state, &request("/api/admin/uploads"));
+        let error = missing.err().unwrap().downcast::<ErrorResponse>().unwrap();
+        assert_eq!(error.status(), 400);
+    }
+
+    #[test]
+    fn test_expiry_fallback() {
+        let mut general = crate::test_state().config().general.clone();
+        let mut user = UserConfig::default();
+        assert_eq!(user.expire_s(&general, None), 7 * 24 * 60 * 60);
+        assert_eq!(user.expire_s(&general, Some(60)), 60);
+
+        general.default_expire_s = 100;
+        general.max_expire_s = Some(1000);
+        assert_eq!(user.expire_s(&general, None), 100);
+        assert_eq!(user.expire_s(&general, Some(5000)), 1000);
+
+        // The user's own values come first, the default is capped as well.
+        user.default_expire_s = Some(50_000);
+        assert_eq!(user.expire_s(&general, None), 1000);
+        user.max_expire_s = Some(30_000);
+        assert_eq!(user.expire_s(&general, None), 30_000);
+        assert_eq!(user.expire_s(&general, Some(40_000)), 30_000);
+        assert_eq!(user.expire_limit_s(&general), Some(30_000));
+    }
+
+    #[test]
+    fn test_requested_expiry() {
+        let state = crate::test_state();
+        state
+            .config
+            .update(|config| config.general.max_expire_s = Some(3600));
+        let upload = |expire_in: &str| {
+            let hash = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+            let request = raw_request(
+                &[("X-Toc-Expire-In", expire_in)],
+                &crate::test_encrypt(b"code", b"data"),
+            );
+            post_upload_raw(&state, &request, hash.clone()).map(|_| hash)
+        };
+
+        let hash = upload("60").unwrap();
+        let meta = state.meta.get(&hash).unwrap().unwrap();
+        assert_eq!(meta.delete_at_unix - meta.created_at_unix, 60);
+
+        let hash = upload("86400").unwrap();
+        let meta = state.meta.get(&hash).unwrap().unwrap();
+        assert_eq!(meta.delete_at_unix - meta.created_at_unix, 3600);
+
+        assert_eq!(
+            error_status(upload("soon").map(|_| Response::empty_204())),
+            400
+        );
+
+        let request = rouille::Request::fake_http(
+            "GET",
+            "/whoami",
+            vec![("Authorization".to_string(), "Bearer secret".to_string())],
+            vec![],
+        );
+        let (mut reader, _) = get_whoami(&state, &request)
+            .unwrap()
+            .data
+            .into_reader_and_size();
+        let json: serde_json::Value = serde_json::from_reader(&mut reader).unwrap();
+        assert_eq!(json["username"], "test");
+        assert_eq!(json["default_expire_s"], 3600);
+        assert_eq!(json["max_expire_s"], 3600);
+    }
+}
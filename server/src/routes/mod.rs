@@ -3,3 +3,6 @@ pub use unauth::*;
 
 mod auth;
 pub use auth::*;
+
+mod protocol;
+pub use protocol::*;
@@ -0,0 +1,163 @@
+use common::{BLOCK_SIZE, PAYLOAD_SIZE};
+use rouille::Response;
+
+use crate::AppState;
+
+/// Version of the document below, bumped on incompatible changes.
+const PROTOCOL_VERSION: u32 = 1;
+
+/// Machine readable description of the endpoints and the stream format,
+/// so other clients can be written without reading this code.
+///
+/// `/raw/{hash}/` always deals in the encrypted stream, the client encrypts and decrypts.
+/// The `/{code}/` routes get the code and decrypt on the server.
+pub fn get_protocol(state: &AppState, _request: &rouille::Request) -> anyhow::Result<Response> {
+    let endpoint = |method: &str, path: &str, auth: bool, description: &str| {
+        serde_json::json!({
+            "method": method,
+            "path": path,
+            "auth": if auth { Some("bearer") } else { None },
+            "description": description,
+        })
+    };
+
+    Ok(Response::json(&serde_json::json!({
+        "version": PROTOCOL_VERSION,
+        "hostname": state.config().general.hostname,
+        "ids": {
+            "code": "NNNN-word-word-word-word, a 4 digit number and 4 bip39 english words. \
+                     Routes correct a word one edit away from exactly one bip39 word.",
+            "hash": "hex(argon2i v13, t=3, m=65536, p=1, len=32, password=code, salt=hostname)",
+        },
+        "endpoints": [
+            endpoint("POST", "/upload", true,
+                "Plain body or multipart field `file`, encrypted by the server. Returns the code."),
+            endpoint("GET", "/upload", true,
+                "Websocket upload with acks, resume and an explicit finish frame."),
+            endpoint("POST", "/upload/form", false,
+                "Browser form with a `token` field and files, packed into a tar."),
+            endpoint("GET", "/{code}", false,
+                "Redirects to `/{code}/` with the corrected code."),
+            endpoint("GET", "/{code}/", false,
+                "Decrypted tar, or the index page for browsers."),
+            endpoint("GET", "/{code}/pipe", false,
+                "Decrypted tar. Supports `offset`, `length` and `name` parameters and ranges."),
+            endpoint("GET", "/{code}/stream", false,
+                "Decrypted tar as `application/x-tar`, streamed while the upload is running."),
+            endpoint("GET", "/{code}/ws", false,
+                "Decrypted tar over a websocket."),
+            endpoint("GET", "/{code}/zip", false,
+                "Archive converted to zip, `prefix` limits it to a directory."),
+            endpoint("GET", "/{code}/tar.zst", false,
+                "Archive compressed with zstd, `prefix` limits it to a directory. \
+                 409 while unfinished."),
+            endpoint("GET", "/{code}/sha256", false,
+                "`sha256sum` compatible checksums of the archived files, 409 while unfinished."),
+            endpoint("GET", "/{code}/thumbnail", false,
+                "Preview image contained in the archive."),
+            endpoint("DELETE", "/{code}/", true,
+                "Deletes an upload of the token's user."),
+            endpoint("GET", "/raw/{hash}/", false,
+                "Encrypted stream as stored. Supports ranges."),
+            endpoint("HEAD", "/raw/{hash}/", false,
+                "Stored length and state in `X-Toc-Stored-Length` and `X-Toc-Finished`."),
+            endpoint("POST", "/raw/{hash}/", true,
+                "Stores a client encrypted stream. `X-Toc-Resumable` keeps partial data, \
+                 `Content-Range: bytes N-*/T` continues it, `X-Toc-Finish` completes it. \
+                 `X-Toc-Allow-Rewrite` allows a later PUT."),
+            endpoint("PATCH", "/raw/{hash}/", true,
+                "Same as POST, for continuing resumable uploads. `X-Toc-Block-Count` \
+                 in the response is the number of complete blocks stored."),
+            endpoint("PUT", "/raw/{hash}/", true,
+                "Replaces an upload created with `X-Toc-Allow-Rewrite: true`."),
+            endpoint("DELETE", "/raw/{hash}/", true,
+                "Deletes an upload of the token's user."),
+            endpoint("GET", "/api/v1/status/{code}/", false,
+                "`exists`, `finished`, `size_bytes` as stored, `created_at` and `expires_at`. \
+                 `file_count` and `content_bytes` of an archive, null otherwise."),
+            endpoint("GET", "/api/v1/uploads", true,
+                "`uploads` of the token's user, newest first, with `hash`, `finished`, \
+                 `created_at`, `expires_at` and completed `downloads` by route. Needs the \
+                 'list' scope."),
+            endpoint("POST", "/api/uploads/{hash}/alias", true,
+                "Body `{\"code\": ..., \"slug\": ...}`, the slug optional: 3 to 32 of a-z, 0-9 \
+                 and inner dashes, random if left out. Answers `alias` and `url`, 409 for a \
+                 taken slug. The server stores the code in the clear for the redirect, so \
+                 anyone with access to its data can open the upload. Replaces an earlier alias \
+                 of the upload, gone with the upload."),
+            endpoint("GET", "/api/uploads/{hash}/status", true,
+                "For the owner: `bytes_received` so far, `finished`, `receiving` while data \
+                 arrives, `created_at` and `delete_at`. `block_count` of a resumable upload \
+                 is where to continue it, null otherwise."),
+            endpoint("GET", "/s/{slug}", false,
+                "302 to the page of the upload of an alias."),
+            endpoint("GET", "/whoami", true,
+                "User of the token with `scopes`, `default_expire_s` and `max_expire_s` \
+                 (null for no limit). Routes needing a scope the token lacks answer 403."),
+            endpoint("DELETE", "/api/admin/uploads", true,
+                "Deletes all uploads of the user in `owner`, answers `deleted` and `errors`. \
+                 With `dry_run=true` only lists their hashes in `uploads`. Needs the 'admin' \
+                 scope."),
+            endpoint("GET", "/metrics", false,
+                "Running downloads in the Prometheus text format."),
+            endpoint("GET", "/protocol", false,
+                "This document."),
+        ],
+        "expiry": "POST uploads may send `X-Toc-Expire-In` in seconds, capped at the user's \
+                   maximum. Websocket uploads use the default.",
+        "anonymous": "With `general.allow_anonymous`, POST /upload, GET /upload and \
+                      POST /raw/{hash}/ also work without a token, within the `[anonymous]` \
+                      size, expiry and rate limits. Such uploads can't be resumed or deleted, \
+                      they only expire.",
+        "methods": "OPTIONS on a path answers 204 with its methods in `Allow`, methods it \
+                    doesn't support get 405 with the same header.",
+        "content_negotiation": "Send `Accept: application/json` for JSON responses and errors. \
+                                Other errors are HTML pages for `text/html`, plain text otherwise.",
+        "crypto": {
+            "cipher": "chacha20-poly1305",
+            "kdf": "argon2i v13, t=3, m=65536, p=1, len=32, password=code, salt=SALT|'#toc'",
+            "block_size": BLOCK_SIZE,
+            "payload_size": PAYLOAD_SIZE,
+            "block": "magic:1|version<<4+variant:1|counter^'544b':4be|salt:10|ciphertext:512|tag:16",
+            "version": 0,
+            "variant": "1, or 2 for the last block of a stream if the data ends inside it",
+            "nonce": "salt[0:8]|counter:4be",
+            "magic": "for counter < 16 the byte '#toc#stream_____'[counter], otherwise any",
+            "padding": "the last block is zero padded. In a variant 2 block the last two bytes of \
+                        the plaintext are the data length, little endian, or only the last one is \
+                        0xFF for 511 bytes. Its associated data is 'last', otherwise empty",
+            "streams": "a file may consist of several concatenated streams with different salts, \
+                        the counter of each starts at 0",
+        },
+        "archive": "tar",
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_protocol_document() {
+        let state = crate::test_state();
+        let request = rouille::Request::fake_http("GET", "/protocol", vec![], vec![]);
+        let response = get_protocol(&state, &request).unwrap();
+
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut body = String::new();
+        reader.read_to_string(&mut body).unwrap();
+        let doc: serde_json::Value = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(doc["version"], 1);
+        assert_eq!(doc["crypto"]["cipher"], "chacha20-poly1305");
+        assert_eq!(doc["crypto"]["block_size"], 544);
+        let endpoints = doc["endpoints"].as_array().unwrap();
+        assert!(endpoints
+            .iter()
+            .any(|e| e["method"] == "GET" && e["path"] == "/raw/{hash}/" && e["auth"].is_null()));
+        assert!(endpoints.iter().any(|e| e["method"] == "POST"
+            && e["path"] == "/raw/{hash}/"
+            && e["auth"] == "bearer"));
+    }
+}
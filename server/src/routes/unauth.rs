@@ -1,22 +1,34 @@
 use crate::{
-    meta::{MetaData, MetaStore},
+    downloads::DownloadGuard,
+    legacy::{self, Decrypted},
+    meta::{DownloadKind, MetaData, MetaStore, SerializedTarEntry},
     responses::ErrorResponse,
-    templates::TarFileInfo,
-    util::handle_range,
-    AppState,
+    storage::{self, BlobReader},
+    templates::{build_tree, IndexSort, TarFileInfo, UploadPage},
+    util::{
+        client_ip, format_rfc3339, handle_range, now_unix, range_end, with_file_name, Origin,
+        Sha256Writer,
+    },
+    zip_stream, AppState,
 };
 use askama::Template;
-use common::{EncryptedReader, TarHash, TarPassword};
-use rouille::Response;
+use common::{
+    format_bytes, human_duration, sanitize_entry_path, scan_tar_index, EncryptedReader, TarHash,
+    TarIndexError, TarPassword,
+};
+use rouille::{websocket, Response};
 use std::{
     fs::File,
     io::Write,
     io::{Read, Seek},
-    path::PathBuf,
+    path::Path,
 };
 
 const DEFAULT_DOWNLOAD_TIMEOUT: u64 = 60;
 
+/// Seconds to wait for a part of an unfinished upload that is not stored yet.
+const UNFINISHED_RETRY_AFTER_S: u64 = 5;
+
 struct UnfinishedBlockingFileReader {
     file: File,
     id: TarHash,
@@ -50,35 +62,116 @@ impl Read for UnfinishedBlockingFileReader {
     }
 }
 
+/// Status of a raw upload, tells a client where to resume.
+pub fn head_upload_raw(
+    state: &AppState,
+    _request: &rouille::Request,
+    id: TarHash,
+) -> anyhow::Result<Response> {
+    let m = state.meta.get(&id)?.ok_or_else(ErrorResponse::not_found)?;
+    let stored = state.storage.size(&id)?;
+
+    Ok(Response::text("")
+        .with_additional_header("X-Toc-Stored-Length", stored.to_string())
+        .with_additional_header("X-Toc-Finished", m.finished.to_string()))
+}
+
+/// For `Last-Modified`. A rewrite updates it, the blob can't change otherwise.
+fn modified(m: &MetaData) -> u64 {
+    m.created_at_unix
+}
+
+/// The content hash, or the upload time for uploads stored without one.
+fn etag(m: &MetaData) -> String {
+    match &m.sha256 {
+        Some(sha256) => format!("sha256-{sha256}"),
+        None => modified(m).to_string(),
+    }
+}
+
+/// Counts the download of `hash` against the limits, until the guard is dropped.
+fn start_download(
+    state: &AppState,
+    request: &rouille::Request,
+    hash: &TarHash,
+) -> anyhow::Result<DownloadGuard> {
+    let config = state.config();
+    let ip = client_ip(&config.general, request);
+    Ok(state.downloads.start(&config.general, hash, ip)?)
+}
+
+/// Like `guard.attach`, and counts a whole response in the stats of `hash`
+/// once it was sent completely. Partial ones and errors are not counted.
+fn attach_counted(
+    state: &AppState,
+    guard: DownloadGuard,
+    hash: &TarHash,
+    kind: DownloadKind,
+    response: Response,
+) -> Response {
+    if response.status_code != 200 {
+        return guard.attach(response);
+    }
+    guard
+        .on_complete(count_download(&state.meta, hash, kind))
+        .attach(response)
+}
+
+fn count_download(
+    meta: &MetaStore,
+    hash: &TarHash,
+    kind: DownloadKind,
+) -> impl FnOnce() + Send + 'static {
+    let (meta, hash) = (meta.clone(), hash.clone());
+    move || {
+        // Deleted meanwhile, nothing to count.
+        let result = meta.get(&hash).and_then(|m| match m {
+            Some(mut m) => {
+                m.download_stats.record(kind, now_unix());
+                meta.set(&hash, &m)
+            }
+            None => Ok(()),
+        });
+        if let Err(e) = result {
+            println!("Error: Failed to count download of {hash}: {e:?}");
+        }
+    }
+}
+
 pub fn get_download_raw(
     state: &AppState,
     request: &rouille::Request,
     id: TarHash,
 ) -> anyhow::Result<Response> {
     let m = state.meta.get(&id)?.ok_or_else(ErrorResponse::not_found)?;
+    let guard = start_download(state, request, &id)?;
 
-    let path = format!("data/{}.tar.age", &id);
-    if m.finished {
-        let m_time = std::fs::metadata(&path)?
-            .modified()?
-            .duration_since(std::time::UNIX_EPOCH)?
-            .as_secs();
-        handle_range(request, None, Some(m_time), File::open(&path)?)
+    let res = if m.finished {
+        let mut file = state.storage.open_reader(&id)?;
+        let is_age = legacy::is_age(&mut file)?;
+        let res = handle_range(request, None, Some(&etag(&m)), Some(modified(&m)), file)?;
+        // Sent as stored, clients have to tell the formats apart.
+        if is_age {
+            res.with_additional_header("X-Toc-Format", "age")
+        } else {
+            res
+        }
     } else {
-        let file = File::open(&path)?;
+        let file = File::open(storage::local_path(&*state.storage, &id)?)?;
         let reader = UnfinishedBlockingFileReader {
             file,
-            id,
+            id: id.clone(),
             meta: state.meta.clone(),
             timeout: DEFAULT_DOWNLOAD_TIMEOUT,
         };
-        Ok(rouille::Response {
+        rouille::Response {
             status_code: 200,
             headers: vec![("Content-Type".into(), "application/octet-stream".into())],
             data: rouille::ResponseBody::from_reader(reader),
             upgrade: None,
-        })
-    }
+        }
+    };
+    Ok(attach_counted(state, guard, &id, DownloadKind::Raw, res))
 }
 
 pub fn get_download(
@@ -86,12 +179,7 @@ pub fn get_download(
     request: &rouille::Request,
     id: TarPassword,
 ) -> anyhow::Result<Response> {
-    let hash = TarHash::from_tarid(&id, &state.config.general.hostname);
-
-    let m = state
-        .meta
-        .get(&hash)?
-        .ok_or_else(ErrorResponse::not_found)?;
+    let (hash, m) = find_upload(state, &id)?;
 
     let offset = request
         .get_param("offset")
@@ -104,21 +192,27 @@ pub fn get_download(
         .transpose()?;
 
     let name = request.get_param("name");
+    let inline = request.get_param("disposition").as_deref() == Some("inline");
+    let with_name = |res: Response| match &name {
+        Some(name) if res.is_success() => with_file_name(res, name, inline),
+        _ => res,
+    };
+    // The index page links single files with their position in the archive.
+    let kind = match offset.is_some() || length.is_some() {
+        true => DownloadKind::SingleFile,
+        false => DownloadKind::Decrypted,
+    };
+    let guard = start_download(state, request, &hash)?;
 
-    let path = PathBuf::from(&format!("data/{}.tar.age", hash));
-    let m_time = std::fs::metadata(&path)?
-        .modified()?
-        .duration_since(std::time::UNIX_EPOCH)?
-        .as_secs();
-    let file = std::fs::File::open(path)?;
     if !m.finished {
-        if offset.is_some() || length.is_some() {
-            return Ok(Response::text("Download not finished").with_status_code(417));
+        if offset.is_some() || length.is_some() || request.header("Range").is_some() {
+            let res = get_unfinished_range(state, request, &id, &hash, offset, length)?;
+            return Ok(attach_counted(state, guard, &hash, kind, with_name(res)));
         }
 
         let reader = UnfinishedBlockingFileReader {
-            file,
-            id: hash,
+            file: File::open(storage::local_path(&*state.storage, &hash)?)?,
+            id: hash.clone(),
             meta: state.meta.clone(),
             timeout: DEFAULT_DOWNLOAD_TIMEOUT,
         };
@@ -126,56 +220,458 @@ pub fn get_download(
         let de_reader = common::EncryptedReader::new(reader, id.to_string().as_bytes());
         let data = rouille::ResponseBody::from_reader(de_reader);
 
-        return Ok(rouille::Response {
+        let res = with_name(rouille::Response {
             status_code: 200,
             headers: vec![("Content-Type".into(), "application/octet-stream".into())],
             data,
             upgrade: None,
         });
+        return Ok(attach_counted(state, guard, &hash, kind, res));
     }
 
-    let mut de_reader = common::EncryptedReader::new(file, id.to_string().as_bytes());
+    let mut de_reader = open_decrypted(state, &id, &hash)?;
     if let Some(offset) = offset {
         de_reader.seek(std::io::SeekFrom::Start(offset))?;
     }
 
-    let res = handle_range(request, length, Some(m_time), de_reader)?;
-    let res = match name {
-        Some(name) => res.with_content_disposition_attachment(&name),
-        None => res,
+    let res = handle_range(
+        request,
+        length,
+        Some(&etag(&m)),
+        Some(modified(&m)),
+        de_reader,
+    )?;
+    Ok(attach_counted(state, guard, &hash, kind, with_name(res)))
+}
+
+/// A part of an upload that is still running, if it is stored already. The
+/// part has to have an end, there is no length to take it from yet.
+fn get_unfinished_range(
+    state: &AppState,
+    request: &rouille::Request,
+    id: &TarPassword,
+    hash: &TarHash,
+    offset: Option<u64>,
+    length: Option<u64>,
+) -> anyhow::Result<Response> {
+    let offset = offset.unwrap_or(0);
+    let end = match range_end(request, length) {
+        Some(end) => offset.saturating_add(end),
+        None => {
+            return Ok(
+                Response::text("Download not finished, ranges need an end until it is")
+                    .with_status_code(417),
+            )
+        }
+    };
+    // Whole blocks, the last one may still be written.
+    if state.storage.size(hash)? < common::encrypted_size(end) {
+        return Ok(Response::text("Not yet available")
+            .with_status_code(503)
+            .with_additional_header("Retry-After", UNFINISHED_RETRY_AFTER_S.to_string()));
+    }
+
+    let file = File::open(storage::local_path(&*state.storage, hash)?)?;
+    let de_reader = EncryptedReader::new(file, id.to_string().as_bytes());
+    let stored = StoredPart {
+        inner: de_reader,
+        len: end,
+        position: offset,
+        seeked: false,
+    };
+    handle_range(request, length, None, None, stored)
+}
+
+/// The first `len` bytes of an upload that is still written. The block after
+/// them may be incomplete, so the reader only moves there once it is read.
+struct StoredPart<R> {
+    inner: R,
+    len: u64,
+    position: u64,
+    seeked: bool,
+}
+
+impl<R: Read + Seek> Read for StoredPart<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let left = self.len.saturating_sub(self.position);
+        if left == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        if !self.seeked {
+            self.inner.seek(std::io::SeekFrom::Start(self.position))?;
+            self.seeked = true;
+        }
+        let len = buf.len().min(usize::try_from(left).unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..len])?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R> Seek for StoredPart<R> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            std::io::SeekFrom::Start(n) => Some(n),
+            std::io::SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+            std::io::SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+        };
+        self.position = position.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seek before the start")
+        })?;
+        self.seeked = false;
+        Ok(self.position)
+    }
+}
+
+/// Decrypted tar for piping into `tar -x`. Unfinished uploads are streamed
+/// as they come in.
+pub fn get_stream(
+    state: &AppState,
+    request: &rouille::Request,
+    id: TarPassword,
+) -> anyhow::Result<Response> {
+    let res = get_download(state, request, id)?;
+    if !res.is_success() {
+        return Ok(res);
+    }
+    Ok(res.with_unique_header("Content-Type", "application/x-tar"))
+}
+
+/// Largest binary frame sent by `ws_download`.
+const WS_FRAME_SIZE: usize = 64 * 1024;
+
+/// Streams the decrypted archive over a websocket, for clients that can not
+/// read fetch bodies progressively.
+///
+/// Frames: a JSON text frame `{"size": .., "finished": ..}`, binary frames with the
+/// archive and a final text frame `done`. Read errors are sent as `{"error": ..}`.
+pub fn ws_download(
+    state: &AppState,
+    request: &rouille::Request,
+    id: TarPassword,
+) -> anyhow::Result<Response> {
+    let (hash, m) = find_upload(state, &id)?;
+    let counted = count_download(&state.meta, &hash, DownloadKind::Decrypted);
+    let guard = start_download(state, request, &hash)?.on_complete(counted);
+
+    let (resp, websocket) = match websocket::start(request, None as Option<&'static str>) {
+        Ok(a) => a,
+        Err(_e) => {
+            return Ok(Response::text("Expected Websocket").with_status_code(400));
+        }
+    };
+
+    let (size, mut reader): (Option<u64>, Box<dyn Read + Send>) = if m.finished {
+        let mut de_reader = open_decrypted(state, &id, &hash)?;
+        let size = de_reader.seek(std::io::SeekFrom::End(0))?;
+        de_reader.seek(std::io::SeekFrom::Start(0))?;
+        (Some(size), Box::new(de_reader))
+    } else {
+        let reader = UnfinishedBlockingFileReader {
+            file: File::open(storage::local_path(&*state.storage, &hash)?)?,
+            id: hash,
+            meta: state.meta.clone(),
+            timeout: DEFAULT_DOWNLOAD_TIMEOUT,
+        };
+        (
+            None,
+            Box::new(EncryptedReader::new(reader, id.to_string().as_bytes())),
+        )
+    };
+
+    std::thread::spawn(move || {
+        let mut ws = match websocket.recv() {
+            Ok(ws) => ws,
+            Err(_) => return,
+        };
+
+        let info = serde_json::json!({ "size": size, "finished": m.finished });
+        if ws.send_text(&info.to_string()).is_err() {
+            return;
+        }
+
+        let mut buf = vec![0; WS_FRAME_SIZE];
+        loop {
+            match read_frame(&mut reader, &mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    // Client is gone, dropping the reader ends the download.
+                    if ws.send_binary(&buf[..n]).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    let _ =
+                        ws.send_text(&serde_json::json!({ "error": e.to_string() }).to_string());
+                    return;
+                }
+            }
+        }
+
+        if ws.send_text("done").is_ok() {
+            guard.complete();
+        }
+    });
+
+    Ok(resp)
+}
+
+/// Fills `buf` as far as possible, the decrypting reader only returns single blocks.
+fn read_frame<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..])? {
+            0 => break,
+            read => n += read,
+        }
+    }
+    Ok(n)
+}
+
+/// Names of images meant as preview, matched without extension.
+const THUMBNAIL_NAMES: [&str; 4] = ["preview", "thumbnail", "thumb", "cover"];
+
+/// Preview image of the archive, for link previews in chat applications.
+/// An image with a preview name wins, otherwise the archive has to contain exactly one image.
+pub fn get_thumbnail(
+    state: &AppState,
+    request: &rouille::Request,
+    id: TarPassword,
+) -> anyhow::Result<Response> {
+    let (hash, mut m) = match find_finished(state, &id)? {
+        Ok(found) => found,
+        Err(res) => return Ok(res),
     };
+    // Within the limits of the listing, and the index is kept for the next time.
+    let index = tar_index(state, &id, &hash, &mut m)?;
+    let (content_type, position, size) =
+        find_thumbnail(&index).ok_or_else(ErrorResponse::not_found)?;
+
+    let mut reader = open_decrypted(state, &id, &hash)?;
+    reader.seek(std::io::SeekFrom::Start(position))?;
+
+    thumbnail_response(request, reader, size, content_type)
+}
+
+/// Content type, position and size of the preview image.
+fn find_thumbnail(index: &[SerializedTarEntry]) -> Option<(&'static str, u64, u64)> {
+    let mut images = vec![];
+    // Links have no contents either.
+    for entry in index.iter().filter(|e| !e.is_dir && e.size > 0) {
+        let path = std::path::Path::new(&entry.path);
+        let content_type = match image_content_type(path) {
+            Some(content_type) => content_type,
+            None => continue,
+        };
+        let image = (content_type, entry.offset, entry.size);
+
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        if THUMBNAIL_NAMES.contains(&stem.to_lowercase().as_str()) {
+            return Some(image);
+        }
+        images.push(image);
+    }
 
-    Ok(res)
+    match images[..] {
+        [image] => Some(image),
+        _ => None,
+    }
+}
+
+fn image_content_type(path: &std::path::Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    Some(match ext.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        _ => return None,
+    })
+}
+
+#[cfg(not(feature = "thumbnail"))]
+fn thumbnail_response<R: Read + Seek + Send + 'static>(
+    request: &rouille::Request,
+    reader: R,
+    size: u64,
+    content_type: &'static str,
+) -> anyhow::Result<Response> {
+    Ok(handle_range(request, Some(size), None, None, reader)?
+        .with_unique_header("Content-Type", content_type))
 }
 
-fn get_decrypted_reader(
+/// Largest image that is decoded for resizing.
+#[cfg(feature = "thumbnail")]
+const THUMBNAIL_MAX_INPUT: u64 = 32 * 1024 * 1024;
+
+#[cfg(feature = "thumbnail")]
+fn thumbnail_response<R: Read + Seek + Send + 'static>(
+    request: &rouille::Request,
+    reader: R,
+    size: u64,
+    content_type: &'static str,
+) -> anyhow::Result<Response> {
+    if size > THUMBNAIL_MAX_INPUT {
+        return Ok(handle_range(request, Some(size), None, None, reader)?
+            .with_unique_header("Content-Type", content_type));
+    }
+
+    let mut data = vec![];
+    reader.take(size).read_to_end(&mut data)?;
+    let thumbnail = image::load_from_memory(&data)?.thumbnail(256, 256);
+
+    let mut out = std::io::Cursor::new(vec![]);
+    thumbnail.write_to(&mut out, image::ImageOutputFormat::Png)?;
+    Ok(Response::from_data("image/png", out.into_inner()))
+}
+
+/// Looks up the upload of a code, also under the hash of former host names.
+/// A match under a former name is noted in the metadata.
+pub(crate) fn find_upload(
     state: &AppState,
     id: &TarPassword,
-) -> anyhow::Result<Result<(EncryptedReader<File>, MetaData), Response>> {
-    let hash = TarHash::from_tarid(id, &state.config.general.hostname);
+) -> anyhow::Result<(TarHash, MetaData)> {
+    lookup_upload(state, id)?.ok_or_else(|| ErrorResponse::not_found().into())
+}
 
-    let m = state
-        .meta
-        .get(&hash)?
-        .ok_or_else(ErrorResponse::not_found)?;
+fn lookup_upload(
+    state: &AppState,
+    id: &TarPassword,
+) -> anyhow::Result<Option<(TarHash, MetaData)>> {
+    let config = state.config();
+    let general = &config.general;
+    let hash = TarHash::from_tarid(id, &general.hostname);
+    if let Some(m) = state.meta.get(&hash)? {
+        return Ok(Some((hash, m)));
+    }
+
+    for hostname in &general.accepted_hostnames {
+        let hash = TarHash::from_tarid(id, hostname);
+        if let Some(mut m) = state.meta.get(&hash)? {
+            if m.hostname.as_ref() != Some(hostname) {
+                m.hostname = Some(hostname.clone());
+                state.meta.set(&hash, &m)?;
+            }
+            return Ok(Some((hash, m)));
+        }
+    }
+    Ok(None)
+}
+
+/// State of an upload for polling, without the owner. Unknown codes are
+/// not an error, a recipient may ask before the upload started.
+pub fn get_status(
+    state: &AppState,
+    _request: &rouille::Request,
+    id: TarPassword,
+) -> anyhow::Result<Response> {
+    let (hash, mut m) = match lookup_upload(state, &id)? {
+        Some(found) => found,
+        None => return Ok(Response::json(&serde_json::json!({ "exists": false }))),
+    };
+    let size = match state.storage.size(&hash) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        size => size?,
+    };
+    // The index is built once, like for the file list. Uploads that are no
+    // archive, or too big to list, have no file count.
+    let files = match m.finished {
+        true => tar_index(state, &id, &hash, &mut m).ok(),
+        false => None,
+    }
+    .map(|index| index.into_iter().filter(|e| !e.is_dir).collect::<Vec<_>>());
+
+    Ok(Response::json(&serde_json::json!({
+        "exists": true,
+        "finished": m.finished,
+        "size_bytes": size,
+        "file_count": files.as_ref().map(|files| files.len()),
+        "content_bytes": files.map(|files| files.iter().map(|e| e.size).sum::<u64>()),
+        "created_at": format_rfc3339(m.created_at_unix),
+        "expires_at": format_rfc3339(m.delete_at_unix),
+    })))
+}
+
+fn find_finished(
+    state: &AppState,
+    id: &TarPassword,
+) -> anyhow::Result<Result<(TarHash, MetaData), Response>> {
+    let (hash, m) = find_upload(state, id)?;
 
     if !m.finished {
         return Ok(Err(
             Response::text("Upload not finished yet").with_status_code(200)
         ));
     }
+    Ok(Ok((hash, m)))
+}
+
+type DecryptedReader = Decrypted<Box<dyn BlobReader>>;
+
+/// Uploads of the old server are age files, see `legacy`.
+fn open_decrypted(
+    state: &AppState,
+    id: &TarPassword,
+    hash: &TarHash,
+) -> anyhow::Result<DecryptedReader> {
+    let file = state.storage.open_reader(hash)?;
+    Decrypted::open(file, id)
+}
+
+/// Entries of a finished upload. They are read from the archive once and then
+/// kept in the metadata. Archives over the configured limits fail with 413,
+/// the scan stops at the first entry too many.
+fn tar_index(
+    state: &AppState,
+    id: &TarPassword,
+    hash: &TarHash,
+    m: &mut MetaData,
+) -> anyhow::Result<Vec<SerializedTarEntry>> {
+    let config = state.config();
+    let general = &config.general;
+    let too_many = || {
+        ErrorResponse::payload_too_large(format!(
+            "Archive has more than {} entries, download it as tar instead",
+            general.max_archive_entries
+        ))
+    };
+    let too_long = || {
+        ErrorResponse::payload_too_large(format!(
+            "Archive has paths longer than {} bytes, download it as tar instead",
+            general.max_path_length
+        ))
+    };
 
-    let path = PathBuf::from(&format!("data/{}.tar.age", hash));
-    let file = std::fs::File::open(path)?;
+    if let Some(index) = m.tar_index(id) {
+        // Cached before the limits were lowered.
+        if index.len() > general.max_archive_entries {
+            return Err(too_many().into());
+        }
+        if index.iter().any(|e| e.path.len() > general.max_path_length) {
+            return Err(too_long().into());
+        }
+        return Ok(index);
+    }
 
-    let de_reader = common::EncryptedReader::new(file, id.to_string().as_bytes());
+    let reader = open_decrypted(state, id, hash)?;
+    let index = scan_tar_index(reader, general.max_archive_entries, general.max_path_length)
+        .map_err(|e| -> anyhow::Error {
+            match e {
+                TarIndexError::TooManyEntries => too_many().into(),
+                TarIndexError::PathTooLong => too_long().into(),
+                TarIndexError::Io(e) => e.into(),
+            }
+        })?;
+    let index: Vec<SerializedTarEntry> = index.into_iter().map(Into::into).collect();
 
-    Ok(Ok((de_reader, m)))
+    m.set_tar_index(id, &index)?;
+    state.meta.set(hash, m)?;
+    Ok(index)
 }
 
 pub fn get_tar_to_zip(
     state: &AppState,
-    _request: &rouille::Request,
+    request: &rouille::Request,
     id: TarPassword,
 ) -> anyhow::Result<Response> {
     struct FakeWriter {
@@ -192,132 +688,1104 @@ pub fn get_tar_to_zip(
         }
     }
 
-    let (mut reader, _) = match get_decrypted_reader(state, &id) {
-        Ok(Ok(reader)) => reader,
-        Ok(Err(res)) => return Ok(res),
-        Err(e) => return Err(e),
+    let (hash, mut m) = match find_finished(state, &id)? {
+        Ok(found) => found,
+        Err(res) => return Ok(res),
+    };
+    let index = tar_index(state, &id, &hash, &mut m)?;
+    let guard = start_download(state, request, &hash)?;
+    let reader = open_decrypted(state, &id, &hash)?;
+
+    // Only include entries below this directory, used by the per-directory links.
+    let prefix = request.get_param("prefix").unwrap_or_default();
+    let file_name = match prefix.trim_end_matches('/').rsplit('/').next() {
+        Some(name) if !name.is_empty() => format!("{name}.zip"),
+        _ => "archive.zip".to_string(),
     };
 
     let (sender, receiver) = common::create_pipe();
 
     let fake_writer = FakeWriter { len: 0 };
 
-    let mut archive = tar::Archive::new(&mut reader);
     let mut zip = streaming_zip::Archive::new(fake_writer);
     let mut content_len = 0;
 
-    for entry in archive.entries_with_seek()? {
-        let entry = entry?;
-        let path = entry.path()?.to_string_lossy().to_string();
-        let mtime = entry.header().mtime().unwrap_or(0);
-        content_len += entry.header().size().unwrap_or(0);
+    // Both passes have to agree on the entries.
+    let zip_path = below_prefix(&prefix);
+    for entry in index {
+        let Some(path) = zip_path(&entry.path) else {
+            continue;
+        };
+        content_len += entry.size;
 
         zip.add_file(
             path.into(),
-            chrono::NaiveDateTime::from_timestamp(mtime as i64, 0),
+            zip_stream::zip_time(entry.mtime),
             streaming_zip::CompressionMode::Store,
             &mut std::io::empty(),
             true,
         )?;
     }
-    let _ = reader.seek(std::io::SeekFrom::Start(0))?;
     let total_len = zip.finish()?.len + content_len;
 
     std::thread::spawn(move || {
-        let mut archive = tar::Archive::new(reader);
-        let mut zip = streaming_zip::Archive::new(sender);
-
-        for entry in archive.entries_with_seek()? {
-            let mut entry = entry?;
-            let path = entry.path()?.to_string_lossy().to_string();
-            let mtime = entry.header().mtime().unwrap_or(0);
-
-            zip.add_file(
-                path.into(),
-                chrono::NaiveDateTime::from_timestamp(mtime as i64, 0),
-                streaming_zip::CompressionMode::Store,
-                &mut entry,
-                true,
-            )?;
-        }
-
-        let written = zip.finish()?.written();
+        let written = zip_stream::tar_to_zip(reader, sender, zip_path)?.written();
         if written != total_len {
             eprintln!("ERROR: ZIP SIZE DOES NOT MATCH EXPECTED SIZE: written={written}, expected={total_len}.");
         }
         Ok(()) as anyhow::Result<()>
     });
 
-    Ok(rouille::Response {
+    let res = rouille::Response {
         status_code: 200,
         headers: vec![("Content-Type".into(), "application/zip ".into())],
         data: rouille::ResponseBody::from_reader_and_size(receiver, total_len as _),
         upgrade: None,
+    };
+    let res = with_file_name(res, &file_name, false);
+    Ok(attach_counted(state, guard, &hash, DownloadKind::Zip, res))
+}
+
+/// Names of the entries in the zip and tar.zst downloads. Names come from the
+/// upload, entries that would land outside of the extracted archive are left
+/// out, and so is everything not in the directory `prefix`.
+fn below_prefix(prefix: &str) -> impl Fn(&str) -> Option<String> + Send + Sync + 'static {
+    let dir = sanitize_entry_path(prefix).map(|dir| dir.trim_end_matches('/').to_string());
+    move |path: &str| {
+        let path = sanitize_entry_path(path).filter(|path| !path.is_empty())?;
+        let dir = dir.as_deref()?;
+        // Whole components, `docs` is not the start of `docs2/`.
+        let below = dir.is_empty()
+            || path
+                .strip_prefix(dir)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+        below.then_some(path)
     }
-    .with_content_disposition_attachment("archive.zip"))
 }
 
-pub fn get_ui_index(
+/// The tar compressed with zstd, streamed as it is compressed. `prefix`
+/// limits it to a directory like for the zip.
+pub fn get_tar_zst(
+    state: &AppState,
+    request: &rouille::Request,
+    id: TarPassword,
+) -> anyhow::Result<Response> {
+    let (hash, mut m) = find_upload(state, &id)?;
+    if !m.finished {
+        return Err(ErrorResponse::conflict("Upload not finished yet").into());
+    }
+
+    let prefix = request.get_param("prefix").unwrap_or_default();
+    if !prefix.is_empty() {
+        // The entries are read one by one then, within the same limits as the zip.
+        tar_index(state, &id, &hash, &mut m)?;
+    }
+    let file_name = match prefix.trim_end_matches('/').rsplit('/').next() {
+        Some(name) if !name.is_empty() => format!("{name}.tar.zst"),
+        _ => "archive.tar.zst".to_string(),
+    };
+
+    let guard = start_download(state, request, &hash)?;
+    let mut reader = open_decrypted(state, &id, &hash)?;
+    let level = state.config().general.zstd_level;
+    let entry_path = below_prefix(&prefix);
+    let (sender, receiver) = common::create_pipe();
+
+    std::thread::spawn(move || {
+        let mut encoder = zstd::Encoder::new(sender, level)?;
+        if prefix.is_empty() {
+            std::io::copy(&mut reader, &mut encoder)?;
+        } else {
+            let mut archive = tar::Archive::new(reader);
+            let mut builder = tar::Builder::new(&mut encoder);
+            for entry in archive.entries_with_seek()? {
+                let mut entry = entry?;
+                let Some(path) = entry_path(&entry.path()?.to_string_lossy()) else {
+                    continue;
+                };
+                let mut header = entry.header().clone();
+                builder.append_data(&mut header, path, &mut entry)?;
+            }
+            builder.finish()?;
+        }
+        encoder.finish()?;
+        Ok(()) as anyhow::Result<()>
+    });
+
+    let res = rouille::Response {
+        status_code: 200,
+        headers: vec![("Content-Type".into(), "application/zstd".into())],
+        data: rouille::ResponseBody::from_reader(receiver),
+        upgrade: None,
+    };
+    let res = with_file_name(res, &file_name, false);
+    Ok(attach_counted(
+        state,
+        guard,
+        &hash,
+        DownloadKind::Decrypted,
+        res,
+    ))
+}
+
+/// `sha256sum` compatible list of the regular files. It takes reading the
+/// whole archive, so the result is kept in the metadata.
+pub fn get_checksums(
+    state: &AppState,
+    request: &rouille::Request,
+    id: TarPassword,
+) -> anyhow::Result<Response> {
+    let (hash, mut m) = find_upload(state, &id)?;
+    if !m.finished {
+        return Err(ErrorResponse::conflict("Upload not finished yet").into());
+    }
+
+    let checksums = match m.checksums(&id) {
+        Some(checksums) => checksums,
+        None => {
+            let _guard = start_download(state, request, &hash)?;
+            let mut archive = tar::Archive::new(open_decrypted(state, &id, &hash)?);
+            let mut checksums = String::new();
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let path = entry.path()?.to_string_lossy().to_string();
+                let mut hasher = Sha256Writer::new(std::io::sink());
+                std::io::copy(&mut entry, &mut hasher)?;
+                checksums += &format!("{}  {}\n", hasher.into_parts().1, path);
+            }
+
+            m.set_checksums(&id, &checksums)?;
+            state.meta.set(&hash, &m)?;
+            checksums
+        }
+    };
+    Ok(Response::text(checksums))
+}
+
+/// Gauges in the Prometheus text format.
+pub fn get_metrics(state: &AppState, _request: &rouille::Request) -> anyhow::Result<Response> {
+    let (downloads, uploads, clients) = state.downloads.counts();
+    let gauges = [
+        (
+            "tarcloud_active_downloads",
+            "Downloads being sent.",
+            downloads,
+        ),
+        (
+            "tarcloud_downloaded_uploads",
+            "Uploads with a running download.",
+            uploads,
+        ),
+        (
+            "tarcloud_downloading_clients",
+            "Client IPs with a running download.",
+            clients,
+        ),
+        (
+            "tarcloud_active_uploads",
+            "Uploads being received.",
+            state.uploads.count(),
+        ),
+    ];
+    let mut text = String::new();
+    for (name, help, value) in gauges {
+        text += &format!("# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n");
+    }
+    Ok(Response::from_data("text/plain; version=0.0.4", text))
+}
+
+/// A padlock, so browsers stop asking for a missing `/favicon.ico`.
+const FAVICON: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 16 16"><rect x="2" y="7" width="12" height="8" rx="1.5" fill="#333"/><path d="M5 7V5a3 3 0 0 1 6 0v2" fill="none" stroke="#333" stroke-width="1.8"/><circle cx="8" cy="11" r="1.3" fill="#fff"/></svg>"##;
+
+pub fn get_favicon(_state: &AppState, _request: &rouille::Request) -> anyhow::Result<Response> {
+    Ok(Response::from_data("image/svg+xml", FAVICON)
+        .with_additional_header("Cache-Control", "public, max-age=86400"))
+}
+
+/// Links pasted without the trailing slash. Codes with a typo arrive here
+/// corrected, so the redirect goes to the canonical code.
+pub fn redirect_index(
+    _state: &AppState,
+    request: &rouille::Request,
+    id: TarPassword,
+) -> anyhow::Result<Response> {
+    let mut location = format!("/{id}/");
+    if !request.raw_query_string().is_empty() {
+        location += "?";
+        location += request.raw_query_string();
+    }
+    Ok(Response::redirect_301(location))
+}
+
+/// Short link made with `POST /api/uploads/{hash}/alias`. Temporary, the
+/// alias goes away with the upload or for another one.
+pub fn get_alias(
     state: &AppState,
     _request: &rouille::Request,
+    slug: String,
+) -> anyhow::Result<Response> {
+    if !crate::routes::valid_slug(&slug) {
+        return Err(ErrorResponse::not_found().into());
+    }
+    let alias = state
+        .meta
+        .get_alias(&slug)?
+        .ok_or_else(ErrorResponse::not_found)?;
+    let hash: TarHash = alias
+        .hash
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid hash in alias {slug}"))?;
+    if state.meta.get(&hash)?.is_none() {
+        // Left over from an upload removed without its alias.
+        state.meta.delete_alias(&slug)?;
+        return Err(ErrorResponse::not_found().into());
+    }
+    Ok(Response::redirect_302(format!("/{}/", alias.code)))
+}
+
+pub fn get_upload_ui(state: &AppState, _request: &rouille::Request) -> anyhow::Result<Response> {
+    let page = UploadPage {
+        // Not the forwarded host, `toc` salts the hash with what it is given.
+        hostname: state.config().general.hostname.clone(),
+        valid_days: state.config().general.default_expire_s / (60 * 60 * 24),
+    };
+    Ok(Response::html(page.render()?))
+}
+
+pub fn get_ui_index(
+    state: &AppState,
+    request: &rouille::Request,
     id: TarPassword,
 ) -> anyhow::Result<Response> {
-    let (reader, meta_data) = match get_decrypted_reader(state, &id) {
-        Ok(Ok(reader)) => reader,
-        Ok(Err(res)) => return Ok(res),
-        Err(e) => return Err(e),
+    let (hash, mut meta_data) = match find_finished(state, &id)? {
+        Ok(found) => found,
+        Err(res) => return Ok(res),
     };
+    let index = tar_index(state, &id, &hash, &mut meta_data)?;
+
+    let mut files = Vec::new();
+    let mut skipped_entries = 0;
+    for entry in index {
+        let path = match sanitize_entry_path(&entry.path) {
+            Some(path) if path.is_empty() => continue,
+            Some(path) => path,
+            None => {
+                skipped_entries += 1;
+                continue;
+            }
+        };
+        let name = Path::new(&path)
+            .file_name()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        files.push(TarFileInfo {
+            is_dir: entry.is_dir,
+            path,
+            name,
+            offset: entry.offset,
+            size: entry.size,
+            human_size: format_bytes(entry.size),
+            m_time: chrono::NaiveDateTime::from_timestamp(entry.mtime as i64, 0),
+        });
+    }
+
+    let total_size = files.iter().map(|f| f.size).sum();
+    let entry_count = files.iter().filter(|f| !f.is_dir).count();
+
+    let sort = IndexSort::parse(
+        request.get_param("sort").as_deref(),
+        request.get_param("order").as_deref(),
+        request.get_param("filter").as_deref(),
+    );
+    sort.apply(&mut files);
+    let hidden_entries = files
+        .len()
+        .saturating_sub(state.config().general.max_index_entries);
+    files.truncate(state.config().general.max_index_entries);
 
-    let mut index = crate::templates::TarIndex {
-        files: Vec::new(),
-        hostname: state.config.general.hostname.clone(),
-        protocol: state.config.general.protocol.clone(),
+    // Whether the recipient got the files is the owner's business.
+    let is_owner = || {
+        crate::routes::request_token(request)
+            .and_then(|token| crate::routes::find_user(state, token))
+            .is_some_and(|user| user.username == meta_data.owner)
+    };
+    let stats = (!state.config().general.private_stats || is_owner())
+        .then(|| meta_data.download_stats.clone());
+
+    let origin = Origin::of_request(&state.config().general, request);
+    let index = crate::templates::TarIndex {
+        hostname: origin.host,
+        protocol: origin.protocol,
         id: id.to_string(),
-        craeted_at: chrono::NaiveDateTime::from_timestamp(meta_data.created_at_unix as i64, 0),
         valid_until: chrono::NaiveDateTime::from_timestamp(meta_data.delete_at_unix as i64, 0),
+        remaining: human_duration(meta_data.delete_at_unix.saturating_sub(now_unix())),
+        total_size,
+        human_total_size: format_bytes(total_size),
+        entry_count,
+        shown_entries: files.len(),
+        hidden_entries,
+        skipped_entries,
+        stats,
+        tree: build_tree(files),
+        sort,
+        uploaded: request.get_param("uploaded").is_some(),
     };
 
-    let mut archive = tar::Archive::new(reader);
-    for entry in archive.entries_with_seek()? {
-        let entry = entry?;
-        let path = entry.path()?;
-        if path.is_dir() {
-            continue;
+    Ok(Response::html(index.render()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::DownloadStats;
+    use common::{BLOCK_SIZE, PAYLOAD_SIZE};
+
+    /// Stores `data` as a finished upload and returns its code.
+    fn store(state: &AppState, data: &[u8]) -> TarPassword {
+        let id = TarPassword::generate();
+        let hash = TarHash::from_tarid(&id, &state.config().general.hostname);
+
+        let encrypted = crate::test_encrypt(id.to_string().as_bytes(), data);
+        std::fs::write(state.meta.file_path(&hash), encrypted).unwrap();
+
+        state.meta.set(&hash, &finished_meta()).unwrap();
+        id
+    }
+
+    fn finished_meta() -> MetaData {
+        MetaData {
+            owner: "test".to_string(),
+            delete_at_unix: now_unix() + 60,
+            created_at_unix: now_unix(),
+            allow_write: false,
+            allow_rewrite: false,
+            finished: true,
+            resumable: false,
+            hostname: None,
+            tar_index: None,
+            sha256: None,
+            checksums: None,
+            plaintext_hash: None,
+            download_stats: DownloadStats::default(),
+            alias: None,
         }
-        let name = &path
-            .file_name()
-            .map(|s| s.to_string_lossy().to_string())
-            .unwrap_or_default()
-            .to_string();
+    }
+
+    #[test]
+    fn test_renamed_hostname() {
+        let state = crate::test_state();
+        let old_code = store(&state, b"old");
 
-        let path = &path.to_string_lossy().to_string();
+        state
+            .config
+            .update(|config| config.general.hostname = "new.example".to_string());
+        let new_code = store(&state, b"new");
+        let request = rouille::Request::fake_http("GET", "/", vec![], vec![]);
+        assert!(get_download(&state, &request, old_code.clone()).is_err());
+
+        state
+            .config
+            .update(|config| config.general.accepted_hostnames = vec!["localhost".to_string()]);
+        let (hash, meta) = find_upload(&state, &old_code).unwrap();
+        assert_eq!(hash, TarHash::from_tarid(&old_code, "localhost"));
+        assert_eq!(meta.hostname.as_deref(), Some("localhost"));
+        assert_eq!(
+            state.meta.get(&hash).unwrap().unwrap().hostname.as_deref(),
+            Some("localhost")
+        );
+        assert!(get_download(&state, &request, old_code).is_ok());
+
+        let (hash, meta) = find_upload(&state, &new_code).unwrap();
+        assert_eq!(hash, TarHash::from_tarid(&new_code, "new.example"));
+        assert_eq!(meta.hostname, None);
+        assert!(find_upload(&state, &TarPassword::generate()).is_err());
+    }
 
-        let offset = entry.raw_file_position();
-        let length = entry.size();
+    #[test]
+    fn test_stream_during_upload() {
+        let state = crate::test_state();
+        let id = TarPassword::generate();
+        let hash = TarHash::from_tarid(&id, &state.config().general.hostname);
 
-        let mtime = entry.header().mtime().unwrap_or(0);
+        let file = File::create(state.meta.file_path(&hash)).unwrap();
+        let mut writer = common::EncryptedWriter::new(file, id.to_string().as_bytes());
+        writer.write_all(&[1; PAYLOAD_SIZE]).unwrap();
+        let mut meta = MetaData {
+            finished: false,
+            ..finished_meta()
+        };
+        state.meta.set(&hash, &meta).unwrap();
+
+        let request = rouille::Request::fake_http("GET", "/", vec![], vec![]);
+        let response = get_stream(&state, &request, id).unwrap();
+        assert_eq!(response.status_code, 200);
+        assert!(response
+            .headers
+            .iter()
+            .any(|(k, v)| k == "Content-Type" && v == "application/x-tar"));
+
+        // The first block can be read before the upload is done.
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut first = vec![0; PAYLOAD_SIZE];
+        reader.read_exact(&mut first).unwrap();
+        assert_eq!(first, vec![1; PAYLOAD_SIZE]);
+
+        writer.write_all(&[2; PAYLOAD_SIZE]).unwrap();
+        drop(writer);
+        meta.finished = true;
+        state.meta.set(&hash, &meta).unwrap();
+
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, vec![2; PAYLOAD_SIZE]);
+    }
 
-        index.files.push(TarFileInfo {
-            is_dir: path.ends_with('/'),
-            path: path.clone(),
-            name: name.clone(),
-            offset,
-            size: length,
-            human_size: human_size(length),
-            m_time: chrono::NaiveDateTime::from_timestamp(mtime as i64, 0),
+    #[test]
+    fn test_status() {
+        let state = crate::test_state();
+        let request = rouille::Request::fake_http("GET", "/", vec![], vec![]);
+        let status = |id: TarPassword| {
+            let response = get_status(&state, &request, id).unwrap();
+            let (mut reader, _) = response.data.into_reader_and_size();
+            serde_json::from_reader::<_, serde_json::Value>(&mut reader).unwrap()
+        };
+
+        assert_eq!(
+            status(TarPassword::generate()),
+            serde_json::json!({ "exists": false })
+        );
+
+        let code = store(&state, b"hello");
+        let json = status(code);
+        assert_eq!(json["exists"], true);
+        assert_eq!(json["finished"], true);
+        assert_eq!(json["size_bytes"], common::encrypted_size(5));
+        assert!(json["expires_at"].as_str().unwrap().ends_with("+00:00"));
+        assert!(json.get("owner").is_none());
+        // Not an archive.
+        assert_eq!(json["file_count"], serde_json::Value::Null);
+
+        let code = store_tar(&state, &[("a.txt", b"hello"), ("dir/b.txt", b"world!")]);
+        let json = status(code);
+        assert_eq!(json["file_count"], 2);
+        assert_eq!(json["content_bytes"], 11);
+    }
+
+    #[test]
+    fn test_download_limits() {
+        let state = crate::test_state();
+        state.config.update(|config| {
+            config.general.max_downloads_per_upload = Some(2);
+            config.general.max_downloads_per_ip = Some(2);
         });
+        let code = store(&state, &[1; 5000]);
+        let other = store(&state, &[2; 5000]);
+        let from = |n: u8| {
+            let addr = std::net::SocketAddr::from(([10, 0, 0, n], 4000));
+            rouille::Request::fake_http_from(addr, "GET", "/", vec![], vec![])
+        };
+        let status = |res: anyhow::Result<Response>| match res {
+            Ok(res) => res.status_code,
+            Err(e) => e.downcast_ref::<ErrorResponse>().unwrap().status(),
+        };
+
+        // Bodies that are not read yet are downloads in progress.
+        let first = get_download(&state, &from(1), code.clone()).unwrap();
+        let second = get_download(&state, &from(2), code.clone()).unwrap();
+        assert_eq!(status(get_download(&state, &from(3), code.clone())), 429);
+
+        let a = get_download(&state, &from(1), other.clone()).unwrap();
+        assert_eq!(status(get_download(&state, &from(1), other.clone())), 429);
+        assert_eq!(state.downloads.counts(), (3, 2, 2));
+
+        // A body read to the end releases its download, a dropped one as well.
+        let (mut reader, _) = first.data.into_reader_and_size();
+        let mut body = vec![];
+        reader.read_to_end(&mut body).unwrap();
+        assert_eq!(body[..5000], [1; 5000]);
+        assert_eq!(status(get_download(&state, &from(1), other.clone())), 200);
+        let third = get_download(&state, &from(3), code.clone()).unwrap();
+        drop((reader, second, third, a));
+        assert_eq!(state.downloads.counts(), (0, 0, 0));
+
+        let request = rouille::Request::fake_http("GET", "/metrics", vec![], vec![]);
+        let (mut reader, _) = get_metrics(&state, &request)
+            .unwrap()
+            .data
+            .into_reader_and_size();
+        let mut text = String::new();
+        reader.read_to_string(&mut text).unwrap();
+        assert!(text.contains("\ntarcloud_active_downloads 0\n"));
     }
 
-    Ok(Response::html(index.render()?))
-}
+    #[test]
+    fn test_favicon() {
+        let state = crate::test_state();
+        let request = rouille::Request::fake_http("GET", "/favicon.ico", vec![], vec![]);
+        let response = crate::handle(&state, &request);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(
+            header(&response, "Content-Type").as_deref(),
+            Some("image/svg+xml")
+        );
+        assert_eq!(
+            header(&response, "Cache-Control").as_deref(),
+            Some("public, max-age=86400")
+        );
+        assert!(body(response).starts_with(b"<svg"));
+    }
+
+    fn header(response: &Response, name: &str) -> Option<String> {
+        response
+            .headers
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.to_string())
+    }
+
+    #[test]
+    fn test_content_etag() {
+        let state = crate::test_state();
+        let headers = vec![
+            ("Authorization".to_string(), "Bearer secret".to_string()),
+            ("Accept".to_string(), "application/json".to_string()),
+        ];
+        let request = rouille::Request::fake_http("POST", "/upload", headers, vec![3; 2000]);
+        let (mut reader, _) = crate::routes::post_upload(&state, &request)
+            .unwrap()
+            .data
+            .into_reader_and_size();
+        let json: serde_json::Value = serde_json::from_reader(&mut reader).unwrap();
+        let hash: TarHash = json["hash"].as_str().unwrap().parse().unwrap();
+        let code = TarPassword::parse(json["code"].as_str().unwrap()).unwrap();
+
+        let mut hasher = crate::util::Sha256Writer::new(std::io::sink());
+        hasher
+            .write_all(&std::fs::read(state.meta.file_path(&hash)).unwrap())
+            .unwrap();
+        let etag = format!("\"sha256-{}\"", hasher.into_parts().1);
+
+        let get = |headers: Vec<(&str, &str)>| {
+            let headers = headers
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            let request = rouille::Request::fake_http("GET", "/", headers, vec![]);
+            get_download_raw(&state, &request, hash.clone()).unwrap()
+        };
+        assert_eq!(header(&get(vec![]), "ETag"), Some(etag.clone()));
+        assert_eq!(get(vec![("If-None-Match", &etag)]).status_code, 304);
+        assert_eq!(get(vec![("If-None-Match", "\"other\"")]).status_code, 200);
+        assert_eq!(get(vec![("If-Match", "\"other\"")]).status_code, 412);
+        let range = ("Range", "bytes=0-9");
+        assert_eq!(get(vec![range, ("If-Range", &etag)]).status_code, 206);
+        assert_eq!(get(vec![range, ("If-Range", "\"1\"")]).status_code, 200);
+
+        let request = rouille::Request::fake_http("GET", "/", vec![], vec![]);
+        let response = get_download(&state, &request, code).unwrap();
+        assert_eq!(header(&response, "ETag"), Some(etag));
+
+        // Uploads from before the hash was recorded keep their time based tag.
+        let code = store(&state, b"legacy");
+        let hash = TarHash::from_tarid(&code, &state.config().general.hostname);
+        let created = state.meta.get(&hash).unwrap().unwrap().created_at_unix;
+        let response = get_download(&state, &request, code).unwrap();
+        assert_eq!(header(&response, "ETag"), Some(format!("\"{created}\"")));
+    }
+
+    #[test]
+    fn test_ws_download() {
+        let state = crate::test_state();
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let code = store(&state, &data);
+
+        let server = rouille::Server::new("127.0.0.1:0", {
+            let code = code.clone();
+            move |request| ws_download(&state, request, code.clone()).unwrap()
+        })
+        .unwrap();
+        let addr = server.server_addr();
+        let (handle, stop) = server.stoppable();
+
+        let (mut ws, _) = tungstenite::connect(format!("ws://{addr}/{code}/ws").as_str()).unwrap();
+
+        let info: serde_json::Value = match ws.read_message().unwrap() {
+            tungstenite::Message::Text(t) => serde_json::from_str(&t).unwrap(),
+            m => panic!("unexpected {m:?}"),
+        };
+        assert_eq!(info["finished"], true);
+        assert_eq!(info["size"], 200_000);
+
+        let mut received = vec![];
+        loop {
+            match ws.read_message().unwrap() {
+                tungstenite::Message::Binary(b) => {
+                    assert!(b.len() <= WS_FRAME_SIZE);
+                    received.extend(b);
+                }
+                tungstenite::Message::Text(t) => {
+                    assert_eq!(t, "done");
+                    break;
+                }
+                m => panic!("unexpected {m:?}"),
+            }
+        }
+        assert_eq!(received, data);
+
+        let _ = stop.send(());
+        handle.join().unwrap();
+    }
 
-fn human_size(mut size: u64) -> String {
-    let prefix = ["b", "K", "M", "G", "T", "P", "E", "Z", "Y"];
-    for i in prefix {
-        if size < 4096 {
-            return format!("{size} {i}");
+    fn store_tar(state: &AppState, files: &[(&str, &[u8])]) -> TarPassword {
+        let mut tar = tar::Builder::new(vec![]);
+        for (path, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, path, *data).unwrap();
         }
-        size /= 1024;
+        store(state, &tar.into_inner().unwrap())
+    }
+
+    #[test]
+    fn test_tar_index_cache() {
+        let state = crate::test_state();
+        let code = store_tar(
+            &state,
+            &[("docs/secret.txt", b"hello"), ("b.bin", &[1; 700])],
+        );
+        let hash = TarHash::from_tarid(&code, "localhost");
+        let request = rouille::Request::fake_http("GET", "/", vec![], vec![]);
+
+        let response = get_ui_index(&state, &request, code.clone()).unwrap();
+        assert_eq!(response.status_code, 200);
+
+        let meta = state.meta.get(&hash).unwrap().unwrap();
+        let cached = meta.tar_index.as_ref().unwrap();
+        // "secret" in hex, the names are not stored in the clear.
+        assert!(!cached.contains("736563726574"));
+        let index = meta.tar_index(&code).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index[0].path, "docs/secret.txt");
+        assert_eq!((index[0].size, index[0].offset), (5, 512));
+        assert_eq!((index[1].size, index[1].offset), (700, 1536));
+        assert!(meta.tar_index(&TarPassword::generate()).is_none());
+
+        // The second listing and the zip size don't read the archive.
+        let path = state.meta.file_path(&hash);
+        let archive = std::fs::read(&path).unwrap();
+        std::fs::write(&path, b"").unwrap();
+        assert!(get_ui_index(&state, &request, code.clone()).is_ok());
+
+        std::fs::write(&path, archive).unwrap();
+        let response = get_tar_to_zip(&state, &request, code).unwrap();
+        let (mut reader, size) = response.data.into_reader_and_size();
+        let mut zip = vec![];
+        reader.read_to_end(&mut zip).unwrap();
+        assert_eq!(Some(zip.len()), size);
+    }
+
+    fn body(response: Response) -> Vec<u8> {
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_archive_limits() {
+        let state = crate::test_state();
+        state.config.update(|config| {
+            config.general.max_index_entries = 10;
+            config.general.max_archive_entries = 50;
+            config.general.max_path_length = 120;
+        });
+        let request = rouille::Request::fake_http("GET", "/", vec![], vec![]);
+        let status = |result: anyhow::Result<Response>| match result {
+            Ok(response) => response.status_code,
+            Err(e) => e.downcast_ref::<ErrorResponse>().unwrap().status(),
+        };
+
+        let names: Vec<String> = (0..51).map(|i| format!("f{i:02}")).collect();
+        let files: Vec<(&str, &[u8])> = names.iter().map(|n| (n.as_str(), &b""[..])).collect();
+
+        // At the limit the page is cut off, the zip still has everything.
+        let code = store_tar(&state, &files[..50]);
+        let html = body(get_ui_index(&state, &request, code.clone()).unwrap());
+        let html = String::from_utf8(html).unwrap();
+        assert_eq!(html.matches("&amp;name=").count(), 10);
+        assert!(html.contains("die ersten 10 Einträge angezeigt, 40 weitere"));
+        assert_eq!(status(get_tar_to_zip(&state, &request, code)), 200);
+
+        let code = store_tar(&state, &files);
+        assert_eq!(status(get_ui_index(&state, &request, code.clone())), 413);
+        assert_eq!(status(get_tar_to_zip(&state, &request, code)), 413);
+
+        let long = "d".repeat(120);
+        let code = store_tar(&state, &[(long.as_str(), b"")]);
+        assert_eq!(status(get_ui_index(&state, &request, code.clone())), 200);
+        let long = long + "x";
+        let code = store_tar(&state, &[(long.as_str(), b"")]);
+        assert_eq!(status(get_tar_to_zip(&state, &request, code)), 413);
+    }
+
+    #[test]
+    fn test_hostile_entry_names() {
+        let state = crate::test_state();
+        let request = rouille::Request::fake_http("GET", "/", vec![], vec![]);
+        // `append_data` refuses these, the header is filled in by hand.
+        let mut tar = tar::Builder::new(vec![]);
+        for name in ["../../evil.txt", "C:\\x.txt", "/abs/<b>.txt", "a & b#1.txt"] {
+            let mut header = tar::Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(5);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append(&header, &b"hello"[..]).unwrap();
+        }
+        let code = store(&state, &tar.into_inner().unwrap());
+
+        let html = body(get_ui_index(&state, &request, code.clone()).unwrap());
+        let html = String::from_utf8(html).unwrap();
+        assert!(html.contains("1 Einträge mit ungültigem Pfad"));
+        assert!(!html.contains("evil"));
+        assert!(html.contains("&lt;b&gt;.txt") && !html.contains("<b>"));
+        assert!(html.contains("&amp;name=a%20%26%20b%231.txt\""));
+        assert!(html.contains("&amp;name=x.txt\""));
+
+        let response = get_tar_to_zip(&state, &request, code).unwrap();
+        let (mut reader, size) = response.data.into_reader_and_size();
+        let mut zip = vec![];
+        reader.read_to_end(&mut zip).unwrap();
+        assert_eq!(Some(zip.len()), size);
+        let contains = |name: &str| zip.windows(name.len()).any(|w| w == name.as_bytes());
+        assert!(contains("abs/<b>.txt") && contains("x.txt") && contains("a & b#1.txt"));
+        assert!(!contains("evil") && !contains("C:") && !contains("/abs"));
+    }
+
+    #[test]
+    fn test_download_stats() {
+        let state = crate::test_state();
+        let code = store_tar(&state, &[("a.txt", b"hello")]);
+        let hash = TarHash::from_tarid(&code, "localhost");
+        let get = |url: &str, headers: Vec<(String, String)>| {
+            rouille::Request::fake_http("GET", url, headers, vec![])
+        };
+        let stats = || state.meta.get(&hash).unwrap().unwrap().download_stats;
+
+        body(get_download(&state, &get("/", vec![]), code.clone()).unwrap());
+        body(get_download(&state, &get("/?offset=512&length=5", vec![]), code.clone()).unwrap());
+        body(get_tar_to_zip(&state, &get("/", vec![]), code.clone()).unwrap());
+        body(get_download_raw(&state, &get("/", vec![]), hash.clone()).unwrap());
+        assert_eq!(
+            stats(),
+            DownloadStats {
+                raw: 1,
+                decrypted: 1,
+                zip: 1,
+                single_file: 1,
+                last_download_at_unix: stats().last_download_at_unix,
+            }
+        );
+        assert!(stats().last_download_at_unix.unwrap() + 60 > now_unix());
+
+        // Aborted and partial downloads don't count.
+        drop(get_download(&state, &get("/", vec![]), code.clone()).unwrap());
+        let range = vec![("Range".to_string(), "bytes=0-99".to_string())];
+        let response = get_download(&state, &get("/", range), code.clone()).unwrap();
+        assert_eq!(response.status_code, 206);
+        body(response);
+        assert_eq!(stats().total(), 4);
+
+        // Only the owner sees them, unless they are public.
+        let page = |headers: Vec<(String, String)>| {
+            let html = body(get_ui_index(&state, &get("/", headers), code.clone()).unwrap());
+            String::from_utf8(html).unwrap()
+        };
+        let token = |token: &str| vec![("Authorization".to_string(), format!("Bearer {token}"))];
+        assert!(page(token("secret")).contains("4 Mal heruntergeladen (1 TAR, 1 ZIP"));
+        assert!(!page(vec![]).contains("heruntergeladen"));
+        assert!(!page(token("wrong")).contains("heruntergeladen"));
+        state
+            .config
+            .update(|config| config.general.private_stats = false);
+        assert!(page(vec![]).contains("4 Mal heruntergeladen"));
+    }
+
+    #[test]
+    fn test_checksums() {
+        let state = crate::test_state();
+        let code = store_tar(&state, &[("docs/a.txt", b"hello"), ("b.txt", b"")]);
+        let hash = TarHash::from_tarid(&code, "localhost");
+        let request = rouille::Request::fake_http("GET", "/", vec![], vec![]);
+        let checksums = || {
+            let response = get_checksums(&state, &request, code.clone()).unwrap();
+            let (mut reader, _) = response.data.into_reader_and_size();
+            let mut text = String::new();
+            reader.read_to_string(&mut text).unwrap();
+            text
+        };
+
+        let expected = "\
+            2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824  docs/a.txt\n\
+            e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  b.txt\n";
+        assert_eq!(checksums(), expected);
+
+        // Served from the metadata afterwards.
+        std::fs::write(state.meta.file_path(&hash), b"").unwrap();
+        assert_eq!(checksums(), expected);
+
+        let mut meta = state.meta.get(&hash).unwrap().unwrap();
+        meta.finished = false;
+        state.meta.set(&hash, &meta).unwrap();
+        let error = get_checksums(&state, &request, code).unwrap_err();
+        assert_eq!(error.downcast_ref::<ErrorResponse>().unwrap().status(), 409);
+    }
+
+    #[test]
+    fn test_unfinished_range() {
+        let state = crate::test_state();
+        let data: Vec<u8> = (0..10 * PAYLOAD_SIZE).map(|i| (i % 251) as u8).collect();
+        let code = store(&state, &data);
+        let hash = TarHash::from_tarid(&code, "localhost");
+
+        // Four blocks and a part of the fifth are written so far.
+        let path = state.meta.file_path(&hash);
+        let mut encrypted = std::fs::read(&path).unwrap();
+        encrypted.truncate(4 * BLOCK_SIZE + 100);
+        std::fs::write(&path, encrypted).unwrap();
+        let mut meta = state.meta.get(&hash).unwrap().unwrap();
+        meta.finished = false;
+        state.meta.set(&hash, &meta).unwrap();
+
+        let get = |query: &str, headers: &[(&str, &str)]| {
+            let headers = headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            let request = rouille::Request::fake_http("GET", format!("/{query}"), headers, vec![]);
+            get_download(&state, &request, code.clone()).unwrap()
+        };
+
+        let response = get("?offset=100&length=1000", &[]);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(body(response), &data[100..1100]);
+
+        let response = get("", &[("Range", "bytes=2000-2047")]);
+        assert_eq!(response.status_code, 206);
+        assert_eq!(body(response), &data[2000..2048]);
+
+        // The fifth block is not complete yet.
+        let response = get("?offset=2000&length=100", &[]);
+        assert_eq!(response.status_code, 503);
+        assert!(response
+            .headers
+            .iter()
+            .any(|(k, v)| k == "Retry-After" && v == "5"));
+        assert_eq!(get("?offset=100", &[]).status_code, 417);
+    }
+
+    #[test]
+    fn test_below_prefix() {
+        let all = below_prefix("");
+        assert_eq!(all("./a.txt").as_deref(), Some("a.txt"));
+        assert_eq!(all("../a.txt"), None);
+        assert_eq!(all("./"), None);
+
+        let docs = below_prefix("docs/");
+        assert_eq!(docs("docs/").as_deref(), Some("docs/"));
+        assert_eq!(docs("./docs/a.txt").as_deref(), Some("docs/a.txt"));
+        assert_eq!(docs("docs2/a.txt"), None);
+        assert_eq!(docs("docs.txt"), None);
+        assert_eq!(
+            below_prefix("./docs")("docs/a.txt").as_deref(),
+            Some("docs/a.txt")
+        );
+        assert_eq!(below_prefix("../docs")("docs/a.txt"), None);
+    }
+
+    #[test]
+    fn test_download_name() {
+        let state = crate::test_state();
+        let code = store(&state, b"hello");
+        let get = |query: &str| {
+            let request = rouille::Request::fake_http("GET", format!("/{query}"), vec![], vec![]);
+            let response = get_download(&state, &request, code.clone()).unwrap();
+            (
+                header(&response, "Content-Type").unwrap(),
+                header(&response, "Content-Disposition").unwrap(),
+            )
+        };
+
+        let (_, disposition) = get("?name=a%22%0D%0ASet-Cookie:%20x.bin");
+        assert_eq!(
+            disposition,
+            "attachment; filename=\"a_Set-Cookie: x.bin\"; filename*=UTF-8''a%22Set-Cookie%3A%20x.bin"
+        );
+        let (content_type, disposition) = get("?name=notes.txt&disposition=inline");
+        assert_eq!(content_type, "text/plain; charset=utf-8");
+        assert_eq!(disposition, "inline; filename=\"notes.txt\"");
+    }
+
+    #[test]
+    fn test_tar_zst() {
+        let state = crate::test_state();
+        let code = store_tar(
+            &state,
+            &[
+                ("docs/a.txt", b"hello"),
+                ("docs/b.txt", b""),
+                ("docs2/c.txt", b"x"),
+                ("c.bin", &[1; 700]),
+            ],
+        );
+        let entries = |query: &str| {
+            let request = rouille::Request::fake_http("GET", format!("/{query}"), vec![], vec![]);
+            let response = get_tar_zst(&state, &request, code.clone()).unwrap();
+            assert!(response
+                .headers
+                .iter()
+                .any(|(k, v)| k == "Content-Disposition" && v.contains(".tar.zst")));
+            let tar = zstd::decode_all(&body(response)[..]).unwrap();
+            let mut archive = tar::Archive::new(&tar[..]);
+            let mut entries = vec![];
+            for entry in archive.entries().unwrap() {
+                let mut entry = entry.unwrap();
+                let path = entry.path().unwrap().to_string_lossy().to_string();
+                let mut data = vec![];
+                entry.read_to_end(&mut data).unwrap();
+                entries.push((path, data));
+            }
+            entries
+        };
+
+        let all = entries("");
+        assert_eq!(all.len(), 4);
+        assert_eq!(all[0], ("docs/a.txt".to_string(), b"hello".to_vec()));
+        assert_eq!(all[3], ("c.bin".to_string(), vec![1; 700]));
+
+        let docs = entries("?prefix=docs");
+        let paths: Vec<&str> = docs.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, ["docs/a.txt", "docs/b.txt"]);
+        assert_eq!(docs[0].1, b"hello");
+        assert_eq!(entries("?prefix=./docs/").len(), 2);
+        assert_eq!(entries("?prefix=doc").len(), 0);
+
+        let hash = TarHash::from_tarid(&code, "localhost");
+        let mut meta = state.meta.get(&hash).unwrap().unwrap();
+        meta.finished = false;
+        state.meta.set(&hash, &meta).unwrap();
+        let request = rouille::Request::fake_http("GET", "/", vec![], vec![]);
+        let error = get_tar_zst(&state, &request, code).unwrap_err();
+        assert_eq!(error.downcast_ref::<ErrorResponse>().unwrap().status(), 409);
+    }
+
+    #[test]
+    fn test_legacy_age_upload() {
+        // Made by the old server's age path, `hello.txt` and `docs/readme.md`.
+        let fixture = include_bytes!("../../testdata/legacy.tar.age");
+        let state = crate::test_state();
+        let code = TarPassword::parse("0005-abandon-ability-able-about").unwrap();
+        let hash = TarHash::from_tarid(&code, "localhost");
+        std::fs::write(state.meta.file_path(&hash), fixture).unwrap();
+        state.meta.set(&hash, &finished_meta()).unwrap();
+
+        let request = |url: &str, accept: &str| {
+            let headers = vec![("Accept".to_string(), accept.to_string())];
+            crate::handle(
+                &state,
+                &rouille::Request::fake_http("GET", url, headers, vec![]),
+            )
+        };
+        let response = request(&format!("/{code}/"), "text/html");
+        assert_eq!(response.status_code, 200);
+        let html = String::from_utf8(body(response)).unwrap();
+        assert!(html.contains("hello.txt") && html.contains("readme.md"));
+
+        let response = request(&format!("/{code}/pipe"), "*/*");
+        assert_eq!(response.status_code, 200);
+        let mut archive = tar::Archive::new(std::io::Cursor::new(body(response)));
+        let mut entries = archive.entries().unwrap().map(|e| e.unwrap());
+        let mut hello = entries.next().unwrap();
+        assert_eq!(hello.path().unwrap().to_str(), Some("hello.txt"));
+        let mut text = String::new();
+        hello.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "hello from the old server\n");
+
+        let response = request(&format!("/{code}/sha256"), "*/*");
+        assert!(String::from_utf8(body(response))
+            .unwrap()
+            .contains("  docs/readme.md\n"));
+
+        // Raw downloads are passed on as stored.
+        let response = request(&format!("/raw/{hash}/"), "*/*");
+        assert_eq!(header(&response, "X-Toc-Format").as_deref(), Some("age"));
+        assert_eq!(body(response), fixture);
+        let code = store(&state, b"new");
+        let hash = TarHash::from_tarid(&code, "localhost");
+        let response = request(&format!("/raw/{hash}/"), "*/*");
+        assert_eq!(header(&response, "X-Toc-Format"), None);
+    }
+
+    #[cfg(not(feature = "thumbnail"))]
+    fn thumbnail(state: &AppState, code: TarPassword) -> anyhow::Result<(String, Vec<u8>)> {
+        let request = rouille::Request::fake_http("GET", "/thumbnail", vec![], vec![]);
+        let response = get_thumbnail(state, &request, code)?;
+        let content_type = response
+            .headers
+            .iter()
+            .find(|(k, _)| k == "Content-Type")
+            .unwrap()
+            .1
+            .to_string();
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut body = vec![];
+        reader.read_to_end(&mut body)?;
+        Ok((content_type, body))
+    }
+
+    #[test]
+    #[cfg(not(feature = "thumbnail"))]
+    fn test_thumbnail() {
+        let state = crate::test_state();
+
+        let code = store_tar(&state, &[("notes.txt", b"text"), ("photos/a.PNG", b"png!")]);
+        let (content_type, body) = thumbnail(&state, code).unwrap();
+        assert_eq!(content_type, "image/png");
+        assert_eq!(body, b"png!");
+
+        let code = store_tar(
+            &state,
+            &[
+                ("a.jpg", b"a"),
+                ("Preview.jpg", b"preview"),
+                ("b.gif", b"b"),
+            ],
+        );
+        let (content_type, body) = thumbnail(&state, code).unwrap();
+        assert_eq!(content_type, "image/jpeg");
+        assert_eq!(body, b"preview");
+
+        let code = store_tar(&state, &[("a.jpg", b"a"), ("b.jpg", b"b")]);
+        assert!(thumbnail(&state, code).is_err());
+
+        let code = store_tar(&state, &[("notes.txt", b"text")]);
+        let err = thumbnail(&state, code).unwrap_err();
+        assert!(err.downcast_ref::<ErrorResponse>().is_some());
+
+        // Archives too large to list aren't searched either.
+        let code = store_tar(
+            &state,
+            &[("a.txt", b"a"), ("b.txt", b"b"), ("cover.png", b"c")],
+        );
+        state
+            .config
+            .update(|config| config.general.max_archive_entries = 2);
+        let err = thumbnail(&state, code).unwrap_err();
+        assert_eq!(err.downcast_ref::<ErrorResponse>().unwrap().status(), 413);
     }
-    format!("{size}x∞")
 }
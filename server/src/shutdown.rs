@@ -0,0 +1,189 @@
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{config::SharedConfig, util::now_unix, AppState};
+
+/// How often `drain` looks whether the transfers are done.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Set once the server is asked to stop. Long sleeps wait on it, so they
+/// end as soon as that happens.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Shutdown {
+    pub fn request(&self) {
+        let (requested, changed) = &*self.requested;
+        *requested.lock().unwrap() = true;
+        changed.notify_all();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.requested.0.lock().unwrap()
+    }
+
+    /// Sleeps for `duration`, `true` if it ended early for a shutdown.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let (requested, changed) = &*self.requested;
+        let requested = requested.lock().unwrap();
+        let (requested, _) = changed
+            .wait_timeout_while(requested, duration, |requested| !*requested)
+            .unwrap();
+        *requested
+    }
+
+    pub fn wait(&self) {
+        let (requested, changed) = &*self.requested;
+        let requested = requested.lock().unwrap();
+        let _requested = changed.wait_while(requested, |requested| !*requested);
+    }
+}
+
+/// Turns SIGTERM and SIGINT into a shutdown, a second one exits right away.
+/// SIGHUP reloads the config.
+///
+/// The signals are blocked and picked up by a thread of their own, so this
+/// has to run before any other thread is started, they inherit the mask.
+pub fn handle_signals(shutdown: &Shutdown, config: &SharedConfig) -> std::io::Result<()> {
+    // SAFETY: the set is initialized by `sigemptyset` before it is used.
+    let set = unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        set
+    };
+    // SAFETY: `set` is a valid signal set, the old mask isn't needed.
+    let result = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+    if result != 0 {
+        return Err(std::io::Error::from_raw_os_error(result));
+    }
+
+    let (shutdown, config) = (shutdown.clone(), config.clone());
+    std::thread::spawn(move || loop {
+        let mut signal = 0;
+        // SAFETY: both pointers are valid for the call.
+        if unsafe { libc::sigwait(&set, &mut signal) } != 0 {
+            continue;
+        }
+        if signal == libc::SIGHUP {
+            let _ = crate::reload_config(&config);
+            continue;
+        }
+        if shutdown.is_requested() {
+            println!("=== Received signal {signal} again, exiting");
+            std::process::exit(1);
+        }
+        println!("=== Received signal {signal}, shutting down");
+        shutdown.request();
+    });
+    Ok(())
+}
+
+/// Waits up to `grace` for running uploads and downloads to finish.
+///
+/// Uploads still running after that would claim to be in progress until
+/// they expire, so they are set to expire now and the next GC removes them.
+/// Resumable uploads are left alone, the client can continue them after
+/// the restart. Returns how many uploads were set to expire.
+pub fn drain(state: &AppState, grace: Duration) -> usize {
+    let deadline = Instant::now() + grace;
+    let busy = || state.uploads.count() > 0 || state.downloads.counts().0 > 0;
+    while busy() && Instant::now() < deadline {
+        std::thread::sleep(DRAIN_POLL_INTERVAL);
+    }
+
+    let mut expired = 0;
+    for hash in state.uploads.active() {
+        let mut meta = match state.meta.get(&hash) {
+            Ok(Some(meta)) if !meta.finished && !meta.resumable => meta,
+            _ => continue,
+        };
+        meta.delete_at_unix = now_unix();
+        match state.meta.set(&hash, &meta) {
+            Ok(()) => expired += 1,
+            Err(e) => println!("Error expiring {}: {:?}", hash, e),
+        }
+    }
+    expired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{TarHash, TarPassword};
+
+    #[test]
+    fn test_sleep() {
+        let shutdown = Shutdown::default();
+        assert!(!shutdown.sleep(Duration::from_millis(1)));
+
+        let started = Instant::now();
+        std::thread::spawn({
+            let shutdown = shutdown.clone();
+            move || {
+                std::thread::sleep(Duration::from_millis(50));
+                shutdown.request();
+            }
+        });
+        assert!(shutdown.sleep(Duration::from_secs(60)));
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        // Already requested, no waiting at all.
+        assert!(shutdown.sleep(Duration::from_secs(60)));
+        shutdown.wait();
+    }
+
+    #[test]
+    fn test_drain() {
+        let state = crate::test_state();
+        let user = state.config().users[0].clone();
+        let [done, stuck, resumable] = [(); 3].map(|_| {
+            let hash = TarHash::from_tarid(&TarPassword::generate(), "localhost");
+            state
+                .meta
+                .set(&hash, &crate::routes::upload_meta(&user, 60))
+                .unwrap();
+            hash
+        });
+        let mut meta = state.meta.get(&resumable).unwrap().unwrap();
+        meta.resumable = true;
+        state.meta.set(&resumable, &meta).unwrap();
+
+        let ip = "127.0.0.1".parse().unwrap();
+        let download = state
+            .downloads
+            .start(&state.config().general, &done, ip)
+            .unwrap();
+        let upload = state.uploads.start(&done);
+        let _stuck = state.uploads.start(&stuck);
+        let _resumable = state.uploads.start(&resumable);
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            drop(upload);
+            drop(download);
+        });
+
+        let started = Instant::now();
+        assert_eq!(drain(&state, Duration::from_millis(300)), 1);
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert_eq!(state.downloads.counts().0, 0);
+        assert_eq!(state.uploads.count(), 2);
+
+        let expires = |hash: &TarHash| state.meta.get(hash).unwrap().unwrap().delete_at_unix;
+        assert!(expires(&stuck) <= now_unix());
+        assert!(expires(&done) > now_unix());
+        assert!(expires(&resumable) > now_unix());
+
+        // Nothing running, nothing to wait for.
+        let state = crate::test_state();
+        let started = Instant::now();
+        assert_eq!(drain(&state, Duration::from_secs(60)), 0);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,53 @@
+use std::{fs::File, path::PathBuf};
+
+use common::TarHash;
+
+use super::{BlobReader, BlobWriter, Storage};
+use crate::meta::MetaStore;
+
+/// Blobs as files next to the metadata, see `MetaStore::file_path`.
+#[derive(Clone)]
+pub struct FsStorage {
+    meta: MetaStore,
+}
+
+impl FsStorage {
+    pub fn new(meta: MetaStore) -> Self {
+        Self { meta }
+    }
+}
+
+impl BlobWriter for File {
+    fn finish(self: Box<Self>) -> std::io::Result<()> {
+        self.sync_all()
+    }
+}
+
+impl Storage for FsStorage {
+    fn create_writer(&self, id: &TarHash) -> std::io::Result<Box<dyn BlobWriter>> {
+        Ok(Box::new(File::create(self.meta.file_path(id))?))
+    }
+
+    fn open_reader(&self, id: &TarHash) -> std::io::Result<Box<dyn BlobReader>> {
+        Ok(Box::new(File::open(self.meta.file_path(id))?))
+    }
+
+    fn delete(&self, id: &TarHash) -> std::io::Result<()> {
+        match std::fs::remove_file(self.meta.file_path(id)) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn size(&self, id: &TarHash) -> std::io::Result<u64> {
+        Ok(std::fs::metadata(self.meta.file_path(id))?.len())
+    }
+
+    fn exists(&self, id: &TarHash) -> std::io::Result<bool> {
+        Ok(self.meta.file_path(id).exists())
+    }
+
+    fn local_path(&self, id: &TarHash) -> Option<PathBuf> {
+        Some(self.meta.file_path(id))
+    }
+}
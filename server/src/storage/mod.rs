@@ -0,0 +1,60 @@
+use std::{
+    io::{Read, Seek, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use common::TarHash;
+
+use crate::{config::StorageConfig, meta::MetaStore, responses::ErrorResponse};
+
+mod fs;
+#[cfg(feature = "s3")]
+mod s3;
+
+pub use fs::FsStorage;
+#[cfg(feature = "s3")]
+pub use s3::S3Storage;
+
+/// A blob being written, it is only complete once `finish` returned.
+pub trait BlobWriter: Write + Send {
+    fn finish(self: Box<Self>) -> std::io::Result<()>;
+}
+
+pub trait BlobReader: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> BlobReader for T {}
+
+/// Where the encrypted uploads are kept. Metadata stays in the `MetaStore`.
+pub trait Storage: Send + Sync {
+    /// Creates the blob or replaces it. Readers may see the partial data
+    /// before `finish` only with the filesystem storage.
+    fn create_writer(&self, id: &TarHash) -> std::io::Result<Box<dyn BlobWriter>>;
+    /// Reads from the start, seeking reads other ranges.
+    fn open_reader(&self, id: &TarHash) -> std::io::Result<Box<dyn BlobReader>>;
+    fn delete(&self, id: &TarHash) -> std::io::Result<()>;
+    fn size(&self, id: &TarHash) -> std::io::Result<u64>;
+    fn exists(&self, id: &TarHash) -> std::io::Result<bool>;
+    /// The blob as a file on disk. Resumable uploads, rewrites and downloads
+    /// of unfinished uploads work on it, other storages don't support them.
+    fn local_path(&self, id: &TarHash) -> Option<PathBuf>;
+}
+
+pub fn from_config(config: &StorageConfig, meta: &MetaStore) -> anyhow::Result<Arc<dyn Storage>> {
+    match config {
+        StorageConfig::Fs => Ok(Arc::new(FsStorage::new(meta.clone()))),
+        #[cfg(feature = "s3")]
+        StorageConfig::S3(s3) => Ok(Arc::new(S3Storage::new(s3.clone()))),
+        #[cfg(not(feature = "s3"))]
+        StorageConfig::S3(_) => {
+            anyhow::bail!("[storage] is s3, but the server was built without the `s3` feature")
+        }
+    }
+}
+
+/// For the features that need `Storage::local_path`.
+pub fn local_path(storage: &dyn Storage, id: &TarHash) -> anyhow::Result<PathBuf> {
+    storage.local_path(id).ok_or_else(|| {
+        ErrorResponse::not_implemented("Not supported with this storage backend").into()
+    })
+}
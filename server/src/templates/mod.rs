@@ -1,16 +1,195 @@
 use askama::Template;
+use common::format_bytes;
+use std::collections::BTreeMap;
+
+use crate::{meta::DownloadStats, util::glob_match};
 
 #[derive(Template)]
 #[template(path = "tar_index.html")]
 pub struct TarIndex {
     pub valid_until: chrono::NaiveDateTime,
-    pub craeted_at: chrono::NaiveDateTime,
-    pub files: Vec<TarFileInfo>,
+    pub remaining: String,
+    pub total_size: u64,
+    pub human_total_size: String,
+    pub entry_count: usize,
+    /// Rows listed, the rest is cut off by `max_index_entries`.
+    pub shown_entries: usize,
+    pub hidden_entries: usize,
+    /// Entries with paths leading out of the archive, not listed.
+    pub skipped_entries: usize,
+    /// Only for the owner, unless `general.private_stats` is off.
+    pub stats: Option<DownloadStats>,
+    pub tree: Vec<TarTreeNode>,
+    pub sort: IndexSort,
+    /// Set after a browser upload redirected here, shows the code prominently.
+    pub uploaded: bool,
     pub id: String,
     pub hostname: String,
     pub protocol: String,
 }
 
+impl TarIndex {
+    /// Link for a column header, toggles the order if already sorted by `key`.
+    fn sort_link(&self, key: &str) -> String {
+        let order = if self.sort.key == SortKey::parse(key) && !self.sort.desc {
+            "desc"
+        } else {
+            "asc"
+        };
+        let mut link = format!("?sort={key}&order={order}");
+        if !self.sort.filter.is_empty() {
+            link += "&filter=";
+            link += &urlencode(&self.sort.filter);
+        }
+        link
+    }
+
+    /// Download of a single file, the name comes from the upload.
+    fn file_link(&self, file: &TarFileInfo) -> String {
+        format!(
+            "pipe?offset={}&length={}&name={}",
+            file.offset,
+            file.size,
+            urlencode(&file.name)
+        )
+    }
+
+    fn last_download(&self) -> String {
+        match self.stats.as_ref().and_then(|s| s.last_download_at_unix) {
+            Some(unix) => format!(
+                "zuletzt {} UTC",
+                chrono::NaiveDateTime::from_timestamp(unix as i64, 0).format("%Y-%m-%d %H:%M")
+            ),
+            None => "noch nie".to_string(),
+        }
+    }
+
+    fn sort_marker(&self, key: &str) -> &'static str {
+        match (self.sort.key == SortKey::parse(key), self.sort.desc) {
+            (false, _) => "",
+            (true, false) => "▲",
+            (true, true) => "▼",
+        }
+    }
+}
+
+fn urlencode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    Size,
+    MTime,
+}
+
+impl SortKey {
+    fn parse(key: &str) -> Option<Self> {
+        match key {
+            "name" => Some(SortKey::Name),
+            "size" => Some(SortKey::Size),
+            "mtime" => Some(SortKey::MTime),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::MTime => "mtime",
+        }
+    }
+}
+
+/// Sorting and filtering of the index, from the `sort`, `order` and `filter` query parameters.
+/// Invalid values fall back to the archive order.
+#[derive(Clone, Debug, Default)]
+pub struct IndexSort {
+    pub key: Option<SortKey>,
+    pub desc: bool,
+    pub filter: String,
+}
+
+impl IndexSort {
+    pub fn parse(sort: Option<&str>, order: Option<&str>, filter: Option<&str>) -> Self {
+        let key = sort.and_then(SortKey::parse);
+        Self {
+            key,
+            desc: key.is_some() && order == Some("desc"),
+            filter: filter.unwrap_or_default().trim().to_string(),
+        }
+    }
+
+    /// Filters by substring, or by glob if the filter contains `*` or `?`.
+    /// Patterns without `/` match the file name only.
+    fn matches(&self, file: &TarFileInfo) -> bool {
+        if self.filter.is_empty() {
+            return true;
+        }
+        if file.is_dir {
+            return false;
+        }
+
+        let filter = self.filter.to_lowercase();
+        if filter.contains(['*', '?']) {
+            let text = if filter.contains('/') {
+                &file.path
+            } else {
+                &file.name
+            };
+            glob_match(&filter, &text.to_lowercase())
+        } else {
+            file.path.to_lowercase().contains(&filter)
+        }
+    }
+
+    pub fn apply(&self, files: &mut Vec<TarFileInfo>) {
+        files.retain(|f| self.matches(f));
+
+        let key = match self.key {
+            Some(key) => key,
+            None => return,
+        };
+        // stable, so equal entries keep the archive order
+        files.sort_by(|a, b| {
+            let ord = match key {
+                SortKey::Name => a.name.cmp(&b.name),
+                SortKey::Size => a.size.cmp(&b.size),
+                SortKey::MTime => a.m_time.cmp(&b.m_time),
+            };
+            if self.desc {
+                ord.reverse()
+            } else {
+                ord
+            }
+        });
+    }
+}
+
+#[derive(Template)]
+#[template(path = "upload.html")]
+pub struct UploadPage {
+    pub hostname: String,
+    pub valid_days: u64,
+}
+
+#[derive(Template)]
+#[template(path = "error.html")]
+pub struct ErrorPage {
+    pub status: u16,
+    /// Starts with the status.
+    pub title: String,
+}
+
 pub struct TarFileInfo {
     pub path: String,
     pub name: String,
@@ -20,3 +199,277 @@ pub struct TarFileInfo {
     pub is_dir: bool,
     pub m_time: chrono::NaiveDateTime,
 }
+
+pub struct TarDirInfo {
+    /// Path of the directory inside the archive, with trailing slash.
+    pub path: String,
+    pub name: String,
+    pub size: u64,
+    pub human_size: String,
+    pub file_count: usize,
+}
+
+/// Flattened directory tree, directories are opened and closed explicitly
+/// so the template can render nested sections without recursion.
+pub enum TarTreeNode {
+    DirStart(TarDirInfo),
+    File(TarFileInfo),
+    DirEnd,
+}
+
+#[derive(Default)]
+struct DirNode {
+    dirs: BTreeMap<String, DirNode>,
+    files: Vec<TarFileInfo>,
+}
+
+impl DirNode {
+    fn size(&self) -> u64 {
+        self.files.iter().map(|f| f.size).sum::<u64>()
+            + self.dirs.values().map(|d| d.size()).sum::<u64>()
+    }
+
+    fn file_count(&self) -> usize {
+        self.files.len() + self.dirs.values().map(|d| d.file_count()).sum::<usize>()
+    }
+
+    fn flatten(self, prefix: &str, out: &mut Vec<TarTreeNode>) {
+        for (name, dir) in self.dirs {
+            let path = format!("{prefix}{name}/");
+            let size = dir.size();
+            out.push(TarTreeNode::DirStart(TarDirInfo {
+                path: path.clone(),
+                name,
+                size,
+                human_size: format_bytes(size),
+                file_count: dir.file_count(),
+            }));
+            dir.flatten(&path, out);
+            out.push(TarTreeNode::DirEnd);
+        }
+        out.extend(self.files.into_iter().map(TarTreeNode::File));
+    }
+}
+
+/// Groups the entries by directory, keeping the order of files within a directory.
+/// Directory entries only create (possibly empty) directories.
+pub fn build_tree(files: Vec<TarFileInfo>) -> Vec<TarTreeNode> {
+    let mut root = DirNode::default();
+    for file in files {
+        let mut components: Vec<String> = file
+            .path
+            .split('/')
+            .filter(|c| !c.is_empty() && *c != ".")
+            .map(|c| c.to_string())
+            .collect();
+        if !file.is_dir {
+            components.pop();
+        }
+
+        let mut node = &mut root;
+        for c in components {
+            node = node.dirs.entry(c).or_default();
+        }
+        if !file.is_dir {
+            node.files.push(file);
+        }
+    }
+
+    let mut out = Vec::new();
+    root.flatten("", &mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, size: u64) -> TarFileInfo {
+        TarFileInfo {
+            path: path.to_string(),
+            name: path.rsplit('/').next().unwrap().to_string(),
+            size,
+            human_size: format_bytes(size),
+            offset: 0,
+            is_dir: path.ends_with('/'),
+            m_time: chrono::NaiveDateTime::from_timestamp(0, 0),
+        }
+    }
+
+    fn synthetic_tree() -> Vec<TarTreeNode> {
+        build_tree(vec![
+            file("readme.txt", 10),
+            file("photos/", 0),
+            file("photos/a.jpg", 100),
+            file("photos/2020/b.jpg", 200),
+            file("photos/2020/c.jpg", 300),
+            file("./docs/d.pdf", 5),
+            file("empty/", 0),
+        ])
+    }
+
+    fn dir<'a>(tree: &'a [TarTreeNode], path: &str) -> &'a TarDirInfo {
+        tree.iter()
+            .find_map(|n| match n {
+                TarTreeNode::DirStart(d) if d.path == path => Some(d),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_tree_totals() {
+        let tree = synthetic_tree();
+
+        assert_eq!(dir(&tree, "photos/").size, 600);
+        assert_eq!(dir(&tree, "photos/").file_count, 3);
+        assert_eq!(dir(&tree, "photos/2020/").size, 500);
+        assert_eq!(dir(&tree, "photos/2020/").file_count, 2);
+        assert_eq!(dir(&tree, "docs/").file_count, 1);
+        assert_eq!(dir(&tree, "empty/").file_count, 0);
+
+        let starts = tree
+            .iter()
+            .filter(|n| matches!(n, TarTreeNode::DirStart(_)))
+            .count();
+        let ends = tree
+            .iter()
+            .filter(|n| matches!(n, TarTreeNode::DirEnd))
+            .count();
+        assert_eq!(starts, 4);
+        assert_eq!(starts, ends);
+    }
+
+    #[test]
+    fn test_render_tree() {
+        let index = TarIndex {
+            valid_until: chrono::NaiveDateTime::from_timestamp(0, 0),
+            remaining: "6d 23h".to_string(),
+            total_size: 615,
+            human_total_size: format_bytes(615),
+            entry_count: 5,
+            shown_entries: 5,
+            hidden_entries: 0,
+            skipped_entries: 0,
+            stats: None,
+            tree: synthetic_tree(),
+            sort: IndexSort::default(),
+            uploaded: false,
+            id: "0005-abandon-ability-able-about".to_string(),
+            hostname: "localhost".to_string(),
+            protocol: "http".to_string(),
+        };
+        let html = index.render().unwrap();
+
+        assert!(html.contains("6d 23h"));
+        assert!(html.contains("615 b"));
+        assert!(html.contains("zip?prefix=photos/2020/"));
+        assert!(html.contains("photos/2020/b.jpg"));
+        assert_eq!(html.matches("<details").count(), 4);
+        assert_eq!(html.matches("</details>").count(), 4);
+        // nested directories are rendered inside their parent
+        let photos = html.find("zip?prefix=photos/\"").unwrap();
+        let sub = html.find("zip?prefix=photos/2020/").unwrap();
+        let readme = html.find("readme.txt").unwrap();
+        assert!(photos < sub && sub < readme);
+    }
+
+    fn render_sorted(sort: Option<&str>, order: Option<&str>, filter: Option<&str>) -> String {
+        let mut files = vec![
+            file("b.txt", 20),
+            file("c.txt", 30),
+            file("a.txt", 10),
+            file("dir/e.jpg", 50),
+            file("dir/d.jpg", 40),
+        ];
+        for (i, f) in files.iter_mut().enumerate() {
+            f.m_time = chrono::NaiveDateTime::from_timestamp([3, 1, 2, 5, 4][i], 0);
+        }
+
+        let sort = IndexSort::parse(sort, order, filter);
+        sort.apply(&mut files);
+        TarIndex {
+            valid_until: chrono::NaiveDateTime::from_timestamp(0, 0),
+            remaining: String::new(),
+            total_size: 0,
+            human_total_size: String::new(),
+            entry_count: 0,
+            shown_entries: 0,
+            hidden_entries: 0,
+            skipped_entries: 0,
+            stats: None,
+            tree: build_tree(files),
+            sort,
+            uploaded: false,
+            id: "0005-abandon-ability-able-about".to_string(),
+            hostname: "localhost".to_string(),
+            protocol: "http".to_string(),
+        }
+        .render()
+        .unwrap()
+    }
+
+    /// File names in the order they are rendered.
+    fn rows(html: &str) -> Vec<String> {
+        html.split("&amp;name=")
+            .skip(1)
+            .map(|s| s.split('"').next().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_sort_rows() {
+        let default = rows(&render_sorted(None, None, None));
+        assert_eq!(default, ["e.jpg", "d.jpg", "b.txt", "c.txt", "a.txt"]);
+
+        let by_name = rows(&render_sorted(Some("name"), None, None));
+        assert_eq!(by_name, ["d.jpg", "e.jpg", "a.txt", "b.txt", "c.txt"]);
+
+        let by_size = rows(&render_sorted(Some("size"), Some("desc"), None));
+        assert_eq!(by_size, ["e.jpg", "d.jpg", "c.txt", "b.txt", "a.txt"]);
+
+        let by_mtime = rows(&render_sorted(Some("mtime"), Some("asc"), None));
+        assert_eq!(by_mtime, ["d.jpg", "e.jpg", "c.txt", "a.txt", "b.txt"]);
+    }
+
+    #[test]
+    fn test_invalid_sort_falls_back() {
+        let default = rows(&render_sorted(None, None, None));
+        assert_eq!(
+            rows(&render_sorted(Some("color"), Some("desc"), None)),
+            default
+        );
+        assert_eq!(
+            rows(&render_sorted(Some("size"), Some("sideways"), None)),
+            rows(&render_sorted(Some("size"), Some("asc"), None))
+        );
+    }
+
+    #[test]
+    fn test_filter_rows() {
+        assert_eq!(
+            rows(&render_sorted(None, None, Some(".jpg"))),
+            ["e.jpg", "d.jpg"]
+        );
+        assert_eq!(
+            rows(&render_sorted(Some("name"), None, Some("*.JPG"))),
+            ["d.jpg", "e.jpg"]
+        );
+        assert_eq!(
+            rows(&render_sorted(None, None, Some("?.txt"))),
+            ["b.txt", "c.txt", "a.txt"]
+        );
+        assert_eq!(
+            rows(&render_sorted(None, None, Some("dir/*"))),
+            ["e.jpg", "d.jpg"]
+        );
+        assert!(rows(&render_sorted(None, None, Some("*.png"))).is_empty());
+    }
+
+    #[test]
+    fn test_sort_links_toggle() {
+        let html = render_sorted(Some("size"), Some("asc"), Some("a b"));
+        assert!(html.contains("?sort=size&amp;order=desc&amp;filter=a%20b"));
+        assert!(html.contains("?sort=name&amp;order=asc&amp;filter=a%20b"));
+    }
+}
@@ -0,0 +1,89 @@
+//! The real server on a local port, for the tests of clients.
+
+use std::{path::PathBuf, sync::mpsc::Sender};
+
+use common::{TarHash, TarPassword};
+
+use crate::{config, handle, shutdown::Shutdown, AppState};
+
+/// The token of the only user.
+pub const TOKEN: &str = "secret";
+
+/// Serves from a new directory under the system temp dir until dropped.
+pub struct TestServer {
+    /// `127.0.0.1:PORT`, also the hostname the uploads are stored for.
+    pub host: String,
+    state: AppState,
+    dir: PathBuf,
+    stop: Sender<()>,
+}
+
+impl TestServer {
+    pub fn start() -> Self {
+        let dir = std::env::temp_dir().join(format!("tarcloud-server-{}", TarPassword::generate()));
+        let config: config::Config = toml::from_str(&format!(
+            r#"
+            [general]
+            hostname = "localhost"
+            protocol = "http"
+            data_dir = "{}"
+
+            [[users]]
+            username = "test"
+            token = "{TOKEN}"
+            "#,
+            dir.display()
+        ))
+        .unwrap();
+        let shared = config::SharedConfig::new(config.clone(), None);
+        let state = AppState::new(&config, shared, Shutdown::default()).unwrap();
+
+        let server = rouille::Server::new("127.0.0.1:0", {
+            let state = state.clone();
+            move |request| handle(&state, request)
+        })
+        .unwrap();
+        // Known once bound, before the first request.
+        let host = server.server_addr().to_string();
+        state
+            .config
+            .update(|config| config.general.hostname = host.clone());
+        let (_, stop) = server.stoppable();
+
+        Self {
+            host,
+            state,
+            dir,
+            stop,
+        }
+    }
+
+    /// Stored uploads, finished or not.
+    pub fn upload_count(&self) -> usize {
+        self.state.meta.list().unwrap().len()
+    }
+
+    /// Size of the stored, encrypted upload.
+    pub fn stored_size(&self, code: &TarPassword) -> u64 {
+        self.state.storage.size(&self.hash(code)).unwrap()
+    }
+
+    /// Replaces the `sha256` list the server keeps for an upload.
+    pub fn set_checksums(&self, code: &TarPassword, checksums: &str) {
+        let hash = self.hash(code);
+        let mut meta = self.state.meta.get(&hash).unwrap().unwrap();
+        meta.set_checksums(code, checksums).unwrap();
+        self.state.meta.set(&hash, &meta).unwrap();
+    }
+
+    fn hash(&self, code: &TarPassword) -> TarHash {
+        TarHash::from_tarid(code, &self.host)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
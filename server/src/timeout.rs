@@ -0,0 +1,134 @@
+use std::{
+    fmt::{Display, Formatter},
+    io::Read,
+    time::{Duration, Instant},
+};
+
+use crate::config::GeneralConfig;
+
+/// Why an upload was cut off, carried inside the `io::Error` of a read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimedOut {
+    Idle(Duration),
+    Total(Duration),
+}
+
+impl Display for TimedOut {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimedOut::Idle(d) => write!(f, "No data received for {}s", d.as_secs()),
+            TimedOut::Total(d) => write!(f, "Upload took longer than {}s", d.as_secs()),
+        }
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+impl TimedOut {
+    /// The timeout inside `e`, if it is one.
+    pub fn from_io(e: &std::io::Error) -> Option<&TimedOut> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+/// Limits how long an upload may wait for the client, per wait and in total.
+///
+/// Waits can't be interrupted, a timeout is noticed once the wait returns.
+/// That is enough for clients trickling in data to hold a thread.
+pub struct UploadTimer {
+    idle: Duration,
+    max: Duration,
+    started: Instant,
+}
+
+impl UploadTimer {
+    pub fn new(idle: Duration, max: Duration) -> Self {
+        Self {
+            idle,
+            max,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn from_config(config: &GeneralConfig) -> Self {
+        Self::new(
+            Duration::from_secs(config.upload_idle_timeout_s),
+            Duration::from_secs(config.upload_max_duration_s),
+        )
+    }
+
+    /// Runs `f`, which waits for the client, and checks how long it took.
+    pub fn wait<T, F: FnOnce() -> T>(&mut self, f: F) -> Result<T, TimedOut> {
+        let start = Instant::now();
+        let result = f();
+        if start.elapsed() > self.idle {
+            return Err(TimedOut::Idle(self.idle));
+        }
+        if self.started.elapsed() > self.max {
+            return Err(TimedOut::Total(self.max));
+        }
+        Ok(result)
+    }
+
+    pub fn reader<R: Read>(self, inner: R) -> TimeoutReader<R> {
+        TimeoutReader { inner, timer: self }
+    }
+}
+
+/// Fails reads with `ErrorKind::TimedOut` once the timer runs out.
+pub struct TimeoutReader<R> {
+    inner: R,
+    timer: UploadTimer,
+}
+
+impl<R: Read> Read for TimeoutReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.timer
+            .wait(|| self.inner.read(buf))
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::TimedOut, e))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns one byte per read, sleeping before each.
+    struct Trickle(Duration);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            std::thread::sleep(self.0);
+            buf[0] = 1;
+            Ok(1)
+        }
+    }
+
+    fn read_error(timer: UploadTimer, delay: Duration) -> TimedOut {
+        let mut buf = [0; 16];
+        let mut reader = timer.reader(Trickle(delay));
+        let e = loop {
+            if let Err(e) = reader.read(&mut buf) {
+                break e;
+            }
+        };
+        assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+        *TimedOut::from_io(&e).unwrap()
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let idle = Duration::from_millis(10);
+        let timer = UploadTimer::new(idle, Duration::from_secs(60));
+        let e = read_error(timer, Duration::from_millis(20));
+        assert_eq!(e, TimedOut::Idle(idle));
+    }
+
+    #[test]
+    fn test_total_timeout() {
+        let max = Duration::from_millis(30);
+        let timer = UploadTimer::new(Duration::from_secs(60), max);
+        let e = read_error(timer, Duration::from_millis(5));
+        assert_eq!(e, TimedOut::Total(max));
+    }
+}
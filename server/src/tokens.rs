@@ -0,0 +1,107 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::config::UserConfig;
+
+/// How long the token file is trusted before it is read again.
+const TOKEN_FILE_TTL: Duration = Duration::from_secs(5);
+
+type Cache = Option<(Instant, Vec<UserConfig>)>;
+
+/// Users from `general.allowed_tokens_file`, one `username:token` per line.
+/// Re-read on lookup once the cache is older than the TTL, so tokens can be
+/// rotated without a restart.
+#[derive(Clone)]
+pub struct TokenFile {
+    path: PathBuf,
+    ttl: Duration,
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl TokenFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            ttl: TOKEN_FILE_TTL,
+            cache: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn find(&self, token: &str) -> Option<UserConfig> {
+        let mut cache = self.cache.lock().unwrap();
+        match &*cache {
+            Some((read_at, _)) if read_at.elapsed() < self.ttl => (),
+            _ => {
+                let users = match std::fs::read_to_string(&self.path) {
+                    Ok(content) => parse_tokens(&content),
+                    Err(e) => {
+                        // Keep the old tokens, the file may be in the middle of being replaced.
+                        println!("Could not read {}: {}", self.path.display(), e);
+                        cache.take().map(|(_, users)| users).unwrap_or_default()
+                    }
+                };
+                *cache = Some((Instant::now(), users));
+            }
+        }
+
+        let (_, users) = cache.as_ref()?;
+        users.iter().find(|user| user.matches_token(token)).cloned()
+    }
+}
+
+/// Skips empty lines, `#` comments and lines without a `:`.
+fn parse_tokens(content: &str) -> Vec<UserConfig> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(_, token)| !token.trim().is_empty())
+        .map(|(username, token)| UserConfig {
+            username: username.trim().to_string(),
+            token: token.trim().to_string(),
+            ..Default::default()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tokens() {
+        let users = parse_tokens("# comment\nalice:abc\n\n bob : def \nbroken\nempty:\n");
+        let users: Vec<_> = users
+            .iter()
+            .map(|u| (u.username.as_str(), u.token.as_str()))
+            .collect();
+        assert_eq!(users, [("alice", "abc"), ("bob", "def")]);
+    }
+
+    #[test]
+    fn test_reload() {
+        let path = std::env::temp_dir().join(format!(
+            "tarcloud-tokens-{}",
+            common::TarPassword::generate()
+        ));
+        std::fs::write(&path, "alice:abc\n").unwrap();
+
+        let mut tokens = TokenFile::new(path.clone());
+        assert_eq!(tokens.find("abc").unwrap().username, "alice");
+
+        // Cached until the TTL is over.
+        std::fs::write(&path, "alice:xyz\n").unwrap();
+        assert!(tokens.find("abc").is_some());
+
+        tokens.ttl = Duration::ZERO;
+        assert!(tokens.find("abc").is_none());
+        assert_eq!(tokens.find("xyz").unwrap().username, "alice");
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(tokens.find("xyz").is_some());
+    }
+}
@@ -0,0 +1,49 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use common::TarHash;
+
+/// Uploads being received, so a shutdown can wait for them. Counted per
+/// upload, a resumable one may get several parts at once.
+#[derive(Clone, Default)]
+pub struct UploadTracker {
+    active: Arc<Mutex<HashMap<TarHash, usize>>>,
+}
+
+/// Counts an upload until it is dropped.
+pub struct UploadGuard {
+    active: Arc<Mutex<HashMap<TarHash, usize>>>,
+    hash: TarHash,
+}
+
+impl UploadTracker {
+    pub fn start(&self, hash: &TarHash) -> UploadGuard {
+        *self.active.lock().unwrap().entry(hash.clone()).or_default() += 1;
+        UploadGuard {
+            active: self.active.clone(),
+            hash: hash.clone(),
+        }
+    }
+
+    pub fn active(&self) -> Vec<TarHash> {
+        self.active.lock().unwrap().keys().cloned().collect()
+    }
+
+    pub fn count(&self) -> usize {
+        self.active.lock().unwrap().values().sum()
+    }
+}
+
+impl Drop for UploadGuard {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.hash) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.hash);
+            }
+        }
+    }
+}
@@ -1,8 +1,12 @@
+use sha2::Digest;
 use std::{
     borrow::Cow,
-    io::{Read, Seek},
+    io::{Read, Seek, Write},
+    net::IpAddr,
 };
 
+use crate::{config::GeneralConfig, listener::Listen};
+
 pub fn now_unix() -> u64 {
     use std::time::{SystemTime, UNIX_EPOCH};
     SystemTime::now()
@@ -11,14 +15,343 @@ pub fn now_unix() -> u64 {
         .as_secs()
 }
 
+/// Allocates `len` bytes at `offset` without changing the file size, so
+/// readers following the file don't see the reserved space.
+#[cfg(target_os = "linux")]
+pub fn fallocate(file: &std::fs::File, offset: u64, len: u64) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let (offset, len) = match (i64::try_from(offset), i64::try_from(len)) {
+        (Ok(offset), Ok(len)) => (offset, len),
+        _ => return Err(std::io::Error::from_raw_os_error(libc::EFBIG)),
+    };
+    // SAFETY: the descriptor stays open for the call.
+    let result =
+        unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, offset, len) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn fallocate(_file: &std::fs::File, _offset: u64, _len: u64) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Hashes what is written through it, for content based ETags.
+pub struct Sha256Writer<W> {
+    inner: W,
+    hasher: sha2::Sha256,
+}
+
+impl<W: Write> Sha256Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: sha2::Sha256::new(),
+        }
+    }
+
+    /// The inner writer and the hex hash of everything written.
+    pub fn into_parts(self) -> (W, String) {
+        (self.inner, to_hex(&self.hasher.finalize()))
+    }
+}
+
+impl<W: Write> Write for Sha256Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Takes as long for any content of equally long inputs.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn accepts_json(request: &rouille::Request) -> bool {
+    request
+        .header("Accept")
+        .map(|v| v.contains("application/json"))
+        .unwrap_or(false)
+}
+
+/// Browsers, `curl` and `toc` send no `text/html`.
+pub fn accepts_html(request: &rouille::Request) -> bool {
+    request
+        .header("Accept")
+        .map(|v| v.contains("text/html"))
+        .unwrap_or(false)
+}
+
+/// Protocol and host for links, as the client reached the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Origin {
+    pub protocol: String,
+    pub host: String,
+}
+
+impl Origin {
+    pub fn from_config(config: &GeneralConfig) -> Self {
+        Self {
+            protocol: config.protocol.clone(),
+            host: config.hostname.clone(),
+        }
+    }
+
+    /// Requests from one of `general.trusted_proxies` may override the config
+    /// with `X-Forwarded-Proto` and `X-Forwarded-Host`. Only for links, hashes
+    /// are always salted with the configured `general.hostname`.
+    pub fn of_request(config: &GeneralConfig, request: &rouille::Request) -> Self {
+        let mut origin = Self::from_config(config);
+        let remote = request.remote_addr().ip().to_canonical();
+        if !config.trusted_proxies.contains(&remote) {
+            return origin;
+        }
+
+        // Proxies in a chain append, the first value is what the client used.
+        let forwarded = |name| {
+            let value = request.header(name)?.split(',').next()?.trim();
+            Some(value.to_string()).filter(|v| !v.is_empty())
+        };
+        if let Some(proto) = forwarded("X-Forwarded-Proto") {
+            if proto == "http" || proto == "https" {
+                origin.protocol = proto;
+            }
+        }
+        if let Some(host) = forwarded("X-Forwarded-Host") {
+            if !host.contains(|c: char| c == '/' || c == '\\' || c.is_whitespace()) {
+                origin.host = host;
+            }
+        }
+        origin
+    }
+
+    /// `path` starts with a `/`.
+    pub fn url(&self, path: &str) -> String {
+        format!("{}://{}{}", self.protocol, self.host, path)
+    }
+}
+
+/// Address of the client for per-IP limits. Behind one of
+/// `general.trusted_proxies` or the unix socket it is the last
+/// `X-Forwarded-For` entry, the one the proxy added.
+pub fn client_ip(config: &GeneralConfig, request: &rouille::Request) -> IpAddr {
+    let remote = request.remote_addr().ip().to_canonical();
+    let proxied = config.trusted_proxies.contains(&remote)
+        || matches!(Listen::parse(&config.listen), Listen::Unix(_));
+    if !proxied {
+        return remote;
+    }
+    request
+        .header("X-Forwarded-For")
+        .and_then(|value| value.rsplit(',').next()?.trim().parse().ok())
+        .unwrap_or(remote)
+}
+
+/// Matches `text` against a shell style pattern with `*` and `?`.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // Position after the last `*` in pattern and text, to backtrack to.
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            p += 1;
+            star = Some((p, t));
+        } else if let Some((sp, st)) = star {
+            p = sp;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Parses an HTTP date (RFC 7231), including the obsolete RFC 850 and asctime forms.
+pub fn parse_http_date(s: &str) -> Option<u64> {
+    let s = s.trim();
+    [
+        "%a, %d %b %Y %H:%M:%S GMT",
+        "%A, %d-%b-%y %H:%M:%S GMT",
+        "%a %b %e %H:%M:%S %Y",
+    ]
+    .iter()
+    .find_map(|format| chrono::NaiveDateTime::parse_from_str(s, format).ok())
+    .and_then(|date| u64::try_from(date.timestamp()).ok())
+}
+
+/// Timestamps in JSON answers.
+pub fn format_rfc3339(unix: u64) -> Option<String> {
+    use chrono::TimeZone;
+    chrono::Utc
+        .timestamp_opt(unix as i64, 0)
+        .single()
+        .map(|t| t.to_rfc3339())
+}
+
+pub fn format_http_date(unix: u64) -> String {
+    chrono::NaiveDateTime::from_timestamp(unix as i64, 0)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+/// `name` as a file name for a download: without control characters, path
+/// separators and leading dots.
+pub fn sanitize_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| if c == '/' || c == '\\' { '_' } else { c })
+        .collect();
+    match name.trim().trim_start_matches('.') {
+        "" => "download".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Types a browser may show in the page itself. No html or svg, those could
+/// run scripts on this origin.
+fn inline_content_type(name: &str) -> Option<&'static str> {
+    let ext = name.rsplit_once('.')?.1.to_ascii_lowercase();
+    Some(match ext.as_str() {
+        "txt" | "md" | "log" => "text/plain; charset=utf-8",
+        "pdf" => "application/pdf",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        _ => return None,
+    })
+}
+
+/// `Content-Disposition` value with an ASCII `filename` for old clients and,
+/// if that is not exact, the UTF-8 name as `filename*` (RFC 5987).
+fn content_disposition(kind: &str, name: &str) -> String {
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    if fallback == name {
+        return format!("{kind}; filename=\"{fallback}\"");
+    }
+    let encoded: String =
+        name.bytes()
+            .map(|b| match b {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => (b as char).to_string(),
+                b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|'
+                | b'~' => (b as char).to_string(),
+                b => format!("%{b:02X}"),
+            })
+            .collect();
+    format!("{kind}; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+/// Makes `response` a download of `name`. `inline` is only followed for
+/// types in `inline_content_type`, everything else stays an attachment.
+pub fn with_file_name(response: rouille::Response, name: &str, inline: bool) -> rouille::Response {
+    let name = sanitize_file_name(name);
+    match inline_content_type(&name).filter(|_| inline) {
+        Some(content_type) => response
+            .with_unique_header("Content-Type", content_type)
+            .with_unique_header("X-Content-Type-Options", "nosniff")
+            .with_unique_header("Content-Disposition", content_disposition("inline", &name)),
+        None => response.with_unique_header(
+            "Content-Disposition",
+            content_disposition("attachment", &name),
+        ),
+    }
+}
+
+enum ByteRange {
+    /// `N-M` or the open-ended `N-`.
+    From(u64, Option<u64>),
+    /// `-N`, the last N bytes.
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Offset and length within `len` bytes, `None` if nothing of it is available.
+    fn resolve(&self, len: u64) -> Option<(u64, u64)> {
+        let (offset, end) = match *self {
+            ByteRange::From(offset, end) => {
+                (offset, end.unwrap_or(u64::MAX).min(len.checked_sub(1)?))
+            }
+            ByteRange::Suffix(n) => (len.saturating_sub(n), len.checked_sub(1)?),
+        };
+        if offset > end {
+            return None;
+        }
+        Some((offset, end - offset + 1))
+    }
+}
+
+fn parse_range(s: &str) -> Option<ByteRange> {
+    let (start, end) = s.trim().split_once('-')?;
+    if start.is_empty() {
+        return Some(ByteRange::Suffix(end.parse().ok().filter(|n| *n > 0)?));
+    }
+    let start = start.parse().ok()?;
+    let end = match end {
+        "" => None,
+        end => Some(end.parse().ok().filter(|end| *end >= start)?),
+    };
+    Some(ByteRange::From(start, end))
+}
+
+/// End of what `handle_range` serves from the current position, relative to
+/// it. `None` if that depends on the length of the file.
+pub fn range_end(request: &rouille::Request, max_len: Option<u64>) -> Option<u64> {
+    let range = request
+        .header("Range")
+        .and_then(|s| s.trim().strip_prefix("bytes="))
+        .and_then(parse_range);
+    let end = match range {
+        Some(ByteRange::From(_, Some(end))) => Some(end + 1),
+        _ => None,
+    };
+    match (end, max_len) {
+        (Some(end), Some(max_len)) => Some(end.min(max_len)),
+        (end, max_len) => end.or(max_len),
+    }
+}
+
 /***
  * Handles range requests if needed.
  *
- * The file is served from the current position.
+ * The file is served from the current position. `etag` is sent quoted and
+ * checked by the conditional headers, `mod_time` by `If-Modified-Since`.
  */
 pub fn handle_range<T: Read + Seek + Send + 'static>(
     request: &rouille::Request,
     max_len: Option<u64>,
+    etag: Option<&str>,
     mod_time: Option<u64>,
     mut file: T,
 ) -> anyhow::Result<rouille::Response> {
@@ -42,63 +375,80 @@ pub fn handle_range<T: Read + Seek + Send + 'static>(
     let range = request
         .header("Range")
         .and_then(|s| s.trim().strip_prefix("bytes="))
-        .and_then(|s| {
-            let mut parts = s.splitn(2, '-');
-            let offset = parts.next()?.parse::<u64>().ok()?;
-            // TODO: allow open-ended ranges
-            let end = parts.next()?;
-            let end = if end.is_empty() {
-                u64::MAX
-            } else {
-                end.parse::<u64>().ok()?
-            };
+        .and_then(parse_range);
 
-            let length = end.saturating_sub(offset) + 1;
-            Some((offset, length))
-        });
-
-    // No If range header means do Range.
+    // No If range header means do Range. It holds either the ETag or the
+    // Last-Modified date the client saw.
     let if_range_fullfilled = request
         .header("If-Range")
-        .map(|v| match mod_time {
-            Some(mod_time) => format!("\"{}\"", mod_time) == v.trim(),
-            None => false,
+        .map(|v| match parse_http_date(v) {
+            // Only an exact match, a later date doesn't make it the same file.
+            Some(date) => mod_time == Some(date),
+            None => etag.is_some_and(|etag| format!("\"{}\"", etag) == v.trim()),
         })
         .unwrap_or(true);
     // if etag changed, return 200 and full file.
     let range = if if_range_fullfilled { range } else { None };
 
-    let if_match_value = request
-        .header("If-Match")
-        .or_else(|| request.header("If-Match"));
-    let if_match_matches = match (if_match_value, mod_time) {
-        (Some(v), Some(time)) => v.contains(&format!("\"{}\"", time)),
-        _ => false,
+    // A list of tags or `*`, weak tags compare like strong ones for these.
+    let matches = |value: &str| {
+        etag.is_some_and(|etag| {
+            let quoted = format!("\"{}\"", etag);
+            value.split(',').any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.trim_start_matches("W/") == quoted
+            })
+        })
     };
 
-    if !if_match_matches && request.header("If-Match").is_some() {
+    if request.header("If-Match").is_some_and(|v| !matches(v)) {
         return Ok(rouille::Response::text("Precondition Failed.").with_status_code(412));
     }
 
-    if if_match_matches && request.header("If-None-Match").is_some() {
+    if request.header("If-None-Match").is_some_and(matches) {
+        return Ok(rouille::Response::text("Not Modified.").with_status_code(304));
+    }
+
+    // If-None-Match takes precedence, see RFC 7232 3.3.
+    let not_modified_since = match (request.header("If-Modified-Since"), mod_time) {
+        (Some(since), Some(time)) if request.header("If-None-Match").is_none() => {
+            parse_http_date(since).is_some_and(|since| time <= since)
+        }
+        _ => false,
+    };
+    if not_modified_since {
         return Ok(rouille::Response::text("Not Modified.").with_status_code(304));
     }
 
-    let current_pos = file.seek(std::io::SeekFrom::Current(0))?;
+    let current_pos = file.stream_position()?;
     let rest_len =
-        (file.seek(std::io::SeekFrom::End(0))? - current_pos).min(max_len.unwrap_or(std::u64::MAX));
+        (file.seek(std::io::SeekFrom::End(0))? - current_pos).min(max_len.unwrap_or(u64::MAX));
     let _ = file.seek(std::io::SeekFrom::Start(current_pos))?;
 
     let mut headers: Vec<(Cow<'static, str>, Cow<'static, str>)> =
         vec![("Content-Type".into(), "application/octet-stream".into())];
 
+    if let Some(etag) = etag {
+        headers.push(("ETag".into(), format!("\"{}\"", etag).into()));
+    }
     if let Some(mod_time) = mod_time {
-        headers.push(("ETag".into(), format!("\"{}\"", mod_time).into()));
+        headers.push(("Last-Modified".into(), format_http_date(mod_time).into()));
     }
 
-    match range {
-        Some((offset, length)) => {
-            let length = length.min(rest_len.saturating_sub(offset));
+    match range.map(|range| range.resolve(rest_len)) {
+        Some(None) => {
+            headers.push((
+                "Content-Range".into(),
+                format!("bytes */{}", rest_len).into(),
+            ));
+            Ok(rouille::Response {
+                status_code: 416,
+                headers,
+                data: rouille::ResponseBody::empty(),
+                upgrade: None,
+            })
+        }
+        Some(Some((offset, length))) => {
             let _ = file.seek(std::io::SeekFrom::Start(current_pos + offset))?;
             let file = MaxRead {
                 left: length,
@@ -132,3 +482,221 @@ pub fn handle_range<T: Read + Seek + Send + 'static>(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_ip() {
+        let mut config = crate::test_state().config().general.clone();
+        config.trusted_proxies = vec!["10.0.0.1".parse().unwrap()];
+        let from = |ip: [u8; 4], forwarded: &str| {
+            let addr = std::net::SocketAddr::from((ip, 4000));
+            let headers = vec![("X-Forwarded-For".to_string(), forwarded.to_string())];
+            rouille::Request::fake_http_from(addr, "GET", "/", headers, vec![])
+        };
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        // The proxy appends, earlier entries are up to the client.
+        let request = from([10, 0, 0, 1], "1.1.1.1, 2.2.2.2");
+        assert_eq!(client_ip(&config, &request), ip("2.2.2.2"));
+        let request = from([10, 0, 0, 1], "garbage");
+        assert_eq!(client_ip(&config, &request), ip("10.0.0.1"));
+        let request = from([3, 3, 3, 3], "2.2.2.2");
+        assert_eq!(client_ip(&config, &request), ip("3.3.3.3"));
+
+        // Everything through the unix socket comes from the proxy in front.
+        config.listen = "unix:/run/piper.sock".to_string();
+        let request = from([127, 0, 0, 1], "2.2.2.2");
+        assert_eq!(client_ip(&config, &request), ip("2.2.2.2"));
+    }
+
+    #[test]
+    fn test_forwarded_origin() {
+        let mut config = crate::test_state().config().general.clone();
+        config.trusted_proxies = vec!["10.0.0.1".parse().unwrap()];
+
+        let forwarded = vec![
+            ("X-Forwarded-Proto".to_string(), "https".to_string()),
+            (
+                "X-Forwarded-Host".to_string(),
+                "files.example, inner".to_string(),
+            ),
+        ];
+        let from = |ip: [u8; 4], headers: Vec<(String, String)>| {
+            let addr = std::net::SocketAddr::from((ip, 4000));
+            rouille::Request::fake_http_from(addr, "GET", "/", headers, vec![])
+        };
+
+        let origin = Origin::of_request(&config, &from([10, 0, 0, 1], forwarded.clone()));
+        assert_eq!(origin.url("/x/"), "https://files.example/x/");
+
+        // Headers from anyone else, or missing headers, leave the config.
+        let origin = Origin::of_request(&config, &from([10, 0, 0, 2], forwarded));
+        assert_eq!(origin.url("/x/"), "http://localhost/x/");
+        let origin = Origin::of_request(&config, &from([10, 0, 0, 1], vec![]));
+        assert_eq!(origin, Origin::from_config(&config));
+
+        let bogus = vec![
+            ("X-Forwarded-Proto".to_string(), "javascript".to_string()),
+            ("X-Forwarded-Host".to_string(), "evil/path".to_string()),
+        ];
+        let origin = Origin::of_request(&config, &from([10, 0, 0, 1], bogus));
+        assert_eq!(origin, Origin::from_config(&config));
+    }
+
+    #[test]
+    fn test_parse_http_date() {
+        let expected = Some(784111777);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), expected);
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), expected);
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), expected);
+        assert_eq!(parse_http_date(&format_http_date(784111777)), expected);
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(sanitize_file_name("../etc/passwd"), "_etc_passwd");
+        assert_eq!(sanitize_file_name("a\r\nSet-Cookie: x"), "aSet-Cookie: x");
+        assert_eq!(sanitize_file_name(".."), "download");
+
+        let disposition = |name: &str, inline: bool| {
+            let response = rouille::Response::from_data("application/octet-stream", vec![]);
+            let response = with_file_name(response, name, inline);
+            let header = |name: &str| {
+                let (_, value) = response.headers.iter().find(|(k, _)| k == name).unwrap();
+                value.to_string()
+            };
+            (header("Content-Type"), header("Content-Disposition"))
+        };
+        let binary = "application/octet-stream".to_string();
+        assert_eq!(
+            disposition("a.txt", false),
+            (binary.clone(), "attachment; filename=\"a.txt\"".to_string())
+        );
+        assert_eq!(
+            disposition("say \"hi\".txt", false).1,
+            "attachment; filename=\"say _hi_.txt\"; filename*=UTF-8''say%20%22hi%22.txt"
+        );
+        assert_eq!(
+            disposition("Grüße.pdf", false).1,
+            "attachment; filename=\"Gr__e.pdf\"; filename*=UTF-8''Gr%C3%BC%C3%9Fe.pdf"
+        );
+        assert_eq!(
+            disposition("a.pdf", true),
+            (
+                "application/pdf".to_string(),
+                "inline; filename=\"a.pdf\"".to_string()
+            )
+        );
+        // Could run scripts.
+        assert_eq!(
+            disposition("a.html", true),
+            (binary, "attachment; filename=\"a.html\"".to_string())
+        );
+    }
+
+    fn range_response(range: &str) -> (u16, Option<String>, Vec<u8>) {
+        let headers = vec![("Range".to_string(), range.to_string())];
+        let request = rouille::Request::fake_http("GET", "/", headers, vec![]);
+        let file = std::io::Cursor::new((0..100u8).collect::<Vec<_>>());
+        let response = handle_range(&request, None, None, None, file).unwrap();
+
+        let content_range = response
+            .headers
+            .iter()
+            .find(|(k, _)| k == "Content-Range")
+            .map(|(_, v)| v.to_string());
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut body = vec![];
+        reader.read_to_end(&mut body).unwrap();
+        (response.status_code, content_range, body)
+    }
+
+    #[test]
+    fn test_ranges() {
+        let (status, range, body) = range_response("bytes=10-19");
+        assert_eq!((status, range.as_deref()), (206, Some("bytes 10-19/100")));
+        assert_eq!(body, (10..20).collect::<Vec<u8>>());
+
+        let (status, range, body) = range_response("bytes=0-");
+        assert_eq!((status, range.as_deref()), (206, Some("bytes 0-99/100")));
+        assert_eq!(body.len(), 100);
+
+        let (status, range, body) = range_response("bytes=90-200");
+        assert_eq!((status, range.as_deref()), (206, Some("bytes 90-99/100")));
+        assert_eq!(body, (90..100).collect::<Vec<u8>>());
+
+        let (status, range, body) = range_response("bytes=-10");
+        assert_eq!((status, range.as_deref()), (206, Some("bytes 90-99/100")));
+        assert_eq!(body, (90..100).collect::<Vec<u8>>());
+
+        let (status, range, _) = range_response("bytes=-500");
+        assert_eq!((status, range.as_deref()), (206, Some("bytes 0-99/100")));
+
+        let (status, range, _) = range_response("bytes=100-");
+        assert_eq!((status, range.as_deref()), (416, Some("bytes */100")));
+
+        // Unparsable ranges are ignored.
+        assert_eq!(range_response("bytes=20-10").0, 200);
+        assert_eq!(range_response("bytes=-0").0, 200);
+        assert_eq!(range_response("lines=1-2").0, 200);
+    }
+
+    #[test]
+    fn test_if_range() {
+        let status = |if_range: &str| {
+            let headers = vec![
+                ("Range".to_string(), "bytes=0-3".to_string()),
+                ("If-Range".to_string(), if_range.to_string()),
+            ];
+            let request = rouille::Request::fake_http("GET", "/", headers, vec![]);
+            let file = std::io::Cursor::new(vec![0u8; 16]);
+            handle_range(&request, None, Some("tag"), Some(784111777), file)
+                .unwrap()
+                .status_code
+        };
+
+        assert_eq!(status("\"tag\""), 206);
+        assert_eq!(status("\"other\""), 200);
+        assert_eq!(status("Sun, 06 Nov 1994 08:49:37 GMT"), 206);
+        assert_eq!(status("Sun, 06 Nov 1994 08:49:36 GMT"), 200);
+        assert_eq!(status("Sun, 06 Nov 1994 08:49:38 GMT"), 200);
+        assert_eq!(status("garbage"), 200);
+    }
+
+    #[test]
+    fn test_if_modified_since() {
+        let status = |headers: Vec<(&str, &str)>| {
+            let headers = headers
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            let request = rouille::Request::fake_http("GET", "/", headers, vec![]);
+            let file = std::io::Cursor::new(vec![0u8; 16]);
+            handle_range(&request, None, None, Some(784111777), file)
+                .unwrap()
+                .status_code
+        };
+
+        assert_eq!(status(vec![]), 200);
+        assert_eq!(
+            status(vec![("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")]),
+            304
+        );
+        assert_eq!(
+            status(vec![("If-Modified-Since", "Sun, 06 Nov 1994 08:49:36 GMT")]),
+            200
+        );
+        assert_eq!(status(vec![("If-Modified-Since", "garbage")]), 200);
+        assert_eq!(
+            status(vec![
+                ("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                ("If-None-Match", "\"other\""),
+            ]),
+            200
+        );
+    }
+}
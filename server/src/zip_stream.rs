@@ -0,0 +1,243 @@
+use std::{
+    io::{Read, Seek, Write},
+    sync::mpsc::{self, Receiver, SyncSender},
+};
+
+/// Bytes of an entry per message.
+const CHUNK_SIZE: usize = 512 * 1024;
+/// Messages the reader may be ahead, 2 MiB of data.
+const CHANNEL_CHUNKS: usize = 4;
+
+enum Chunk {
+    Entry {
+        path: String,
+        mtime: u64,
+    },
+    Data(Vec<u8>),
+    /// After the data of every entry.
+    EntryEnd,
+    /// After the last entry. Without it the tar could not be read to the end.
+    Done,
+}
+
+/// Zip dates start in 1980, earlier modification times are moved there.
+pub fn zip_time(mtime: u64) -> chrono::NaiveDateTime {
+    const ZIP_EPOCH: u64 = 315_532_800;
+    chrono::NaiveDateTime::from_timestamp(mtime.max(ZIP_EPOCH) as i64, 0)
+}
+
+/// Converts the tar in `reader` to a zip without compression and returns
+/// `out`. Entries for which `zip_path` gives no name are left out.
+///
+/// Decrypting and reading the tar runs in a thread of its own, the zip is
+/// written in the calling one.
+pub fn tar_to_zip<R, W, F>(reader: R, out: W, zip_path: F) -> anyhow::Result<W>
+where
+    R: Read + Seek + Send,
+    W: Write,
+    F: Fn(&str) -> Option<String> + Sync,
+{
+    let (sender, receiver) = mpsc::sync_channel(CHANNEL_CHUNKS);
+    std::thread::scope(|scope| {
+        let zip_path = &zip_path;
+        let entries = scope.spawn(move || read_entries(reader, zip_path, sender));
+        let written = write_zip(receiver, out);
+        // The writer only gets no `Done` when reading failed, that error says why.
+        entries
+            .join()
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Reading the tar panicked")))?;
+        written
+    })
+}
+
+fn read_entries<R: Read + Seek>(
+    reader: R,
+    zip_path: &impl Fn(&str) -> Option<String>,
+    sender: SyncSender<Chunk>,
+) -> anyhow::Result<()> {
+    // Fails only when the writer stopped, it reports its own error.
+    let send = |chunk| sender.send(chunk).is_ok();
+
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries_with_seek()? {
+        let mut entry = entry?;
+        let Some(path) = zip_path(&entry.path()?.to_string_lossy()) else {
+            continue;
+        };
+        let mtime = entry.header().mtime().unwrap_or(0);
+        if !send(Chunk::Entry { path, mtime }) {
+            return Ok(());
+        }
+        loop {
+            let mut data = vec![];
+            (&mut entry)
+                .take(CHUNK_SIZE as u64)
+                .read_to_end(&mut data)?;
+            if data.is_empty() {
+                break;
+            }
+            if !send(Chunk::Data(data)) {
+                return Ok(());
+            }
+        }
+        if !send(Chunk::EntryEnd) {
+            return Ok(());
+        }
+    }
+    send(Chunk::Done);
+    Ok(())
+}
+
+fn write_zip<W: Write>(receiver: Receiver<Chunk>, out: W) -> anyhow::Result<W> {
+    let mut zip = streaming_zip::Archive::new(out);
+    loop {
+        match receiver.recv() {
+            Ok(Chunk::Entry { path, mtime }) => {
+                let mut data = EntryReader {
+                    receiver: &receiver,
+                    chunk: vec![],
+                    position: 0,
+                    end: false,
+                };
+                // Stored as is, the data only has to be copied here.
+                zip.add_file(
+                    path.into(),
+                    zip_time(mtime),
+                    streaming_zip::CompressionMode::Store,
+                    &mut data,
+                    true,
+                )?;
+                if !data.end {
+                    anyhow::bail!("Entry not read to the end");
+                }
+            }
+            Ok(Chunk::Done) => return Ok(zip.finish()?),
+            Ok(_) => anyhow::bail!("Data outside of an entry"),
+            Err(_) => anyhow::bail!("Tar ended early"),
+        }
+    }
+}
+
+/// The data of one entry, as the reader thread sends it.
+struct EntryReader<'a> {
+    receiver: &'a Receiver<Chunk>,
+    chunk: Vec<u8>,
+    position: usize,
+    end: bool,
+}
+
+impl Read for EntryReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.chunk.len() {
+            if self.end {
+                return Ok(0);
+            }
+            match self.receiver.recv() {
+                Ok(Chunk::Data(data)) => {
+                    self.chunk = data;
+                    self.position = 0;
+                }
+                Ok(Chunk::EntryEnd) => self.end = true,
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "Tar ended early",
+                    ))
+                }
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.position);
+        buf[..n].copy_from_slice(&self.chunk[self.position..][..n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, SeekFrom};
+
+    fn tar(files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mtime(1_700_000_000);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, path, &data[..]).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn keep(path: &str) -> Option<String> {
+        Some(path.to_string()).filter(|path| !path.starts_with("skip/"))
+    }
+
+    #[test]
+    fn test_tar_to_zip() {
+        let large: Vec<u8> = (0..CHUNK_SIZE * 5 / 2).map(|i| (i % 251) as u8).collect();
+        let files = [
+            ("a.txt", b"hello".to_vec()),
+            ("skip/b.txt", b"left out".to_vec()),
+            ("empty", vec![]),
+            ("dir/large.bin", large),
+        ];
+        let data = tar(&files);
+
+        // The same as converting the entries one after another.
+        let mut expected = streaming_zip::Archive::new(vec![]);
+        let mut archive = tar::Archive::new(Cursor::new(&data));
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let Some(path) = keep(&entry.path().unwrap().to_string_lossy()) else {
+                continue;
+            };
+            expected
+                .add_file(
+                    path.into(),
+                    chrono::NaiveDateTime::from_timestamp(1_700_000_000, 0),
+                    streaming_zip::CompressionMode::Store,
+                    &mut entry,
+                    true,
+                )
+                .unwrap();
+        }
+        let expected = expected.finish().unwrap();
+
+        let zip = tar_to_zip(Cursor::new(data), vec![], keep).unwrap();
+        assert!(zip == expected);
+    }
+
+    /// Fails once `limit` bytes were read.
+    struct Failing {
+        inner: Cursor<Vec<u8>>,
+        limit: u64,
+    }
+
+    impl Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.inner.position() >= self.limit {
+                return Err(std::io::Error::other("disk gone"));
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    impl Seek for Failing {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_read_error() {
+        let data = tar(&[("a.bin", vec![7; 3 * CHUNK_SIZE])]);
+        let reader = Failing {
+            inner: Cursor::new(data),
+            limit: CHUNK_SIZE as u64,
+        };
+        let error = tar_to_zip(reader, vec![], keep).unwrap_err();
+        assert!(format!("{error:#}").contains("disk gone"), "{error:#}");
+    }
+}
@@ -1,5 +1,6 @@
+use piper_client::Protocol;
 use serde::{Deserialize, Serialize};
-use std::{fmt::Display, path::PathBuf};
+use std::path::PathBuf;
 
 #[derive(Deserialize, Serialize, Debug, Default)]
 pub struct Config {
@@ -9,22 +10,6 @@ pub struct Config {
     pub history_file: Option<PathBuf>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Default)]
-pub enum Protocol {
-    #[default]
-    Https,
-    Http,
-}
-
-impl Display for Protocol {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Protocol::Https => write!(f, "https"),
-            Protocol::Http => write!(f, "http"),
-        }
-    }
-}
-
 pub fn config_path() -> PathBuf {
     let mut path = dirs::config_dir().expect("Could not find config directory");
     path.push("toc");
@@ -32,7 +17,6 @@ pub fn config_path() -> PathBuf {
     path
 }
 
-#[allow(unused)]
 pub fn history_path() -> PathBuf {
     let mut path = dirs::config_dir().expect("Could not find config directory");
     path.push("toc");
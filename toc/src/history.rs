@@ -0,0 +1,107 @@
+use anyhow::Context;
+use chrono::{DateTime, Local, Utc};
+use common::TarPassword;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{cmp::Reverse, io::Write, path::Path, str::FromStr};
+
+/// A line of the history file, written by `send`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub code: String,
+    pub url: String,
+    pub sent_at: String,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+pub enum SortBy {
+    /// Newest first
+    #[default]
+    Time,
+    /// Largest first
+    Size,
+    Code,
+}
+
+pub fn append(path: &Path, entry: &HistoryEntry) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    Ok(())
+}
+
+/// Lines that are not an entry with a valid code are left out, the file may
+/// have been edited by hand.
+fn read(path: &Path) -> anyhow::Result<Vec<(DateTime<Utc>, HistoryEntry)>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read history file {}", path.display()))?;
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str::<HistoryEntry>(line).ok())
+        .filter(|entry| TarPassword::from_str(&entry.code).is_ok())
+        .filter_map(|entry| {
+            let sent_at = DateTime::parse_from_rfc3339(&entry.sent_at).ok()?;
+            Some((sent_at.with_timezone(&Utc), entry))
+        })
+        .collect())
+}
+
+pub fn list_codes(path: &Path, filter: Option<&Regex>, sort: SortBy) -> anyhow::Result<()> {
+    if !path.exists() {
+        println!(
+            "No history yet, {} does not exist. Uploads are recorded there by `toc send`.",
+            path.display()
+        );
+        return Ok(());
+    }
+
+    let mut rows = vec![];
+    for (sent_at, entry) in read(path)? {
+        let date = sent_at
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M")
+            .to_string();
+        if let Some(filter) = filter {
+            if !filter.is_match(&entry.url) && !filter.is_match(&date) {
+                continue;
+            }
+        }
+        rows.push((sent_at, date, entry));
+    }
+
+    match sort {
+        SortBy::Time => rows.sort_by_key(|row| Reverse(row.0)),
+        SortBy::Size => rows.sort_by_key(|row| Reverse(row.2.bytes)),
+        SortBy::Code => rows.sort_by(|a, b| a.2.code.cmp(&b.2.code)),
+    }
+
+    if rows.is_empty() {
+        println!("No uploads found.");
+        return Ok(());
+    }
+
+    let code_width = rows.iter().map(|(_, _, e)| e.code.len()).max().unwrap_or(0);
+    let url_width = rows.iter().map(|(_, _, e)| e.url.len()).max().unwrap_or(0);
+    println!(
+        "{:code_width$}  {:url_width$}  {:16}  {:>5}  {:>8}",
+        "CODE", "URL", "SENT", "FILES", "SIZE"
+    );
+    for (_, date, entry) in rows {
+        println!(
+            "{:code_width$}  {:url_width$}  {:16}  {:>5}  {:>8}",
+            entry.code,
+            entry.url,
+            date,
+            entry.files,
+            common::format_bytes(entry.bytes)
+        );
+    }
+    Ok(())
+}
@@ -1,30 +1,38 @@
 use anyhow::Context;
+use chrono::TimeZone;
 use clap::{Parser, Subcommand};
-use common::{EncryptedWriter, TarHash, TarPassword};
+use common::{ProgressBar, TarPassword};
 use config::Config;
+use piper_client::{Client, Download, Progress, Protocol, ReceiveOptions, SendOptions};
+use serde::Serialize;
 use std::{
-    fmt::Display,
-    fs::Permissions,
     io::{Read, Write},
-    os::unix::prelude::PermissionsExt,
     path::{Path, PathBuf},
     str::FromStr,
 };
 
 mod config;
+mod history;
+mod watch;
 
 #[derive(Debug, Parser)]
+// `toc CODE send ...` still sends with that code.
+#[command(subcommand_precedence_over_arg = true)]
 struct Cli {
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
 
+    /// Only print the url after sending
+    #[arg(short, long)]
+    quiet: bool,
+
     #[arg(short, long, value_name = "FILE")]
     config: Option<PathBuf>,
 
     #[arg(short = 'H', long, value_name = "HOST")]
     host: Option<String>,
     #[arg(short, long, value_parser = procotol_parser)]
-    protocol: Option<config::Protocol>,
+    protocol: Option<Protocol>,
     #[arg(short, long, value_name = "TOKEN")]
     token: Option<String>,
 
@@ -40,11 +48,57 @@ struct Cli {
     #[arg(short, long)]
     no_history_file: bool,
 
+    /// Pipe the received tar stream into COMMAND (run with `sh -c`) instead of extracting it
+    #[arg(long, value_name = "COMMAND")]
+    pipe_to: Option<String>,
+
+    /// Check the extracted files against the checksums of the server afterwards
+    #[arg(long)]
+    verify: bool,
+
+    /// Keep the modification times of the sent files, the default
+    #[arg(long, overrides_with = "no_preserve_mtime")]
+    preserve_mtime: bool,
+
+    /// Give received files the current time instead
+    #[arg(long, overrides_with = "preserve_mtime")]
+    no_preserve_mtime: bool,
+
+    /// Receive this many of the given codes at the same time
+    #[arg(long, value_name = "N")]
+    parallel: Option<usize>,
+
     #[clap(subcommand)]
     subcmd: Option<Commands>,
 
+    /// Several codes are received one after another, each into a directory
+    /// named after its number. Without a command or codes, the codes in
+    /// TOC_CODE are received
     #[arg(value_parser = tar_password_parser)]
-    code: Option<TarUrl>,
+    codes: Vec<TarUrl>,
+}
+
+impl Cli {
+    /// For the commands that work with a single code.
+    fn code(&self) -> anyhow::Result<Option<TarUrl>> {
+        match self.codes.as_slice() {
+            [] => Ok(None),
+            [code] => Ok(Some(code.clone())),
+            _ => anyhow::bail!("Only one code can be given for this command."),
+        }
+    }
+
+    /// Uploads are recorded unless `--no-history-file` is given.
+    fn history_file(&self) -> Option<PathBuf> {
+        if self.no_history_file {
+            return None;
+        }
+        Some(
+            self.history_file
+                .clone()
+                .unwrap_or_else(config::history_path),
+        )
+    }
 }
 
 #[derive(Debug, Subcommand)]
@@ -53,8 +107,52 @@ enum Commands {
     Send {
         /// lists test values
         files: Vec<PathBuf>,
+        /// Write a JSON receipt of the upload to FILE, `-` for stdout
+        #[arg(long, value_name = "FILE")]
+        receipt: Option<PathBuf>,
+        /// Check afterwards that the server stored the whole upload
+        #[arg(long)]
+        verify_upload: bool,
+        /// Print the BLAKE3 hash of the archive, as a fingerprint of the content
+        #[arg(long)]
+        hash_output: bool,
+        /// What to do when two files end up at the same path in the archive
+        #[arg(long, value_enum, default_value_t)]
+        on_duplicate: OnDuplicate,
+        #[command(flatten)]
+        walk: WalkArgs,
     },
     Login,
+    /// Shows the state of an upload without downloading it
+    Info {
+        #[arg(value_parser = tar_password_parser)]
+        code: TarUrl,
+        #[arg(long, value_enum, default_value_t)]
+        output_format: OutputFormat,
+    },
+    /// Makes a short link to an upload. The server keeps the code for it,
+    /// so anyone with access to the server can open the upload
+    Alias {
+        #[arg(value_parser = tar_password_parser)]
+        code: TarUrl,
+        /// 3 to 32 of a-z, 0-9 and inner dashes, random if left out
+        slug: Option<String>,
+    },
+    /// Lists the uploads recorded in the history file
+    ListCodes {
+        /// Only show uploads whose url or date matches REGEX
+        #[arg(long, value_name = "REGEX")]
+        filter: Option<regex::Regex>,
+        #[arg(long, value_enum, default_value_t)]
+        sort: history::SortBy,
+    },
+    /// Sends DIR again whenever files in it change, each time with a new code
+    Watch {
+        dir: PathBuf,
+        /// Wait until nothing changed for this long before sending
+        #[arg(long, value_name = "MS")]
+        debounce_ms: Option<u64>,
+    },
     Encrypt {
         #[arg(long)]
         input: Option<PathBuf>,
@@ -69,19 +167,48 @@ enum Commands {
     },
 }
 
+/// How `send` goes through directories.
+#[derive(Debug, Clone, Copy, Default, clap::Args)]
+struct WalkArgs {
+    /// Follow symlinks inside the directories instead of leaving them out
+    #[arg(long)]
+    follow_symlinks: bool,
+    /// Go at most N levels below the given paths
+    #[arg(long, value_name = "N")]
+    max_depth: Option<usize>,
+    /// Pack files sorted by path, for reproducible archives
+    #[arg(long)]
+    sort_files: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+enum OnDuplicate {
+    #[default]
+    Error,
+    /// Keep the first file, warn about the others
+    Skip,
+}
+
 #[derive(Debug, Clone)]
 struct TarUrl {
-    protocol: Option<config::Protocol>,
+    protocol: Option<Protocol>,
     host: Option<String>,
     code: TarPassword,
 }
 
-fn procotol_parser(p: &str) -> Result<config::Protocol, String> {
+fn procotol_parser(p: &str) -> Result<Protocol, String> {
     match p.to_ascii_lowercase().as_str() {
-        "https" => Ok(config::Protocol::Https),
-        "http" => Ok(config::Protocol::Http),
-        "wss" => Ok(config::Protocol::Https),
-        "ws" => Ok(config::Protocol::Http),
+        "https" => Ok(Protocol::Https),
+        "http" => Ok(Protocol::Http),
+        "wss" => Ok(Protocol::Wss),
+        "ws" => Ok(Protocol::Ws),
         _ => Err(format!("Unknown protocol: {}", p)),
     }
 }
@@ -133,8 +260,43 @@ fn main() -> anyhow::Result<()> {
     }
 
     match &cli.subcmd {
-        Some(Commands::Send { files }) => {
-            send(&cli, files)?;
+        Some(Commands::Send {
+            files,
+            receipt,
+            verify_upload,
+            hash_output,
+            on_duplicate,
+            walk,
+        }) => {
+            send(
+                &cli,
+                files,
+                receipt.as_deref(),
+                *verify_upload,
+                *hash_output,
+                *on_duplicate,
+                *walk,
+            )?;
+        }
+        Some(Commands::Info {
+            code,
+            output_format,
+        }) => {
+            info(&cli, code, *output_format)?;
+        }
+        Some(Commands::Alias { code, slug }) => {
+            let client = client_for(&cli, Some(code))?;
+            println!("{}", client.alias(&code.code, slug.as_deref())?);
+            eprintln!("The server now stores the code of this upload.");
+        }
+        Some(Commands::Watch { dir, debounce_ms }) => {
+            watch::watch(&cli, dir, *debounce_ms)?;
+        }
+        Some(Commands::ListCodes { filter, sort }) => {
+            let path = cli
+                .history_file()
+                .ok_or_else(|| anyhow::anyhow!("The history file is turned off."))?;
+            history::list_codes(&path, filter.as_ref(), *sort)?;
         }
         Some(Commands::Login) => {
             let file = Config {
@@ -152,40 +314,45 @@ fn main() -> anyhow::Result<()> {
         }
         Some(Commands::Decrypt { input, output }) => {
             let code = cli
-                .code
+                .code()?
                 .ok_or_else(|| anyhow::anyhow!("No code provided."))?;
-            let mut input = get_read_stream(&input.clone().unwrap_or_else(|| PathBuf::from("-")))?;
-            let mut output =
-                get_write_stream(&output.clone().unwrap_or_else(|| PathBuf::from("-")))?;
-
-            let mut reader =
-                common::EncryptedReader::new(&mut input, code.code.to_string().as_bytes());
-            std::io::copy(&mut reader, &mut output)?;
+            let input = get_read_stream(&input.clone().unwrap_or_else(|| PathBuf::from("-")))?;
+            let output = get_write_stream(&output.clone().unwrap_or_else(|| PathBuf::from("-")))?;
+            Client::decrypt_stream(input, output, &code.code)?;
         }
         Some(Commands::Encrypt { input, output }) => {
-            let code = cli.code.map(|c| c.code).unwrap_or_else(|| {
+            let code = cli.code()?.map(|c| c.code).unwrap_or_else(|| {
                 let pwd = TarPassword::generate();
                 eprintln!("Generated code: {}", pwd);
                 pwd
             });
-            let mut input = get_read_stream(&input.clone().unwrap_or_else(|| PathBuf::from("-")))?;
-            let mut output =
-                get_write_stream(&output.clone().unwrap_or_else(|| PathBuf::from("-")))?;
-
-            let mut writer = common::EncryptedWriter::new(&mut output, code.to_string().as_bytes());
-            std::io::copy(&mut input, &mut writer)?;
-        }
-        None if cli.code.is_some() => {
-            receive(&cli)?;
+            let input = get_read_stream(&input.clone().unwrap_or_else(|| PathBuf::from("-")))?;
+            let output = get_write_stream(&output.clone().unwrap_or_else(|| PathBuf::from("-")))?;
+            Client::encrypt_stream(input, output, &code)?;
         }
         None => {
-            println!("No action specified. See --help for usage.");
-            std::process::exit(1);
+            if cli.codes.is_empty() {
+                cli.codes = codes_from_env()?;
+            }
+            receive(&cli)?;
         }
     }
     Ok(())
 }
 
+/// For scripts, `export TOC_CODE=$(...); toc`. Several codes are separated
+/// by whitespace.
+fn codes_from_env() -> anyhow::Result<Vec<TarUrl>> {
+    let codes = std::env::var("TOC_CODE").unwrap_or_default();
+    if codes.trim().is_empty() {
+        anyhow::bail!("No code given and TOC_CODE is not set. See --help for usage.");
+    }
+    codes
+        .split_whitespace()
+        .map(|code| tar_password_parser(code).map_err(|e| anyhow::anyhow!("TOC_CODE: {e}")))
+        .collect()
+}
+
 fn get_read_stream(path: &PathBuf) -> anyhow::Result<Box<dyn Read>> {
     if path.display().to_string() == "-" {
         Ok(Box::new(std::io::stdin()))
@@ -208,372 +375,363 @@ fn get_write_stream(path: &PathBuf) -> anyhow::Result<Box<dyn Write>> {
     }
 }
 
-fn send(cli: &Cli, files: &[PathBuf]) -> anyhow::Result<()> {
-    let mut files_out = vec![];
-    for file in files {
-        collect_files(file, &mut files_out)?;
-    }
-    const TAR_HEADER_SIZE: usize = 512;
-    let total_size = files_out
-        .iter()
-        .map(|(_, s, _)| *s + TAR_HEADER_SIZE)
-        .sum::<usize>();
-
-    let base = if files.len() == 1 {
-        if files[0].is_dir() {
-            Some(files[0].to_path_buf())
-        } else if files[0].is_file() {
-            Some(files[0].parent().unwrap().to_path_buf())
-        } else {
-            None
-        }
-    } else {
-        None
-    };
-
-    let code = cli.code.clone().unwrap_or_else(|| TarUrl {
-        code: TarPassword::generate(),
-        host: None,
-        protocol: None,
-    });
-
-    if cli.verbose > 0 {
-        for (path, size, _) in &files_out {
-            println!("{} ({})", path.display(), size);
-        }
-        println!("Total size: {}", total_size);
-        println!("base: {:?}", base);
-    }
+/// Written by `send --receipt`, so later jobs can find the upload.
+#[derive(Debug, Serialize)]
+struct Receipt {
+    code: String,
+    url: String,
+    expires_at: Option<String>,
+    files: Vec<String>,
+    total_bytes: u64,
+    sent_at: String,
+}
 
+/// The server of `code`, else the configured one.
+fn client_for(cli: &Cli, code: Option<&TarUrl>) -> anyhow::Result<Client> {
     let host = code
-        .host
-        .as_ref()
-        .or(cli.host.as_ref())
+        .and_then(|code| code.host.clone())
+        .or_else(|| cli.host.clone())
         .ok_or_else(|| anyhow::anyhow!("No host specified."))?;
-
     let protocol = code
-        .protocol
+        .and_then(|code| code.protocol)
         .or(cli.protocol)
-        .unwrap_or(config::Protocol::Https);
-
-    let token = cli
-        .token
-        .as_ref()
-        .ok_or_else(|| anyhow::anyhow!("No token specified."))?;
-
-    let agent = ureq::agent();
-
-    let code_hash = TarHash::from_tarid(&code.code, host);
-
-    let url = format!("{}://{}/raw/{}/", protocol, host, code_hash);
+        .unwrap_or(Protocol::Https);
+    Ok(Client::new(host, protocol, cli.token.clone()))
+}
 
+/// Returns the url to share.
+fn send(
+    cli: &Cli,
+    files: &[PathBuf],
+    receipt: Option<&Path>,
+    verify_upload: bool,
+    hash_output: bool,
+    on_duplicate: OnDuplicate,
+    walk: WalkArgs,
+) -> anyhow::Result<String> {
+    // JSON on stdout replaces the normal output.
+    let receipt_to_stdout = receipt.map(|p| p == Path::new("-")).unwrap_or(false);
+    let show_progress = !receipt_to_stdout && !cli.quiet;
+
+    let given_code = cli.code()?;
+    let client = client_for(cli, given_code.as_ref())?;
+
+    let sent_at = chrono::Utc::now();
+    let mut progress = ProgressBar::new(None).with_visible(show_progress);
+
+    let sent = client.send(
+        files,
+        SendOptions {
+            code: given_code.map(|code| code.code),
+            on_duplicate: match on_duplicate {
+                OnDuplicate::Error => piper_client::OnDuplicate::Error,
+                OnDuplicate::Skip => piper_client::OnDuplicate::Skip,
+            },
+            verify_upload,
+            follow_symlinks: walk.follow_symlinks,
+            max_depth: walk.max_depth,
+            sort_files: walk.sort_files,
+            on_url: Some(&mut |url| {
+                if show_progress {
+                    println!("\n\n{url}\n\n");
+                }
+            }),
+            progress: Some(&mut |p| progress.set(p.done, total(p), p.path)),
+        },
+    )?;
+
+    for path in &sent.skipped {
+        eprintln!(
+            "Warning: Skipped {}, its path is already in the archive.",
+            path.display()
+        );
+    }
+    for path in &sent.skipped_links {
+        if walk.follow_symlinks {
+            eprintln!("Warning: Skipped {}, it loops back.", path.display());
+        } else {
+            eprintln!(
+                "Warning: Skipped symlink {}, see --follow-symlinks.",
+                path.display()
+            );
+        }
+    }
     if cli.verbose > 0 {
-        println!("Downloading from {}", url);
+        println!("Uploaded to {}", client.raw_url(&sent.code));
+        if let Some(id) = &sent.upload_id {
+            println!("Upload id: {}", id);
+        }
     }
 
-    let (writer, reader) = common::create_pipe();
-    let mut writer = EncryptedWriter::new(writer, code.code.to_string().as_bytes());
-
-    std::thread::scope(|s| {
-        let handle_a = s.spawn(|| {
-            let _response = agent
-                .post(&url)
-                .set("Authorization", &format!("Bearer {}", token))
-                .send(reader)
-                .context("Failed to send request.")?;
-            Ok::<(), anyhow::Error>(())
-        });
-
-        println!("\n\n{protocol}://{host}/{}/\n\n", code.code);
-
-        let mut progress = ProgressBar::new(total_size as u64);
-
-        let mut tar = tar::Builder::new(&mut writer);
-        for (src_path, size, is_dir) in files_out {
-            let mut header = tar::Header::new_gnu();
-
-            let mut p = if let Some(base) = &base {
-                src_path.strip_prefix(&base).unwrap()
-            } else {
-                &src_path
-            }
-            .display()
-            .to_string();
-            if p.is_empty() {
-                continue;
-            }
-
-            if is_dir {
-                p += "/";
-            }
-
-            if cli.verbose > 0 {
-                println!("Adding {} ({})", p, size);
-            }
+    let elapsed = progress.elapsed();
+    let share_url = sent.url.clone();
+    let expires_at = sent
+        .expires_at
+        .and_then(|t| chrono::Utc.timestamp_opt(t, 0).single());
+
+    if cli.quiet && !receipt_to_stdout {
+        println!("{share_url}");
+    } else if !receipt_to_stdout {
+        let secs = elapsed.as_secs_f64();
+        let rate = sent.bytes as f64 / secs.max(0.001);
+        let expires = expires_at
+            .map(|t| (t - chrono::Utc::now()).num_seconds().max(0) as u64)
+            .map(common::human_duration)
+            .unwrap_or_else(|| "unknown".to_string());
+        println!(
+            "\nSent {} files ({}) in {secs:.1}s ({}). Code: {}. URL: {share_url}. Expires: {expires}.",
+            sent.files.len(),
+            common::format_bytes(sent.bytes),
+            common::format_speed(rate),
+            sent.code,
+        );
+        println!("\ncurl '{share_url}' | tar -xkvf -\n");
+    }
 
-            if p.len() > 100 {
-                p = p[..50].to_string() + &p[p.len() - 50..];
-                eprint!("Warning: Path {} is too long. Triming.", p);
-            }
+    if hash_output {
+        let hash = sent
+            .plaintext_hash
+            .ok_or_else(|| anyhow::anyhow!("The server encrypted, there is no hash."))?;
+        let hex = hash.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        // Keeps a JSON receipt on stdout parseable.
+        if receipt_to_stdout {
+            eprintln!("BLAKE3: {hex}");
+        } else {
+            println!("BLAKE3: {hex}");
+        }
+    }
 
-            header.set_path(p)?;
-
-            progress.update(TAR_HEADER_SIZE as _, src_path.display());
-            if is_dir {
-                header.set_size(0);
-                header.set_cksum();
-                tar.append(&header, std::io::empty())?;
-            } else {
-                let file = std::fs::File::open(&src_path)?;
-                let mode = file.metadata()?.permissions().mode();
-                let time = file.metadata()?.modified()?;
-                header.set_size(size as u64);
-                header.set_mode(mode);
-                header.set_mtime(time.duration_since(std::time::UNIX_EPOCH)?.as_secs());
-                header.set_cksum();
-                tar.append(&header, progress.reader(src_path.display(), file))?;
-            }
+    if let Some(path) = cli.history_file() {
+        let entry = history::HistoryEntry {
+            code: sent.code.to_string(),
+            url: share_url.clone(),
+            sent_at: sent_at.to_rfc3339(),
+            files: sent.files.len(),
+            bytes: sent.bytes,
+        };
+        // The upload went through, a missing history line is not worth failing for.
+        if let Err(e) = history::append(&path, &entry) {
+            eprintln!(
+                "Warning: Failed to write history {}: {:#}",
+                path.display(),
+                e
+            );
         }
-        tar.finish()?;
+    }
 
-        println!("\n\n{protocol}://{host}/{}/\n\n", code.code);
-        drop(tar);
-        drop(writer);
-        handle_a.join().unwrap()?;
-        Ok::<(), anyhow::Error>(())
-    })
+    if let Some(receipt) = receipt {
+        let json = serde_json::to_string_pretty(&Receipt {
+            code: sent.code.to_string(),
+            url: share_url.clone(),
+            expires_at: expires_at.map(|t| t.to_rfc3339()),
+            files: sent.files,
+            total_bytes: sent.bytes,
+            sent_at: sent_at.to_rfc3339(),
+        })?;
+
+        if receipt_to_stdout {
+            println!("{json}");
+        } else {
+            std::fs::write(receipt, json + "\n")
+                .with_context(|| format!("Failed to write receipt {}", receipt.display()))?;
+        }
+    }
+    Ok(share_url)
 }
 
-fn receive(cli: &Cli) -> anyhow::Result<()> {
-    let code = cli.code.clone().unwrap();
-
-    let host = code
-        .host
-        .as_ref()
-        .or(cli.host.as_ref())
-        .ok_or_else(|| anyhow::anyhow!("No host specified."))?;
-    let protocol = code
-        .protocol
-        .or(cli.protocol)
-        .unwrap_or(config::Protocol::Https);
-
-    let agent = ureq::agent();
-
-    let code_hash = TarHash::from_tarid(&code.code, host);
-
-    let url = format!("{}://{}/raw/{}/", protocol, host, code_hash);
-    if cli.verbose > 0 {
-        println!("Downloading from {}", url);
+fn info(cli: &Cli, code: &TarUrl, format: OutputFormat) -> anyhow::Result<()> {
+    let client = client_for(cli, Some(code))?;
+    let info = client.info(&code.code)?;
+    if let OutputFormat::Json = format {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
+    if !info.exists {
+        anyhow::bail!("Upload not found.");
     }
 
-    let response = match agent.get(&url).call() {
-        Ok(r) => r,
-        Err(ureq::Error::Status(404, _)) => {
-            println!("Repo not found.");
-            std::process::exit(1);
-        }
-        Err(ureq::Error::Status(code, response)) => {
-            println!("Server returned status code: {}", code);
-            let s = response.into_string()?;
-            println!("{}", s);
-            std::process::exit(1);
-        }
-        Err(e) => {
-            return Err(e.into());
-        }
+    let local = |time: &Option<String>| {
+        time.as_deref()
+            .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+            .map(|t| t.with_timezone(&chrono::Local))
+    };
+    let created = local(&info.created_at)
+        .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let expires = local(&info.expires_at)
+        .map(|t| {
+            let left = (t - chrono::Local::now()).num_seconds().max(0) as u64;
+            format!(
+                "{} (in {})",
+                t.format("%Y-%m-%d %H:%M"),
+                common::human_duration(left)
+            )
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+    let files = match (info.file_count, info.content_bytes) {
+        (Some(count), Some(bytes)) => format!("{count} ({})", common::format_bytes(bytes)),
+        _ => "unknown".to_string(),
     };
 
-    let content_length = response
-        .header("Content-Length")
-        .and_then(|s| s.parse::<u64>().ok())
-        .unwrap_or(0);
-
-    let reader = response.into_reader();
-    let reader = common::EncryptedReader::new(reader, code.code.to_string().as_bytes());
+    println!("URL:     {}", client.share_url(&code.code));
+    println!("Created: {created}");
+    println!("Expires: {expires}");
+    println!("Files:   {files}");
+    println!("Stored:  {}", common::format_bytes(info.size_bytes));
+    println!(
+        "State:   {}",
+        if info.finished {
+            "finished"
+        } else {
+            "still uploading"
+        }
+    );
+    Ok(())
+}
 
-    let mut tar = tar::Archive::new(reader);
+fn receive(cli: &Cli) -> anyhow::Result<()> {
+    if cli.verify && cli.pipe_to.is_some() {
+        anyhow::bail!("--verify needs extracted files, it can't be used with --pipe-to.");
+    }
     let destination = cli
         .destination
         .clone()
         .unwrap_or_else(|| PathBuf::from("."));
-    let overwrite = cli.overwrite;
-
-    let mut progress = ProgressBar::new(content_length);
-
-    println!(); // For progress bar
-    let mut buf = vec![0; 128 * 1024];
-    for entry in tar.entries()? {
-        let mut file = entry?;
-        let display = file.path()?.display().to_string();
-        let file_destination = destination.join(file.path()?);
-
-        progress.update(512, &display);
 
-        if content_length == 0 {
-            progress.total += 512;
-            progress.total += file.header().size().unwrap_or(0);
-
-            if cli.verbose > 0 {
-                println!("New Total Size: {}", progress.total);
-            }
-        }
+    if let [code] = cli.codes.as_slice() {
+        return receive_one(cli, code, &destination, true);
+    }
 
-        if display == "./" || display == "." {
-            // Current directory does not need to be created
-            continue;
+    // One directory per code, named after the number in front.
+    let mut jobs = vec![];
+    for code in &cli.codes {
+        let code_str = code.code.to_string();
+        let number = code_str.split('-').next().unwrap().to_string();
+        if let Some((other, _)) = jobs
+            .iter()
+            .find(|(_, dir)| *dir == destination.join(&number))
+        {
+            anyhow::bail!(
+                "{} and {} would both be received into {}.",
+                other,
+                code_str,
+                number
+            );
         }
+        jobs.push((code_str, destination.join(number)));
+    }
 
-        if file_destination.exists() && !overwrite {
-            println!("Skipping because it already exists: {}", display);
-            loop {
-                let n = file.read(&mut buf)?;
-                if n == 0 {
-                    break;
+    let parallel = cli.parallel.unwrap_or(1).max(1);
+    let queue = std::sync::Mutex::new(cli.codes.iter().zip(&jobs));
+    let failed = std::sync::Mutex::new(vec![]);
+    std::thread::scope(|s| {
+        for _ in 0..parallel {
+            s.spawn(|| loop {
+                let next = queue.lock().unwrap().next();
+                let (code, (code_str, dir)) = match next {
+                    Some(job) => job,
+                    None => break,
+                };
+                println!("Receiving {} into {}", code_str, dir.display());
+                // Progress bars of simultaneous downloads would overwrite each other.
+                let result = std::fs::create_dir_all(dir)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|_| receive_one(cli, code, dir, parallel == 1));
+                match result {
+                    Ok(()) => println!("Received {}", code_str),
+                    Err(e) => {
+                        println!("Failed to receive {}: {:#}", code_str, e);
+                        failed.lock().unwrap().push(code_str.clone());
+                    }
                 }
-                progress.update(n as u64, &display);
-            }
-            continue;
+            });
         }
+    });
 
-        let perm = file.header().mode().unwrap_or(0o644);
-        if file.header().entry_type().is_dir() {
-            std::fs::create_dir_all(&file_destination)?;
-            std::fs::set_permissions(&file_destination, Permissions::from_mode(perm))?;
-        } else if file.header().entry_type().is_file() {
-            let mut new_file = if overwrite {
-                std::fs::OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(&file_destination)
-            } else {
-                std::fs::OpenOptions::new()
-                    .write(true)
-                    .create_new(true)
-                    .open(&file_destination)
-            }
-            .with_context(|| format!("Failed to create file {}", file_destination.display()))?;
-
-            loop {
-                let n = file.read(&mut buf)?;
-                if n == 0 {
-                    break;
-                }
-                new_file.write_all(&buf[..n])?;
-                progress.update(n as u64, &display);
-            }
-        }
+    let failed = failed.into_inner().unwrap();
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "{} of {} downloads failed: {}",
+            failed.len(),
+            jobs.len(),
+            failed.join(", ")
+        );
     }
-
-    println!("\nDone.");
     Ok(())
 }
 
-fn collect_files(root: &Path, out: &mut Vec<(PathBuf, usize, bool)>) -> anyhow::Result<()> {
-    if root.is_dir() {
-        out.push((root.to_path_buf(), 0, true));
-        for entry in std::fs::read_dir(root)? {
-            let entry = entry?;
-            let path = entry.path();
-            collect_files(&path, out)?;
-        }
-        Ok(())
-    } else if root.is_file() {
-        let len = std::fs::metadata(root)?.len() as usize;
-        out.push((root.to_path_buf(), len, false));
-        Ok(())
-    } else {
-        Err(anyhow::anyhow!("Invalid path: {}", root.display()))
+fn receive_one(
+    cli: &Cli,
+    code: &TarUrl,
+    destination: &Path,
+    show_progress: bool,
+) -> anyhow::Result<()> {
+    let client = client_for(cli, Some(code))?;
+    if cli.verbose > 0 {
+        println!("Downloading from {}", client.raw_url(&code.code));
     }
-}
-
-const DELETE_LINE: &str = "\x1B[2K\r";
 
-struct ProgressBar {
-    last_update: std::time::Instant,
-    current: u64,
-    last_progress: u64,
-    total: u64,
-}
+    if let Some(command) = &cli.pipe_to {
+        return pipe_to(command, client.download(&code.code)?);
+    }
 
-struct ProgressReader<'a, D, R> {
-    bar: &'a mut ProgressBar,
-    display: D,
-    inner: R,
-}
+    let mut progress = ProgressBar::new(None).with_visible(show_progress);
 
-impl<'a, D: Display, R: Read> Read for ProgressReader<'a, D, R> {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let n = self.inner.read(buf)?;
-        self.bar.update(n as u64, &self.display);
-        Ok(n)
+    if show_progress {
+        println!(); // For progress bar
+    }
+    let received = client.receive(
+        &code.code,
+        destination,
+        ReceiveOptions {
+            overwrite: cli.overwrite,
+            verify: cli.verify,
+            preserve_mtime: !cli.no_preserve_mtime,
+            progress: Some(&mut |p| progress.set(p.done, total(p), p.path)),
+        },
+    )?;
+
+    for path in &received.skipped {
+        println!("Skipped because it already exists: {}", path);
+    }
+    if show_progress {
+        println!("\nDone.");
     }
+    if cli.verify {
+        println!("Verified {} files.", received.verified);
+    }
+    Ok(())
 }
 
-impl ProgressBar {
-    fn new(total: u64) -> Self {
-        Self {
-            last_update: std::time::Instant::now(),
-            current: 0,
-            last_progress: 0,
-            total,
-        }
+fn pipe_to(command: &str, download: Download) -> anyhow::Result<()> {
+    let mut child = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to start `{}`", command))?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    let total = (download.content_length > 0).then_some(download.content_length);
+    let mut progress = ProgressBar::new(total);
+    let copied = std::io::copy(&mut progress.reader("", download), &mut stdin);
+    // Close stdin so the command sees the end of the stream.
+    drop(stdin);
+
+    let status = child.wait()?;
+    match copied {
+        // The command may exit before reading everything, its status tells if that is fine.
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+        Err(e) => return Err(e.into()),
+        Ok(_) => {}
     }
-
-    fn reader<D: Display, R: Read>(&mut self, display: D, inner: R) -> ProgressReader<D, R> {
-        ProgressReader {
-            bar: self,
-            display,
-            inner,
-        }
+    if !status.success() {
+        anyhow::bail!("`{}` failed with {}", command, status);
     }
+    Ok(())
+}
 
-    fn update<D: Display>(&mut self, progress: u64, message: D) {
-        self.current += progress;
-
-        let now = std::time::Instant::now();
-        let elapsed = now.duration_since(self.last_update).as_secs_f32();
-        if elapsed < 0.5 {
-            return;
-        }
-
-        let speed = (self.current - self.last_progress) as f32 / (elapsed + 0.0001);
-        self.last_progress = self.current;
-        self.last_update = now;
-
-        let percent = if self.current < self.total && self.total > 0 {
-            (self.current as f64 / self.total as f64) * 100.0
-        } else {
-            100.0
-        };
-        let eta = if self.current < self.total && self.total > 0 && speed > 0.0 {
-            let remaining = self.total - self.current;
-            remaining as f32 / speed
-        } else {
-            0.0
-        };
-
-        let speed = if speed > 1024.0 * 1024.0 {
-            format!("{:.2} MB/s", speed / 1024.0 / 1024.0)
-        } else if speed > 1024.0 {
-            format!("{:.2} KB/s", speed / 1024.0)
-        } else {
-            format!("{:.2} B/s", speed)
-        };
-
-        let eta = if eta > 60.0 * 60.0 {
-            format!("{:.2} h", eta / 60.0 / 60.0)
-        } else if eta > 60.0 {
-            format!("{:.2} m", eta / 60.0)
-        } else {
-            format!("{:.2} s", eta)
-        };
-
-        let bar = (0..((percent / 5.0) as isize))
-            .map(|_| "=")
-            .collect::<String>();
-
-        print!("{DELETE_LINE}|{bar:20}|  {percent:02.0}%  {speed:10}  eta {eta:9} - {message}");
-        let _ = std::io::stdout().flush();
-    }
+/// The client reports 0 while the total is not known.
+fn total(progress: Progress) -> Option<u64> {
+    (progress.total > 0).then_some(progress.total)
 }
@@ -0,0 +1,84 @@
+use std::{
+    collections::VecDeque,
+    path::Path,
+    sync::mpsc::{channel, Receiver, RecvTimeoutError},
+    time::Duration,
+};
+
+use anyhow::Context;
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use crate::{Cli, OnDuplicate, WalkArgs};
+
+const DEFAULT_DEBOUNCE_MS: u64 = 2000;
+const RECENT_UPLOADS: usize = 5;
+
+/// Sends `dir` once and then again after every change, until interrupted.
+pub fn watch(cli: &Cli, dir: &Path, debounce_ms: Option<u64>) -> anyhow::Result<()> {
+    if !cli.codes.is_empty() {
+        anyhow::bail!("A code can't be given for watch, every upload gets a new one.");
+    }
+    if !dir.is_dir() {
+        anyhow::bail!("{} is not a directory.", dir.display());
+    }
+    let debounce = Duration::from_millis(debounce_ms.unwrap_or(DEFAULT_DEBOUNCE_MS));
+
+    let (tx, rx) = channel();
+    let mut watcher = notify::recommended_watcher(tx).context("Failed to watch directory.")?;
+    watcher
+        .watch(dir, RecursiveMode::Recursive)
+        .with_context(|| format!("Failed to watch {}", dir.display()))?;
+
+    let mut recent = VecDeque::with_capacity(RECENT_UPLOADS);
+    loop {
+        match crate::send(
+            cli,
+            &[dir.to_path_buf()],
+            None,
+            false,
+            false,
+            OnDuplicate::Error,
+            WalkArgs::default(),
+        ) {
+            Ok(url) => {
+                if recent.len() == RECENT_UPLOADS {
+                    recent.pop_front();
+                }
+                recent.push_back((chrono::Local::now(), url));
+                println!("Recent uploads:");
+                for (time, url) in &recent {
+                    println!("  [{}] {}", time.format("%H:%M:%S"), url);
+                }
+            }
+            // The next change may well go through again.
+            Err(e) => eprintln!("Upload failed: {:#}", e),
+        }
+
+        println!("Watching {} for changes...", dir.display());
+        wait_for_changes(&rx, debounce)?;
+    }
+}
+
+/// Blocks until files were created or modified and then nothing happened for `debounce`.
+fn wait_for_changes(
+    rx: &Receiver<notify::Result<notify::Event>>,
+    debounce: Duration,
+) -> anyhow::Result<()> {
+    let is_change = |event: notify::Result<notify::Event>| -> anyhow::Result<bool> {
+        Ok(matches!(
+            event?.kind,
+            EventKind::Create(_) | EventKind::Modify(_)
+        ))
+    };
+
+    while !is_change(rx.recv()?)? {}
+    loop {
+        match rx.recv_timeout(debounce) {
+            Ok(event) => {
+                is_change(event)?;
+            }
+            Err(RecvTimeoutError::Timeout) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}